@@ -0,0 +1,45 @@
+// allm/examples/compare_models.rs
+//
+// Capabilities and prices of the models allm knows about, as a
+// Markdown table (or CSV), plus the cheapest model with tool calling
+// on stderr.
+//
+//   cargo run --example compare_models
+//   cargo run --example compare_models -- --csv
+//
+// No API calls are made; the matrix comes from the model catalog.
+
+use allm::capabilities::ModelRequirements;
+use allm::config::{AllmConfig, ProviderConfig};
+use allm::AllmBackend;
+
+#[tokio::main]
+async fn main() -> Result<(), allm::Error>
+{   env_logger::init();
+
+    let provider = |name: &str| ProviderConfig
+    {   name: name.to_string()
+      , ..Default::default()
+    };
+    let config = AllmConfig
+    {   providers: vec![provider("mistral"), provider("cerebras")]
+      , ..Default::default()
+    };
+    let backend = AllmBackend::new_with_config(config).await?;
+
+    let matrix = backend.capability_matrix().await?;
+    match std::env::args().any(|arg| arg == "--csv")
+    {   true => print!("{}", matrix.to_csv())
+      , false => print!("{}", matrix.to_markdown_table())
+    }
+
+    let tools = ModelRequirements { tools: true, ..Default::default() };
+    match matrix.cheapest_for(tools)
+    {   Some(row) => eprintln!(
+          "\nCheapest with tools: {}/{}", row.provider.name(), row.model
+        )
+      , None => eprintln!("\nNo model supports tools")
+    }
+
+    backend.shutdown().await
+}
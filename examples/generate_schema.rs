@@ -0,0 +1,16 @@
+// allm/examples/generate_schema.rs
+//
+// Print the JSON Schema of allm's configuration, for editors:
+//
+//   cargo run --example generate_schema > allm-config-schema.json
+//
+// VS Code checks JSON configs against it with a `json.schemas` entry
+// in settings.json; Even Better TOML takes it through a `#:schema`
+// comment at the top of a TOML config.
+
+use allm::config::AllmConfig;
+
+fn main()
+{   let schema = AllmConfig::json_schema();
+    println!("{}", serde_json::to_string_pretty(&schema).expect("schema serializes"));
+}
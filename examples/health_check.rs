@@ -0,0 +1,37 @@
+// allm/examples/health_check.rs
+//
+// Run allm as a service with its health server:
+//
+//   cargo run --example health_check --features health-server
+//
+// then, from another shell:
+//
+//   curl -i http://localhost:8080/health
+//   curl http://localhost:8080/metrics
+//   curl http://localhost:8080/providers
+
+use allm::config::{AllmConfig, ProviderConfig};
+use allm::AllmBackend;
+
+#[tokio::main]
+async fn main() -> Result<(), allm::Error>
+{   env_logger::init();
+
+    let config = AllmConfig
+    {   providers: vec![ProviderConfig
+        {   name: "mistral".to_string()
+          , api_key: std::env::var("MISTRAL_API_KEY").ok()
+          , ..Default::default()
+        }]
+      , health_server_port: Some(8080)
+      , ..Default::default()
+    };
+    let backend = AllmBackend::new_with_config(config).await?;
+    if let Some(addr) = backend.health_server_addr()
+    {   println!("Health server on http://{}/health", addr);
+    }
+
+    println!("Press Ctrl-C to stop");
+    let _ = tokio::signal::ctrl_c().await;
+    backend.shutdown().await
+}
@@ -0,0 +1,48 @@
+// allm/examples/jsonrpc_server.rs
+//
+// Serve JSON-RPC 2.0 on stdin/stdout, one request per line:
+//
+//   MISTRAL_API_KEY=... cargo run --example jsonrpc_server --features jsonrpc_server
+//
+// Logs go to stderr, so stdout only carries responses. A Python
+// client driving it as a subprocess:
+//
+//   import json, subprocess
+//
+//   server = subprocess.Popen(
+//       ["cargo", "run", "-q", "--example", "jsonrpc_server",
+//        "--features", "jsonrpc_server"],
+//       stdin=subprocess.PIPE, stdout=subprocess.PIPE, text=True)
+//
+//   def call(method, params=None, id=1):
+//       request = {"jsonrpc": "2.0", "method": method, "id": id}
+//       if params is not None:
+//           request["params"] = params
+//       server.stdin.write(json.dumps(request) + "\n")
+//       server.stdin.flush()
+//       return json.loads(server.stdout.readline())
+//
+//   print(call("get_model_lists"))
+//   print(call("send_prompt",
+//              {"prompt": "Say hi", "model": "mistral-small-latest"}, id=2))
+//   call("shutdown", id=3)
+//   server.wait()
+
+use allm::config::{AllmConfig, ProviderConfig};
+use allm::AllmBackend;
+
+#[tokio::main]
+async fn main() -> Result<(), allm::Error>
+{   env_logger::init();
+
+    let config = AllmConfig
+    {   providers: vec![ProviderConfig
+        {   name: "mistral".to_string()
+          , api_key: std::env::var("MISTRAL_API_KEY").ok()
+          , ..Default::default()
+        }]
+      , ..Default::default()
+    };
+    let backend = AllmBackend::new_with_config(config).await?;
+    backend.run_jsonrpc_server().await
+}
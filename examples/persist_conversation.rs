@@ -0,0 +1,71 @@
+// allm/examples/persist_conversation.rs
+//
+// A conversation that survives restarts: the session is saved to disk
+// after each exchange and reloaded on the next run.
+//
+//   MISTRAL_API_KEY=... cargo run --example persist_conversation
+//
+// Type a line per turn; an empty line (or EOF) quits. Delete
+// `conversation.json` to start over.
+
+use std::io::BufRead;
+
+use allm::config::{AllmConfig, ProviderConfig};
+use allm::session::PruningStrategy;
+use allm::AllmBackend;
+
+const SESSION_FILE: &str = "conversation.json";
+const MODEL: &str = "mistral-small-latest";
+
+#[tokio::main]
+async fn main() -> Result<(), allm::Error>
+{   env_logger::init();
+
+    let config = AllmConfig
+    {   providers: vec![ProviderConfig
+        {   name: "mistral".to_string()
+          , api_key: std::env::var("MISTRAL_API_KEY").ok()
+          , ..Default::default()
+        }]
+      , ..Default::default()
+    };
+    let backend = AllmBackend::new_with_config(config).await?;
+
+    let session_id = match std::fs::read_to_string(SESSION_FILE)
+    {   Ok(json) => {
+          let id = backend.import_session(&json).await?;
+          println!("Resumed conversation from {}", SESSION_FILE);
+          id
+        }
+      , Err(_) => {
+          let mut rx = backend
+            .create_session(20, PruningStrategy::DropOldest)
+            .await?;
+          rx.recv().await.unwrap_or_else(|| {
+            Err(allm::Error::Other("Backend disconnected".to_string()))
+          })?
+        }
+    };
+
+    let stdin = std::io::stdin();
+    for line in stdin.lock().lines()
+    {   let prompt = line.map_err(|e| allm::Error::Other(e.to_string()))?;
+        if prompt.trim().is_empty()
+        {   break;
+        }
+        let mut rx = backend
+          .send_session_prompt(session_id, prompt, MODEL.to_string())
+          .await?;
+        match rx.recv().await
+        {   Some(Ok(reply)) => println!("{}", reply)
+          , Some(Err(e)) => eprintln!("Error: {}", e)
+          , None => break
+        }
+
+        let json = backend.export_session(session_id).await?;
+        std::fs::write(SESSION_FILE, json)
+          .map_err(|e| allm::Error::Other(e.to_string()))?;
+    }
+
+    backend.shutdown().await
+}
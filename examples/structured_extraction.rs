@@ -0,0 +1,54 @@
+// allm/examples/structured_extraction.rs
+//
+// Pull a typed record out of free text with `ask_structured`: the
+// model is asked for JSON matching a schema, and the reply is
+// deserialized into `PersonInfo`.
+//
+//   MISTRAL_API_KEY=... cargo run --example structured_extraction
+
+use allm::config::{AllmConfig, ProviderConfig};
+use allm::AllmBackend;
+
+const MODEL: &str = "mistral-small-latest";
+
+#[derive(Debug, serde::Deserialize)]
+struct PersonInfo
+{   name: String
+  , age: u32
+}
+
+#[tokio::main]
+async fn main() -> Result<(), allm::Error>
+{   env_logger::init();
+
+    let config = AllmConfig
+    {   providers: vec![ProviderConfig
+        {   name: "mistral".to_string()
+          , api_key: std::env::var("MISTRAL_API_KEY").ok()
+          , ..Default::default()
+        }]
+      , ..Default::default()
+    };
+    let backend = AllmBackend::new_with_config(config).await?;
+
+    let schema = serde_json::json!(
+    {   "type": "object"
+      , "properties":
+        {   "name": { "type": "string" }
+          , "age": { "type": "integer", "minimum": 0 }
+        }
+      , "required": ["name", "age"]
+      , "additionalProperties": false
+    });
+    let text = "Grace Hopper, who was 85 when she died in 1992, \
+      popularised the term debugging.";
+    let prompt = format!(
+      "Extract the person's name and age as JSON.\n\n{}", text
+    );
+    let person: PersonInfo = backend
+      .ask_structured(&prompt, MODEL, Some(schema))
+      .await?;
+    println!("{} was {}", person.name, person.age);
+
+    backend.shutdown().await
+}
@@ -0,0 +1,48 @@
+//! Sources of API keys for provider clients
+//!
+//! Provider actors ask their `ApiKeyProvider` for a key before every
+//! request, so short-lived credentials (OAuth, STS, ...) can be
+//! refreshed behind this trait without touching the clients.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Future returned by `ApiKeyProvider::current_key`
+pub type KeyFuture<'a> = Pin<Box<
+  dyn Future<Output = Result<String, crate::error::Error>> + Send + 'a
+>>;
+
+/// Key provider shared between a client and its owner
+pub type SharedKeyProvider = Arc<dyn ApiKeyProvider>;
+
+/// Supplies the key to use for the next request.
+///
+/// The future is boxed so providers can be stored as trait objects;
+/// implement it as `Box::pin(async move { ... })`.
+pub trait ApiKeyProvider: Send + Sync
+{   fn current_key(&self) -> KeyFuture<'_>;
+}
+
+/// Always returns the same key
+#[derive(Debug, Clone)]
+pub struct StaticKeyProvider
+{   key: String
+}
+
+impl StaticKeyProvider
+{   pub fn new(key: String) -> Self
+    {   StaticKeyProvider { key }
+    }
+
+    /// Shared provider for a fixed key
+    pub fn shared(key: String) -> SharedKeyProvider
+    {   Arc::new(StaticKeyProvider::new(key))
+    }
+}
+
+impl ApiKeyProvider for StaticKeyProvider
+{   fn current_key(&self) -> KeyFuture<'_>
+    {   Box::pin(async move { Ok(self.key.clone()) })
+    }
+}
@@ -0,0 +1,83 @@
+// allm/src/blocking.rs
+
+//! Blocking calls for code that is not async (`blocking` feature)
+//!
+//! `BlockingAllm` runs an `AllmBackend` on a runtime of its own and
+//! waits for each reply. It is for plain threads only: tokio panics
+//! when a runtime blocks inside another, so every method first checks
+//! for one and fails with `Error::Other` instead.
+
+use log::debug;
+
+use crate::client::AllmBackend;
+use crate::config::AllmConfig;
+use crate::error::Error;
+
+/// `AllmBackend` behind blocking methods
+pub struct BlockingAllm
+{   /// `None` once shut down
+    backend: Option<AllmBackend>
+  , /// `None` only while dropping
+    runtime: Option<tokio::runtime::Runtime>
+}
+
+impl BlockingAllm
+{   /// Start a backend for `config` on a new runtime
+    pub fn new(config: AllmConfig) -> Result<Self, Error>
+    {   check_not_async("BlockingAllm::new")?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+          .thread_name("allm-blocking")
+          .enable_all()
+          .build()
+          .map_err(|e| Error::Other(format!("Runtime not started: {}", e)))?;
+        let backend = runtime.block_on(AllmBackend::new_with_config(config))?;
+        debug!("BlockingAllm started");
+        Ok(BlockingAllm { backend: Some(backend), runtime: Some(runtime) })
+    }
+
+    /// `AllmBackend::ask`, waiting for the reply
+    pub fn send_prompt(&self, prompt: &str, model: &str)
+      -> Result<String, Error>
+    {   check_not_async("BlockingAllm::send_prompt")?;
+        let (Some(backend), Some(runtime)) = (&self.backend, &self.runtime)
+        else
+        {   return Err(Error::Other("Backend already shutdown".to_string()));
+        };
+        runtime.block_on(backend.ask(prompt, model))
+    }
+
+    /// Stop the backend and wait for it; dropping does the same
+    pub fn shutdown(mut self) -> Result<(), Error>
+    {   check_not_async("BlockingAllm::shutdown")?;
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<(), Error>
+    {   match (self.backend.take(), &self.runtime)
+        {   (Some(backend), Some(runtime)) => runtime.block_on(backend.shutdown())
+          , _ => Ok(())
+        }
+    }
+}
+
+impl Drop for BlockingAllm
+{   fn drop(&mut self)
+    {   if check_not_async("BlockingAllm::drop").is_ok()
+        {   let _ = self.stop();
+        } else if let Some(runtime) = self.runtime.take()
+        {   // Blocking here would panic; drop the backend's tasks instead
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Fail when called on a tokio runtime, where blocking on another
+/// would panic
+fn check_not_async(method: &str) -> Result<(), Error>
+{   match tokio::runtime::Handle::try_current()
+    {   Ok(_) => Err(Error::Other(format!(
+          "{} called from an async context; use AllmBackend there", method
+        )))
+      , Err(_) => Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+// allm/src/capabilities.rs
+
+//! Side-by-side comparison of the models a backend can route to
+//!
+//! `AllmBackend::capability_matrix` builds a `CapabilityMatrix` from
+//! the model catalog (see `register_models`), falling back to the
+//! built-in `default_model_info` of providers the catalog lacks.
+
+use serde::Serialize;
+use std::fmt::Write;
+
+/// One model of the matrix
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelCapabilityRow
+{   pub provider: crate::Provider
+  , pub model: String
+  , pub context_tokens: usize
+  , pub supports_tools: bool
+  , pub supports_streaming: bool
+  , /// Accepts image input, alone or combined with text
+    pub supports_vision: bool
+  , /// USD per million input tokens
+    pub cost_per_million_in: Option<f32>
+  , /// USD per million output tokens
+    pub cost_per_million_out: Option<f32>
+  , pub is_available: bool
+}
+
+impl ModelCapabilityRow
+{   pub fn from_model_info(info: &crate::ModelInfo) -> Self
+    {   let supports_vision = info.accepts(&crate::BaseModality::Image);
+        ModelCapabilityRow
+        {   provider: info.provider.clone()
+          , model: info.name.clone()
+          , context_tokens: info.max_context_tokens
+          , supports_tools: info.supports_tools
+          , supports_streaming: info.supports_streaming
+          , supports_vision
+          , cost_per_million_in: info.cost_per_million_input_tokens
+          , cost_per_million_out: info.cost_per_million_output_tokens
+          , is_available: info.is_available
+        }
+    }
+
+    /// Input plus output cost per million tokens, when both are known
+    pub fn cost_per_million(&self) -> Option<f32>
+    {   Some(self.cost_per_million_in? + self.cost_per_million_out?)
+    }
+
+    /// Whether the model is available and has everything
+    /// `requirements` asks for
+    pub fn meets(&self, requirements: &ModelRequirements) -> bool
+    {   self.is_available
+          && self.context_tokens >= requirements.min_context_tokens
+          && (self.supports_tools || !requirements.tools)
+          && (self.supports_streaming || !requirements.streaming)
+          && (self.supports_vision || !requirements.vision)
+    }
+}
+
+/// What `CapabilityMatrix::cheapest_for` looks for; the default
+/// accepts any available model
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelRequirements
+{   pub min_context_tokens: usize
+  , pub tools: bool
+  , pub streaming: bool
+  , pub vision: bool
+}
+
+/// Which listed models `AllmBackend::get_model_lists_filtered`
+/// returns; the default keeps every model with a catalog or built-in
+/// `ModelInfo`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelFilter
+{   pub requires_tools: bool
+  , /// Input the model accepts, alone or combined with text
+    pub requires_modality: Option<crate::BaseModality>
+  , /// USD; models without a known output cost fail it
+    pub max_cost_per_million_output: Option<f32>
+}
+
+impl ModelFilter
+{   pub fn matches(&self, info: &crate::ModelInfo) -> bool
+    {   (info.supports_tools || !self.requires_tools)
+          && self.requires_modality.as_ref()
+            .is_none_or(|modality| info.accepts(modality))
+          && self.max_cost_per_million_output.is_none_or(|max| {
+            info.cost_per_million_output_tokens
+              .is_some_and(|cost| cost <= max)
+          })
+    }
+}
+
+/// Models of every provider, ordered by provider and model name
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CapabilityMatrix
+{   pub rows: Vec<ModelCapabilityRow>
+}
+
+impl CapabilityMatrix
+{   /// Cheapest available model meeting `requirements`, by input
+    /// plus output cost; models without a known cost come last
+    pub fn cheapest_for(&self, requirements: ModelRequirements)
+      -> Option<&ModelCapabilityRow>
+    {   self.rows.iter()
+          .filter(|row| row.meets(&requirements))
+          .min_by(|a, b| {
+            match (a.cost_per_million(), b.cost_per_million())
+            {   (Some(a), Some(b)) => a.total_cmp(&b)
+              , (Some(_), None) => std::cmp::Ordering::Less
+              , (None, Some(_)) => std::cmp::Ordering::Greater
+              , (None, None) => std::cmp::Ordering::Equal
+            }
+          })
+    }
+
+    /// GitHub-flavored Markdown table, one row per model
+    pub fn to_markdown_table(&self) -> String
+    {   let mut out = String::from(
+          "| Provider | Model | Context | Tools | Streaming | Vision \
+           | $/M in | $/M out | Available |\n\
+           |---|---|---:|:-:|:-:|:-:|---:|---:|:-:|\n"
+        );
+        let mark = |yes: bool| if yes { "yes" } else { "no" };
+        for row in &self.rows
+        {   let _ = writeln!(
+              out, "| {} | {} | {} | {} | {} | {} | {} | {} | {} |",
+              row.provider.name(),
+              row.model.replace('|', "\\|"),
+              row.context_tokens,
+              mark(row.supports_tools),
+              mark(row.supports_streaming),
+              mark(row.supports_vision),
+              cost(row.cost_per_million_in, "-"),
+              cost(row.cost_per_million_out, "-"),
+              mark(row.is_available)
+            );
+        }
+        out
+    }
+
+    /// CSV with a header line; unknown costs are empty fields
+    pub fn to_csv(&self) -> String
+    {   let mut out = String::from(
+          "provider,model,context_tokens,supports_tools,supports_streaming,\
+           supports_vision,cost_per_million_in,cost_per_million_out,\
+           is_available\n"
+        );
+        for row in &self.rows
+        {   let _ = writeln!(
+              out, "{},{},{},{},{},{},{},{},{}",
+              row.provider.name(),
+              csv_field(&row.model),
+              row.context_tokens,
+              row.supports_tools,
+              row.supports_streaming,
+              row.supports_vision,
+              cost(row.cost_per_million_in, ""),
+              cost(row.cost_per_million_out, ""),
+              row.is_available
+            );
+        }
+        out
+    }
+}
+
+fn cost(value: Option<f32>, unknown: &str) -> String
+{   value.map(|v| v.to_string()).unwrap_or_else(|| unknown.to_string())
+}
+
+/// Quote a field holding a comma, quote or line break
+fn csv_field(value: &str) -> String
+{   if value.contains([',', '"', '\n', '\r'])
+    {   format!("\"{}\"", value.replace('"', "\"\""))
+    } else
+    {   value.to_string()
+    }
+}
+
+/// Plain-language account of one model, from its `ModelInfo` alone
+/// (see `AllmBackend::explain`)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelExplanation
+{   pub model_info: crate::ModelInfo
+  , /// `ModelInfo::to_human_description`
+    pub human_description: String
+  , /// What the model's modalities and features are good for
+    pub example_use_cases: Vec<String>
+  , /// Features the model lacks, or limits allm does not know
+    pub limitations: Vec<String>
+}
+
+/// Models taking this many tokens or more handle whole documents
+const LONG_CONTEXT_TOKENS: usize = 100_000;
+
+impl ModelExplanation
+{   pub fn from_model_info(info: crate::ModelInfo) -> Self
+    {   use crate::BaseModality;
+
+        let mut uses = vec![];
+        let mut limits = vec![];
+        if info.accepts(&BaseModality::Text)
+        {   uses.push("Chat, summarization and other text generation");
+        }
+        if info.accepts(&BaseModality::Image)
+        {   uses.push("Describing images and answering questions about them");
+        } else
+        {   limits.push("No image input");
+        }
+        if info.accepts(&BaseModality::Video)
+        {   uses.push("Understanding video");
+        }
+        if info.accepts(&BaseModality::File)
+        {   uses.push("Reading uploaded files");
+        }
+        if info.supports_tools
+        {   uses.push("Agents that call functions and tools");
+        } else
+        {   limits.push("No tool calling");
+        }
+        if info.max_context_tokens >= LONG_CONTEXT_TOKENS
+        {   uses.push("Long documents in a single prompt");
+        }
+        if info.supports_streaming
+        {   uses.push("Interactive replies streamed as they are generated");
+        } else
+        {   limits.push("No streaming");
+        }
+        if info.supports_raw_completion
+        {   uses.push("Raw text and code completion");
+        }
+        if info.supports_logprobs
+        {   uses.push("Scoring text with token log probabilities");
+        }
+        if info.max_context_tokens == 0
+        {   limits.push("Context window unknown");
+        }
+        if !info.is_available
+        {   limits.push("Currently unavailable");
+        }
+        ModelExplanation
+        {   human_description: info.to_human_description()
+          , example_use_cases: uses.into_iter().map(String::from).collect()
+          , limitations: limits.into_iter().map(String::from).collect()
+          , model_info: info
+        }
+    }
+}
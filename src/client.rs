@@ -1,10 +1,53 @@
 // Don't remove any comments.
 // allm/src/client.rs
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use tokio::sync::mpsc;
-use log::{debug, trace, error, info};
+use log::{debug, error, info, warn};
 use crate::AllmFoot;
+use crate::capabilities::{CapabilityMatrix, ModelCapabilityRow};
+use crate::metrics::{
+  MetricsSnapshot, ProviderCounters, ProviderHealth, ProviderStatus
+};
+use crate::middleware::{
+  ContentValidator, RefusalDetector, RequestInterceptor, ValidationCode
+};
+use crate::providers::ProviderClient;
+use crate::providers::ChatRequest;
+use crate::providers::ChatResponse;
+use crate::providers::mistral::{ChatMessage, Role};
+use crate::providers::openai::{
+  BatchItem, BatchResult, ModerationResult, OpenAICommand, OpenAIRun,
+  OpenAIThread
+};
+use crate::session::{ConversationSession, MessageRole, PruningStrategy};
+use crate::supervisor::{ProviderSupervision, SupervisionState, Supervisor};
+use crate::utils::cost::PriceTable;
+use crate::utils::normalize::ResponseNormalizer;
+use crate::utils::thinking::ExtractThinking;
+use crate::utils::hash::content_hash;
+use crate::utils::stream_share::SharedStream;
+use crate::utils::uuid::Uuid;
+use crate::utils::rate_limiter::{RateLimitStatus, TokenBucketRateLimiter};
+use crate::utils::secrets::{
+  secret_id, MemorySecretStore, SecretStoreKeyProvider, SharedSecretStore
+};
+
+/// How long `shutdown` waits for the backend task to finish once
+/// the loop has confirmed `KillProcess`
+const SHUTDOWN_JOIN_TIMEOUT: std::time::Duration
+  = std::time::Duration::from_secs(5);
+
+/// A provider reply tagged with the id of its pending prompt
+type Completion = Pin<Box<
+  dyn Future<Output = (usize, Result<ChatResponse, crate::error::Error>)>
+    + Send
+>>;
 
 /// Union of all possible handler commands to execute
 pub enum HandlerCommand
@@ -18,141 +61,4832 @@ pub enum HandlerCommand
   , SetModelFallbackPreference(Vec<(crate::Provider, String)>)
 }
 
+/// What the reply to a pending prompt is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptKind
+{   /// Plain `SendPrompt`
+    Direct
+  , /// Turn of a conversation session
+    Session(Uuid)
+  , /// Summary of a session's first `summarized` messages
+    Summary
+    {   session_id: Uuid
+      , summarized: usize
+    }
+}
+
+/// Where the reply to a pending prompt goes
+pub enum PromptReplySender
+{   /// Reply text (`SendPrompt`, sessions, summaries)
+    Text(crate::SendPromptReplySender)
+  , /// `PromptResponse` (`SendRequest`)
+    Response(crate::SendRequestReplySender)
+}
+
+impl PromptReplySender
+{   /// Deliver a reply produced by `model` of `provider` after
+    /// `hops`
+    fn send(
+      self
+    , result: Result<ChatResponse, crate::error::Error>
+    , provider: crate::Provider
+    , request: ChatRequest
+    , hops: Vec<crate::failover::FailoverHop>
+    )
+    {   match self
+        {   PromptReplySender::Text(reply) => {
+              let _ = reply.send(result.map(|r| r.text));
+            }
+          , PromptReplySender::Response(reply) => {
+              let _ = reply.send(result.map(|r| {
+                let (tagged, text) = ExtractThinking::extract(&r.text);
+                let choices = r.choices.iter()
+                  .map(|choice| ExtractThinking::extract(choice).1)
+                  .collect();
+                crate::request::PromptResponse
+                {   text
+                  , provider
+                  , model: request.model
+                  , tokens_used: None
+                  , raw: r.raw
+                  , request_id: request.request_id.unwrap_or_default()
+                  , metadata: request.metadata
+                  , rephrase_attempts: 0
+                  , choices
+                  , prompt_logprobs: r.prompt_logprobs
+                  , thinking: r.thinking.or(tagged)
+                  , failover_chain: hops
+                }
+              }));
+            }
+        }
+    }
+}
+
+impl From<crate::SendPromptReplySender> for PromptReplySender
+{   fn from(reply: crate::SendPromptReplySender) -> Self
+    {   PromptReplySender::Text(reply)
+    }
+}
+
+/// Characters of a timed-out reply kept to resume from
+pub const CHECKPOINT_TAIL_CHARS: usize = 512;
+
+/// Text received so far for a `CheckpointMode::Enabled` prompt
+pub struct Checkpoint
+{   pub request_id: crate::RequestId
+  , /// System message of the prompt as sent by the caller
+    pub system_message: Option<String>
+  , /// Text of the attempts that timed out, in order
+    pub received: String
+  , /// Text of the attempt in flight, filled as chunks arrive
+    pub attempt: Arc<std::sync::Mutex<String>>
+  , pub resumes: usize
+}
+
+/// System message of a prompt resumed after `partial`
+pub fn resume_system_message(
+  partial: &str
+, system_message: Option<&str>
+) -> String
+{   match system_message
+    {   Some(system) => format!("Continue from: {}\n\n{}", partial, system)
+      , None => format!("Continue from: {}", partial)
+    }
+}
+
+/// Last `max` characters of `text`
+fn tail(text: &str, max: usize) -> &str
+{   let start = text.char_indices()
+      .rev()
+      .nth(max.saturating_sub(1))
+      .map(|(i, _)| i)
+      .unwrap_or(0);
+    &text[start..]
+}
+
+/// A prompt accepted by the backend and not yet answered
+pub struct PendingPrompt
+{   /// Request for the next dispatch; its model changes on
+    /// failover
+    pub request: ChatRequest
+  , pub kind: PromptKind
+  , /// Provider the next dispatch goes to
+    pub provider: crate::Provider
+  , pub reply: PromptReplySender
+  , /// Models already tried for this prompt, in order
+    pub tried: Vec<(crate::Provider, String)>
+  , /// Callers of identical prompts coalesced into this one
+    pub duplicates: Vec<crate::SendPromptReplySender>
+  , /// Set for `CheckpointMode::Enabled` prompts
+    pub checkpoint: Option<Checkpoint>
+  , /// Passed `moderate_before_send`; retries are not screened again
+    pub moderated: bool
+  , /// From `total_deadline`: no retry or failover starts after it
+    pub deadline: Option<std::time::Instant>
+  , /// A retry was given up because of `deadline`
+    pub deadline_hit: bool
+  , /// Set for prompts with `PromptRequest::json_schema`
+    pub schema_check: Option<SchemaCheck>
+  , /// Sent to its provider and not answered yet
+    pub in_flight: bool
+  , /// Order among prompts waiting for a rate limit
+    pub priority: crate::request::Priority
+  , /// See `PromptRequest::no_failover`
+    pub no_failover: bool
+  , /// Answers `Err(Other("cancelled"))` once cancelled, dropping
+    /// the provider call in flight
+    pub cancel: Option<tokio_util::sync::CancellationToken>
+  , /// Models the prompt has left, for `PromptResponse::failover_chain`
+    pub hops: Vec<crate::failover::FailoverHop>
+  , /// First dispatch to the current model
+    pub hop_started: Option<std::time::Instant>
+}
+
+impl PendingPrompt
+{   /// Id of the prompt in `AllmEvent`s: its `request_id`, else the
+    /// backend's own number for it
+    fn event_id(&self, id: usize) -> crate::RequestId
+    {   self.request.request_id.clone().unwrap_or_else(|| id.to_string())
+    }
+
+    /// Close the hop on the current model, which failed with `error`
+    /// or answered
+    fn finish_hop(&mut self, error: Option<crate::error::Error>)
+    {   self.hops.push(crate::failover::FailoverHop
+        {   provider: self.provider.clone()
+          , model: self.request.model.clone()
+          , error
+          , latency: self.hop_started.take()
+              .map(|started| started.elapsed())
+              .unwrap_or_default()
+        });
+    }
+}
+
+/// Chunks a prompt above its provider's limits is sent as
+pub struct SplitPrompt
+{   pub chunks: Vec<String>
+  , pub merge_strategy: crate::utils::prompt_splitter::ChunkMergeStrategy
+  , pub limits: crate::utils::prompt_splitter::PromptLimits
+}
+
+/// Replies a `JsonSchemaRetry` prompt has received so far
+pub struct SchemaCheck
+{   pub retry: crate::request::JsonSchemaRetry
+  , pub attempts: usize
+}
+
+impl SchemaCheck
+{   pub fn new(retry: crate::request::JsonSchemaRetry) -> Self
+    {   SchemaCheck { retry, attempts: 0 }
+    }
+}
+
+/// Creates a client for a provider, at startup and again whenever the
+/// supervisor restarts it
+pub type ClientFactory = Arc<
+  dyn Fn() -> Result<Box<dyn ProviderClient>, crate::error::Error>
+    + Send + Sync
+>;
+
+/// A provider client, created on first use when its configuration
+/// sets `lazy_init`
+pub enum ProviderClientState
+{   /// Not created yet
+    Uninitialized(Box<crate::config::ProviderConfig>)
+  , /// Being created, or waiting for the supervisor to restart it
+    Initializing
+  , Ready(Box<dyn ProviderClient>)
+}
+
+impl ProviderClientState
+{   /// The client, once created
+    pub fn ready(&self) -> Option<&dyn ProviderClient>
+    {   match self
+        {   ProviderClientState::Ready(client) => Some(client.as_ref())
+          , _ => None
+        }
+    }
+}
+
 /// Backend state machine for managing LLM requests
 pub struct AllmBackendState
 {   pub current_model: (crate::Provider, crate::ModelInfo)
-  , pub api_keys: HashMap<(crate::Provider, String), String>
-  , pub fallback_preferences
-      : Vec<(crate::Provider, String)>
-  , pub mistral_client: crate::providers::mistral::MistralClient
+  , /// Keys set through `SetApiKeys`, and the master keys of every
+    /// client when given to `use_secret_store`
+    pub secret_store: SharedSecretStore
+  , /// Fallback models in order, skipping rate-limited ones until
+    /// they cool off
+    pub fallback_preferences: crate::failover::FailoverSequence
+  , /// Friendly model names, resolved when a prompt is accepted
+    pub model_aliases: HashMap<String, (crate::Provider, String)>
+  , /// Provider actors by provider (Mistral is always present)
+    pub clients: HashMap<crate::Provider, ProviderClientState>
+  , /// Set by `use_secret_store`: clients read their master key from
+    /// `secret_store`, including those created later
+    pub secret_store_keys: bool
+  , /// How to recreate each client when the supervisor restarts it
+    pub client_factories: HashMap<crate::Provider, ClientFactory>
+  , /// Models (`None` for the master key) given keys through
+    /// `SetApiKeys`; a restarted client reads them from `secret_store`
+    pub keyed_models: HashMap<crate::Provider, HashSet<Option<String>>>
+  , pub supervisor: Supervisor
+  , /// Latest status of each watched provider, see
+    /// `AllmBackend::watch_provider_status`
+    pub status_watch
+      : HashMap<crate::Provider, tokio::sync::watch::Sender<ProviderStatus>>
+  , /// See `AllmBackend::subscribe_events`
+    pub event_subscribers: Vec<mpsc::UnboundedSender<crate::AllmEvent>>
+  , /// Status each provider was last reported with to subscribers
+    event_status: HashMap<crate::Provider, ProviderStatus>
+  , /// Request-per-minute buckets from `ProviderConfig::rate_limit_rpm`
+    pub rate_limiters: HashMap<crate::Provider, TokenBucketRateLimiter>
+  , /// Token-per-minute buckets from `ProviderConfig::rate_limit_tpm`
+    pub token_rate_limiters
+      : HashMap<crate::Provider, TokenBucketRateLimiter>
+  , pub config: crate::config::AllmConfig
+  , /// Run on every successful reply before it is delivered
+    pub validators: Vec<Box<dyn ContentValidator + Send + Sync>>
+  , /// Run in order on every request before it is sent
+    pub interceptors: Vec<Box<dyn RequestInterceptor + Send + Sync>>
+  , /// Model per client that the backend uses on its own
+    pub default_models: HashMap<crate::Provider, String>
+  , /// Known models, consulted by `context_overflow_upgrade`
+    pub model_catalog: Vec<crate::ModelInfo>
+  , /// Prices overriding the catalog's, from `price_table_path`
+    pub price_table: PriceTable
+  , /// Request outcomes per provider, for `GetMetrics`
+    pub provider_counters: HashMap<crate::Provider, ProviderCounters>
+  , /// Prompts by id, from acceptance until their reply is sent
+    pub pending: HashMap<usize, PendingPrompt>
+  , pub sessions: HashMap<Uuid, ConversationSession>
+  , /// Sessions being summarized, with the prompts held back until
+    /// the summary is in place
+    pub summarizing: HashMap<Uuid, Vec<crate::SendSessionPromptArgs>>
+  , /// Prompts open to deduplication: content hash to prompt id and
+    /// acceptance time
+    pub dedup_index: HashMap<u64, (usize, std::time::Instant)>
+  , /// Streams open to deduplication: content hash to start time and
+    /// the stream
+    pub stream_index: HashMap<u64, (std::time::Instant, SharedStream)>
+  , /// Tail of the text received before a checkpointed request timed
+    /// out; cleared once it completes
+    pub partial_responses: HashMap<crate::RequestId, String>
+  , /// `AllmConfig::context_warning_tx`, replaceable through
+    /// `AllmBackend::with_context_warning_channel`
+    pub context_warning_tx: ContextWarningSlot
+  , /// Set through `AllmBackend::with_failover_callback`
+    pub failover_callback: FailoverCallbackSlot
+  , next_prompt_id: usize
+  , in_flight: FuturesUnordered<Completion>
+  , delayed_tx: mpsc::UnboundedSender<usize>
+  , delayed_rx: Option<mpsc::UnboundedReceiver<usize>>
+  , /// Prompts waiting for a rate limit slot, by provider, highest
+    /// priority and then oldest first
+    pub rate_limited
+      : HashMap<crate::Provider, BTreeSet<(crate::request::Priority, usize)>>
+  , /// Models answered with a 429, until its `retry-after` passes
+    pub rate_limited_models
+      : HashMap<(crate::Provider, String), std::time::SystemTime>
+  , slot_tx: mpsc::UnboundedSender<crate::Provider>
+  , slot_rx: Option<mpsc::UnboundedReceiver<crate::Provider>>
+  , moderated_tx: mpsc::UnboundedSender<Moderated>
+  , moderated_rx: Option<mpsc::UnboundedReceiver<Moderated>>
+  , /// Chunks of split prompts, and their merge prompts, re-entering
+    /// the loop as `SendPrompt`s
+    resubmit_tx: mpsc::UnboundedSender<crate::SendPromptArgs>
+  , resubmit_rx: Option<mpsc::UnboundedReceiver<crate::SendPromptArgs>>
 }
 
+/// Where a provider client confirms a key given to `set_api_key`
+type KeyConfirmation = mpsc::UnboundedReceiver<Result<(), crate::error::Error>>;
+
+/// Called with every `FailoverEvent`, on the backend loop
+pub type FailoverCallback
+  = Arc<dyn Fn(crate::FailoverEvent) + Send + Sync>;
+
+/// Where the failover callback goes, shared by the backend loop and
+/// its handle
+pub type FailoverCallbackSlot
+  = Arc<std::sync::Mutex<Option<FailoverCallback>>>;
+
+/// Prompt id and verdict of `moderate_before_send`
+type Moderated = (usize, Result<(), crate::error::Error>);
+
+/// Where `ContextUsageWarning`s go, shared by the backend loop and
+/// its handle
+pub type ContextWarningSlot = Arc<std::sync::Mutex<
+  Option<mpsc::UnboundedSender<crate::ContextUsageWarning>>
+>>;
+
 impl AllmBackendState
 {   /// Create a new backend state with default configuration
     pub fn new(
       mistral_api_key: Option<String>
     ) -> Self
     {   debug!("Initializing AllmBackendState");
-        let mistral_client
-          = crate::providers::mistral::MistralClient::new(
-              mistral_api_key,
-              None
-            );
+        let mistral_factory: ClientFactory = Arc::new(move || {
+          Ok(Box::new(crate::providers::mistral::MistralClient::new(
+            mistral_api_key.clone(),
+            None
+          )))
+        });
+        let mistral_client = mistral_factory()
+          .expect("Mistral client without config cannot fail");
+        let mut clients: HashMap<crate::Provider, ProviderClientState>
+          = HashMap::new();
+        clients.insert(
+          crate::Provider::MistralAi,
+          ProviderClientState::Ready(mistral_client)
+        );
+        let (delayed_tx, delayed_rx) = mpsc::unbounded_channel();
+        let (slot_tx, slot_rx) = mpsc::unbounded_channel();
+        let (moderated_tx, moderated_rx) = mpsc::unbounded_channel();
+        let (resubmit_tx, resubmit_rx) = mpsc::unbounded_channel();
         AllmBackendState
         {   current_model: (
               crate::Provider::MistralAi
             , crate::providers::mistral::default_model_info()
             )
-          , api_keys: HashMap::new()
-          , fallback_preferences: vec![]
-          , mistral_client
+          , secret_store: MemorySecretStore::shared()
+          , fallback_preferences: crate::failover::FailoverSequence::default()
+          , model_aliases: HashMap::new()
+          , clients
+          , secret_store_keys: false
+          , client_factories: HashMap::from([(
+              crate::Provider::MistralAi, mistral_factory
+            )])
+          , keyed_models: HashMap::new()
+          , supervisor: Supervisor::default()
+          , status_watch: HashMap::new()
+          , event_subscribers: vec![]
+          , event_status: HashMap::new()
+          , rate_limiters: HashMap::new()
+          , token_rate_limiters: HashMap::new()
+          , config: crate::config::AllmConfig::default()
+          , validators: vec![]
+          , interceptors: vec![]
+          , default_models: crate::providers::default_model(
+              &crate::Provider::MistralAi
+            ).map(|m| (crate::Provider::MistralAi, m)).into_iter().collect()
+          , model_catalog: vec![
+              crate::providers::mistral::default_model_info(),
+              crate::providers::cerebras::default_model_info()
+            ]
+          , price_table: PriceTable::new()
+          , provider_counters: HashMap::new()
+          , pending: HashMap::new()
+          , sessions: HashMap::new()
+          , summarizing: HashMap::new()
+          , dedup_index: HashMap::new()
+          , stream_index: HashMap::new()
+          , partial_responses: HashMap::new()
+          , context_warning_tx: ContextWarningSlot::default()
+          , failover_callback: FailoverCallbackSlot::default()
+          , next_prompt_id: 0
+          , in_flight: FuturesUnordered::new()
+          , delayed_tx
+          , delayed_rx: Some(delayed_rx)
+          , rate_limited: HashMap::new()
+          , rate_limited_models: HashMap::new()
+          , slot_tx
+          , slot_rx: Some(slot_rx)
+          , moderated_tx
+          , moderated_rx: Some(moderated_rx)
+          , resubmit_tx
+          , resubmit_rx: Some(resubmit_rx)
         }
     }
-}
 
-/// Public API for ALLM backend - owns the task
-pub struct AllmBackend
-{   hand: crate::AllmHand
-  , _task_handle: tokio::task::JoinHandle<()>
-}
+    /// Create a backend state with one client per configured
+    /// provider. Mistral is created with defaults when absent.
+    pub fn new_with_config(
+      config: &crate::config::AllmConfig
+    ) -> Result<Self, crate::error::Error>
+    {   debug!(
+          "Initializing AllmBackendState with {} provider configs",
+          config.providers.len()
+        );
+        let mut state = AllmBackendState::new(None);
+        state.config = config.clone();
+        state.context_warning_tx = Arc::new(std::sync::Mutex::new(
+          config.context_warning_tx.clone()
+        ));
+        state.supervisor = Supervisor::new(config.supervisor.clone());
+        for provider_config in &config.providers
+        {   let provider = provider_config.provider()
+              .ok_or_else(|| {
+                error!("Unknown provider name: {}", provider_config.name);
+                crate::error::Error::InvalidConfiguration(
+                  format!("unknown provider: {}", provider_config.name)
+                )
+              })?;
+            config.check_provider_policy([&provider])?;
+            let client = if provider_config.lazy_init
+            {   check_client_config(
+                  &provider,
+                  provider_config,
+                  &config.http_client_config
+                )?;
+                ProviderClientState::Uninitialized(
+                  Box::new(provider_config.clone())
+                )
+            } else
+            {   ProviderClientState::Ready(create_client(
+                  &provider,
+                  provider_config,
+                  &config.http_client_config,
+                  config.pin_model_versions
+                )?)
+            };
+            state.clients.insert(provider.clone(), client);
+            let factory_config = provider_config.clone();
+            let http_config = config.http_client_config.clone();
+            let factory_provider = provider.clone();
+            let pin_model_versions = config.pin_model_versions;
+            state.client_factories.insert(provider.clone(), Arc::new(move || {
+              create_client(
+                &factory_provider, &factory_config, &http_config,
+                pin_model_versions
+              )
+            }));
+            let default_model = provider_config.default_model.clone()
+              .or_else(|| crate::providers::default_model(&provider));
+            if let Some(model) = default_model
+            {   state.default_models.insert(provider.clone(), model);
+            }
+            if let Some(rpm) = provider_config.rate_limit_rpm
+            {   state.rate_limiters.insert(
+                  provider.clone(),
+                  TokenBucketRateLimiter::per_minute(rpm)
+                );
+            }
+            if let Some(tpm) = provider_config.rate_limit_tpm
+            {   state.token_rate_limiters.insert(
+                  provider,
+                  TokenBucketRateLimiter::per_minute(tpm)
+                );
+            }
+        }
+        if let Some(path) = &config.model_registry_cache_path
+        {   state.load_model_registry(path);
+        }
+        state.register_models(config.model_overrides.clone());
+        if let Some(path) = &config.price_table_path
+        {   state.price_table = crate::utils::cost::load_price_table(path)?;
+        }
+        Ok(state)
+    }
 
-impl AllmBackend
-{   /// Create and spawn a new ALLM backend
-    /// Returns immediately - spawns background task
-    pub fn new(
-      mistral_api_key: Option<String>
-    ) -> Self
-    {   debug!("Creating AllmBackend with task ownership");
-        
-        let (send_prompt_tx, send_prompt_rx)
-          = mpsc::unbounded_channel();
-        let (set_api_keys_tx, set_api_keys_rx)
-          = mpsc::unbounded_channel();
-        let (get_model_lists_tx, get_model_lists_rx)
-          = mpsc::unbounded_channel();
-        let (kill_process_tx, kill_process_rx)
-          = mpsc::unbounded_channel();
-        let (set_model_fallback_preference_tx
-             , set_model_fallback_preference_rx)
-          = mpsc::unbounded_channel();
+    /// Keep keys in `store`, and have every client look its master
+    /// key up there before each request
+    pub fn use_secret_store(&mut self, store: SharedSecretStore)
+    {   self.secret_store = store;
+        self.secret_store_keys = true;
+        for (provider, client) in &self.clients
+        {   if let Some(client) = client.ready()
+            {   self.read_keys_from_store(provider, client);
+            }
+        }
+    }
 
-        let hand = crate::AllmHand
-        {   send_prompt_tx: send_prompt_tx.clone()
-          , set_api_keys_tx: set_api_keys_tx.clone()
-          , get_model_lists_tx: get_model_lists_tx.clone()
-          , kill_process_tx: kill_process_tx.clone()
-          , set_model_fallback_preference_tx
-              : set_model_fallback_preference_tx.clone()
+    fn read_keys_from_store(
+      &self
+    , provider: &crate::Provider
+    , client: &dyn ProviderClient
+    )
+    {   let key_provider = SecretStoreKeyProvider::shared(
+          self.secret_store.clone(),
+          secret_id(provider, None)
+        );
+        let (reply_tx, _reply_rx) = mpsc::unbounded_channel();
+        if let Err(e) = client.set_key_provider(None, key_provider, reply_tx)
+        {   warn!("{:?} ignores the secret store: {}", provider, e);
+        }
+    }
+
+    /// Add a client made by `factory`, which the supervisor also uses
+    /// to restart it. Replaces any client of `provider`.
+    pub fn register_client(
+      &mut self
+    , provider: crate::Provider
+    , factory: ClientFactory
+    ) -> Result<(), crate::error::Error>
+    {   let client = factory()?;
+        self.clients.insert(
+          provider.clone(),
+          ProviderClientState::Ready(client)
+        );
+        self.client_factories.insert(provider, factory);
+        Ok(())
+    }
+
+    /// Restart clients whose actor died, and take out of routing
+    /// those that keep dying
+    async fn supervise(&mut self)
+    {   if !self.supervisor.enabled()
+        {   return;
+        }
+        let now = std::time::Instant::now();
+        let dead: Vec<crate::Provider> = self.clients.iter()
+          .filter(|(_, c)| c.ready().is_some_and(|c| !c.is_alive()))
+          .map(|(provider, _)| provider.clone())
+          .collect();
+        for provider in dead
+        {   self.client_died(&provider, now);
+        }
+        for provider in self.supervisor.due(now)
+        {   self.restart_client(&provider);
+        }
+    }
+
+    fn client_died(&mut self, provider: &crate::Provider, now: std::time::Instant)
+    {   match self.supervisor.crashed(provider, now)
+        {   SupervisionState::Failed => {
+              error!("{:?} client keeps crashing, marked failed", provider);
+              self.clients.remove(provider);
+            }
+          , SupervisionState::Restarting { attempt, backoff_ms } => {
+              warn!(
+                "{:?} client died (crash {}), restarting in {}ms",
+                provider, attempt, backoff_ms
+              );
+              self.clients.insert(
+                provider.clone(),
+                ProviderClientState::Initializing
+              );
+            }
+          , SupervisionState::Running => {}
+        }
+    }
+
+    fn restart_client(&mut self, provider: &crate::Provider)
+    {   let Some(factory) = self.client_factories.get(provider).cloned() else
+        {   self.supervisor.restarted(provider);
+            return;
         };
+        match factory()
+        {   Ok(client) => {
+              info!("{:?} client restarted", provider);
+              if self.secret_store_keys
+              {   self.read_keys_from_store(provider, client.as_ref());
+              }
+              for model in self.keyed_models.get(provider).into_iter().flatten()
+              {   let key_provider = SecretStoreKeyProvider::shared(
+                    self.secret_store.clone(),
+                    secret_id(provider, model.as_deref())
+                  );
+                  let (reply_tx, _reply_rx) = mpsc::unbounded_channel();
+                  let _ = client.set_key_provider(
+                    model.clone(), key_provider, reply_tx
+                  );
+              }
+              self.clients.insert(
+                provider.clone(),
+                ProviderClientState::Ready(client)
+              );
+              self.supervisor.restarted(provider);
+            }
+          , Err(e) => {
+              error!("{:?} client not restarted: {}", provider, e);
+              self.client_died(provider, std::time::Instant::now());
+            }
+        }
+    }
 
-        let foot = crate::AllmFoot
-        {   send_prompt_rx
-          , set_api_keys_rx
-          , get_model_lists_rx
-          , kill_process_rx
-          , set_model_fallback_preference_rx
+    /// Supervision records of every provider the backend can create
+    fn supervision_status(&self) -> Vec<ProviderSupervision>
+    {   self.supervisor.status(self.client_factories.keys().cloned())
+    }
+
+    /// Store `key_spec` and hand it to its provider's client,
+    /// returning the channel the client confirms it on (`None` for a
+    /// master key kept for a client not created yet). A key for a
+    /// provider without a client is stored all the same, and flagged
+    /// `ProviderNotImplemented`, as prompts for it would be.
+    async fn set_api_key(&mut self, key_spec: &crate::ApiKeySpec)
+      -> Result<Option<KeyConfirmation>, crate::error::Error>
+    {   let model = Some(key_spec.model.as_str())
+          .filter(|m| !m.is_empty());
+        let id = secret_id(&key_spec.provider, model);
+        let stored = self.secret_store.set(&id, &key_spec.key).await;
+        match &stored
+        {   Ok(()) => {
+              self.keyed_models.entry(key_spec.provider.clone())
+                .or_default()
+                .insert(model.map(|m| m.to_string()));
+            }
+          , Err(e) => warn!("Secret store rejected {}: {}", id, e)
+        }
+
+        // A master key waits in the config of a client not created
+        // yet; a model key needs the client
+        match self.clients.get_mut(&key_spec.provider)
+        {   None => return Err(crate::error::Error::ProviderNotImplemented(
+              format!("{:?}", key_spec.provider)
+            ))
+          , Some(ProviderClientState::Uninitialized(config))
+              if model.is_none() => {
+              config.api_key = Some(key_spec.key.clone());
+              return stored.map(|()| None);
+            }
+          , Some(_) => {}
+        }
+        self.init_client(&key_spec.provider)?;
+        let confirmation = match self.clients.get(&key_spec.provider)
+          .and_then(ProviderClientState::ready)
+        {   Some(client) => {
+              let (reply_tx, reply_rx) = mpsc::unbounded_channel();
+              client.set_api_key(
+                model.map(str::to_string), key_spec.key.clone(), reply_tx
+              )?;
+              Some(reply_rx)
+            }
+          , None => None
+        };
+        stored.map(|()| confirmation)
+    }
+
+    /// Create the client of `provider` if it was left for its first
+    /// request. Unknown providers are left to the caller.
+    pub fn init_client(
+      &mut self
+    , provider: &crate::Provider
+    ) -> Result<(), crate::error::Error>
+    {   let config = match self.clients.remove(provider)
+        {   Some(ProviderClientState::Uninitialized(config)) => config
+          , Some(state) => {
+              self.clients.insert(provider.clone(), state);
+              return Ok(());
+            }
+          , None => return Ok(())
+        };
+        self.clients.insert(
+          provider.clone(),
+          ProviderClientState::Initializing
+        );
+        info!("Initializing {:?} client on first use", provider);
+        match create_client(
+          provider,
+          &config,
+          &self.config.http_client_config,
+          self.config.pin_model_versions
+        )
+        {   Ok(client) => {
+              if self.secret_store_keys
+              {   self.read_keys_from_store(provider, client.as_ref());
+              }
+              self.clients.insert(
+                provider.clone(),
+                ProviderClientState::Ready(client)
+              );
+              Ok(())
+            }
+          , Err(e) => {
+              error!("{:?} client not created: {}", provider, e);
+              self.clients.insert(
+                provider.clone(),
+                ProviderClientState::Uninitialized(config)
+              );
+              Err(e)
+            }
+        }
+    }
+
+    /// Create the clients of `providers` now rather than on first use
+    pub fn preload_providers(
+      &mut self
+    , providers: &[crate::Provider]
+    ) -> Result<(), crate::error::Error>
+    {   for provider in providers
+        {   if !self.clients.contains_key(provider)
+            {   return Err(crate::error::Error::ProviderNotImplemented(
+                  format!("{:?}", provider)
+                ));
+            }
+            self.init_client(provider)?;
+        }
+        Ok(())
+    }
+
+    /// Take rate-limit tokens for a prompt to `provider` and return
+    /// how long dispatch must be delayed
+    async fn acquire_rate_limit(
+      &mut self
+    , provider: &crate::Provider
+    , prompt: &str
+    ) -> std::time::Duration
+    {   let mut wait = std::time::Duration::ZERO;
+        if let Some(limiter) = self.rate_limiters.get_mut(provider)
+        {   wait = wait.max(limiter.acquire(1).await);
+        }
+        if let Some(limiter) = self.token_rate_limiters.get_mut(provider)
+        {   let tokens
+              = crate::utils::tokens::estimate_tokens(prompt) as u32;
+            wait = wait.max(limiter.acquire(tokens).await);
+        }
+        wait
+    }
+
+    /// Provider and model a prompt goes to. An alias names both;
+    /// any other model is used as given, on `provider` or the
+    /// current model's provider.
+    fn resolve_model(
+      &self
+    , provider: Option<crate::Provider>
+    , model: String
+    ) -> (crate::Provider, String)
+    {   match self.model_aliases.get(&model)
+        {   Some((provider, real)) => {
+              debug!("Alias {} -> {:?}/{}", model, provider, real);
+              (provider.clone(), real.clone())
+            }
+          , None => (
+              provider.unwrap_or_else(|| self.current_model.0.clone()),
+              model
+            )
+        }
+    }
+
+    /// Where a prompt for `model` of `provider` may go under
+    /// `allowed_providers` and `blocked_providers`: `None` when the
+    /// policy allows it as is, else the first fallback it allows.
+    /// Fails with `InvalidConfiguration` under `strict_provider_policy`
+    /// or when no fallback is allowed.
+    fn policy_target(&self, provider: Option<crate::Provider>, model: &str)
+      -> Result<Option<(crate::Provider, String)>, crate::error::Error>
+    {   let (provider, _) = self.resolve_model(provider, model.to_string());
+        let Err(e) = self.config.check_provider_policy([&provider]) else
+        {   return Ok(None);
+        };
+        if self.config.strict_provider_policy
+        {   return Err(e);
+        }
+        let fallback = self.fallback_preferences.available()
+          .find(|entry| self.config.allows_provider(&entry.provider))
+          .map(|entry| (entry.provider.clone(), entry.model.clone()))
+          .ok_or(e)?;
+        info!(
+          "{:?} is not allowed, prompt goes to {:?}/{}",
+          provider, fallback.0, fallback.1
+        );
+        Ok(Some(fallback))
+    }
+
+    /// `wait`, cut to `max_retry_after_secs`
+    fn cap_retry_after(&self, wait: std::time::Duration)
+      -> std::time::Duration
+    {   match self.config.max_retry_after_secs
+        {   Some(max) => wait.min(std::time::Duration::from_secs(max))
+          , None => wait
+        }
+    }
+
+    /// Quota `provider` reported with its last response, marked
+    /// `is_rate_limited` for a `model` still cooling off from a 429,
+    /// or when a quota is used up until its reset
+    fn rate_limit_status(&mut self, provider: &crate::Provider, model: &str)
+      -> Result<RateLimitStatus, crate::error::Error>
+    {   let Some(client) = self.clients.get(provider)
+        else
+        {   return Err(crate::error::Error::ProviderNotImplemented(
+              format!("{:?}", provider)
+            ));
         };
+        let mut status = client.ready()
+          .map(|client| client.rate_limit_status())
+          .unwrap_or_default();
+        let now = std::time::SystemTime::now();
+        self.close_rate_limits(now);
+        if let Some(until)
+          = self.rate_limited_models.get(&(provider.clone(), model.to_string()))
+        {   status.is_rate_limited = true;
+            status.reset_at = status.reset_at.max(Some(*until));
+        }
+        let used_up = status.remaining_requests == Some(0)
+          || status.remaining_tokens == Some(0);
+        if used_up && status.reset_at.is_some_and(|reset| reset > now)
+        {   status.is_rate_limited = true;
+        }
+        Ok(status)
+    }
 
-        let _task_handle = tokio::spawn(async move {
-          run_backend_loop(foot, mistral_api_key).await
+    /// Forget the rate-limited models whose reset has passed at `now`
+    fn close_rate_limits(&mut self, now: std::time::SystemTime)
+    {   let subscribers = &mut self.event_subscribers;
+        self.rate_limited_models.retain(|(provider, model), until| {
+          let open = *until > now;
+          if !open
+          {   broadcast(subscribers, crate::AllmEvent::CircuitBreakerClosed
+              {   provider: provider.clone()
+                , model: model.clone()
+              });
+          }
+          open
         });
+    }
 
-        AllmBackend
-        {   hand
-          , _task_handle
+    /// How long until `model` of `provider` may be sent to again:
+    /// zero unless it is rate-limited, and at most
+    /// `max_retry_after_secs`
+    fn rate_limit_reset(&mut self, provider: &crate::Provider, model: &str)
+      -> Result<std::time::Duration, crate::error::Error>
+    {   let status = self.rate_limit_status(provider, model)?;
+        let wait = status.reset_at
+          .filter(|_| status.is_rate_limited)
+          .and_then(|reset| reset.duration_since(std::time::SystemTime::now()).ok())
+          .unwrap_or_default();
+        Ok(self.cap_retry_after(wait))
+    }
+
+    /// First entry of `fallback_preferences` (the default model when
+    /// there are none) a request would go to: not cooling off after
+    /// a rate limit, allowed by the provider policy, with a running
+    /// client, headroom in its rate limiters and, given
+    /// `requirements`, a catalog entry meeting them
+    fn first_available_provider(
+      &mut self
+    , requirements: Option<crate::capabilities::ModelRequirements>
+    ) -> Result<(crate::Provider, String), crate::error::Error>
+    {   let mut candidates: Vec<(crate::Provider, String)>
+          = self.fallback_preferences.available()
+            .map(|entry| (entry.provider.clone(), entry.model.clone()))
+            .collect();
+        if self.fallback_preferences.providers.is_empty()
+        {   candidates.push((
+              self.current_model.0.clone(),
+              self.current_model.1.name.clone()
+            ));
+        }
+        for (provider, model) in candidates
+        {   let running = self.clients.get(&provider)
+              .is_some_and(|c| !matches!(c, ProviderClientState::Initializing))
+              && self.supervisor.state(&provider) == SupervisionState::Running;
+            let headroom = self.rate_limiters.get_mut(&provider)
+                .is_none_or(|limiter| limiter.available() >= 1.0)
+              && self.token_rate_limiters.get_mut(&provider)
+                .is_none_or(|limiter| limiter.available() > 0.0);
+            let capable = requirements.as_ref().is_none_or(|requirements| {
+              self.model_info(&provider, &model).is_some_and(|info| {
+                crate::capabilities::ModelCapabilityRow::from_model_info(&info)
+                  .meets(requirements)
+              })
+            });
+            if running && headroom && capable && self.config.allows_provider(&provider)
+            {   return Ok((provider, model));
+            }
+            debug!(
+              "{:?}/{} unavailable: running {}, headroom {}, capable {}",
+              provider, model, running, headroom, capable
+            );
         }
+        Err(crate::error::Error::Other(
+          "No available provider meets the requirements".to_string()
+        ))
     }
 
-    /// Send a prompt - returns almost immediately
-    pub async fn send_prompt(
+    /// Configured provider whose catalog entries or default model
+    /// include `model`; when several do, the first of them in
+    /// `fallback_preferences`, else the first by name
+    fn detect_provider(&self, model: &str) -> Option<crate::Provider>
+    {   let mut candidates: Vec<crate::Provider> = self.model_catalog.iter()
+          .filter(|m| m.name == model)
+          .map(|m| m.provider.clone())
+          .chain(self.default_models.iter()
+            .filter(|(_, m)| *m == model)
+            .map(|(p, _)| p.clone()))
+          .filter(|p| self.clients.contains_key(p))
+          .collect();
+        candidates.sort_by_key(|p| p.name());
+        candidates.dedup();
+        self.fallback_preferences.providers.iter()
+          .map(|entry| &entry.provider)
+          .find(|p| candidates.contains(p))
+          .or(candidates.first())
+          .cloned()
+    }
+
+    /// Fill in the provider of an `auto_detect_provider` prompt;
+    /// aliases already name theirs
+    fn detect_prompt_provider(&self, cmd: &mut crate::SendPromptArgs)
+      -> Result<(), crate::error::Error>
+    {   if !cmd.auto_detect_provider
+          || cmd.provider.is_some()
+          || self.model_aliases.contains_key(&cmd.model)
+        {   return Ok(());
+        }
+        match self.detect_provider(&cmd.model)
+        {   Some(provider) => {
+              debug!("Model {} routes to {:?}", cmd.model, provider);
+              cmd.provider = Some(provider);
+              Ok(())
+            }
+          , None => Err(crate::error::Error::ModelNotFound(cmd.model.clone()))
+        }
+    }
+
+    /// Hand a raw completion to the provider of `cmd.model`, once the
+    /// model is known to serve them: its catalog entry says so, or,
+    /// uncatalogued, `mistral::RAW_COMPLETION_MODELS` lists it
+    fn send_completion(&mut self, mut cmd: crate::SendCompletionArgs)
+    {   let (provider, model) = self.resolve_model(None, cmd.model);
+        let supported = match self.model_info(&provider, &model)
+        {   Some(info) => info.supports_raw_completion
+          , None => crate::providers::mistral::supports_raw_completion(&model)
+        };
+        cmd.model = model;
+        let reply = cmd.reply.clone();
+        let sent = if supported
+        {   self.init_client(&provider)
+              .and_then(|()| {
+                self.clients.get(&provider)
+                  .and_then(ProviderClientState::ready)
+                  .ok_or_else(|| crate::error::Error::ProviderNotImplemented(
+                    format!("{:?}", provider)
+                  ))
+              })
+              .and_then(|client| client.send_completion(cmd))
+        } else
+        {   Err(crate::error::Error::InvalidConfiguration(format!(
+              "model {} does not support raw completion", cmd.model
+            )))
+        };
+        if let Err(e) = sent
+        {   let _ = reply.send(Err(e));
+        }
+    }
+
+    /// Hand a perplexity request to its provider
+    fn compute_perplexity(&mut self, cmd: crate::ComputePerplexityArgs)
+    {   let (provider, model) = self.resolve_model(Some(cmd.provider), cmd.model);
+        let reply = cmd.reply.clone();
+        let sent = self.init_client(&provider)
+          .and_then(|()| {
+            self.clients.get(&provider)
+              .and_then(ProviderClientState::ready)
+              .ok_or_else(|| crate::error::Error::ProviderNotImplemented(
+                format!("{:?}", provider)
+              ))
+          })
+          .and_then(|client| client.compute_perplexity(cmd.text, model, cmd.reply));
+        if let Err(e) = sent
+        {   let _ = reply.send(Err(e));
+        }
+    }
+
+    /// Have the provider of `cmd.model` count its prompts, or
+    /// estimate them off the loop when it cannot
+    fn count_tokens(&mut self, cmd: crate::CountTokensArgs)
+    {   let (provider, model) = self.resolve_model(None, cmd.model);
+        // The client takes the prompts even when it cannot count
+        // them; keep a copy for the estimate
+        let prompts = cmd.prompts.clone();
+        let counted = self.init_client(&provider)
+          .ok()
+          .and_then(|()| self.clients.get(&provider))
+          .and_then(ProviderClientState::ready)
+          .map(|client| client.count_tokens(prompts, model, cmd.reply.clone()));
+        if let Some(Ok(())) = counted
+        {   return;
+        }
+        debug!("Estimating tokens of {} prompts locally", cmd.prompts.len());
+        let reply = cmd.reply;
+        let prompts = cmd.prompts;
+        tokio::task::spawn_blocking(move || {
+          let counts = prompts.iter()
+            .map(|p| crate::utils::tokens::estimate_tokens(p))
+            .collect();
+          let _ = reply.send(Ok(counts));
+        });
+    }
+
+    /// Provider and chat request for a single prompt, with the
+    /// model alias resolved and the system message first
+    fn build_chat(
       &self
-    , prompt: String
+    , provider: Option<crate::Provider>
     , model: String
-    ) -> Result<
-        mpsc::UnboundedReceiver<crate::SendPromptReply>,
-        crate::error::Error
-      >
-    {   debug!("send_prompt queuing command for model: {}", model);
-        let (reply_tx, reply_rx)
-          = mpsc::unbounded_channel();
-        
-        let cmd = crate::SendPromptArgs
-        {   prompt
+    , system_message: Option<String>
+    , prompt: String
+    , max_tokens: Option<usize>
+    , temperature: Option<f32>
+    ) -> (crate::Provider, ChatRequest)
+    {   let (provider, model) = self.resolve_model(provider, model);
+        let mut messages = vec![];
+        if let Some(system) = system_message
+        {   messages.push(ChatMessage::system(system));
+        }
+        messages.push(ChatMessage::user(prompt));
+        let request = ChatRequest
+        {   messages
           , model
-          , reply: reply_tx
+          , max_tokens
+          , temperature
+          , ..Default::default()
         };
+        (provider, request)
+    }
 
-        self.hand.send_prompt_tx
-          .send(cmd)
-          .map_err(|_| {
-            error!("Backend channel closed");
-            crate::error::Error::Other(
-              "Backend disconnected".to_string()
-            )
-          })?;
+    /// Accept a prompt and return its id
+    fn register_prompt(&mut self, cmd: crate::SendPromptArgs) -> usize
+    {   let checkpoint = (cmd.checkpoint == crate::CheckpointMode::Enabled
+          && self.config.checkpoint.enabled)
+          .then(|| Checkpoint
+          {   request_id: cmd.request_id.clone()
+                .unwrap_or_else(|| Uuid::new_v4().to_string())
+            , system_message: cmd.system_message.clone()
+            , received: String::new()
+            , attempt: Arc::default()
+            , resumes: 0
+          });
+        let (provider, mut request) = self.build_chat(
+          cmd.provider,
+          cmd.model,
+          cmd.system_message,
+          cmd.prompt,
+          cmd.max_tokens,
+          cmd.temperature
+        );
+        if let Some(few_shot) = &cmd.few_shot
+        {   request.add_examples(few_shot);
+        }
+        request.extra_params = cmd.extra_params;
+        request.extra_headers = cmd.extra_headers;
+        request.stop = cmd.stop_sequences;
+        if let Some(prefill) = cmd.prefill
+        {   request.messages.push(ChatMessage::assistant(prefill));
+        }
+        request.request_id = checkpoint.as_ref()
+          .map(|c| c.request_id.clone())
+          .or(cmd.request_id);
+        self.register(PendingPrompt
+        {   request
+          , kind: PromptKind::Direct
+          , provider
+          , reply: cmd.reply.into()
+          , tried: vec![]
+          , duplicates: vec![]
+          , checkpoint
+          , moderated: false
+          , deadline: cmd.total_deadline
+              .map(|limit| std::time::Instant::now() + limit)
+          , deadline_hit: false
+          , schema_check: cmd.json_schema.map(SchemaCheck::new)
+          , in_flight: false
+          , priority: cmd.priority
+          , no_failover: cmd.no_failover
+          , cancel: cmd.cancel
+          , hops: vec![]
+          , hop_started: None
+        })
+    }
 
-        Ok(reply_rx)
+    /// Catalog entry of `model` on `provider`, else the provider's
+    /// built-in default model when that is `model`
+    fn model_info(&self, provider: &crate::Provider, model: &str)
+      -> Option<crate::ModelInfo>
+    {   self.model_catalog.iter()
+          .find(|m| m.provider == *provider && m.name == model)
+          .cloned()
+          .or_else(|| {
+            crate::providers::default_model_info(provider)
+              .filter(|info| info.name == model)
+          })
     }
 
-    /// Set API keys - returns almost immediately
-    pub async fn set_api_keys(
+    /// `request::check_capabilities` of a prompt against its model,
+    /// with `strict_capability_validation`; models allm knows nothing
+    /// about pass
+    fn check_capabilities(
       &self
-    , keys: Vec<crate::ApiKeySpec>
-    ) -> Result<
-        mpsc::UnboundedReceiver<crate::SetApiKeysReply>,
-        crate::error::Error
-      >
-    {   debug!("set_api_keys queuing {} keys", keys.len());
-        let (reply_tx, reply_rx)
-          = mpsc::unbounded_channel();
-        
-        let cmd = crate::SetApiKeysArgs
-        {   keys
+    , provider: Option<crate::Provider>
+    , model: &str
+    , extra_params: Option<&serde_json::Map<String, serde_json::Value>>
+    ) -> Result<(), crate::error::Error>
+    {   if !self.config.strict_capability_validation
+        {   return Ok(());
+        }
+        let (provider, model) = self.resolve_model(provider, model.to_string());
+        match self.model_info(&provider, &model)
+        {   Some(info) => crate::request::check_capabilities(extra_params, &info)
+          , None => Ok(())
+        }
+    }
+
+    /// `Error::Other("queue full")` when `max_queued_prompts` prompts
+    /// are waiting for their replies already
+    fn check_queue_room(&self) -> Result<(), crate::error::Error>
+    {   match self.config.max_queued_prompts
+        {   Some(max) if self.pending.len() >= max => {
+              warn!("{} prompts queued, rejecting another", self.pending.len());
+              Err(crate::error::Error::Other("queue full".to_string()))
+            }
+          , _ => Ok(())
+        }
+    }
+
+    /// Check, size up and accept a `SendPrompt`, or answer it with
+    /// the error that stopped it; a `chunk` of a split prompt is not
+    /// split again
+    async fn take_prompt(&mut self, mut cmd: crate::SendPromptArgs, chunk: bool)
+    {   // Route to appropriate provider
+        let checked = self.check_queue_room()
+          .and_then(|()| self.detect_prompt_provider(&mut cmd))
+          .and_then(|()| {
+            let target = self.policy_target(cmd.provider.clone(), &cmd.model)?;
+            if let Some((provider, model)) = target
+            {   cmd.provider = Some(provider);
+                cmd.model = model;
+            }
+            Ok(())
+          })
+          .and_then(|()| self.check_capabilities(
+            cmd.provider.clone(), &cmd.model, cmd.extra_params.as_ref()
+          ))
+          .and_then(|()| check_few_shot(cmd.few_shot.as_ref()))
+          .and_then(|()| self.fit_prompt_size(&mut cmd, chunk));
+        match checked
+        {   Err(e) => {
+              let _ = cmd.reply.send(Err(e));
+            }
+          , Ok(Some(split)) => self.send_split_prompt(cmd, split)
+          , Ok(None) => {
+              if let Some(id) = self.accept_prompt(cmd)
+              {   self.schedule_prompt(id).await;
+              }
+            }
+        }
+    }
+
+    /// Limits of `provider`'s configuration, if it sets any
+    fn prompt_limits(&self, provider: &crate::Provider)
+      -> Option<crate::utils::prompt_splitter::PromptLimits>
+    {   self.config.providers.iter()
+          .find(|config| config.provider().as_ref() == Some(provider))
+          .and_then(crate::utils::prompt_splitter::PromptLimits::from_config)
+    }
+
+    /// Apply `large_prompt_strategy` to a prompt above its provider's
+    /// limits: fail it, truncate it in place, or return its chunks. A
+    /// `chunk` that does not fit fails whatever the strategy.
+    fn fit_prompt_size(&self, cmd: &mut crate::SendPromptArgs, chunk: bool)
+      -> Result<Option<SplitPrompt>, crate::error::Error>
+    {   use crate::utils::prompt_splitter::LargePromptStrategy;
+
+        let (provider, _) = self.resolve_model(cmd.provider.clone(), cmd.model.clone());
+        let Some(limits) = self.prompt_limits(&provider) else { return Ok(None) };
+        if limits.fits(&cmd.prompt)
+        {   return Ok(None);
+        }
+        match self.config.large_prompt_strategy
+        {   _ if chunk => Err(crate::error::Error::RequestTooLarge(
+              limits.describe_excess(&cmd.prompt)
+            ))
+          , LargePromptStrategy::Fail => Err(crate::error::Error::RequestTooLarge(
+              limits.describe_excess(&cmd.prompt)
+            ))
+          , LargePromptStrategy::Truncate => {
+              warn!(
+                "Truncating prompt for {:?}: {}",
+                provider, limits.describe_excess(&cmd.prompt)
+              );
+              cmd.prompt = limits.truncate(&cmd.prompt).to_string();
+              Ok(None)
+            }
+          , LargePromptStrategy::Split { merge_strategy } => Ok(Some(SplitPrompt
+            {   chunks: limits.split(&cmd.prompt)
+              , merge_strategy
+              , limits
+            }))
+        }
+    }
+
+    /// Chunks `prompt` is sent as on `model`'s provider: itself when
+    /// the provider sets no limits
+    fn chunk_prompt(&self, prompt: String, model: String) -> Vec<String>
+    {   let (provider, _) = self.resolve_model(None, model);
+        match self.prompt_limits(&provider)
+        {   Some(limits) => limits.split(&prompt)
+          , None => vec![prompt]
+        }
+    }
+
+    /// Send each chunk of a split prompt back through the loop and
+    /// answer `cmd` with their replies merged, or the first error
+    fn send_split_prompt(&self, cmd: crate::SendPromptArgs, split: SplitPrompt)
+    {   use crate::utils::prompt_splitter::{merge_prompt, ChunkMergeStrategy};
+
+        info!(
+          "Splitting a {}-byte prompt for {} into {} chunks",
+          cmd.prompt.len(), cmd.model, split.chunks.len()
+        );
+        let resubmit_tx = self.resubmit_tx.clone();
+        let ask = move |cmd: &crate::SendPromptArgs, prompt: String| {
+          let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+          let _ = resubmit_tx.send(cmd.with_prompt(prompt, reply_tx));
+          async move { recv_reply(&mut reply_rx).await }
+        };
+        let replies: Vec<_> = split.chunks.into_iter()
+          .map(|chunk| ask(&cmd, chunk))
+          .collect();
+        tokio::spawn(async move {
+          let mut texts = vec![];
+          for reply in replies
+          {   match reply.await
+              {   Ok(text) => texts.push(text)
+                , Err(e) => {
+                    let _ = cmd.reply.send(Err(e));
+                    return;
+                  }
+              }
+          }
+          let merged = match split.merge_strategy
+          {   ChunkMergeStrategy::LlmMerge
+                if split.limits.fits(&merge_prompt(&texts)) => {
+                ask(&cmd, merge_prompt(&texts)).await
+              }
+            , ChunkMergeStrategy::LlmMerge => {
+                warn!("Merge prompt too large, concatenating the replies");
+                Ok(texts.join("\n\n"))
+              }
+            , ChunkMergeStrategy::Concatenate => Ok(texts.join("\n\n"))
+          };
+          let _ = cmd.reply.send(merged);
+        });
+    }
+
+    /// Accept a `SendPrompt` and return its id, or `None` when it
+    /// joined an identical prompt still waiting for its reply
+    fn accept_prompt(&mut self, cmd: crate::SendPromptArgs) -> Option<usize>
+    {   let dedup = self.config.deduplication.clone();
+        let accepted = cmd.accepted.clone();
+        let accept = |id: usize| {
+          if let Some(accepted) = &accepted
+          {   let _ = accepted.send(id);
+          }
+        };
+        // A cancellable prompt is only ever cancelled alone
+        if !dedup.enabled || cmd.cancel.is_some()
+        {   let id = self.register_prompt(cmd);
+            accept(id);
+            return Some(id);
+        }
+        let window = std::time::Duration::from_millis(dedup.window_ms);
+        let pending = &self.pending;
+        self.dedup_index.retain(|_, (id, accepted)| {
+          accepted.elapsed() < window && pending.contains_key(id)
+        });
+        let provider = cmd.provider.as_ref()
+          .map(|p| p.name())
+          .unwrap_or_default();
+        // Headers can bill or route a prompt apart from its twins
+        let mut headers: Vec<String> = cmd.extra_headers.iter()
+          .flatten()
+          .map(|(name, value)| format!("{}: {}", name, value))
+          .collect();
+        headers.sort();
+        // As do the settings that shape the request body or its route
+        let settings = format!(
+          "{:?}",
+          (
+            cmd.max_tokens,
+            cmd.temperature,
+            &cmd.extra_params,
+            &cmd.stop_sequences,
+            &cmd.few_shot,
+            &cmd.json_schema,
+            &cmd.request_id,
+            cmd.checkpoint,
+            cmd.total_deadline,
+            cmd.no_failover,
+            cmd.auto_detect_provider
+          )
+        );
+        let key = content_hash(&[
+          provider,
+          &cmd.model,
+          cmd.system_message.as_deref().unwrap_or_default(),
+          &cmd.prompt,
+          cmd.prefill.as_deref().unwrap_or_default(),
+          &headers.join("\n"),
+          &settings
+        ]);
+        if let Some((id, _)) = self.dedup_index.get(&key)
+        {   if let Some(original) = self.pending.get_mut(id)
+            {   debug!("Prompt joins identical prompt {}", id);
+                original.duplicates.push(cmd.reply);
+                accept(*id);
+                return None;
+            }
+        }
+        let tracked = self.dedup_index.len() < dedup.max_pending;
+        let id = self.register_prompt(cmd);
+        accept(id);
+        if tracked
+        {   self.dedup_index.insert(key, (id, std::time::Instant::now()));
+        } else
+        {   debug!("Deduplication full, prompt {} sent as is", id);
+        }
+        Some(id)
+    }
+
+    /// Accept a request answered with a `PromptResponse` and return
+    /// its id
+    fn register_request(&mut self, cmd: crate::SendRequestArgs) -> usize
+    {   let prompt = cmd.request;
+        let (provider, mut request) = self.build_chat(
+          Some(prompt.provider),
+          prompt.model,
+          prompt.system_message,
+          prompt.prompt,
+          prompt.max_tokens,
+          prompt.temperature
+        );
+        if let Some(few_shot) = &prompt.few_shot
+        {   request.add_examples(few_shot);
+        }
+        request.return_raw = prompt.return_raw;
+        request.extra_params = prompt.extra_params;
+        request.stop = prompt.stop_sequences;
+        request.n = prompt.n;
+        if let Some(prefill) = prompt.prefill
+        {   request.messages.push(ChatMessage::assistant(prefill));
+        }
+        request.request_id = Some(
+          prompt.request_id
+            .unwrap_or_else(|| Uuid::new_v4().to_string())
+        );
+        self.register(PendingPrompt
+        {   request
+          , kind: PromptKind::Direct
+          , provider
+          , reply: PromptReplySender::Response(cmd.reply)
+          , tried: vec![]
+          , duplicates: vec![]
+          , checkpoint: None
+          , moderated: false
+          , deadline: prompt.total_deadline
+              .map(|limit| std::time::Instant::now() + limit)
+          , deadline_hit: false
+          , schema_check: prompt.json_schema.map(SchemaCheck::new)
+          , in_flight: false
+          , priority: prompt.priority
+          , no_failover: prompt.no_failover
+          , cancel: None
+          , hops: vec![]
+          , hop_started: None
+        })
+    }
+
+    /// Run the request interceptors on `request`
+    fn intercept(&self, request: &mut ChatRequest)
+    {   for interceptor in &self.interceptors
+        {   interceptor.intercept(request);
+        }
+    }
+
+    /// Where prompt `id` is; ids below `next_prompt_id` that are no
+    /// longer pending have been answered
+    fn prompt_status(&self, id: usize)
+      -> Result<crate::PromptStatus, crate::error::Error>
+    {   match self.pending.get(&id)
+        {   Some(pending) if pending.in_flight => Ok(crate::PromptStatus::InFlight)
+          , Some(_) => Ok(crate::PromptStatus::Queued)
+          , None if id < self.next_prompt_id => Ok(crate::PromptStatus::Done)
+          , None => Err(crate::error::Error::PromptNotFound(id))
+        }
+    }
+
+    fn register(&mut self, mut pending: PendingPrompt) -> usize
+    {   self.intercept(&mut pending.request);
+        let id = self.next_prompt_id;
+        self.next_prompt_id += 1;
+        self.pending.insert(id, pending);
+        id
+    }
+
+    /// Add the prompt to its session and send the whole history,
+    /// unless the session is being summarized
+    async fn send_session_prompt(
+      &mut self
+    , cmd: crate::SendSessionPromptArgs
+    )
+    {   if let Some(held) = self.summarizing.get_mut(&cmd.session_id)
+        {   debug!("Holding prompt for session {}", cmd.session_id);
+            held.push(cmd);
+            return;
+        }
+        let Some(session) = self.sessions.get_mut(&cmd.session_id) else
+        {   let _ = cmd.reply.send(Err(
+              crate::error::Error::SessionNotFound(cmd.session_id)
+            ));
+            return;
+        };
+        session.push(MessageRole::User, cmd.prompt);
+        let messages = session.chat_messages();
+        let (provider, model) = match session.pinned_model.clone()
+        {   Some(pinned) => pinned
+          , None => self.resolve_model(cmd.provider, cmd.model)
+        };
+        let request = ChatRequest
+        {   messages
+          , model
+          , ..Default::default()
+        };
+        let id = self.register(PendingPrompt
+        {   request
+          , kind: PromptKind::Session(cmd.session_id)
+          , provider
+          , reply: cmd.reply.into()
+          , tried: vec![]
+          , duplicates: vec![]
+          , checkpoint: None
+          , moderated: false
+          , deadline: None
+          , deadline_hit: false
+          , schema_check: None
+          , in_flight: false
+          , priority: crate::request::Priority::Normal
+          , no_failover: false
+          , cancel: None
+          , hops: vec![]
+          , hop_started: None
+        });
+        self.schedule_prompt(id).await;
+    }
+
+    /// Ask `model` to summarize a session. Its prompts are held
+    /// until the summary replaces the turns it covers.
+    async fn summarize_session(
+      &mut self
+    , session_id: Uuid
+    , model: (crate::Provider, String)
+    , reply: crate::SummarizeSessionReplySender
+    )
+    {   let Some(session) = self.sessions.get(&session_id) else
+        {   let _ = reply.send(Err(
+              crate::error::Error::SessionNotFound(session_id)
+            ));
+            return;
+        };
+        let summarized = session.messages.len();
+        self.summarize_messages(session_id, model, summarized, reply).await;
+    }
+
+    /// Ask `model` to summarize the first `summarized` messages of a
+    /// session, holding its prompts meanwhile
+    async fn summarize_messages(
+      &mut self
+    , session_id: Uuid
+    , model: (crate::Provider, String)
+    , summarized: usize
+    , reply: crate::SummarizeSessionReplySender
+    )
+    {   let Some(session) = self.sessions.get(&session_id) else
+        {   let _ = reply.send(Err(
+              crate::error::Error::SessionNotFound(session_id)
+            ));
+            return;
+        };
+        if self.summarizing.contains_key(&session_id)
+        {   let _ = reply.send(Err(crate::error::Error::Other(
+              format!("session {} is already being summarized", session_id)
+            )));
+            return;
+        }
+        info!(
+          "Summarizing {} messages of session {} with {:?}",
+          summarized, session_id, model
+        );
+        let (provider, model) = model;
+        let request = ChatRequest::from_prompt(
+          session.summary_prompt_of(summarized), model
+        );
+        self.summarizing.insert(session_id, vec![]);
+        let id = self.register(PendingPrompt
+        {   request
+          , kind: PromptKind::Summary { session_id, summarized }
+          , provider
+          , reply: reply.into()
+          , tried: vec![]
+          , duplicates: vec![]
+          , checkpoint: None
+          , moderated: false
+          , deadline: None
+          , deadline_hit: false
+          , schema_check: None
+          , in_flight: false
+          , priority: crate::request::Priority::Normal
+          , no_failover: false
+          , cancel: None
+          , hops: vec![]
+          , hop_started: None
+        });
+        self.schedule_prompt(id).await;
+    }
+
+    /// Record a session turn's reply and prune the session if it
+    /// grew too long
+    async fn finish_session_turn(
+      &mut self
+    , session_id: Uuid
+    , used: Option<(crate::Provider, String)>
+    , result: &crate::SendPromptReply
+    )
+    {   let Some(session) = self.sessions.get_mut(&session_id) else
+        {   return
+        };
+        if result.is_ok()
+        {   session.last_used_model = used;
+        }
+        match result
+        {   Ok(text) => session.push(MessageRole::Assistant, text.clone())
+          , Err(_) => {
+              // Keep the history a sequence of answered turns
+              if session.messages.last()
+                .is_some_and(|m| m.role == MessageRole::User)
+              {   session.messages.pop();
+              }
+            }
+        }
+        if !session.needs_pruning()
+        {   self.summarize_near_context_limit(session_id).await;
+            return;
+        }
+        match session.pruning.clone()
+        {   PruningStrategy::DropOldest => session.drop_oldest()
+          , PruningStrategy::Summary { model } => {
+              if !self.summarizing.contains_key(&session_id)
+              {   // Nobody waits on an automatic summary
+                  let (reply_tx, _) = mpsc::unbounded_channel();
+                  self.summarize_session(session_id, model, reply_tx)
+                    .await;
+              }
+            }
+        }
+    }
+
+    /// Summarize a session's oldest turns once its history fills the
+    /// trigger share of its last model's context window
+    async fn summarize_near_context_limit(&mut self, session_id: Uuid)
+    {   if self.summarizing.contains_key(&session_id)
+        {   return;
+        }
+        let Some(session) = self.sessions.get(&session_id) else { return };
+        let (Some(strategy), Some((provider, model)))
+          = (&session.summarization, &session.last_used_model)
+        else
+        {   return
+        };
+        let tokens_available = self.context_window(provider, model);
+        if tokens_available == 0
+        {   return;
+        }
+        let percent_full = session.estimated_tokens() as f32
+          / tokens_available as f32 * 100.0;
+        if percent_full < strategy.trigger_percent
+        {   return;
+        }
+        let summarized = session.oldest_turns_end(strategy.oldest_messages);
+        let model = strategy.model.clone();
+        debug!(
+          "Session {} filled {:.1}% of the {} context",
+          session_id, percent_full, model.1
+        );
+        // Nobody waits on an automatic summary
+        let (reply_tx, _) = mpsc::unbounded_channel();
+        self.summarize_messages(session_id, model, summarized, reply_tx)
+          .await;
+    }
+
+    /// Put a summary in place and release the session's held prompts
+    async fn finish_summary(
+      &mut self
+    , session_id: Uuid
+    , summarized: usize
+    , result: &crate::SendPromptReply
+    )
+    {   if let Some(session) = self.sessions.get_mut(&session_id)
+        {   match result
+            {   Ok(summary) => {
+                  debug!("Session {} summarized", session_id);
+                  session.apply_summary(summary.clone(), summarized);
+                }
+              , Err(e) => {
+                  warn!("Summarizing session {} failed: {}", session_id, e);
+                  if session.needs_pruning()
+                  {   session.drop_oldest();
+                  }
+                }
+            }
+        }
+        for cmd in self.summarizing.remove(&session_id).unwrap_or_default()
+        {   self.send_session_prompt(cmd).await;
+        }
+    }
+
+    /// Dispatch a pending prompt now, or after the provider's rate
+    /// limiter allows it
+    async fn schedule_prompt(&mut self, id: usize)
+    {   let Some(pending) = self.pending.get(&id) else { return };
+        let provider = pending.provider.clone();
+        let prompt = pending.request.messages.iter()
+          .map(|m| m.content.as_str())
+          .collect::<Vec<_>>()
+          .join("\n");
+        let priority = pending.priority;
+        let cancel = pending.cancel.clone();
+        let wait = self.acquire_rate_limit(&provider, &prompt).await;
+        if wait.is_zero()
+        {   self.dispatch_prompt(id);
+        } else
+        {   debug!("Rate limited {:?}, delaying {:?}", provider, wait);
+            self.rate_limited.entry(provider.clone())
+              .or_default()
+              .insert((priority, id));
+            // Cancelled, it leaves the queue; the slot goes to the next
+            if let Some(token) = cancel
+            {   let delayed_tx = self.delayed_tx.clone();
+                tokio::spawn(async move {
+                  tokio::select!
+                  {   () = token.cancelled() => { let _ = delayed_tx.send(id); }
+                    , () = tokio::time::sleep(wait) => {}
+                  }
+                });
+            }
+            let slot_tx = self.slot_tx.clone();
+            tokio::spawn(async move {
+              tokio::time::sleep(wait).await;
+              let _ = slot_tx.send(provider);
+            });
+        }
+    }
+
+    /// Use a rate limit slot of `provider` that has come up: dispatch
+    /// its waiting prompt of highest priority, which need not be the
+    /// one that reserved the slot
+    fn dispatch_rate_limited(&mut self, provider: &crate::Provider)
+    {   let Some(waiting) = self.rate_limited.get_mut(provider) else { return };
+        while let Some((priority, id)) = waiting.pop_first()
+        {   if self.pending.contains_key(&id)
+            {   debug!("Dispatching rate-limited prompt {} ({:?})", id, priority);
+                self.dispatch_prompt(id);
+                return;
+            }
+        }
+    }
+
+    /// Dispatch a pending prompt once `wait` has passed, or as soon
+    /// as it is cancelled
+    fn dispatch_after(&self, id: usize, wait: std::time::Duration)
+    {   let delayed_tx = self.delayed_tx.clone();
+        let token = self.pending.get(&id)
+          .and_then(|p| p.cancel.clone())
+          .unwrap_or_default();
+        tokio::spawn(async move {
+          tokio::select!
+          {   () = tokio::time::sleep(wait) => {}
+            , () = token.cancelled() => {}
+          }
+          let _ = delayed_tx.send(id);
+        });
+    }
+
+    /// Retry a prompt its provider rejected: transient errors on the
+    /// same model after `retry_after` or the failover backoff, then
+    /// on the next fallback; others `failover_on` covers on the next
+    /// fallback right away. A rate limit that outlasts the retries
+    /// takes its provider out of the fallbacks until it cools off.
+    /// Returns false when the error goes to the caller.
+    async fn retry_provider_error(
+      &mut self
+    , id: usize
+    , error: &crate::error::Error
+    ) -> bool
+    {   let crate::error::Error::ProviderApiError { code, retry_after, .. }
+          = error else { return false };
+        let failover = self.config.failover.clone();
+        let Some(pending) = self.pending.get(&id) else { return false };
+        let retry_after = retry_after.map(|wait| self.cap_retry_after(wait));
+        if error.failover_trigger()
+          == Some(crate::failover::FailoverTrigger::RateLimit)
+        {   let until = std::time::SystemTime::now() + retry_after
+              .unwrap_or(crate::failover::DEFAULT_RATE_LIMIT_COOL_OFF);
+            let model = (pending.provider.clone(), pending.request.model.clone());
+            let opened = self.rate_limited_models.insert(model.clone(), until)
+              .is_none_or(|previous| previous <= std::time::SystemTime::now());
+            if opened
+            {   broadcast(
+                  &mut self.event_subscribers,
+                  crate::AllmEvent::CircuitBreakerOpened
+                  {   provider: model.0
+                    , model: model.1
+                  }
+                );
+            }
+        }
+        if !failover.enabled || pending.no_failover
+        {   return false;
+        }
+        if code.is_transient()
+        {   let current = (pending.provider.clone(), pending.request.model.clone());
+            let attempts = pending.tried.iter()
+              .filter(|tried| **tried == current)
+              .count();
+            if attempts <= failover.max_retries
+            {   let wait = retry_after.unwrap_or_else(|| {
+                  crate::failover::RetryPolicy::new(
+                    failover.max_retries,
+                    failover.backoff_multiplier,
+                    failover.initial_backoff_ms
+                  ).backoff_for_attempt(attempts - 1)
+                });
+                if !self.within_deadline(id, wait)
+                {   return false;
+                }
+                info!(
+                  "Prompt {} got {} from {:?}, retry {} in {:?}",
+                  id, code, current.0, attempts, wait
+                );
+                self.dispatch_after(id, wait);
+                return true;
+            }
+            if error.failover_trigger()
+              == Some(crate::failover::FailoverTrigger::RateLimit)
+            {   self.fallback_preferences.mark_rate_limited(
+                  &current.0,
+                  retry_after.unwrap_or(crate::failover::DEFAULT_RATE_LIMIT_COOL_OFF)
+                );
+            }
+        }
+        self.fail_over(id, error).await
+    }
+
+    /// Move a prompt that failed with `error` to its next fallback
+    /// when `failover_on` covers the error. Returns false when the
+    /// error goes to the caller.
+    async fn fail_over(&mut self, id: usize, error: &crate::error::Error)
+      -> bool
+    {   let failover = &self.config.failover;
+        if !failover.enabled || !error.is_failover_eligible(&failover.failover_on)
+        {   return false;
+        }
+        if self.advance_to_fallback(id, error)
+        {   self.schedule_prompt(id).await;
+            return true;
+        }
+        false
+    }
+
+    /// Match a reply against the prompt's `json_schema`, if any
+    fn check_json_schema(&mut self, id: usize, text: &str)
+      -> Result<(), String>
+    {   let Some(check) = self.pending.get_mut(&id)
+          .and_then(|p| p.schema_check.as_mut()) else { return Ok(()) };
+        check.attempts += 1;
+        let output = crate::utils::json_schema::parse_output(text)?;
+        crate::utils::json_schema::validate(&check.retry.schema, &output)
+    }
+
+    /// Send a reply that did not match the schema back to the model
+    /// with a correction message. Returns false once `max_attempts`
+    /// replies were received.
+    fn correct_json_schema(&mut self, id: usize, text: &str, mismatch: &str)
+      -> bool
+    {   let Some(pending) = self.pending.get(&id) else { return false };
+        let Some(check) = pending.schema_check.as_ref() else { return false };
+        if check.attempts >= check.retry.max_attempts
+        {   warn!(
+              "Prompt {} still off schema after {} replies: {}",
+              id, check.attempts, mismatch
+            );
+            return false;
+        }
+        if !self.within_deadline(id, std::time::Duration::ZERO)
+        {   return false;
+        }
+        let Some(pending) = self.pending.get_mut(&id) else { return false };
+        info!("Prompt {} off schema ({}), asking to correct", id, mismatch);
+        pending.request.messages.push(ChatMessage::assistant(text));
+        pending.request.messages.push(ChatMessage::user(format!(
+          "Your output didn't match the schema: {}. Reply with \
+           corrected JSON only, matching this schema: {}",
+          mismatch, pending.schema_check.as_ref()
+            .map(|c| c.retry.schema.to_string())
+            .unwrap_or_default()
+        )));
+        true
+    }
+
+    /// True when the next attempt of `id`, `wait` from now, starts
+    /// before the prompt's `total_deadline`; otherwise the prompt is
+    /// marked to fail with `Error::DeadlineExceeded`
+    fn within_deadline(&mut self, id: usize, wait: std::time::Duration)
+      -> bool
+    {   let Some(pending) = self.pending.get_mut(&id) else { return false };
+        let Some(deadline) = pending.deadline else { return true };
+        if std::time::Instant::now() + wait < deadline
+        {   return true;
+        }
+        info!("Prompt {} gives up: its total deadline has passed", id);
+        pending.deadline_hit = true;
+        false
+    }
+
+    /// Hand a pending prompt to its provider actor; the reply comes
+    /// back through `in_flight`
+    fn dispatch_prompt(&mut self, id: usize)
+    {   let Some(pending) = self.pending.get_mut(&id) else { return };
+        if pending.cancel.as_ref().is_some_and(|token| token.is_cancelled())
+        {   if pending.in_flight
+            {   return;
+            }
+            pending.in_flight = true;
+            for waiting in self.rate_limited.values_mut()
+            {   waiting.retain(|(_, waiting)| *waiting != id);
+            }
+            self.in_flight.push(Box::pin(async move { (id, Err(cancelled())) }));
+            return;
+        }
+        if self.config.moderate_before_send
+          && !pending.moderated
+          && !matches!(pending.kind, PromptKind::Summary { .. })
+        {   self.moderate_prompt(id);
+            return;
+        }
+        let provider = pending.provider.clone();
+        let model = pending.request.model.clone();
+        if let Some(request_id) = &pending.request.request_id
+        {   debug!(
+              "Prompt {} [{}] to {:?}/{}",
+              id, request_id, provider, model
+            );
+        }
+        broadcast(&mut self.event_subscribers, crate::AllmEvent::RequestStarted
+        {   request_id: pending.event_id(id)
+          , provider: provider.clone()
+          , model: model.clone()
+        });
+        pending.tried.push((provider.clone(), model));
+        pending.in_flight = true;
+        pending.hop_started.get_or_insert_with(std::time::Instant::now);
+        self.provider_counters.entry(provider.clone())
+          .or_default()
+          .requests += 1;
+        let checked = crate::providers::check_stop_sequences(
+          &provider, &pending.request
+        ).and_then(|()| {
+          crate::providers::check_prefill(&provider, &pending.request)
+        });
+        if let Err(e) = checked.and_then(|()| self.init_client(&provider))
+        {   self.in_flight.push(Box::pin(async move { (id, Err(e)) }));
+            return;
+        }
+
+        let Some(pending) = self.pending.get(&id) else { return };
+        let client = self.clients.get(&provider)
+          .and_then(ProviderClientState::ready);
+        let completion: Completion = match client
+        {   Some(client) if pending.checkpoint.is_some() => {
+              let attempt = pending.checkpoint.as_ref()
+                .map(|c| c.attempt.clone())
+                .unwrap_or_default();
+              if let Ok(mut text) = attempt.lock()
+              {   text.clear();
+              }
+              let (reply_tx, reply_rx)
+                = mpsc::channel(self.config.stream_buffer_size.max(1));
+              match client.send_chat_stream(pending.request.clone(), reply_tx)
+              {   Ok(()) => Box::pin(collect_stream(id, reply_rx, attempt))
+                , Err(e) => Box::pin(async move { (id, Err(e)) })
+              }
+            }
+          , Some(client)
+              if pending.request.return_raw
+                || pending.request.n.is_some()
+                || pending.request.echoes_prompt() => {
+              send_chat_response(client, id, pending.request.clone())
+                .unwrap_or_else(|e| Box::pin(async move { (id, Err(e)) }))
+            }
+          , // Token usage and separate thinking come with full
+            // responses; providers without them get estimated usage
+            // and thinking from the text alone
+            Some(client)
+              if self.context_warnings_enabled()
+                || matches!(pending.reply, PromptReplySender::Response(_)) => {
+              send_chat_response(client, id, pending.request.clone())
+                .unwrap_or_else(|_| send_chat(client, id, &pending.request))
+            }
+          , Some(client) => send_chat(client, id, &pending.request)
+          , None => {
+              error!("Provider not implemented");
+              let e = crate::error::Error::ProviderNotImplemented(
+                format!("{:?}", provider)
+              );
+              Box::pin(async move { (id, Err(e)) })
+            }
+        };
+        let completion: Completion = match pending.cancel.clone()
+        {   Some(token) => Box::pin(async move {
+              tokio::select!
+              {   biased;
+                  () = token.cancelled() => (id, Err(cancelled()))
+                , completion = completion => completion
+              }
+            })
+          , None => completion
+        };
+        self.in_flight.push(completion);
+    }
+
+    /// Screen the newest message of a pending prompt; the verdict
+    /// comes back through `moderated_rx`
+    fn moderate_prompt(&mut self, id: usize)
+    {   let Some(pending) = self.pending.get(&id) else { return };
+        let input = pending.request.messages.last()
+          .map(|m| m.content.clone())
+          .unwrap_or_default();
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+        self.send_openai_command(OpenAICommand::Moderate
+        {   input
+          , reply: reply_tx
+        });
+        let moderated_tx = self.moderated_tx.clone();
+        tokio::spawn(async move {
+          let verdict = match reply_rx.recv().await
+          {   Some(Ok(result)) if result.flagged => Err(
+                crate::error::Error::ContentFlagged(result.flagged_categories())
+              )
+            , Some(Ok(_)) => Ok(())
+            , Some(Err(e)) => Err(e)
+            , None => Err(crate::error::Error::Other(
+                "OpenAI assistants disconnected".to_string()
+              ))
+          };
+          let _ = moderated_tx.send((id, verdict));
+        });
+    }
+
+    /// Send a screened prompt on, or fail it without contacting its
+    /// provider
+    async fn finish_moderation(
+      &mut self
+    , id: usize
+    , verdict: Result<(), crate::error::Error>
+    )
+    {   let Some(pending) = self.pending.get_mut(&id) else { return };
+        match verdict
+        {   Ok(()) => {
+              pending.moderated = true;
+              self.dispatch_prompt(id);
+            }
+          , Err(e) => {
+              warn!("Prompt {} not sent: {}", id, e);
+              let kind = pending.kind;
+              self.deliver(id, kind, Err(e)).await;
+            }
+        }
+    }
+
+    /// Apply the validators' rewrites, then `response_normalization`,
+    /// to a reply that passed them
+    fn rewrite_response(&mut self, id: usize, mut response: ChatResponse)
+      -> ChatResponse
+    {   let Some(pending) = self.pending.get_mut(&id) else
+        {   return response;
+        };
+        for text in std::iter::once(&mut response.text)
+          .chain(response.choices.iter_mut())
+        {   for validator in &self.validators
+            {   if let Some(rewritten) = validator.rewrite(
+                  text,
+                  &mut pending.request.metadata
+                )
+                {   *text = rewritten;
+                }
+            }
+            *text = ResponseNormalizer::normalize(
+              text,
+              &self.config.response_normalization
+            );
+        }
+        response
+    }
+
+    /// Resend a checkpointed prompt that timed out, asking the model
+    /// to continue from the text received so far. Returns false when
+    /// it has no checkpoint or no resume attempts left.
+    fn resume_from_checkpoint(&mut self, id: usize) -> bool
+    {   let max_resumes = self.config.checkpoint.max_resume_attempts;
+        let Some(pending) = self.pending.get_mut(&id) else { return false };
+        let Some(checkpoint) = pending.checkpoint.as_mut() else
+        {   return false;
+        };
+        if let Ok(mut attempt) = checkpoint.attempt.lock()
+        {   checkpoint.received.push_str(&std::mem::take(&mut *attempt));
+        }
+        let partial = tail(&checkpoint.received, CHECKPOINT_TAIL_CHARS)
+          .to_string();
+        self.partial_responses.insert(
+          checkpoint.request_id.clone(),
+          partial.clone()
+        );
+        if checkpoint.resumes >= max_resumes
+        {   return false;
+        }
+        checkpoint.resumes += 1;
+        info!(
+          "Prompt {} [{}] timed out, resume {} from {} characters",
+          id, checkpoint.request_id, checkpoint.resumes, partial.chars().count()
+        );
+        let system = ChatMessage::system(resume_system_message(
+          &partial,
+          checkpoint.system_message.as_deref()
+        ));
+        match pending.request.messages.first_mut()
+        {   Some(first) if first.role == Role::System => *first = system
+          , _ => pending.request.messages.insert(0, system)
+        }
+        true
+    }
+
+    /// Complete the text of a checkpointed prompt with what its
+    /// timed-out attempts received, and drop the checkpoint
+    fn finish_checkpoint(
+      &mut self
+    , id: usize
+    , result: Result<ChatResponse, crate::error::Error>
+    ) -> Result<ChatResponse, crate::error::Error>
+    {   let Some(checkpoint) = self.pending.get(&id)
+          .and_then(|p| p.checkpoint.as_ref())
+        else
+        {   return result;
+        };
+        let mut response = result?;
+        self.partial_responses.remove(&checkpoint.request_id);
+        response.text.insert_str(0, &checkpoint.received);
+        Ok(response)
+    }
+
+    /// Provider request body a prompt would produce
+    fn dry_run(
+      &mut self
+    , request: crate::request::PromptRequest
+    ) -> Result<serde_json::Value, crate::error::Error>
+    {   check_few_shot(request.few_shot.as_ref())?;
+        let extra_params = request.extra_params;
+        let stop = request.stop_sequences;
+        let prefill = request.prefill;
+        let n = request.n;
+        let (provider, mut chat) = self.build_chat(
+          Some(request.provider),
+          request.model,
+          request.system_message,
+          request.prompt,
+          request.max_tokens,
+          request.temperature
+        );
+        if let Some(few_shot) = &request.few_shot
+        {   chat.add_examples(few_shot);
+        }
+        chat.extra_params = extra_params;
+        chat.stop = stop;
+        chat.n = n;
+        if let Some(prefill) = prefill
+        {   chat.messages.push(ChatMessage::assistant(prefill));
+        }
+        self.intercept(&mut chat);
+        crate::providers::check_stop_sequences(&provider, &chat)?;
+        crate::providers::check_prefill(&provider, &chat)?;
+        self.init_client(&provider)?;
+        match self.clients.get(&provider).and_then(ProviderClientState::ready)
+        {   Some(client) => client.request_body(chat)
+          , None => Err(crate::error::Error::ProviderNotImplemented(
+              format!("{:?}", provider)
+            ))
+        }
+    }
+
+    /// Hand a streaming prompt straight to its provider actor,
+    /// which sends the chunks to the caller
+    fn dispatch_stream(&mut self, cmd: crate::SendStreamArgs)
+    {   let (provider, model) = match self.policy_target(cmd.provider.clone(), &cmd.model)
+        {   Ok(Some(target)) => target
+          , Ok(None) => self.resolve_model(cmd.provider, cmd.model)
+          , Err(e) => {
+              let _ = cmd.reply.try_send(Err(e));
+              return;
+            }
+        };
+        let Some(reply) = self.share_stream(&provider, &model, &cmd.prompt, cmd.reply)
+        else { return };
+        let mut request = ChatRequest::from_prompt(cmd.prompt, model);
+        self.intercept(&mut request);
+        let result = self.init_client(&provider).and_then(|()| {
+          match self.clients.get(&provider).and_then(ProviderClientState::ready)
+          {   Some(client) => client.send_chat_stream(request, reply.clone())
+            , None => Err(crate::error::Error::ProviderNotImplemented(
+                format!("{:?}", provider)
+              ))
+          }
+        });
+        if let Err(e) = result
+        {   error!("Stream not started: {}", e);
+            // The channel is new, so there is room for the error
+            let _ = reply.try_send(Err(e));
+        }
+    }
+
+    /// Sender the provider should stream to, or `None` when the
+    /// stream joined an identical one started less than `window_ms`
+    /// ago. With deduplication on, the provider streams to a
+    /// `SharedStream` that `reply` and any later duplicates read.
+    fn share_stream(
+      &mut self
+    , provider: &crate::Provider
+    , model: &str
+    , prompt: &str
+    , reply: crate::StreamReplySender
+    ) -> Option<crate::StreamReplySender>
+    {   let dedup = self.config.deduplication.clone();
+        if !dedup.enabled
+        {   return Some(reply);
+        }
+        let window = std::time::Duration::from_millis(dedup.window_ms);
+        self.stream_index.retain(|_, (started, _)| started.elapsed() < window);
+        let key = content_hash(&[provider.name(), model, prompt]);
+        let reply = match self.stream_index.get(&key)
+        {   Some((_, shared)) => match shared.join(reply)
+            {   Ok(()) => {
+                  debug!("Stream joins identical stream of {}", model);
+                  return None;
+                }
+              , Err(reply) => reply
+            }
+          , None => reply
+        };
+        let buffer_size = self.config.stream_buffer_size.max(1);
+        let shared = SharedStream::new(
+          self.config.stream_dedup_replay_buffer_size,
+          buffer_size
+        );
+        // A stream nothing was sent on yet always takes subscribers
+        let _ = shared.join(reply);
+        let (provider_tx, provider_rx) = mpsc::channel(buffer_size);
+        tokio::spawn(shared.clone().pump(provider_rx));
+        if self.stream_index.len() < dedup.max_pending
+        {   self.stream_index.insert(key, (std::time::Instant::now(), shared));
+        } else
+        {   debug!("Deduplication full, stream of {} sent as is", model);
+        }
+        Some(provider_tx)
+    }
+
+    /// True, with a warning, for a turn of a session pinned to its
+    /// model, which must not move to another one
+    fn pinned_session(&self, id: usize) -> bool
+    {   let Some(pending) = self.pending.get(&id) else { return false };
+        let PromptKind::Session(session_id) = pending.kind else { return false };
+        let pinned = self.sessions.get(&session_id)
+          .is_some_and(|s| s.pinned_model.is_some());
+        if pinned
+        {   warn!(
+              "Prompt {} of session {} pinned to {:?}/{}, not failing over",
+              id, session_id, pending.provider, pending.request.model
+            );
+        }
+        pinned
+    }
+
+    /// True for a prompt sent with `no_failover`, or a turn of a
+    /// pinned session: it must stay on its model
+    fn stays_on_model(&self, id: usize) -> bool
+    {   self.pending.get(&id).is_some_and(|p| p.no_failover)
+          || self.pinned_session(id)
+    }
+
+    /// Point a pending prompt at the first fallback preference it
+    /// has not tried yet, reporting the switch away from the model
+    /// that failed with `reason`. Returns false when none are left
+    /// or the prompt's deadline has passed; nothing is reported then.
+    fn advance_to_fallback(&mut self, id: usize, reason: &crate::error::Error)
+      -> bool
+    {   if self.stays_on_model(id)
+        {   return false;
+        }
+        let Some(pending) = self.pending.get(&id) else { return false };
+        let costs = crate::failover::CostAwareFailoverStrategy
+        {   catalog: &self.model_catalog
+          , prices: &self.price_table
+          , budget_usd: self.config.failover_budget_constraint_usd
+        };
+        let input_tokens: usize = pending.request.messages.iter()
+          .map(|m| crate::utils::tokens::estimate_tokens(&m.content))
+          .sum();
+        let candidates = self.fallback_preferences.available()
+          .filter(|entry| {
+            !pending.tried.iter()
+              .any(|(p, m)| *p == entry.provider && *m == entry.model)
+          })
+          .filter(|entry| costs.within_budget(entry, input_tokens));
+        let next = match self.config.failover.strategy
+        {   // Taken by value so the borrows end before `within_deadline`
+            crate::failover::FailoverStrategy::Ordered
+              => candidates.into_iter().next()
+          , crate::failover::FailoverStrategy::CostMinimizing
+              => costs.cheapest(candidates)
+        };
+        let Some((provider, model)) = next
+          .map(|entry| (entry.provider.clone(), entry.model.clone()))
+        else { return false };
+        if !self.within_deadline(id, std::time::Duration::ZERO)
+        {   return false;
+        }
+        let Some(pending) = self.pending.get_mut(&id) else { return false };
+        info!(
+          "Prompt {} falling back to {:?}/{}",
+          id, provider, model
+        );
+        let event = crate::FailoverEvent
+        {   from: (pending.provider.clone(), pending.request.model.clone())
+          , to: (provider.clone(), model.clone())
+          , reason: reason.clone()
+          , attempt: pending.tried.len()
+        };
+        broadcast(&mut self.event_subscribers, crate::AllmEvent::Failover
+        {   from: event.from.clone()
+          , to: event.to.clone()
+          , reason: reason.to_string()
+        });
+        pending.finish_hop(Some(reason.clone()));
+        pending.provider = provider;
+        pending.request.model = model;
+        let callback = self.failover_callback.lock().ok()
+          .and_then(|slot| slot.clone());
+        if let Some(callback) = callback
+        {   callback(event);
+        }
+        true
+    }
+
+    /// Point a prompt that overflowed its model's context at a
+    /// larger model of the same family. Returns false when the
+    /// catalog has none.
+    fn upgrade_context(&mut self, id: usize) -> bool
+    {   if self.stays_on_model(id)
+        {   return false;
+        }
+        let Some(pending) = self.pending.get_mut(&id) else { return false };
+        let upgrade = crate::failover::larger_context_model(
+          &self.model_catalog,
+          &pending.provider,
+          &pending.request.model,
+          &pending.tried
+        );
+        match upgrade
+        {   Some(model) => {
+              info!(
+                "Prompt {} overflowed {}, upgrading to {} ({} tokens)",
+                id, pending.request.model, model.name,
+                model.max_context_tokens
+              );
+              pending.finish_hop(Some(
+                crate::error::Error::ContextWindowExceeded
+              ));
+              pending.request.model = model.name.clone();
+              true
+            }
+          , None => false
+        }
+    }
+
+    /// Add an exported session under a new id, so it cannot collide
+    /// with a live one
+    fn import_session(&mut self, json: &str)
+      -> Result<Uuid, crate::error::Error>
+    {   let mut session = ConversationSession::from_json(json)
+          .map_err(|e| {
+            error!("Invalid session JSON: {}", e);
+            crate::error::Error::ParseError(e.to_string())
+          })?;
+        if session.max_messages == 0
+        {   return Err(crate::error::Error::InvalidConfiguration(
+              "session max_messages must be positive".to_string()
+            ));
+        }
+        let original = session.id;
+        session.id = Uuid::new_v4();
+        info!("Imported session {} as {}", original, session.id);
+        let id = session.id;
+        self.sessions.insert(id, session);
+        Ok(id)
+    }
+
+    /// Add catalog entries, replacing those with the same provider
+    /// and name
+    fn register_models(&mut self, models: Vec<crate::ModelInfo>)
+    {   for model in models
+        {   self.model_catalog.retain(|m| {
+              m.provider != model.provider || m.name != model.name
+            });
+            self.model_catalog.push(model);
+        }
+    }
+
+    /// The model catalog as a JSON array of `ModelInfo`
+    fn export_model_registry(&self) -> Result<String, crate::error::Error>
+    {   Ok(serde_json::to_string_pretty(&self.model_catalog)?)
+    }
+
+    /// Merge an `export_model_registry` JSON into the catalog; the
+    /// number of entries it held
+    fn import_model_registry(&mut self, json: &str)
+      -> Result<usize, crate::error::Error>
+    {   let models: Vec<crate::ModelInfo> = serde_json::from_str(json)
+          .map_err(|e| {
+            error!("Invalid model registry JSON: {}", e);
+            crate::error::Error::ParseError(e.to_string())
+          })?;
+        let count = models.len();
+        self.register_models(models);
+        Ok(count)
+    }
+
+    /// Merge the registry cached at `path`; a missing or unreadable
+    /// cache only costs a warning
+    fn load_model_registry(&mut self, path: &str)
+    {   match std::fs::read_to_string(path)
+        {   Ok(json) => match self.import_model_registry(&json)
+            {   Ok(count) => {
+                  info!("Loaded {} models from registry cache {}", count, path);
+                }
+              , Err(e) => warn!("Ignoring registry cache {}: {}", path, e)
+            }
+          , Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+              debug!("No registry cache at {} yet", path);
+            }
+          , Err(e) => warn!("Cannot read registry cache {}: {}", path, e)
+        }
+    }
+
+    /// Write the catalog to `model_registry_cache_path`, if set
+    fn save_model_registry(&self)
+    {   let Some(path) = &self.config.model_registry_cache_path
+        else { return };
+        let written = self.export_model_registry()
+          .and_then(|json| Ok(std::fs::write(path, json)?));
+        match written
+        {   Ok(()) => debug!(
+              "Saved {} models to registry cache {}",
+              self.model_catalog.len(), path
+            )
+          , Err(e) => warn!("Cannot save registry cache {}: {}", path, e)
+        }
+    }
+
+    /// Hand `command` to the OpenAI client, creating it if needed
+    fn send_openai_command(&mut self, command: OpenAICommand)
+    {   if let Err(e) = self.init_client(&crate::Provider::OpenAI)
+        {   command.fail(e);
+            return;
+        }
+        match self.clients.get(&crate::Provider::OpenAI)
+          .and_then(ProviderClientState::ready)
+        {   Some(client) => client.send_openai_command(command)
+          , None => command.fail(
+              crate::error::Error::ProviderNotImplemented(
+                "OpenAI is not configured".to_string()
+              )
+            )
+        }
+    }
+
+    /// Catalog models of every provider with a client; providers the
+    /// catalog lacks get their built-in default model
+    fn capability_matrix(&self) -> CapabilityMatrix
+    {   let row = |info: &crate::ModelInfo| {
+          let mut row = ModelCapabilityRow::from_model_info(info);
+          if let Some((input, output))
+            = crate::utils::cost::price_for(&self.price_table, info)
+          {   row.cost_per_million_in = Some(input);
+              row.cost_per_million_out = Some(output);
+          }
+          row
+        };
+        let mut rows: Vec<ModelCapabilityRow> = vec![];
+        for provider in self.clients.keys()
+        {   let entries: Vec<&crate::ModelInfo> = self.model_catalog.iter()
+              .filter(|m| m.provider == *provider)
+              .collect();
+            if entries.is_empty()
+            {   rows.extend(
+                  crate::providers::default_model_info(provider)
+                    .as_ref()
+                    .map(row)
+                );
+            } else
+            {   rows.extend(
+                  entries.into_iter().map(row)
+                );
+            }
+        }
+        rows.sort_by(|a, b| {
+          (a.provider.name(), &a.model).cmp(&(b.provider.name(), &b.model))
+        });
+        CapabilityMatrix { rows }
+    }
+
+    /// Context tokens of `model` from the catalog or the provider's
+    /// default model; 0 when unknown
+    fn context_window(&self, provider: &crate::Provider, model: &str)
+      -> usize
+    {   self.model_catalog.iter()
+          .find(|m| m.provider == *provider && m.name == model)
+          .map(|m| m.max_context_tokens)
+          .or_else(|| {
+            crate::providers::default_model_info(provider)
+              .filter(|m| m.name == model)
+              .map(|m| m.max_context_tokens)
+          })
+          .unwrap_or(0)
+    }
+
+    /// True once a `ContextUsageWarning` channel is set
+    fn context_warnings_enabled(&self) -> bool
+    {   self.context_warning_tx.lock().is_ok_and(|slot| slot.is_some())
+    }
+
+    /// Send a `ContextUsageWarning` when the prompt of `id` filled
+    /// `context_warning_threshold_percent` of its model's context.
+    /// Models of unknown context size are skipped.
+    fn warn_context_usage(&self, id: usize, response: &ChatResponse)
+    {   let Some(pending) = self.pending.get(&id) else { return };
+        if matches!(pending.kind, PromptKind::Summary { .. })
+        {   return;
+        }
+        let Ok(slot) = self.context_warning_tx.lock() else { return };
+        let Some(tx) = slot.as_ref() else { return };
+        let provider = &pending.provider;
+        let model = &pending.request.model;
+        let tokens_available = self.context_window(provider, model);
+        if tokens_available == 0
+        {   return;
+        }
+        let tokens_used = response.input_tokens.unwrap_or_else(|| {
+          pending.request.messages.iter()
+            .map(|m| crate::utils::tokens::estimate_tokens(&m.content))
+            .sum()
+        });
+        let percent_full
+          = tokens_used as f32 / tokens_available as f32 * 100.0;
+        if percent_full >= self.config.context_warning_threshold_percent
+        {   warn!(
+              "Prompt {} filled {:.1}% of the {} context",
+              id, percent_full, model
+            );
+            let _ = tx.send(crate::ContextUsageWarning
+            {   provider: provider.clone()
+              , model: model.clone()
+              , tokens_used
+              , tokens_available
+              , percent_full
+            });
+        }
+    }
+
+    /// Current counters of every provider client, ordered by name
+    fn metrics_snapshot(&self) -> MetricsSnapshot
+    {   let mut providers: Vec<ProviderStatus> = self.clients.keys()
+          .map(|provider| self.provider_status(provider))
+          .collect();
+        providers.sort_by_key(|p| format!("{:?}", p.provider));
+        MetricsSnapshot
+        {   pending_prompts: self.pending.len()
+          , sessions: self.sessions.len()
+          , providers
+        }
+    }
+
+    /// Counters and health of one provider client
+    fn provider_status(&self, provider: &crate::Provider) -> ProviderStatus
+    {   let counters = self.provider_counters.get(provider)
+          .cloned()
+          .unwrap_or_default();
+        let health = match self.supervisor.state(provider)
+        {   SupervisionState::Failed => ProviderHealth::Unavailable
+          , SupervisionState::Restarting { .. } => ProviderHealth::Degraded
+          , SupervisionState::Running if counters.consecutive_failures > 0 => {
+              ProviderHealth::Degraded
+            }
+          , SupervisionState::Running => ProviderHealth::Healthy
+        };
+        ProviderStatus
+        {   provider: provider.clone()
+          , initialized: self.clients.get(provider)
+              .and_then(ProviderClientState::ready)
+              .is_some()
+          , default_model: self.default_models.get(provider).cloned()
+          , in_flight: self.pending.values()
+              .filter(|p| p.provider == *provider)
+              .count()
+          , requests: counters.requests
+          , succeeded: counters.succeeded
+          , failed: counters.failed
+          , last_error: counters.last_error
+          , health
+        }
+    }
+
+    /// Receiver of `provider`'s status, starting with the current one
+    fn watch_provider_status(&mut self, provider: crate::Provider)
+      -> Result<tokio::sync::watch::Receiver<ProviderStatus>, crate::error::Error>
+    {   if !self.clients.contains_key(&provider)
+        {   return Err(crate::error::Error::ProviderNotImplemented(
+              format!("{:?}", provider)
+            ));
+        }
+        let status = self.provider_status(&provider);
+        let watch = self.status_watch.entry(provider)
+          .or_insert_with(|| tokio::sync::watch::channel(status).0);
+        Ok(watch.subscribe())
+    }
+
+    /// Send each watched provider's status to its watchers, when it
+    /// changed; runs after every command the loop handles
+    fn publish_provider_status(&self)
+    {   for (provider, watch) in &self.status_watch
+        {   let status = self.provider_status(provider);
+            watch.send_if_modified(|current| {
+              let changed = *current != status;
+              if changed
+              {   *current = status;
+              }
+              changed
+            });
+        }
+    }
+
+    /// Tell event subscribers about each provider whose health changed
+    /// since the last call; runs after every command the loop handles
+    fn publish_health_changes(&mut self)
+    {   if self.event_subscribers.is_empty()
+        {   self.event_status.clear();
+            return;
+        }
+        let providers: Vec<crate::Provider> = self.clients.keys()
+          .cloned()
+          .collect();
+        for provider in providers
+        {   let status = self.provider_status(&provider);
+            let previous = self.event_status.insert(
+              provider.clone(), status.clone()
+            );
+            if let Some(previous) = previous
+              .filter(|previous| previous.health != status.health)
+            {   broadcast(
+                  &mut self.event_subscribers,
+                  crate::AllmEvent::ProviderStatusChanged
+                  {   provider
+                    , from: previous
+                    , to: status
+                  }
+                );
+            }
+        }
+    }
+
+    /// Validate a provider reply, retry it elsewhere if configured,
+    /// otherwise deliver it to the caller
+    async fn complete_prompt(
+      &mut self
+    , id: usize
+    , result: Result<ChatResponse, crate::error::Error>
+    )
+    {   let Some(pending) = self.pending.get_mut(&id) else { return };
+        pending.in_flight = false;
+        let kind = pending.kind;
+        if pending.cancel.as_ref().is_some_and(|token| token.is_cancelled())
+        {   info!("Prompt {} cancelled", id);
+            self.deliver(id, kind, Err(cancelled())).await;
+            return;
+        }
+        let counters = self.provider_counters
+          .entry(pending.provider.clone())
+          .or_default();
+        match &result
+        {   Ok(_) => {
+              counters.succeeded += 1;
+              counters.consecutive_failures = 0;
+            }
+          , Err(e) => {
+              counters.failed += 1;
+              counters.consecutive_failures += 1;
+              counters.last_error = Some(e.to_string());
+            }
+        }
+        if let Ok(response) = &result
+        {   self.warn_context_usage(id, response);
+        }
+        let result = match result
+        {   Err(crate::error::Error::Timeout)
+              if self.resume_from_checkpoint(id)
+                && self.within_deadline(id, std::time::Duration::ZERO) => {
+              self.schedule_prompt(id).await;
+              return;
+            }
+          , result => self.finish_checkpoint(id, result)
+        };
+        let stop = self.pending.get(&id)
+          .and_then(|p| p.request.stop.as_deref())
+          .unwrap_or_default();
+        let result = result.map(|mut response| {
+          for text in std::iter::once(&mut response.text)
+            .chain(response.choices.iter_mut())
+          {   let kept = crate::providers::strip_stop_sequence(text, stop).len();
+              text.truncate(kept);
+          }
+          response
+        });
+        let result = match result
+        {   // Summaries are internal and skip validation
+            Ok(response) if matches!(kind, PromptKind::Summary { .. }) => {
+              Ok(response)
+            }
+          , Ok(response) => {
+              let rejection = self.validators.iter()
+                .find_map(|v| v.validate(&response.text).err());
+              match rejection
+              {   None => match self.check_json_schema(id, &response.text)
+                  {   Ok(()) => Ok(self.rewrite_response(id, response))
+                    , Err(mismatch) => {
+                        if self.correct_json_schema(id, &response.text, &mismatch)
+                        {   self.schedule_prompt(id).await;
+                            return;
+                        }
+                        Err(crate::error::Error::ParseError(
+                          format!("reply does not match the schema: {}", mismatch)
+                        ))
+                      }
+                  }
+                , Some(rejection) => {
+                    warn!("Prompt {} failed validation: {}", id, rejection);
+                    let error = crate::error::Error::Other(
+                      format!("validation failed: {}", rejection.code)
+                    );
+                    if rejection.code == ValidationCode::RefusalDetected
+                      && self.config.retry_on_refusal
+                      && self.advance_to_fallback(id, &error)
+                    {   self.schedule_prompt(id).await;
+                        return;
+                    }
+                    Err(error)
+                  }
+              }
+            }
+          , Err(crate::error::Error::ContextWindowExceeded)
+              if self.config.context_overflow_upgrade
+                && self.upgrade_context(id)
+                && self.within_deadline(id, std::time::Duration::ZERO) => {
+              self.schedule_prompt(id).await;
+              return;
+            }
+          , Err(error @ crate::error::Error::ProviderApiError { .. }) => {
+              if self.retry_provider_error(id, &error).await
+              {   return;
+              }
+              Err(error)
+            }
+          , Err(error) => {
+              if self.fail_over(id, &error).await
+              {   return;
+              }
+              Err(error)
+            }
+        };
+        let deadline_hit = self.pending.get(&id)
+          .is_some_and(|p| p.deadline_hit);
+        let result = match result
+        {   Err(e) if deadline_hit => {
+              Err(crate::error::Error::DeadlineExceeded(Box::new(e)))
+            }
+          , result => result
+        };
+        self.deliver(id, kind, result).await;
+    }
+
+    /// Answer a prompt's callers and forget it
+    async fn deliver(
+      &mut self
+    , id: usize
+    , kind: PromptKind
+    , result: Result<ChatResponse, crate::error::Error>
+    )
+    {   let text = || result.as_ref()
+          .map(|r| r.text.clone())
+          .map_err(|e| e.clone());
+        match kind
+        {   PromptKind::Direct => {}
+          , PromptKind::Session(session_id) => {
+              let used = self.pending.get(&id)
+                .map(|p| (p.provider.clone(), p.request.model.clone()));
+              self.finish_session_turn(session_id, used, &text()).await;
+            }
+          , PromptKind::Summary { session_id, summarized } => {
+              self.finish_summary(session_id, summarized, &text()).await;
+            }
+        }
+        if let Some(mut pending) = self.pending.remove(&id)
+        {   pending.finish_hop(result.as_ref().err().cloned());
+            let request_id = pending.event_id(id);
+            let event = match &result
+            {   Ok(response) => {
+                  let tokens_in = response.input_tokens.unwrap_or_else(|| {
+                    pending.request.messages.iter()
+                      .map(|m| crate::utils::tokens::estimate_tokens(&m.content))
+                      .sum()
+                  });
+                  let tokens_out
+                    = crate::utils::tokens::estimate_tokens(&response.text);
+                  let cost_usd = self.model_catalog.iter()
+                    .find(|m| {
+                      m.provider == pending.provider
+                        && m.name == pending.request.model
+                    })
+                    .and_then(|info| crate::utils::cost::estimate_cost(
+                      &self.price_table, info, tokens_in, tokens_out
+                    ));
+                  crate::AllmEvent::RequestCompleted
+                  {   request_id
+                    , tokens_in
+                    , tokens_out
+                    , cost_usd
+                    , latency: pending.hops.iter().map(|hop| hop.latency).sum()
+                  }
+                }
+              , Err(error) => crate::AllmEvent::RequestFailed
+                {   request_id
+                  , error: error.clone()
+                }
+            };
+            broadcast(&mut self.event_subscribers, event);
+            for reply in pending.duplicates
+            {   let _ = reply.send(text());
+            }
+            pending.reply.send(
+              result, pending.provider, pending.request, pending.hops
+            );
+        }
+    }
+}
+
+/// Queue `request` for a full `ChatResponse`
+fn send_chat_response(
+  client: &dyn ProviderClient
+, id: usize
+, request: ChatRequest
+) -> Result<Completion, crate::error::Error>
+{   let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+    client.send_chat_response(request, reply_tx)?;
+    Ok(Box::pin(async move {
+      let result = reply_rx.recv().await
+        .unwrap_or_else(|| Err(
+          crate::error::Error::Other(
+            "Provider disconnected".to_string()
+          )
+        ));
+      (id, result)
+    }))
+}
+
+/// Send `event` to every subscriber, dropping those that are gone
+fn broadcast(
+  subscribers: &mut Vec<mpsc::UnboundedSender<crate::AllmEvent>>
+, event: crate::AllmEvent
+)
+{   subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+}
+
+/// Reply to a prompt whose `CancellationToken` was cancelled
+fn cancelled() -> crate::error::Error
+{   crate::error::Error::Other("cancelled".to_string())
+}
+
+/// Fail a prompt whose `few_shot` has no examples
+fn check_few_shot(few_shot: Option<&crate::request::FewShot>)
+  -> Result<(), crate::error::Error>
+{   few_shot.map_or(Ok(()), crate::request::FewShot::validate)
+}
+
+/// Queue `request` for its reply text alone
+fn send_chat(
+  client: &dyn ProviderClient
+, id: usize
+, request: &ChatRequest
+) -> Completion
+{   let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+    match client.send_chat(request.clone(), reply_tx)
+    {   Ok(()) => Box::pin(async move {
+          let result = reply_rx.recv().await
+            .unwrap_or_else(|| Err(
+              crate::error::Error::Other(
+                "Provider disconnected".to_string()
+              )
+            ));
+          let result = result.map(ChatResponse::from_text);
+          (id, result)
+        })
+      , Err(e) => Box::pin(async move { (id, Err(e)) })
+    }
+}
+
+/// Gather the stream of a checkpointed prompt into one reply,
+/// keeping its text in `received` as it arrives
+async fn collect_stream(
+  id: usize
+, mut reply_rx: mpsc::Receiver<crate::StreamReply>
+, received: Arc<std::sync::Mutex<String>>
+) -> (usize, Result<ChatResponse, crate::error::Error>)
+{   loop
+    {   let chunk = match reply_rx.recv().await
+        {   Some(Ok(chunk)) => chunk
+          , Some(Err(e)) => return (id, Err(e))
+          , None => return (id, Err(crate::error::Error::Other(
+              "Provider disconnected".to_string()
+            )))
+        };
+        let Ok(mut text) = received.lock() else { continue };
+        text.push_str(&chunk.delta);
+        if chunk.end.is_some()
+        {   let text = text.clone();
+            return (id, Ok(ChatResponse::from_text(text)));
+        }
+    }
+}
+
+/// Create the provider actor for a configured provider
+fn create_client(
+  provider: &crate::Provider
+, config: &crate::config::ProviderConfig
+, http_config: &crate::config::HttpClientConfig
+, pin_model_versions: bool
+) -> Result<Box<dyn ProviderClient>, crate::error::Error>
+{   debug!("Creating client for {:?}", provider);
+    match provider
+    {   crate::Provider::MistralAi => Ok(Box::new(
+          crate::providers::MistralClient::from_config_with_pinning(
+            config, http_config, pin_model_versions
+          )?
+        ))
+      , crate::Provider::CloudflareAi => Ok(Box::new(
+          crate::providers::CloudflareClient::from_config(config, http_config)?
+        ))
+      , crate::Provider::Cerebras => Ok(Box::new(
+          crate::providers::CerebrasClient::from_config(config, http_config)?
+        ))
+      , crate::Provider::Replicate => Ok(Box::new(
+          crate::providers::ReplicateClient::from_config(config, http_config)?
+        ))
+      , crate::Provider::OpenAI => Ok(Box::new(
+          crate::providers::OpenAIClient::from_config(config, http_config)?
+        ))
+      , crate::Provider::Local => Ok(Box::new(
+          crate::providers::local::from_config(config, http_config)?
+        ))
+      , crate::Provider::TogetherAi => Ok(Box::new(
+          crate::providers::TogetherClient::from_config(config, http_config)?
+        ))
+      , _ => {
+          error!("Provider not implemented: {:?}", provider);
+          Err(crate::error::Error::ProviderNotImplemented(
+            format!("{:?}", provider)
+          ))
+        }
+    }
+}
+
+/// Check what `create_client` would reject, without starting the
+/// provider actor
+fn check_client_config(
+  provider: &crate::Provider
+, config: &crate::config::ProviderConfig
+, http_config: &crate::config::HttpClientConfig
+) -> Result<(), crate::error::Error>
+{   match provider
+    {   crate::Provider::MistralAi
+      | crate::Provider::Cerebras
+      | crate::Provider::Replicate
+      | crate::Provider::OpenAI
+      | crate::Provider::Local
+      | crate::Provider::TogetherAi => {}
+      , crate::Provider::CloudflareAi => {
+          crate::providers::cloudflare::required_account_id(config)?;
+        }
+      , _ => {
+          error!("Provider not implemented: {:?}", provider);
+          return Err(crate::error::Error::ProviderNotImplemented(
+            format!("{:?}", provider)
+          ));
+        }
+    }
+    crate::providers::build_http_client(Some(config), http_config)
+      .map(|_| ())
+}
+
+/// Public API for ALLM backend - owns the task
+pub struct AllmBackend
+{   hand: Arc<crate::AllmHand>
+  , /// Backend loop task, awaited by `shutdown`
+    task_handle: tokio::task::JoinHandle<()>
+  , /// Set once `shutdown` starts
+    shutting_down: Arc<AtomicBool>
+  , #[cfg(feature = "health-server")]
+    health_server: Option<crate::health_server::AllmHealthServer>
+  , /// Providers with their default model, as targets of `warm_up`
+    warm_up_models: Vec<(crate::Provider, String)>
+  , /// Capacity of each stream's chunk channel
+    stream_buffer_size: usize
+  , context_warning_tx: ContextWarningSlot
+  , failover_callback: FailoverCallbackSlot
+  , /// `Provider::ALL` less those the config's policy forbids
+    allowed_providers: Vec<crate::Provider>
+}
+
+impl AllmBackend
+{   /// Create and spawn a new ALLM backend
+    /// Returns immediately - spawns background task
+    pub fn new(
+      mistral_api_key: Option<String>
+    ) -> Self
+    {   AllmBackend::spawn(AllmBackendState::new(mistral_api_key))
+    }
+
+    /// Create and spawn a backend whose API keys live in `store`;
+    /// the Mistral client reads its key from there on every request
+    pub fn new_with_secret_store(store: SharedSecretStore) -> Self
+    {   let mut state = AllmBackendState::new(None);
+        state.use_secret_store(store);
+        AllmBackend::spawn(state)
+    }
+
+    /// Spawn a backend around a prepared state, e.g. one with
+    /// clients added through `AllmBackendState::register_client`
+    pub fn from_state(state: AllmBackendState) -> Self
+    {   AllmBackend::spawn(state)
+    }
+
+    /// Create and spawn a backend with one client per configured
+    /// provider. Fails on unknown or incomplete provider configs.
+    /// With `warm_up_on_start` it also warms up every provider
+    /// before returning.
+    pub async fn new_with_config(
+      config: crate::config::AllmConfig
+    ) -> Result<Self, crate::error::Error>
+    {   let state = AllmBackendState::new_with_config(&config)?;
+        #[allow(unused_mut)]
+        let mut backend = AllmBackend::spawn(state);
+        if let Some(port) = config.health_server_port
+        {   backend.start_health_server(port)?;
+        }
+        if config.warm_up_on_start
+        {   backend.warm_up().await?;
+        }
+        Ok(backend)
+    }
+
+    /// Create a backend for the providers found in the environment
+    /// (see `AllmConfig::from_env`) and add the models each one lists
+    /// to the catalog. Models the catalog already knows keep their
+    /// entry; listed ones only carry a name (see `ModelInfo::listed`).
+    pub async fn from_env() -> Result<Self, crate::error::Error>
+    {   let backend = AllmBackend::new_with_config(
+          crate::config::AllmConfig::from_env()
+        ).await?;
+        let mut reply_rx = backend.get_model_lists().await?;
+        let listed = recv_reply(&mut reply_rx).await?;
+        let known = backend.capability_matrix().await?.rows;
+        let models: Vec<crate::ModelInfo> = listed.into_iter()
+          .filter(|(provider, name)| !known.iter().any(|row| {
+            row.provider == *provider && row.model == *name
+          }))
+          .map(|(provider, name)| crate::ModelInfo::listed(provider, name))
+          .collect();
+        info!("Registering {} models listed by providers", models.len());
+        let mut reply_rx = backend.register_models(models).await?;
+        recv_reply(&mut reply_rx).await?;
+        Ok(backend)
+    }
+
+    /// Send a `ContextUsageWarning` to `tx` for every reply whose
+    /// prompt fills `context_warning_threshold_percent` of the
+    /// model's context, replacing `AllmConfig::context_warning_tx`
+    pub fn with_context_warning_channel(
+      &mut self
+    , tx: mpsc::UnboundedSender<crate::ContextUsageWarning>
+    ) -> &mut Self
+    {   if let Ok(mut slot) = self.context_warning_tx.lock()
+        {   *slot = Some(tx);
+        }
+        self
+    }
+
+    /// Call `callback` each time a prompt fails over from one
+    /// provider and model to the next fallback preference, e.g. to
+    /// alert when the primary provider struggles. It runs on the
+    /// backend loop, so it should return quickly; hand slow work to
+    /// a channel or task.
+    pub fn with_failover_callback(
+      &mut self
+    , callback: impl Fn(crate::FailoverEvent) + Send + Sync + 'static
+    ) -> &mut Self
+    {   if let Ok(mut slot) = self.failover_callback.lock()
+        {   *slot = Some(Arc::new(callback));
+        }
+        self
+    }
+
+    #[cfg(feature = "health-server")]
+    fn start_health_server(&mut self, port: u16)
+      -> Result<(), crate::error::Error>
+    {   self.health_server = Some(
+          crate::health_server::AllmHealthServer::spawn(
+            self.backend_ref(), port
+          )?
+        );
+        Ok(())
+    }
+
+    #[cfg(not(feature = "health-server"))]
+    fn start_health_server(&mut self, port: u16)
+      -> Result<(), crate::error::Error>
+    {   error!("health_server_port {} set without the feature", port);
+        Err(crate::error::Error::InvalidConfiguration(
+          "health_server_port requires the `health-server` feature"
+            .to_string()
+        ))
+    }
+
+    /// Address the health server listens on, if running
+    #[cfg(feature = "health-server")]
+    pub fn health_server_addr(&self) -> Option<std::net::SocketAddr>
+    {   self.health_server.as_ref().map(|s| s.addr())
+    }
+
+    /// Providers prompts may go to under `AllmConfig::
+    /// allowed_providers` and `blocked_providers`, configured or not
+    pub fn allowed_providers(&self) -> Vec<crate::Provider>
+    {   self.allowed_providers.clone()
+    }
+
+    /// Cloneable handle for monitoring the backend from other tasks
+    pub fn backend_ref(&self) -> AllmBackendRef
+    {   AllmBackendRef
+        {   hand: self.hand.clone()
+          , shutting_down: self.shutting_down.clone()
+        }
+    }
+
+    /// Counters per provider - returns almost immediately
+    pub async fn get_metrics(&self)
+      -> Result<
+        mpsc::UnboundedReceiver<crate::GetMetricsReply>,
+        crate::error::Error
+      >
+    {   self.backend_ref().get_metrics().await
+    }
+
+    fn spawn(state: AllmBackendState) -> Self
+    {   debug!("Creating AllmBackend with task ownership");
+        let warm_up_models = state.default_models.iter()
+          .map(|(provider, model)| (provider.clone(), model.clone()))
+          .collect();
+        let allowed_providers = crate::Provider::ALL.into_iter()
+          .filter(|provider| state.config.allows_provider(provider))
+          .collect();
+        
+        // Bounded so a flood of callers waits on the backend instead
+        // of queueing without limit
+        let capacity = state.config.command_queue_capacity.max(1);
+        let stream_buffer_size = state.config.stream_buffer_size.max(1);
+        let context_warning_tx = state.context_warning_tx.clone();
+        let failover_callback = state.failover_callback.clone();
+        let (send_prompt_tx, send_prompt_rx)
+          = mpsc::channel(capacity);
+        let (send_request_tx, send_request_rx)
+          = mpsc::channel(capacity);
+        let (send_stream_tx, send_stream_rx)
+          = mpsc::channel(capacity);
+        let (dry_run_tx, dry_run_rx)
+          = mpsc::channel(capacity);
+        let (set_api_keys_tx, set_api_keys_rx)
+          = mpsc::channel(capacity);
+        let (set_key_provider_tx, set_key_provider_rx)
+          = mpsc::channel(capacity);
+        let (get_model_lists_tx, get_model_lists_rx)
+          = mpsc::channel(capacity);
+        let (kill_process_tx, kill_process_rx)
+          = mpsc::channel(capacity);
+        let (set_model_fallback_preference_tx
+             , set_model_fallback_preference_rx)
+          = mpsc::channel(capacity);
+        let (set_model_aliases_tx, set_model_aliases_rx)
+          = mpsc::channel(capacity);
+        let (add_request_interceptor_tx, add_request_interceptor_rx)
+          = mpsc::channel(capacity);
+        let (add_response_validator_tx, add_response_validator_rx)
+          = mpsc::channel(capacity);
+        let (register_models_tx, register_models_rx)
+          = mpsc::channel(capacity);
+        let (export_model_registry_tx, export_model_registry_rx)
+          = mpsc::channel(capacity);
+        let (import_model_registry_tx, import_model_registry_rx)
+          = mpsc::channel(capacity);
+        let (get_metrics_tx, get_metrics_rx)
+          = mpsc::channel(capacity);
+        let (create_session_tx, create_session_rx)
+          = mpsc::channel(capacity);
+        let (send_session_prompt_tx, send_session_prompt_rx)
+          = mpsc::channel(capacity);
+        let (summarize_session_tx, summarize_session_rx)
+          = mpsc::channel(capacity);
+        let (pin_session_model_tx, pin_session_model_rx)
+          = mpsc::channel(capacity);
+        let (set_session_summarization_tx, set_session_summarization_rx)
+          = mpsc::channel(capacity);
+        let (get_session_tx, get_session_rx)
+          = mpsc::channel(capacity);
+        let (export_session_tx, export_session_rx)
+          = mpsc::channel(capacity);
+        let (import_session_tx, import_session_rx)
+          = mpsc::channel(capacity);
+        let (preload_providers_tx, preload_providers_rx)
+          = mpsc::channel(capacity);
+        let (openai_tx, openai_rx)
+          = mpsc::channel(capacity);
+        let (get_supervision_status_tx, get_supervision_status_rx)
+          = mpsc::channel(capacity);
+        let (get_partial_response_tx, get_partial_response_rx)
+          = mpsc::channel(capacity);
+        let (get_capability_matrix_tx, get_capability_matrix_rx)
+          = mpsc::channel(capacity);
+        let (get_model_info_tx, get_model_info_rx)
+          = mpsc::channel(capacity);
+        let (split_prompt_tx, split_prompt_rx)
+          = mpsc::channel(capacity);
+        let (would_use_provider_tx, would_use_provider_rx)
+          = mpsc::channel(capacity);
+        let (get_rate_limit_status_tx, get_rate_limit_status_rx)
+          = mpsc::channel(capacity);
+        let (get_rate_limit_reset_tx, get_rate_limit_reset_rx)
+          = mpsc::channel(capacity);
+        let (count_tokens_tx, count_tokens_rx)
+          = mpsc::channel(capacity);
+        let (get_prompt_status_tx, get_prompt_status_rx)
+          = mpsc::channel(capacity);
+        let (watch_provider_status_tx, watch_provider_status_rx)
+          = mpsc::channel(capacity);
+        let (subscribe_events_tx, subscribe_events_rx)
+          = mpsc::channel(capacity);
+        let (completion_tx, completion_rx)
+          = mpsc::channel(capacity);
+        let (compute_perplexity_tx, compute_perplexity_rx)
+          = mpsc::channel(capacity);
+        let (openai_batch_tx, openai_batch_rx)
+          = mpsc::channel(capacity);
+
+        let hand = crate::AllmHand
+        {   send_prompt_tx: send_prompt_tx.clone()
+          , send_request_tx
+          , send_stream_tx
+          , dry_run_tx
+          , set_api_keys_tx: set_api_keys_tx.clone()
+          , set_key_provider_tx
+          , get_model_lists_tx: get_model_lists_tx.clone()
+          , kill_process_tx: kill_process_tx.clone()
+          , set_model_fallback_preference_tx
+              : set_model_fallback_preference_tx.clone()
+          , set_model_aliases_tx
+          , add_response_validator_tx
+          , add_request_interceptor_tx
+          , register_models_tx
+          , export_model_registry_tx
+          , import_model_registry_tx
+          , get_metrics_tx
+          , create_session_tx
+          , send_session_prompt_tx
+          , summarize_session_tx
+          , pin_session_model_tx
+          , set_session_summarization_tx
+          , get_session_tx
+          , export_session_tx
+          , import_session_tx
+          , preload_providers_tx
+          , openai_tx
+          , get_supervision_status_tx
+          , get_partial_response_tx
+          , get_capability_matrix_tx
+          , get_model_info_tx
+          , split_prompt_tx
+          , would_use_provider_tx
+          , get_rate_limit_status_tx
+          , get_rate_limit_reset_tx
+          , count_tokens_tx
+          , get_prompt_status_tx
+          , watch_provider_status_tx
+          , subscribe_events_tx
+          , completion_tx
+          , compute_perplexity_tx
+          , openai_batch_tx
+        };
+
+        let foot = crate::AllmFoot
+        {   send_prompt_rx
+          , send_request_rx
+          , send_stream_rx
+          , dry_run_rx
+          , set_api_keys_rx
+          , set_key_provider_rx
+          , get_model_lists_rx
+          , kill_process_rx
+          , set_model_fallback_preference_rx
+          , set_model_aliases_rx
+          , add_response_validator_rx
+          , add_request_interceptor_rx
+          , register_models_rx
+          , export_model_registry_rx
+          , import_model_registry_rx
+          , get_metrics_rx
+          , create_session_rx
+          , send_session_prompt_rx
+          , summarize_session_rx
+          , pin_session_model_rx
+          , set_session_summarization_rx
+          , get_session_rx
+          , export_session_rx
+          , import_session_rx
+          , preload_providers_rx
+          , openai_rx
+          , get_supervision_status_rx
+          , get_partial_response_rx
+          , get_capability_matrix_rx
+          , get_model_info_rx
+          , split_prompt_rx
+          , would_use_provider_rx
+          , get_rate_limit_status_rx
+          , get_rate_limit_reset_rx
+          , count_tokens_rx
+          , get_prompt_status_rx
+          , watch_provider_status_rx
+          , subscribe_events_rx
+          , completion_rx
+          , compute_perplexity_rx
+          , openai_batch_rx
+        };
+
+        let task_handle = tokio::spawn(async move {
+          run_backend_loop(foot, state).await
+        });
+
+        AllmBackend
+        {   hand: Arc::new(hand)
+          , task_handle
+          , shutting_down: Arc::new(AtomicBool::new(false))
+          , #[cfg(feature = "health-server")]
+            health_server: None
+          , warm_up_models
+          , stream_buffer_size
+          , context_warning_tx
+          , failover_callback
+          , allowed_providers
+        }
+    }
+
+    /// Send a prompt - returns almost immediately
+    pub async fn send_prompt(
+      &self
+    , prompt: String
+    , model: String
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendPromptReply>,
+        crate::error::Error
+      >
+    {   self.queue_prompt(None, prompt, model).await
+    }
+
+    /// Send a prompt to a specific provider - returns almost
+    /// immediately
+    pub async fn send_prompt_to(
+      &self
+    , provider: crate::Provider
+    , prompt: String
+    , model: String
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendPromptReply>,
+        crate::error::Error
+      >
+    {   self.queue_prompt(Some(provider), prompt, model).await
+    }
+
+    /// `send_prompt` that waits for a rate limit ahead of, or
+    /// behind, prompts of other priorities
+    pub async fn send_prompt_with_priority(
+      &self
+    , prompt: String
+    , model: String
+    , priority: crate::request::Priority
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendPromptReply>,
+        crate::error::Error
+      >
+    {   self.queue_prompt_with_priority(None, prompt, model, priority).await
+    }
+
+    async fn queue_prompt(
+      &self
+    , provider: Option<crate::Provider>
+    , prompt: String
+    , model: String
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendPromptReply>,
+        crate::error::Error
+      >
+    {   self.queue_prompt_with_priority(
+          provider, prompt, model, crate::request::Priority::Normal
+        ).await
+    }
+
+    async fn queue_prompt_with_priority(
+      &self
+    , provider: Option<crate::Provider>
+    , prompt: String
+    , model: String
+    , priority: crate::request::Priority
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendPromptReply>,
+        crate::error::Error
+      >
+    {   debug!("send_prompt queuing command for model: {}", model);
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+        
+        let cmd = crate::SendPromptArgs
+        {   prompt
+          , model
+          , provider
+          , system_message: None
+          , max_tokens: None
+          , temperature: None
+          , extra_params: None
+          , request_id: None
+          , stop_sequences: None
+          , total_deadline: None
+          , json_schema: None
+          , prefill: None
+          , auto_detect_provider: false
+          , accepted: None
+          , priority
+          , no_failover: false
+          , few_shot: None
+          , cancel: None
+          , extra_headers: None
+          , checkpoint: crate::CheckpointMode::Disabled
+          , reply: reply_tx
+        };
+        self.submit_prompt(cmd).await?;
+        Ok(reply_rx)
+    }
+
+    /// `send_prompt` that `cancel` can call off: once it is
+    /// cancelled, the provider call in flight is dropped and the reply
+    /// is `Err(Error::Other("cancelled"))`. A prompt still waiting for
+    /// a rate limit or backoff is answered right away, without being
+    /// sent.
+    pub async fn send_prompt_cancellable(
+      &self
+    , prompt: String
+    , model: String
+    , cancel: tokio_util::sync::CancellationToken
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendPromptReply>,
+        crate::error::Error
+      >
+    {   debug!("send_prompt_cancellable queuing command for model: {}", model);
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SendPromptArgs
+        {   prompt
+          , model
+          , provider: None
+          , system_message: None
+          , max_tokens: None
+          , temperature: None
+          , extra_params: None
+          , request_id: None
+          , stop_sequences: None
+          , total_deadline: None
+          , json_schema: None
+          , prefill: None
+          , auto_detect_provider: false
+          , accepted: None
+          , priority: crate::request::Priority::Normal
+          , no_failover: false
+          , few_shot: None
+          , cancel: Some(cancel)
+          , extra_headers: None
+          , checkpoint: crate::CheckpointMode::Disabled
+          , reply: reply_tx
+        };
+        self.submit_prompt(cmd).await?;
+        Ok(reply_rx)
+    }
+
+    /// `send_prompt`, also returning the id the backend gave the
+    /// prompt, for `get_prompt_status`
+    pub async fn send_prompt_with_id(
+      &self
+    , prompt: String
+    , model: String
+    ) -> Result<
+        (usize, mpsc::UnboundedReceiver<crate::SendPromptReply>),
+        crate::error::Error
+      >
+    {   debug!("send_prompt_with_id queuing command for model: {}", model);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+        let (accepted_tx, mut accepted_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SendPromptArgs
+        {   prompt
+          , model
+          , provider: None
+          , system_message: None
+          , max_tokens: None
+          , temperature: None
+          , extra_params: None
+          , request_id: None
+          , stop_sequences: None
+          , total_deadline: None
+          , json_schema: None
+          , prefill: None
+          , auto_detect_provider: false
+          , accepted: Some(accepted_tx)
+          , priority: crate::request::Priority::Normal
+          , no_failover: false
+          , few_shot: None
+          , cancel: None
+          , extra_headers: None
+          , checkpoint: crate::CheckpointMode::Disabled
+          , reply: reply_tx
+        };
+        self.submit_prompt(cmd).await?;
+        match accepted_rx.recv().await
+        {   Some(id) => Ok((id, reply_rx))
+          , // Not accepted: the reason is on the reply channel
+            None => Err(recv_reply(&mut reply_rx).await.err().unwrap_or_else(|| {
+              crate::error::Error::Other("Backend disconnected".to_string())
+            }))
+        }
+    }
+
+    /// Stream a prompt's reply - returns almost immediately.
+    /// Chunks arrive as the provider sends them; the last one has
+    /// `end` set, a provider error arrives as `Err`.
+    pub async fn send_prompt_stream(
+      &self
+    , prompt: String
+    , model: String
+    ) -> Result<crate::CompletionStream, crate::error::Error>
+    {   self.queue_stream(None, prompt, model).await
+    }
+
+    /// Stream a prompt's reply from a specific provider
+    pub async fn send_prompt_stream_to(
+      &self
+    , provider: crate::Provider
+    , prompt: String
+    , model: String
+    ) -> Result<crate::CompletionStream, crate::error::Error>
+    {   self.queue_stream(Some(provider), prompt, model).await
+    }
+
+    async fn queue_stream(
+      &self
+    , provider: Option<crate::Provider>
+    , prompt: String
+    , model: String
+    ) -> Result<crate::CompletionStream, crate::error::Error>
+    {   debug!("send_prompt_stream queuing command for model: {}", model);
+        let (reply_tx, reply_rx)
+          = mpsc::channel(self.stream_buffer_size);
+
+        let cmd = crate::SendStreamArgs
+        {   prompt
+          , model
+          , provider
+          , reply: reply_tx
+        };
+        self.hand.send_stream_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+        Ok(crate::CompletionStream::new(reply_rx))
+    }
+
+    /// Send a prompt with explicit sampling parameters - returns
+    /// almost immediately
+    pub async fn send_request(
+      &self
+    , request: crate::request::PromptRequest
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendPromptReply>,
+        crate::error::Error
+      >
+    {   debug!("send_request queuing command for model: {}", request.model);
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SendPromptArgs
+        {   prompt: request.prompt
+          , model: request.model
+          , provider: Some(request.provider)
+          , system_message: request.system_message
+          , max_tokens: request.max_tokens
+          , temperature: request.temperature
+          , extra_params: request.extra_params
+          , request_id: request.request_id
+          , stop_sequences: request.stop_sequences
+          , total_deadline: request.total_deadline
+          , json_schema: request.json_schema
+          , prefill: request.prefill
+          , auto_detect_provider: false
+          , accepted: None
+          , priority: request.priority
+          , no_failover: request.no_failover
+          , few_shot: request.few_shot
+          , cancel: None
+          , extra_headers: None
+          , checkpoint: crate::CheckpointMode::Disabled
+          , reply: reply_tx
+        };
+        self.submit_prompt(cmd).await?;
+        Ok(reply_rx)
+    }
+
+    /// Send a request answered with a `PromptResponse` - returns
+    /// almost immediately. With `return_raw` the response carries the
+    /// provider's untouched body.
+    pub async fn send_request_full(
+      &self
+    , request: crate::request::PromptRequest
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendRequestReply>,
+        crate::error::Error
+      >
+    {   debug!(
+          "send_request_full queuing command for model: {}", request.model
+        );
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SendRequestArgs
+        {   request
+          , reply: reply_tx
+        };
+        self.hand.send_request_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+        Ok(reply_rx)
+    }
+
+    /// Provider request body `send_request` would post, without
+    /// sending anything
+    pub async fn dry_run(
+      &self
+    , request: crate::request::PromptRequest
+    ) -> Result<serde_json::Value, crate::error::Error>
+    {   debug!("dry_run queuing command for model: {}", request.model);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::DryRunArgs
+        {   request
+          , reply: reply_tx
+        };
+
+        self.hand.dry_run_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    async fn submit_prompt(
+      &self
+    , cmd: crate::SendPromptArgs
+    ) -> Result<(), crate::error::Error>
+    {   self.hand.send_prompt_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })
+    }
+
+    /// Set API keys - returns almost immediately
+    pub async fn set_api_keys(
+      &self
+    , keys: Vec<crate::ApiKeySpec>
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SetApiKeysReply>,
+        crate::error::Error
+      >
+    {   debug!("set_api_keys queuing {} keys", keys.len());
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+        
+        let cmd = crate::SetApiKeysArgs
+        {   keys
+          , reply: reply_tx
+        };
+
+        self.hand.set_api_keys_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Get model lists - returns almost immediately
+    pub async fn get_model_lists(
+      &self
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::GetModelListsReply>,
+        crate::error::Error
+      >
+    {   self.get_model_lists_filtered(None).await
+    }
+
+    /// Listed models matching `filter`, judged by their catalog entry
+    /// or the provider's built-in model; listed models without either
+    /// are left out
+    pub async fn get_model_lists_filtered(
+      &self
+    , filter: Option<crate::capabilities::ModelFilter>
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::GetModelListsReply>,
+        crate::error::Error
+      >
+    {   debug!("get_model_lists queuing command");
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+        
+        let cmd = crate::GetModelListsArgs
+        {   filter
+          , reply: reply_tx
+        };
+
+        self.hand.get_model_lists_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Set fallback preference - returns almost immediately
+    pub async fn set_model_fallback_preference(
+      &self
+    , preferences: Vec<(crate::Provider, String)>
+    ) -> Result<
+        mpsc::UnboundedReceiver
+          <crate::SetModelFallbackPreferenceReply>,
+        crate::error::Error
+      >
+    {   debug!("set_model_fallback_preference queuing");
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+        
+        let cmd = crate::SetModelFallbackPreferenceArgs
+        {   preferences
+          , reply: reply_tx
+        };
+
+        self.hand.set_model_fallback_preference_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Replace a provider's master (`model == None`) or
+    /// model-specific key with a provider asked before every request
+    pub async fn set_key_provider(
+      &self
+    , provider: crate::Provider
+    , model: Option<String>
+    , key_provider: crate::auth::SharedKeyProvider
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SetKeyProviderReply>,
+        crate::error::Error
+      >
+    {   debug!("set_key_provider queuing for {:?}", provider);
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SetKeyProviderArgs
+        {   provider
+          , model
+          , key_provider
+          , reply: reply_tx
+        };
+
+        self.hand.set_key_provider_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Register a validator run on every reply before delivery.
+    /// A rejected reply becomes `Error::Other("validation failed: ..")`
+    pub async fn add_response_validator(
+      &self
+    , validator: Box<dyn ContentValidator + Send + Sync>
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::AddResponseValidatorReply>,
+        crate::error::Error
+      >
+    {   debug!("add_response_validator queuing");
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::AddResponseValidatorArgs
+        {   validator
+          , reply: reply_tx
+        };
+
+        self.hand.add_response_validator_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Register an interceptor run on every request before it is
+    /// sent, e.g. a `PiiRedactor`
+    pub async fn add_request_interceptor(
+      &self
+    , interceptor: Box<dyn RequestInterceptor + Send + Sync>
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::AddRequestInterceptorReply>,
+        crate::error::Error
+      >
+    {   debug!("add_request_interceptor queuing");
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::AddRequestInterceptorArgs
+        {   interceptor
+          , reply: reply_tx
+        };
+
+        self.hand.add_request_interceptor_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Replace the alias table; prompts naming an alias go to its
+    /// provider and model
+    pub async fn set_model_aliases(
+      &self
+    , aliases: HashMap<String, (crate::Provider, String)>
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SetModelAliasesReply>,
+        crate::error::Error
+      >
+    {   debug!("set_model_aliases queuing {} aliases", aliases.len());
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SetModelAliasesArgs
+        {   aliases
+          , reply: reply_tx
+        };
+
+        self.hand.set_model_aliases_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Add models to the catalog used to pick context upgrades
+    pub async fn register_models(
+      &self
+    , models: Vec<crate::ModelInfo>
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::RegisterModelsReply>,
+        crate::error::Error
+      >
+    {   debug!("register_models queuing {} models", models.len());
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::RegisterModelsArgs
+        {   models
+          , reply: reply_tx
+        };
+
+        self.hand.register_models_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// The model catalog as JSON, for `import_model_registry` or
+    /// `AllmConfig::model_registry_cache_path`
+    pub async fn export_model_registry(&self)
+      -> Result<String, crate::error::Error>
+    {   debug!("export_model_registry queuing command");
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::ExportModelRegistryArgs
+        {   reply: reply_tx
+        };
+
+        self.hand.export_model_registry_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Merge an `export_model_registry` JSON into the catalog, as
+    /// `register_models` does; returns how many entries it held
+    pub async fn import_model_registry(&self, json: &str)
+      -> Result<usize, crate::error::Error>
+    {   debug!("import_model_registry queuing command");
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::ImportModelRegistryArgs
+        {   json: json.to_string()
+          , reply: reply_tx
+        };
+
+        self.hand.import_model_registry_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Start a conversation session; the reply carries its id
+    pub async fn create_session(
+      &self
+    , max_messages: usize
+    , pruning: PruningStrategy
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::CreateSessionReply>,
+        crate::error::Error
+      >
+    {   debug!("create_session queuing");
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::CreateSessionArgs
+        {   max_messages
+          , pruning
+          , reply: reply_tx
+        };
+
+        self.hand.create_session_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Send the next user turn of a session along with its history
+    pub async fn send_session_prompt(
+      &self
+    , session_id: Uuid
+    , prompt: String
+    , model: String
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendPromptReply>,
+        crate::error::Error
+      >
+    {   self.queue_session_prompt(session_id, None, prompt, model)
+          .await
+    }
+
+    /// Send the next user turn of a session to a specific provider
+    pub async fn send_session_prompt_to(
+      &self
+    , session_id: Uuid
+    , provider: crate::Provider
+    , prompt: String
+    , model: String
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendPromptReply>,
+        crate::error::Error
+      >
+    {   self.queue_session_prompt(session_id, Some(provider), prompt, model)
+          .await
+    }
+
+    async fn queue_session_prompt(
+      &self
+    , session_id: Uuid
+    , provider: Option<crate::Provider>
+    , prompt: String
+    , model: String
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendPromptReply>,
+        crate::error::Error
+      >
+    {   debug!("send_session_prompt queuing for session {}", session_id);
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SendSessionPromptArgs
+        {   session_id
+          , prompt
+          , model
+          , provider
+          , reply: reply_tx
+        };
+
+        self.hand.send_session_prompt_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Replace a session's turns with a summary written by `model`;
+    /// the reply carries the summary
+    pub async fn summarize_session(
+      &self
+    , session_id: Uuid
+    , provider: crate::Provider
+    , model: String
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SummarizeSessionReply>,
+        crate::error::Error
+      >
+    {   debug!("summarize_session queuing for session {}", session_id);
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SummarizeSessionArgs
+        {   session_id
+          , model: (provider, model)
+          , reply: reply_tx
+        };
+
+        self.hand.summarize_session_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Send every later turn of a session to `model` of `provider`,
+    /// whatever model the turn names, and never fail over from it
+    pub async fn pin_session_model(
+      &self
+    , session_id: Uuid
+    , provider: crate::Provider
+    , model: &str
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::PinSessionModelReply>,
+        crate::error::Error
+      >
+    {   debug!("pin_session_model queuing for session {}", session_id);
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::PinSessionModelArgs
+        {   session_id
+          , provider
+          , model: model.to_string()
+          , reply: reply_tx
+        };
+
+        self.hand.pin_session_model_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Summarize the oldest turns of a session as its history nears
+    /// the context window; `None` turns that off
+    pub async fn set_session_summarization(
+      &self
+    , session_id: Uuid
+    , strategy: Option<crate::session::SummarizationStrategy>
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SetSessionSummarizationReply>,
+        crate::error::Error
+      >
+    {   debug!("set_session_summarization queuing for session {}", session_id);
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SetSessionSummarizationArgs
+        {   session_id
+          , strategy
+          , reply: reply_tx
+        };
+
+        self.hand.set_session_summarization_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Snapshot of a session
+    pub async fn get_session(
+      &self
+    , session_id: Uuid
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::GetSessionReply>,
+        crate::error::Error
+      >
+    {   debug!("get_session queuing for session {}", session_id);
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::GetSessionArgs
+        {   session_id
+          , reply: reply_tx
+        };
+
+        self.hand.get_session_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Session as JSON, to persist it across restarts
+    pub async fn export_session(&self, session_id: Uuid)
+      -> Result<String, crate::error::Error>
+    {   debug!("export_session queuing for session {}", session_id);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::ExportSessionArgs
+        {   session_id
+          , reply: reply_tx
+        };
+
+        self.hand.export_session_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Restore an `export_session` JSON; the session gets a new id
+    pub async fn import_session(&self, json: &str)
+      -> Result<Uuid, crate::error::Error>
+    {   debug!("import_session queuing command");
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::ImportSessionArgs
+        {   json: json.to_string()
+          , reply: reply_tx
+        };
+
+        self.hand.import_session_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Create the clients of `providers` now instead of on their
+    /// first request (`ProviderConfig::lazy_init`)
+    pub async fn preload_providers(
+      &self
+    , providers: &[crate::Provider]
+    ) -> Result<(), crate::error::Error>
+    {   debug!("preload_providers queuing {} providers", providers.len());
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::PreloadProvidersArgs
+        {   providers: providers.to_vec()
+          , reply: reply_tx
+        };
+
+        self.hand.preload_providers_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Create an OpenAI Assistants thread
+    pub async fn create_thread(&self)
+      -> Result<OpenAIThread, crate::error::Error>
+    {   self.openai_command(|reply| OpenAICommand::CreateThread { reply })
+          .await
+    }
+
+    /// Add a user message to an OpenAI Assistants thread
+    pub async fn add_thread_message(
+      &self
+    , thread_id: &str
+    , content: String
+    ) -> Result<(), crate::error::Error>
+    {   self.openai_command(|reply| OpenAICommand::AddThreadMessage
+        {   thread_id: thread_id.to_string()
+          , content
+          , reply
+        }).await
+    }
+
+    /// Start a run of `assistant_id` on a thread
+    pub async fn create_run(
+      &self
+    , thread_id: &str
+    , assistant_id: &str
+    ) -> Result<OpenAIRun, crate::error::Error>
+    {   self.openai_command(|reply| OpenAICommand::CreateRun
+        {   thread_id: thread_id.to_string()
+          , assistant_id: assistant_id.to_string()
+          , reply
+        }).await
+    }
+
+    /// Wait for a run to end and return the newest message of its
+    /// thread
+    pub async fn get_run_result(
+      &self
+    , thread_id: &str
+    , run_id: &str
+    ) -> Result<String, crate::error::Error>
+    {   self.openai_command(|reply| OpenAICommand::GetRunResult
+        {   thread_id: thread_id.to_string()
+          , run_id: run_id.to_string()
+          , reply
+        }).await
+    }
+
+    /// Run `assistant_id` on a thread and return its reply
+    pub async fn run_assistant(
+      &self
+    , thread_id: &str
+    , assistant_id: &str
+    ) -> Result<String, crate::error::Error>
+    {   let run = self.create_run(thread_id, assistant_id).await?;
+        self.get_run_result(thread_id, &run.id).await
+    }
+
+    async fn openai_command<T>(
+      &self
+    , command: impl FnOnce(
+        mpsc::UnboundedSender<Result<T, crate::error::Error>>
+      ) -> OpenAICommand
+    ) -> Result<T, crate::error::Error>
+    {   let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        self.hand.openai_tx
+          .send(command(reply_tx))
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Send prompts through OpenAI's batch API and wait for all of
+    /// them. Batches cost half as much but may take up to 24 hours;
+    /// polling follows `poll_interval_ms` and `poll_timeout_secs` of
+    /// the OpenAI provider config.
+    pub async fn openai_batch(&self, requests: Vec<BatchItem>)
+      -> Result<Vec<BatchResult>, crate::error::Error>
+    {   debug!("openai_batch queuing {} requests", requests.len());
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::OpenAIBatchArgs
+        {   requests
+          , reply: reply_tx
+        };
+
+        self.hand.openai_batch_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Batch `(id, prompt)` pairs with the provider's default model;
+    /// the reply holds `(id, response)` pairs in the same order, or
+    /// the first error of any prompt. Only OpenAI offers batches.
+    pub async fn batch_ask(
+      &self
+    , requests: Vec<(String, String)>
+    , provider: crate::Provider
+    ) -> Result<Vec<(String, String)>, crate::error::Error>
+    {   if provider != crate::Provider::OpenAI
+        {   error!("No batch API for {:?}", provider);
+            return Err(crate::error::Error::ProviderNotImplemented(
+              format!("{:?} batches", provider)
+            ));
+        }
+        let items = requests.into_iter()
+          .map(|(custom_id, prompt)| BatchItem
+          {   custom_id
+            , prompt
+            , model: None
+          })
+          .collect();
+        self.openai_batch(items).await?
+          .into_iter()
+          .map(|result| Ok((result.custom_id, result.response?)))
+          .collect()
+    }
+
+    /// Screen `input` with OpenAI's moderation endpoint
+    pub async fn moderate(&self, input: String)
+      -> Result<ModerationResult, crate::error::Error>
+    {   self.openai_command(|reply| OpenAICommand::Moderate { input, reply })
+          .await
+    }
+
+    /// Send a prompt whose reply is checkpointed as it streams - returns
+    /// almost immediately. A provider timeout mid-reply resends it,
+    /// asking the model to continue from what arrived (see
+    /// `CheckpointConfig`); the reply is the text of all attempts.
+    pub async fn send_prompt_checkpointed(
+      &self
+    , request_id: crate::RequestId
+    , prompt: String
+    , model: String
+    ) -> Result<
+        mpsc::UnboundedReceiver<crate::SendPromptReply>,
+        crate::error::Error
+      >
+    {   debug!("send_prompt_checkpointed queuing {}", request_id);
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SendPromptArgs
+        {   prompt
+          , model
+          , provider: None
+          , system_message: None
+          , max_tokens: None
+          , temperature: None
+          , extra_params: None
+          , request_id: Some(request_id)
+          , stop_sequences: None
+          , total_deadline: None
+          , json_schema: None
+          , prefill: None
+          , auto_detect_provider: false
+          , accepted: None
+          , priority: crate::request::Priority::Normal
+          , no_failover: false
+          , few_shot: None
+          , cancel: None
+          , extra_headers: None
+          , checkpoint: crate::CheckpointMode::Enabled
+          , reply: reply_tx
+        };
+        self.submit_prompt(cmd).await?;
+        Ok(reply_rx)
+    }
+
+    /// Text received for a checkpointed request before it timed out
+    pub async fn get_partial_response(
+      &self
+    , request_id: crate::RequestId
+    ) -> Result<Option<String>, crate::error::Error>
+    {   debug!("get_partial_response queuing {}", request_id);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::GetPartialResponseArgs
+        {   request_id
+          , reply: reply_tx
+        };
+
+        let disconnected = || {
+          error!("Backend channel closed");
+          crate::error::Error::Other("Backend disconnected".to_string())
+        };
+        self.hand.get_partial_response_tx
+          .send(cmd)
+          .await
+          .map_err(|_| disconnected())?;
+
+        reply_rx.recv().await.ok_or_else(disconnected)
+    }
+
+    /// Whether prompt `id` (see `send_prompt_with_id`) is queued, in
+    /// flight or answered
+    pub async fn get_prompt_status(&self, id: usize)
+      -> Result<crate::PromptStatus, crate::error::Error>
+    {   debug!("get_prompt_status queuing {}", id);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::GetPromptStatusArgs
+        {   id
+          , reply: reply_tx
+        };
+
+        self.hand.get_prompt_status_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Status of `provider`: the current one right away, then each
+    /// change as it happens (`changed().await`)
+    pub async fn watch_provider_status(&self, provider: crate::Provider)
+      -> Result<
+        tokio::sync::watch::Receiver<ProviderStatus>,
+        crate::error::Error
+      >
+    {   debug!("watch_provider_status queuing {:?}", provider);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::WatchProviderStatusArgs
+        {   provider
+          , reply: reply_tx
+        };
+
+        self.hand.watch_provider_status_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Every `AllmEvent` from now on, as it happens; drop the receiver
+    /// to unsubscribe
+    pub async fn subscribe_events(&self)
+      -> Result<mpsc::UnboundedReceiver<crate::AllmEvent>, crate::error::Error>
+    {   debug!("subscribe_events queuing");
+        let (subscriber, events) = mpsc::unbounded_channel();
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SubscribeEventsArgs
+        {   subscriber
+          , reply: reply_tx
+        };
+
+        self.hand.subscribe_events_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await?;
+        Ok(events)
+    }
+
+    /// Wait until `provider` is `ProviderHealth::Healthy`; returns at
+    /// once if it is. `Error::Timeout` once `timeout` has passed.
+    pub async fn wait_for_provider_healthy(
+      &self
+    , provider: crate::Provider
+    , timeout: std::time::Duration
+    ) -> Result<(), crate::error::Error>
+    {   let mut status = self.watch_provider_status(provider).await?;
+        let healthy = status.wait_for(|s| s.health == ProviderHealth::Healthy);
+        let healthy = tokio::time::timeout(timeout, healthy).await
+          .map(|seen| seen.map(|_| ()));
+        match healthy
+        {   Ok(Ok(())) => Ok(())
+          , Ok(Err(_)) => Err(crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            ))
+          , Err(_) => Err(crate::error::Error::Timeout)
+        }
+    }
+
+    /// Text continuing `prompt`, from the provider's raw completion
+    /// endpoint rather than its chat one, e.g. for code completion.
+    /// `InvalidConfiguration` for a model without
+    /// `ModelInfo::supports_raw_completion`; uncatalogued models
+    /// qualify when `mistral::RAW_COMPLETION_MODELS` lists them.
+    pub async fn complete(
+      &self
+    , prompt: &str
+    , model: &str
+    , max_tokens: Option<usize>
+    , temperature: Option<f32>
+    , stop: Option<Vec<String>>
+    ) -> Result<String, crate::error::Error>
+    {   debug!("complete queuing for model: {}", model);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SendCompletionArgs
+        {   prompt: prompt.to_string()
+          , model: model.to_string()
+          , max_tokens
+          , temperature
+          , stop
+          , reply: reply_tx
+        };
+
+        self.hand.completion_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Perplexity of `text` under `model` of `provider`: `exp` of the
+    /// negated mean logprob the model gives its tokens, lower for
+    /// text the model finds likelier. Needs a provider returning
+    /// prompt logprobs (Together AI); `ProviderNotImplemented`
+    /// otherwise.
+    pub async fn compute_perplexity(
+      &self
+    , text: &str
+    , provider: crate::Provider
+    , model: &str
+    ) -> Result<f64, crate::error::Error>
+    {   debug!("compute_perplexity queuing for {:?}: {}", provider, model);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::ComputePerplexityArgs
+        {   text: text.to_string()
+          , provider
+          , model: model.to_string()
+          , reply: reply_tx
+        };
+
+        self.hand.compute_perplexity_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Capabilities and prices of the models of every provider, from
+    /// the model catalog (see `register_models`)
+    pub async fn capability_matrix(&self)
+      -> Result<CapabilityMatrix, crate::error::Error>
+    {   debug!("capability_matrix queuing");
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::GetCapabilityMatrixArgs
+        {   reply: reply_tx
+        };
+
+        self.hand.get_capability_matrix_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// What `model` of `provider` can and cannot do, worded from its
+    /// catalog entry without asking any model; `ModelNotFound` when
+    /// the catalog has no such model
+    pub async fn explain(&self, provider: crate::Provider, model: &str)
+      -> Result<crate::capabilities::ModelExplanation, crate::error::Error>
+    {   debug!("explain queuing for {:?}: {}", provider, model);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::GetModelInfoArgs
+        {   provider
+          , model: model.to_string()
+          , reply: reply_tx
+        };
+
+        self.hand.get_model_info_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+          .map(crate::capabilities::ModelExplanation::from_model_info)
+    }
+
+    /// Provider and model the next request meeting `requirements`
+    /// would be sent to, walking the fallback preferences as failover
+    /// does; fails with `Error::Other` when none is available
+    pub async fn get_first_available_provider(
+      &self
+    , requirements: Option<crate::capabilities::ModelRequirements>
+    ) -> Result<(crate::Provider, String), crate::error::Error>
+    {   debug!("get_first_available_provider queuing");
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::WouldUseProviderArgs
+        {   requirements
+          , reply: reply_tx
+        };
+
+        self.hand.would_use_provider_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Ask `model` each `split_by_sentences` chunk of `prompt` sized to
+    /// its provider's limits, concurrently, whatever the config's
+    /// `large_prompt_strategy`; one reply per chunk, in order
+    pub async fn ask_chunked(&self, prompt: &str, model: &str)
+      -> Result<Vec<String>, crate::error::Error>
+    {   debug!("ask_chunked queuing for model: {}", model);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SplitPromptArgs
+        {   prompt: prompt.to_string()
+          , model: model.to_string()
+          , reply: reply_tx
+        };
+
+        self.hand.split_prompt_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        let chunks = recv_reply(&mut reply_rx).await?;
+        futures_util::future::join_all(
+          chunks.iter().map(|chunk| self.ask(chunk, model))
+        ).await.into_iter().collect()
+    }
+
+    /// Quota `provider` reported with its last response, for showing
+    /// e.g. how many requests are left; fields are `None` until a
+    /// response carried the header. `is_rate_limited` says whether
+    /// `model` should wait, see `wait_for_rate_limit_reset`.
+    pub async fn get_rate_limit_status(
+      &self
+    , provider: crate::Provider
+    , model: &str
+    ) -> Result<RateLimitStatus, crate::error::Error>
+    {   debug!("get_rate_limit_status queuing for {:?}", provider);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::GetRateLimitStatusArgs
+        {   provider
+          , model: model.to_string()
+          , reply: reply_tx
+        };
+
+        self.hand.get_rate_limit_status_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Sleep until `model` of `provider` is no longer rate-limited,
+    /// returning how long that was: zero when it is not, and at most
+    /// `AllmConfig::max_retry_after_secs`
+    pub async fn wait_for_rate_limit_reset(
+      &self
+    , provider: crate::Provider
+    , model: &str
+    ) -> Result<std::time::Duration, crate::error::Error>
+    {   debug!("wait_for_rate_limit_reset queuing for {:?}", provider);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::GetRateLimitResetArgs
+        {   provider
+          , model: model.to_string()
           , reply: reply_tx
         };
 
-        self.hand.set_api_keys_tx
+        self.hand.get_rate_limit_reset_tx
           .send(cmd)
+          .await
           .map_err(|_| {
             error!("Backend channel closed");
             crate::error::Error::Other(
@@ -160,26 +4894,29 @@ impl AllmBackend
             )
           })?;
 
-        Ok(reply_rx)
+        let wait = recv_reply(&mut reply_rx).await?;
+        tokio::time::sleep(wait).await;
+        Ok(wait)
     }
 
-    /// Get model lists - returns almost immediately
-    pub async fn get_model_lists(
-      &self
-    ) -> Result<
-        mpsc::UnboundedReceiver<crate::GetModelListsReply>,
-        crate::error::Error
-      >
-    {   debug!("get_model_lists queuing command");
-        let (reply_tx, reply_rx)
+    /// Input tokens of each of `prompts` sent to `model`, counted by
+    /// the provider where it has an endpoint for it and estimated
+    /// locally (`utils::tokens::estimate_tokens`) otherwise
+    pub async fn count_tokens_batch(&self, prompts: &[&str], model: &str)
+      -> Result<Vec<usize>, crate::error::Error>
+    {   debug!("count_tokens_batch queuing {} prompts", prompts.len());
+        let (reply_tx, mut reply_rx)
           = mpsc::unbounded_channel();
-        
-        let cmd = crate::GetModelListsArgs
-        {   reply: reply_tx
+
+        let cmd = crate::CountTokensArgs
+        {   prompts: prompts.iter().map(|p| p.to_string()).collect()
+          , model: model.to_string()
+          , reply: reply_tx
         };
 
-        self.hand.get_model_lists_tx
+        self.hand.count_tokens_tx
           .send(cmd)
+          .await
           .map_err(|_| {
             error!("Backend channel closed");
             crate::error::Error::Other(
@@ -187,29 +4924,23 @@ impl AllmBackend
             )
           })?;
 
-        Ok(reply_rx)
+        recv_reply(&mut reply_rx).await
     }
 
-    /// Set fallback preference - returns almost immediately
-    pub async fn set_model_fallback_preference(
-      &self
-    , preferences: Vec<(crate::Provider, String)>
-    ) -> Result<
-        mpsc::UnboundedReceiver
-          <crate::SetModelFallbackPreferenceReply>,
-        crate::error::Error
-      >
-    {   debug!("set_model_fallback_preference queuing");
-        let (reply_tx, reply_rx)
+    /// Restart counts and state of every provider client
+    pub async fn get_supervision_status(&self)
+      -> Result<Vec<ProviderSupervision>, crate::error::Error>
+    {   debug!("get_supervision_status queuing");
+        let (reply_tx, mut reply_rx)
           = mpsc::unbounded_channel();
-        
-        let cmd = crate::SetModelFallbackPreferenceArgs
-        {   preferences
-          , reply: reply_tx
+
+        let cmd = crate::GetSupervisionStatusArgs
+        {   reply: reply_tx
         };
 
-        self.hand.set_model_fallback_preference_tx
+        self.hand.get_supervision_status_tx
           .send(cmd)
+          .await
           .map_err(|_| {
             error!("Backend channel closed");
             crate::error::Error::Other(
@@ -217,13 +4948,510 @@ impl AllmBackend
             )
           })?;
 
-        Ok(reply_rx)
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Send a prompt and wait for its (validated) reply
+    pub async fn ask(
+      &self
+    , prompt: &str
+    , model: &str
+    ) -> Result<String, crate::error::Error>
+    {   let mut reply_rx = self
+          .send_prompt(prompt.to_string(), model.to_string())
+          .await?;
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// `ask` without naming the provider: the prompt goes to the
+    /// configured provider that has `model` (see
+    /// `SendPromptArgs::auto_detect_provider`). A model in no catalog
+    /// is looked up in every provider's model list, and the providers
+    /// listing it are cataloged, before failing with `ModelNotFound`.
+    pub async fn ask_by_model(
+      &self
+    , prompt: &str
+    , model: &str
+    ) -> Result<String, crate::error::Error>
+    {   match self.ask_detected(prompt, model).await
+        {   Err(crate::error::Error::ModelNotFound(_)) => {
+              debug!("{} not in the catalog, listing models", model);
+              self.register_listed_models(model).await?;
+              self.ask_detected(prompt, model).await
+            }
+          , result => result
+        }
+    }
+
+    /// `ask` with `headers` on the provider request, over the
+    /// provider's `ProviderConfig::extra_headers` of the same name
+    pub async fn ask_with_headers(
+      &self
+    , prompt: &str
+    , model: &str
+    , headers: HashMap<String, String>
+    ) -> Result<String, crate::error::Error>
+    {   debug!("ask_with_headers queuing command for model: {}", model);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SendPromptArgs
+        {   prompt: prompt.to_string()
+          , model: model.to_string()
+          , provider: None
+          , system_message: None
+          , max_tokens: None
+          , temperature: None
+          , extra_params: None
+          , request_id: None
+          , stop_sequences: None
+          , total_deadline: None
+          , json_schema: None
+          , prefill: None
+          , auto_detect_provider: false
+          , accepted: None
+          , priority: crate::request::Priority::Normal
+          , no_failover: false
+          , few_shot: None
+          , cancel: None
+          , extra_headers: Some(headers)
+          , checkpoint: crate::CheckpointMode::Disabled
+          , reply: reply_tx
+        };
+        self.submit_prompt(cmd).await?;
+        recv_reply(&mut reply_rx).await
+    }
+
+    async fn ask_detected(
+      &self
+    , prompt: &str
+    , model: &str
+    ) -> Result<String, crate::error::Error>
+    {   debug!("ask_detected queuing command for model: {}", model);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SendPromptArgs
+        {   prompt: prompt.to_string()
+          , model: model.to_string()
+          , provider: None
+          , system_message: None
+          , max_tokens: None
+          , temperature: None
+          , extra_params: None
+          , request_id: None
+          , stop_sequences: None
+          , total_deadline: None
+          , json_schema: None
+          , prefill: None
+          , auto_detect_provider: true
+          , accepted: None
+          , priority: crate::request::Priority::Normal
+          , no_failover: false
+          , few_shot: None
+          , cancel: None
+          , extra_headers: None
+          , checkpoint: crate::CheckpointMode::Disabled
+          , reply: reply_tx
+        };
+        self.submit_prompt(cmd).await?;
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Catalog the providers listing `model`, which no catalog entry
+    /// names yet
+    async fn register_listed_models(&self, model: &str)
+      -> Result<(), crate::error::Error>
+    {   let mut listing = self.get_model_lists().await?;
+        let listed: Vec<crate::ModelInfo> = recv_reply(&mut listing).await?
+          .into_iter()
+          .filter(|(_, name)| name == model)
+          .map(|(provider, name)| crate::ModelInfo::listed(provider, name))
+          .collect();
+        if listed.is_empty()
+        {   return Ok(());
+        }
+        let mut registered = self.register_models(listed).await?;
+        recv_reply(&mut registered).await
+    }
+
+    /// `ask` for JSON, deserialized into `T`. The request carries a
+    /// `ResponseFormat`; with `schema_hint` the reply is also checked
+    /// against it, and a mismatch sent back for one correction
+    /// (see `PromptRequest::json_schema`). A reply that is not a `T`
+    /// is `Error::ParseError`.
+    pub async fn ask_structured<T: serde::de::DeserializeOwned>(
+      &self
+    , prompt: &str
+    , model: &str
+    , schema_hint: Option<serde_json::Value>
+    ) -> Result<T, crate::error::Error>
+    {   debug!("ask_structured queuing command for model: {}", model);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+        let format = crate::request::ResponseFormat::for_schema(schema_hint.clone());
+        let mut extra_params = serde_json::Map::new();
+        extra_params.insert("response_format".to_string(), format.to_param());
+
+        let cmd = crate::SendPromptArgs
+        {   prompt: prompt.to_string()
+          , model: model.to_string()
+          , provider: None
+          , system_message: None
+          , max_tokens: None
+          , temperature: None
+          , extra_params: Some(extra_params)
+          , request_id: None
+          , stop_sequences: None
+          , total_deadline: None
+          , json_schema: schema_hint
+              .map(|schema| crate::request::JsonSchemaRetry::new(schema, 2))
+          , prefill: None
+          , auto_detect_provider: false
+          , accepted: None
+          , priority: crate::request::Priority::Normal
+          , no_failover: false
+          , few_shot: None
+          , cancel: None
+          , extra_headers: None
+          , checkpoint: crate::CheckpointMode::Disabled
+          , reply: reply_tx
+        };
+        self.submit_prompt(cmd).await?;
+        let reply = recv_reply(&mut reply_rx).await?;
+        let value = crate::utils::json_schema::parse_output(&reply)
+          .map_err(crate::error::Error::ParseError)?;
+        serde_json::from_value(value)
+          .map_err(|e| crate::error::Error::ParseError(e.to_string()))
+    }
+
+    /// `ask` with the prompt rendered from `template` and `vars`;
+    /// nothing is sent when a variable is missing
+    pub async fn ask_template(
+      &self
+    , template: &crate::request::PromptTemplate
+    , vars: &HashMap<String, String>
+    , model: &str
+    ) -> Result<String, crate::error::Error>
+    {   let prompt = template.render(vars)?;
+        self.ask(&prompt, model).await
+    }
+
+    /// `ask` of `prompt_template` with `{{text}}` filled in by each
+    /// of `texts`, `concurrency` at a time; see `map_over_texts_with`
+    pub fn map_over_texts<'a>(
+      &'a self
+    , texts: Vec<String>
+    , prompt_template: &str
+    , model: &'a str
+    , concurrency: usize
+    ) -> impl futures_util::Stream<
+        Item = Result<(String, String), crate::error::Error>
+      > + 'a
+    {   let config = crate::request::MapConfig
+        {   concurrency
+          , ..Default::default()
+        };
+        self.map_over_texts_with(
+          texts,
+          crate::request::PromptTemplate::new(prompt_template),
+          model,
+          config
+        )
+    }
+
+    /// Each of `texts` with its reply to `template`, whose `{{text}}`
+    /// placeholder it fills, in the order the replies arrive. Prompts
+    /// go through the usual queue, so provider rate limits hold
+    /// however high `concurrency` is.
+    pub fn map_over_texts_with<'a>(
+      &'a self
+    , texts: Vec<String>
+    , template: crate::request::PromptTemplate
+    , model: &'a str
+    , config: crate::request::MapConfig
+    ) -> impl futures_util::Stream<
+        Item = Result<(String, String), crate::error::Error>
+      > + 'a
+    {   let total = texts.len();
+        let mut done = 0;
+        let crate::request::MapConfig
+        {   concurrency, fail_fast, progress_callback
+        } = config;
+        futures_util::stream::iter(texts)
+          .map(move |text| {
+            let vars = HashMap::from([("text".to_string(), text.clone())]);
+            let prompt = template.render(&vars);
+            async move {
+              let reply = self.ask(&prompt?, model).await?;
+              Ok((text, reply))
+            }
+          })
+          .buffer_unordered(concurrency.max(1))
+          .scan(false, move |failed, result| {
+            if *failed
+            {   return futures_util::future::ready(None);
+            }
+            done += 1;
+            if let Some(progress) = &progress_callback
+            {   progress(done, total);
+            }
+            *failed = fail_fast && result.is_err();
+            futures_util::future::ready(Some(result))
+          })
+    }
+
+    /// Send a prompt whose generation ends at any of `stop`, and
+    /// wait for its reply without the stop sequence
+    pub async fn ask_until_stop(
+      &self
+    , prompt: &str
+    , model: &str
+    , stop: Vec<&str>
+    ) -> Result<String, crate::error::Error>
+    {   debug!("ask_until_stop queuing command for model: {}", model);
+        let (reply_tx, mut reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::SendPromptArgs
+        {   prompt: prompt.to_string()
+          , model: model.to_string()
+          , provider: None
+          , system_message: None
+          , max_tokens: None
+          , temperature: None
+          , extra_params: None
+          , request_id: None
+          , stop_sequences: Some(
+              stop.into_iter().map(str::to_string).collect()
+            )
+          , total_deadline: None
+          , json_schema: None
+          , prefill: None
+          , auto_detect_provider: false
+          , accepted: None
+          , priority: crate::request::Priority::Normal
+          , no_failover: false
+          , few_shot: None
+          , cancel: None
+          , extra_headers: None
+          , checkpoint: crate::CheckpointMode::Disabled
+          , reply: reply_tx
+        };
+        self.submit_prompt(cmd).await?;
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Send a request and wait for its (validated) reply
+    pub async fn ask_request(
+      &self
+    , request: crate::request::PromptRequest
+    ) -> Result<String, crate::error::Error>
+    {   let mut reply_rx = self.send_request(request).await?;
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// Send a request and wait for its (validated) `PromptResponse`
+    pub async fn ask_full(
+      &self
+    , request: crate::request::PromptRequest
+    ) -> Result<crate::request::PromptResponse, crate::error::Error>
+    {   let mut reply_rx = self.send_request_full(request).await?;
+        recv_reply(&mut reply_rx).await
+    }
+
+    /// `ask`, with the chain-of-thought of a reasoning model split
+    /// off the answer (see `utils::thinking::ExtractThinking`). Only
+    /// the reply text is read; `ask_full` also has the thinking of
+    /// APIs that send it in its own field, like DeepSeek's
+    /// `reasoning_content`, in `PromptResponse::thinking`.
+    pub async fn ask_with_reasoning(
+      &self
+    , prompt: &str
+    , model: &str
+    ) -> Result<crate::request::DeepSeekResponse, crate::error::Error>
+    {   let reply = self.ask(prompt, model).await?;
+        let (thinking, answer) = ExtractThinking::extract(&reply);
+        Ok(crate::request::DeepSeekResponse { thinking, answer })
+    }
+
+    /// `ask`, rewording the prompt with `RephrasingStrategy::
+    /// AutoRephrase` each time `RefusalDetector` flags the reply.
+    /// Fails with `Error::Other("max rephrases exceeded")` when the
+    /// last of `max_rephrases` rewordings is refused too.
+    pub async fn ask_with_retry_on_refusal(
+      &self
+    , prompt: &str
+    , model: &str
+    , max_rephrases: usize
+    ) -> Result<String, crate::error::Error>
+    {   let strategy = crate::request::RephrasingStrategy::AutoRephrase;
+        let mut current = prompt.to_string();
+        for attempt in 0..=max_rephrases
+        {   let reply = self.ask(&current, model).await;
+            if let Some(reply) = unless_refused(reply, String::as_str)?
+            {   return Ok(reply);
+            }
+            if attempt < max_rephrases
+            {   current = self.rephrase(&strategy, prompt, &current, None, model)
+                  .await?;
+            }
+        }
+        Err(crate::error::Error::Other("max rephrases exceeded".to_string()))
+    }
+
+    /// Ask for `n` completions of `request` in one call and wait for
+    /// their text, in the provider's order
+    pub async fn ask_n(
+      &self
+    , request: crate::request::PromptRequest
+    , n: usize
+    ) -> Result<Vec<String>, crate::error::Error>
+    {   if n == 0
+        {   return Err(crate::error::Error::InvalidConfiguration(
+              "n must be at least 1".to_string()
+            ));
+        }
+        let request = crate::request::PromptRequest { n: Some(n), ..request };
+        Ok(self.ask_full(request).await?.choices)
+    }
+
+    /// `ask_full`, rewriting the prompt with `strategy` each time
+    /// `RefusalDetector` flags the reply; the response counts the
+    /// rewrites in `rephrase_attempts`
+    pub async fn ask_with_rephrasing(
+      &self
+    , request: crate::request::PromptRequest
+    , strategy: crate::request::RephrasingStrategy
+    , max_rephrases: usize
+    ) -> Result<crate::request::PromptResponse, crate::error::Error>
+    {   let original = request.prompt.clone();
+        let mut current = request;
+        for attempt in 0..=max_rephrases
+        {   let response = self.ask_full(current.clone()).await;
+            if let Some(mut response)
+              = unless_refused(response, |r| r.text.as_str())?
+            {   response.rephrase_attempts
+                  = u8::try_from(attempt).unwrap_or(u8::MAX);
+                return Ok(response);
+            }
+            if attempt < max_rephrases
+            {   current.prompt = self.rephrase(
+                  &strategy,
+                  &original,
+                  &current.prompt,
+                  Some(current.provider.clone()),
+                  &current.model
+                ).await?;
+            }
+        }
+        Err(crate::error::Error::Other("max rephrases exceeded".to_string()))
+    }
+
+    /// Next prompt to try after `last` was refused
+    async fn rephrase(
+      &self
+    , strategy: &crate::request::RephrasingStrategy
+    , original: &str
+    , last: &str
+    , provider: Option<crate::Provider>
+    , model: &str
+    ) -> Result<String, crate::error::Error>
+    {   use crate::request::RephrasingStrategy;
+        let (provider, model) = match strategy
+        {   RephrasingStrategy::CustomPrefix(prefix) => {
+              return Ok(format!("{}{}", prefix, original));
+            }
+          , RephrasingStrategy::AutoRephrase => (provider, model.to_string())
+          , RephrasingStrategy::AlternativeModel(provider, model) => {
+              (Some(provider.clone()), model.clone())
+            }
+        };
+        let instruction = format!(
+          "Rephrase this question to be less likely to trigger a safety \
+           filter: {}",
+          last
+        );
+        let mut reply_rx = self.queue_prompt(provider, instruction, model)
+          .await?;
+        let rephrased = recv_reply(&mut reply_rx).await?;
+        debug!("Rephrased refused prompt as: {}", rephrased);
+        match rephrased.trim()
+        {   "" => Ok(last.to_string())
+          , rephrased => Ok(rephrased.to_string())
+        }
+    }
+
+    /// Send a one-token "Hi" to every provider concurrently so the
+    /// first real request finds an open connection in the pool.
+    /// Providers that fail (no key, unreachable) are skipped.
+    pub async fn warm_up(&self) -> Result<(), crate::error::Error>
+    {   let providers = self.warm_up_models.iter()
+          .map(|(provider, _)| provider.clone())
+          .collect();
+        self.warm_up_providers(providers).await.map(|_| ())
+    }
+
+    /// `warm_up` limited to `providers`, with how each went: an
+    /// authenticated one-token call to the provider's default model.
+    /// Providers with no client fail with `ProviderNotImplemented`.
+    pub async fn warm_up_providers(&self, providers: Vec<crate::Provider>)
+      -> Result<
+          HashMap<crate::Provider, Result<(), crate::error::Error>>,
+          crate::error::Error
+        >
+    {   debug!("Warming up {} providers", providers.len());
+        let mut warming: FuturesUnordered<_> = providers.into_iter()
+          .map(|provider| async move {
+            let model = self.warm_up_models.iter()
+              .find(|(p, _)| *p == provider)
+              .map(|(_, model)| model.clone());
+            let Some(model) = model
+            else
+            {   let e = crate::error::Error::ProviderNotImplemented(
+                  format!("{:?}", provider)
+                );
+                return (provider, Err(e));
+            };
+            let mut request = crate::request::PromptRequest::new(
+              provider.clone(), model, "Hi".to_string()
+            );
+            request.max_tokens = Some(1);
+            let result = self.ask_request(request).await.map(|_| ());
+            (provider, result)
+          })
+          .collect();
+        let mut results = HashMap::new();
+        while let Some((provider, result)) = warming.next().await
+        {   match &result
+            {   Ok(()) => debug!("Warmed up {:?}", provider)
+              , Err(e) => debug!("Skipping warm-up of {:?}: {}", provider, e)
+            }
+            results.insert(provider, result);
+        }
+        Ok(results)
+    }
+
+    /// Serve JSON-RPC requests from stdin, one per line, answering
+    /// on stdout (see `utils::jsonrpc`), until stdin closes or a
+    /// `shutdown` request; then shut the backend down
+    #[cfg(feature = "jsonrpc_server")]
+    pub async fn run_jsonrpc_server(self)
+      -> Result<(), crate::error::Error>
+    {   let stdin = tokio::io::BufReader::new(tokio::io::stdin());
+        let served = crate::utils::jsonrpc::serve(
+          &self, stdin, tokio::io::stdout()
+        ).await;
+        let shut_down = self.shutdown().await;
+        served.and(shut_down)
     }
 
-    /// Gracefully shutdown the backend
+    /// Gracefully shutdown the backend. Returns `Ok` once the backend
+    /// task has finished and released its clients.
     pub async fn shutdown(self) 
       -> Result<(), crate::error::Error>
     {   debug!("Shutting down AllmBackend");
+        self.shutting_down.store(true, Ordering::SeqCst);
         let (reply_tx, mut reply_rx)
           = mpsc::unbounded_channel();
         
@@ -233,6 +5461,7 @@ impl AllmBackend
 
         self.hand.kill_process_tx
           .send(cmd)
+          .await
           .map_err(|_| {
             error!("Backend channel already closed");
             crate::error::Error::Other(
@@ -243,14 +5472,116 @@ impl AllmBackend
         // Wait for shutdown confirmation
         if let Some(result) = reply_rx.recv().await
         {   debug!("Backend shutdown confirmed");
-            result
+            result?;
         } else
         {   error!("Backend shutdown timeout");
-            Err(crate::error::Error::Timeout)
+            return Err(crate::error::Error::Timeout);
+        }
+
+        // The loop has stopped; wait for it to drop its state
+        match tokio::time::timeout(SHUTDOWN_JOIN_TIMEOUT, self.task_handle)
+          .await
+        {   Ok(Ok(())) => {
+              debug!("Backend task finished");
+              Ok(())
+            }
+          , Ok(Err(e)) => {
+              error!("Backend task failed: {}", e);
+              Err(crate::error::Error::Other(
+                format!("Backend task failed: {}", e)
+              ))
+            }
+          , Err(_) => {
+              error!("Backend task still running after shutdown");
+              Err(crate::error::Error::Timeout)
+            }
+        }
+    }
+}
+
+/// Cloneable view of a running backend for monitoring (health
+/// checks, metrics). It reports not alive as soon as
+/// `AllmBackend::shutdown` starts.
+#[derive(Clone)]
+pub struct AllmBackendRef
+{   hand: Arc<crate::AllmHand>
+  , shutting_down: Arc<AtomicBool>
+}
+
+impl AllmBackendRef
+{   /// True until the backend starts shutting down or its loop ends
+    pub fn is_alive(&self) -> bool
+    {   !self.shutting_down.load(Ordering::SeqCst)
+          && !self.hand.get_metrics_tx.is_closed()
+    }
+
+    /// Counters per provider - returns almost immediately
+    pub async fn get_metrics(&self)
+      -> Result<
+        mpsc::UnboundedReceiver<crate::GetMetricsReply>,
+        crate::error::Error
+      >
+    {   debug!("get_metrics queuing");
+        let (reply_tx, reply_rx)
+          = mpsc::unbounded_channel();
+
+        let cmd = crate::GetMetricsArgs
+        {   reply: reply_tx
+        };
+
+        self.hand.get_metrics_tx
+          .send(cmd)
+          .await
+          .map_err(|_| {
+            error!("Backend channel closed");
+            crate::error::Error::Other(
+              "Backend disconnected".to_string()
+            )
+          })?;
+
+        Ok(reply_rx)
+    }
+
+    /// Fetch a metrics snapshot
+    pub async fn metrics(&self)
+      -> Result<MetricsSnapshot, crate::error::Error>
+    {   let mut reply_rx = self.get_metrics().await?;
+        recv_reply(&mut reply_rx).await
+    }
+}
+
+/// `Ok(None)` for a reply `RefusalDetector` flags, or that a
+/// registered `RefusalDetector` already rejected
+fn unless_refused<T>(
+  result: Result<T, crate::error::Error>
+, text: fn(&T) -> &str
+) -> Result<Option<T>, crate::error::Error>
+{   let rejected
+      = format!("validation failed: {}", ValidationCode::RefusalDetected);
+    match result
+    {   Ok(reply) if RefusalDetector::new().validate(text(&reply)).is_err() => {
+          Ok(None)
+        }
+      , Ok(reply) => Ok(Some(reply))
+      , Err(crate::error::Error::Other(message)) if message == rejected => {
+          Ok(None)
         }
+      , Err(e) => Err(e)
     }
 }
 
+/// Wait for the single reply of a queued command
+async fn recv_reply<T>(
+  reply_rx: &mut mpsc::UnboundedReceiver<Result<T, crate::error::Error>>
+) -> Result<T, crate::error::Error>
+{   reply_rx.recv().await.unwrap_or_else(|| {
+      error!("Backend dropped reply channel");
+      Err(crate::error::Error::Other(
+        "Backend disconnected".to_string()
+      ))
+    })
+}
+
 /// Main backend event loop
 /// 
 /// Design: tokio::select! is ONLY for fast queueing.
@@ -258,87 +5589,464 @@ impl AllmBackend
 /// (in this case: mistral) and returns. No awaiting on work.
 async fn run_backend_loop(
   foot: crate::AllmFoot
-, mistral_api_key: Option<String>
+, mut state: AllmBackendState
 )
 {   debug!("Starting AllmBackend event loop");
-    let mut state = AllmBackendState::new(mistral_api_key);
     let AllmFoot
     {   mut send_prompt_rx
+      , mut send_request_rx
+      , mut send_stream_rx
+      , mut dry_run_rx
       , mut set_api_keys_rx
+      , mut set_key_provider_rx
       , mut get_model_lists_rx
       , mut kill_process_rx
       , mut set_model_fallback_preference_rx
+      , mut set_model_aliases_rx
+      , mut add_response_validator_rx
+      , mut add_request_interceptor_rx
+      , mut register_models_rx
+      , mut export_model_registry_rx
+      , mut import_model_registry_rx
+      , mut get_metrics_rx
+      , mut create_session_rx
+      , mut send_session_prompt_rx
+      , mut summarize_session_rx
+      , mut pin_session_model_rx
+      , mut set_session_summarization_rx
+      , mut get_session_rx
+      , mut export_session_rx
+      , mut import_session_rx
+      , mut preload_providers_rx
+      , mut openai_rx
+      , mut get_supervision_status_rx
+      , mut get_partial_response_rx
+      , mut get_capability_matrix_rx
+      , mut get_model_info_rx
+      , mut split_prompt_rx
+      , mut would_use_provider_rx
+      , mut get_rate_limit_status_rx
+      , mut get_rate_limit_reset_rx
+      , mut count_tokens_rx
+      , mut get_prompt_status_rx
+      , mut watch_provider_status_rx
+      , mut subscribe_events_rx
+      , mut completion_rx
+      , mut compute_perplexity_rx
+      , mut openai_batch_rx
     } = foot;
 
+    // Prompts waiting out a retry backoff re-enter the loop here, and
+    // rate limit slots come up through `slot_rx`
+    let mut delayed_rx = state.delayed_rx.take()
+      .expect("backend state already running");
+    let mut slot_rx = state.slot_rx.take()
+      .expect("backend state already running");
+    let mut moderated_rx = state.moderated_rx.take()
+      .expect("backend state already running");
+    let mut resubmit_rx = state.resubmit_rx.take()
+      .expect("backend state already running");
+    let mut supervise = tokio::time::interval(
+      state.supervisor.check_interval()
+    );
+    supervise.set_missed_tick_behavior(
+      tokio::time::MissedTickBehavior::Delay
+    );
+    let mut cool_off = tokio::time::interval(
+      crate::failover::SMART_RESET_INTERVAL
+    );
+
     loop
     { tokio::select!
       { Some(cmd) = send_prompt_rx.recv() => {
           debug!("Received SendPrompt for model: {}", cmd.model);
-          
-          // Route to appropriate provider
-          match state.current_model.0
-          {   crate::Provider::MistralAi => {
-                let _ = state.mistral_client
-                  .send_prompt(
-                    cmd.prompt,
-                    cmd.model,
-                    cmd.reply
-                  )
-                  .await;
-              }
-            , _ => {
-                error!("Provider not implemented");
-                let _ = cmd.reply.send(
-                  Err(crate::error::Error::ProviderNotImplemented(
-                    format!("{:?}", state.current_model.0)
-                  ))
-                );
-              }
+          state.take_prompt(cmd, false).await;
+        }
+      , Some(cmd) = resubmit_rx.recv() => {
+          debug!("Received part of a split prompt for model: {}", cmd.model);
+          state.take_prompt(cmd, true).await;
+        }
+      , Some(mut cmd) = send_request_rx.recv() => {
+          debug!("Received SendRequest for model: {}", cmd.request.model);
+          let target = state.policy_target(
+            Some(cmd.request.provider.clone()), &cmd.request.model
+          );
+          if let Ok(Some((provider, model))) = &target
+          {   cmd.request.provider = provider.clone();
+              cmd.request.model = model.clone();
           }
+          let request = &cmd.request;
+          if let Err(e) = target.map(|_| ())
+            .and_then(|()| state.check_queue_room())
+            .and_then(|()| state.check_capabilities(
+              Some(request.provider.clone()), &request.model,
+              request.extra_params.as_ref()
+            ))
+            .and_then(|()| check_few_shot(request.few_shot.as_ref()))
+          {   let _ = cmd.reply.send(Err(e));
+          } else
+          {   let id = state.register_request(cmd);
+              state.schedule_prompt(id).await;
+          }
+        }
+      , Some(cmd) = send_stream_rx.recv() => {
+          debug!("Received SendStream for model: {}", cmd.model);
+          state.dispatch_stream(cmd);
+        }
+      , Some(cmd) = dry_run_rx.recv() => {
+          debug!("Received DryRun for model: {}", cmd.request.model);
+          let _ = cmd.reply.send(state.dry_run(cmd.request));
+        }
+      , Some(id) = delayed_rx.recv() => {
+          debug!("Dispatching delayed prompt {}", id);
+          state.dispatch_prompt(id);
+        }
+      , Some(provider) = slot_rx.recv() => {
+          debug!("Rate limit slot of {:?} free", provider);
+          state.dispatch_rate_limited(&provider);
+        }
+      , Some((id, verdict)) = moderated_rx.recv() => {
+          debug!("Moderation done for prompt {}", id);
+          state.finish_moderation(id, verdict).await;
+        }
+      , Some((id, result)) = state.in_flight.next() => {
+          debug!("Provider replied to prompt {}", id);
+          state.complete_prompt(id, result).await;
         }
       , Some(cmd) = set_api_keys_rx.recv() => 
         {
           log::debug!("client.rs Received SetApiKeys");
+          let mut outcomes = Vec::with_capacity(cmd.keys.len());
           for key_spec in cmd.keys
-          {
-            state.api_keys.insert(
-                (key_spec.provider.clone(), key_spec.model.clone()),
-                key_spec.key.clone()
-            );
-
-            if key_spec.provider == crate::Provider::MistralAi
-            {
-              let (reply_tx, _reply_rx) = mpsc::unbounded_channel();
-              let _ = state.mistral_client
-                .set_api_key(
-                  if key_spec.model.is_empty() { None } else { Some(key_spec.model.clone()) },
-                  key_spec.key.clone(),
-                  reply_tx,
-                )
-                .await;
-              // Ignore reply – if it fails, it will log inside the provider anyway
+          {   // Ignore reply – if it fails, it will log inside the provider anyway
+              let outcome = state.set_api_key(&key_spec).await;
+              outcomes.push((key_spec, outcome));
+          }
+          // Future: handle OpenAI, Anthropic, etc. here
+          // The reply waits for every client to confirm its key, so
+          // a prompt sent after it uses the new keys
+          tokio::spawn(async move {
+            let mut results = Vec::with_capacity(outcomes.len());
+            for (key_spec, outcome) in outcomes
+            {   let result = match outcome
+                {   Ok(Some(mut confirmation)) => confirmation.recv().await
+                      .unwrap_or_else(|| Err(crate::error::Error::Other(
+                        "Provider disconnected".to_string()
+                      )))
+                  , Ok(None) => Ok(())
+                  , Err(e) => Err(e)
+                };
+                if let Err(e) = &result
+                {   warn!("Key for {:?} flagged: {}", key_spec.provider, e);
+                }
+                results.push((key_spec, result));
             }
-            // Future: handle OpenAI, Anthropic, etc. here
+            // CRITICAL FIX: Send the reply back!
+            let _ = cmd.reply.send(Ok(results));
+          });
+        }
+      , Some(cmd) = set_key_provider_rx.recv() => {
+          debug!("Received SetKeyProvider for {:?}", cmd.provider);
+          if let Err(e) = state.init_client(&cmd.provider)
+          {   let _ = cmd.reply.send(Err(e));
+              continue;
+          }
+          match state.clients.get(&cmd.provider)
+            .and_then(ProviderClientState::ready)
+          {   Some(client) => {
+                // The provider confirms on the caller's channel
+                if let Err(e) = client.set_key_provider(
+                  cmd.model, cmd.key_provider, cmd.reply.clone()
+                )
+                {   let _ = cmd.reply.send(Err(e));
+                }
+              }
+            , None => {
+                let _ = cmd.reply.send(Err(
+                  crate::error::Error::ProviderNotImplemented(
+                    format!("{:?}", cmd.provider)
+                  )
+                ));
+              }
           }
-          
-          // CRITICAL FIX: Send the reply back!
-          let _ = cmd.reply.send(Ok(()));
         }
       , Some(cmd) = get_model_lists_rx.recv() => {
           debug!("Received GetModelLists");
-          let _ = cmd.reply.send(Ok(vec![]));
+          let mut providers: Vec<crate::Provider>
+            = state.clients.keys().cloned().collect();
+          providers.sort_by_key(|p| p.name());
+          let mut listings = vec![];
+          for provider in providers
+          {   if let Err(e) = state.init_client(&provider)
+              {   warn!("Not listing {:?} models: {}", provider, e);
+                  continue;
+              }
+              let Some(client) = state.clients.get(&provider)
+                .and_then(|c| c.ready()) else
+              {   continue;
+              };
+              let (tx, rx) = mpsc::unbounded_channel();
+              match client.get_available_models(tx)
+              {   Ok(()) => listings.push((provider, rx))
+                , Err(e) => warn!(
+                    "Not listing {:?} models: {}", provider, e
+                  )
+              }
+          }
+          let catalog = match cmd.filter
+          {   Some(_) => state.model_catalog.clone()
+            , None => vec![]
+          };
+          // Providers answer at their own pace; collect off the loop
+          tokio::spawn(async move {
+            let mut models = vec![];
+            for (provider, mut rx) in listings
+            {   match rx.recv().await
+                {   Some(Ok(names)) => models.extend(
+                      names.into_iter().map(|name| (provider.clone(), name))
+                    )
+                  , Some(Err(e)) => warn!(
+                      "Listing {:?} models failed: {}", provider, e
+                    )
+                  , None => warn!("{:?} dropped its model listing", provider)
+                }
+            }
+            if let Some(filter) = &cmd.filter
+            {   models.retain(|(provider, name)| {
+                  catalog.iter()
+                    .find(|m| m.provider == *provider && m.name == *name)
+                    .cloned()
+                    .or_else(|| {
+                      crate::providers::default_model_info(provider)
+                        .filter(|m| m.name == *name)
+                    })
+                    .is_some_and(|info| filter.matches(&info))
+                });
+            }
+            let _ = cmd.reply.send(Ok(models));
+          });
         }
       , Some(cmd) = kill_process_rx.recv() => {
           debug!("Received KillProcess");
+          state.save_model_registry();
           let _ = cmd.reply.send(Ok(()));
           info!("AllmBackend shutting down");
           break;
         }
       , Some(cmd) = set_model_fallback_preference_rx.recv() => {
           debug!("Received SetModelFallbackPreference");
-          state.fallback_preferences = cmd.preferences;
+          let checked = state.config.check_provider_policy(
+            cmd.preferences.iter().map(|(provider, _)| provider)
+          );
+          if checked.is_ok()
+          {   state.fallback_preferences
+                = crate::failover::FailoverSequence::new(cmd.preferences);
+          }
+          let _ = cmd.reply.send(checked);
+        }
+      , Some(cmd) = set_model_aliases_rx.recv() => {
+          debug!("Received SetModelAliases ({} aliases)", cmd.aliases.len());
+          state.model_aliases = cmd.aliases;
+          let _ = cmd.reply.send(Ok(()));
+        }
+      , Some(cmd) = add_request_interceptor_rx.recv() => {
+          debug!("Received AddRequestInterceptor");
+          state.interceptors.push(cmd.interceptor);
+          let _ = cmd.reply.send(Ok(()));
+        }
+      , Some(cmd) = add_response_validator_rx.recv() => {
+          debug!("Received AddResponseValidator");
+          state.validators.push(cmd.validator);
+          let _ = cmd.reply.send(Ok(()));
+        }
+      , Some(cmd) = register_models_rx.recv() => {
+          debug!("Received RegisterModels ({} models)", cmd.models.len());
+          state.register_models(cmd.models);
+          let _ = cmd.reply.send(Ok(()));
+        }
+      , Some(cmd) = export_model_registry_rx.recv() => {
+          debug!("Received ExportModelRegistry");
+          let _ = cmd.reply.send(state.export_model_registry());
+        }
+      , Some(cmd) = import_model_registry_rx.recv() => {
+          debug!("Received ImportModelRegistry");
+          let result = state.import_model_registry(&cmd.json);
+          let _ = cmd.reply.send(result);
+        }
+      , Some(cmd) = get_metrics_rx.recv() => {
+          debug!("Received GetMetrics");
+          let _ = cmd.reply.send(Ok(state.metrics_snapshot()));
+        }
+      , Some(cmd) = create_session_rx.recv() => {
+          let session
+            = ConversationSession::new(cmd.max_messages, cmd.pruning);
+          debug!("Received CreateSession, created {}", session.id);
+          let id = session.id;
+          state.sessions.insert(id, session);
+          let _ = cmd.reply.send(Ok(id));
+        }
+      , Some(cmd) = send_session_prompt_rx.recv() => {
+          debug!("Received SendSessionPrompt for {}", cmd.session_id);
+          state.send_session_prompt(cmd).await;
+        }
+      , Some(cmd) = summarize_session_rx.recv() => {
+          debug!("Received SummarizeSession for {}", cmd.session_id);
+          state.summarize_session(cmd.session_id, cmd.model, cmd.reply)
+            .await;
+        }
+      , Some(cmd) = pin_session_model_rx.recv() => {
+          debug!("Received PinSessionModel for {}", cmd.session_id);
+          let result = match state.sessions.get_mut(&cmd.session_id)
+          {   Some(session) => {
+                info!(
+                  "Session {} pinned to {:?}/{}",
+                  cmd.session_id, cmd.provider, cmd.model
+                );
+                session.pinned_model = Some((cmd.provider, cmd.model));
+                Ok(())
+              }
+            , None => Err(crate::error::Error::SessionNotFound(cmd.session_id))
+          };
+          let _ = cmd.reply.send(result);
+        }
+      , Some(cmd) = set_session_summarization_rx.recv() => {
+          debug!("Received SetSessionSummarization for {}", cmd.session_id);
+          let result = match state.sessions.get_mut(&cmd.session_id)
+          {   Some(session) => {
+                session.summarization = cmd.strategy;
+                Ok(())
+              }
+            , None => Err(crate::error::Error::SessionNotFound(cmd.session_id))
+          };
+          let _ = cmd.reply.send(result);
+        }
+      , Some(cmd) = get_session_rx.recv() => {
+          debug!("Received GetSession for {}", cmd.session_id);
+          let result = state.sessions.get(&cmd.session_id)
+            .cloned()
+            .ok_or(crate::error::Error::SessionNotFound(cmd.session_id));
+          let _ = cmd.reply.send(result);
+        }
+      , Some(cmd) = export_session_rx.recv() => {
+          debug!("Received ExportSession for {}", cmd.session_id);
+          let result = match state.sessions.get(&cmd.session_id)
+          {   Some(session) => session.to_json().map_err(|e| {
+                crate::error::Error::ParseError(e.to_string())
+              })
+            , None => Err(crate::error::Error::SessionNotFound(cmd.session_id))
+          };
+          let _ = cmd.reply.send(result);
+        }
+      , Some(cmd) = import_session_rx.recv() => {
+          debug!("Received ImportSession");
+          let result = state.import_session(&cmd.json);
+          let _ = cmd.reply.send(result);
+        }
+      , Some(cmd) = preload_providers_rx.recv() => {
+          debug!("Received PreloadProviders ({})", cmd.providers.len());
+          let result = state.preload_providers(&cmd.providers);
+          let _ = cmd.reply.send(result);
+        }
+      , _ = supervise.tick() => {
+          state.supervise().await;
+        }
+      , _ = cool_off.tick() => {
+          state.fallback_preferences.smart_reset(std::time::Instant::now());
+          state.close_rate_limits(std::time::SystemTime::now());
+        }
+      , Some(cmd) = get_partial_response_rx.recv() => {
+          debug!("Received GetPartialResponse for {}", cmd.request_id);
+          let partial = state.partial_responses.get(&cmd.request_id).cloned();
+          let _ = cmd.reply.send(partial);
+        }
+      , Some(cmd) = get_capability_matrix_rx.recv() => {
+          debug!("Received GetCapabilityMatrix");
+          let _ = cmd.reply.send(Ok(state.capability_matrix()));
+        }
+      , Some(cmd) = split_prompt_rx.recv() => {
+          debug!("Received SplitPrompt for model: {}", cmd.model);
+          let _ = cmd.reply.send(Ok(state.chunk_prompt(cmd.prompt, cmd.model)));
+        }
+      , Some(cmd) = would_use_provider_rx.recv() => {
+          debug!("Received WouldUseProvider");
+          let _ = cmd.reply.send(state.first_available_provider(cmd.requirements));
+        }
+      , Some(cmd) = get_model_info_rx.recv() => {
+          debug!("Received GetModelInfo for {:?}/{}", cmd.provider, cmd.model);
+          let info = state.model_info(&cmd.provider, &cmd.model)
+            .ok_or(crate::error::Error::ModelNotFound(cmd.model));
+          let _ = cmd.reply.send(info);
+        }
+      , Some(cmd) = count_tokens_rx.recv() => {
+          debug!(
+            "Received CountTokens for {} prompts to {}",
+            cmd.prompts.len(), cmd.model
+          );
+          state.count_tokens(cmd);
+        }
+      , Some(cmd) = get_prompt_status_rx.recv() => {
+          debug!("Received GetPromptStatus for {}", cmd.id);
+          let _ = cmd.reply.send(state.prompt_status(cmd.id));
+        }
+      , Some(cmd) = watch_provider_status_rx.recv() => {
+          debug!("Received WatchProviderStatus for {:?}", cmd.provider);
+          let _ = cmd.reply.send(state.watch_provider_status(cmd.provider));
+        }
+      , Some(cmd) = subscribe_events_rx.recv() => {
+          debug!("Received SubscribeEvents");
+          state.event_subscribers.push(cmd.subscriber);
           let _ = cmd.reply.send(Ok(()));
         }
+      , Some(cmd) = completion_rx.recv() => {
+          debug!("Received SendCompletion for {}", cmd.model);
+          state.send_completion(cmd);
+        }
+      , Some(cmd) = compute_perplexity_rx.recv() => {
+          debug!("Received ComputePerplexity for {:?}", cmd.provider);
+          state.compute_perplexity(cmd);
+        }
+      , Some(cmd) = get_rate_limit_status_rx.recv() => {
+          debug!("Received GetRateLimitStatus for {:?}", cmd.provider);
+          let _ = cmd.reply.send(
+            state.rate_limit_status(&cmd.provider, &cmd.model)
+          );
+        }
+      , Some(cmd) = get_rate_limit_reset_rx.recv() => {
+          debug!("Received GetRateLimitReset for {:?}", cmd.provider);
+          let _ = cmd.reply.send(
+            state.rate_limit_reset(&cmd.provider, &cmd.model)
+          );
+        }
+      , Some(cmd) = get_supervision_status_rx.recv() => {
+          debug!("Received GetSupervisionStatus");
+          let _ = cmd.reply.send(Ok(state.supervision_status()));
+        }
+      , Some(command) = openai_rx.recv() => {
+          debug!("Received OpenAI assistants command");
+          state.send_openai_command(command);
+        }
+      , Some(cmd) = openai_batch_rx.recv() => {
+          debug!("Received OpenAIBatch of {} requests", cmd.requests.len());
+          let default_model = state.default_models
+            .get(&crate::Provider::OpenAI)
+            .cloned()
+            .unwrap_or_default();
+          let requests = cmd.requests.into_iter()
+            .map(|item| {
+              let model = item.model
+                .unwrap_or_else(|| default_model.clone());
+              ( item.custom_id
+              , crate::providers::ChatRequest::from_prompt(item.prompt, model)
+              )
+            })
+            .collect();
+          state.send_openai_command(OpenAICommand::Batch
+          {   requests
+            , reply: cmd.reply
+          });
+        }
       }
+      state.publish_provider_status();
+      state.publish_health_changes();
     }
 }
\ No newline at end of file
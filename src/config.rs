@@ -1,6 +1,49 @@
 //! Configuration for ALLM providers and failover behavior
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// `User-Agent` sent when `HttpClientConfig::user_agent` is unset
+pub const DEFAULT_USER_AGENT: &str = concat!(
+  "allm/", env!("CARGO_PKG_VERSION"),
+  " (rust; https://github.com/yasteven/allm)"
+);
+
+/// Default `AllmConfig::stream_buffer_size`
+pub const DEFAULT_STREAM_BUFFER_SIZE: usize = 64;
+
+fn default_stream_buffer_size() -> usize
+{   DEFAULT_STREAM_BUFFER_SIZE
+}
+
+/// Default `AllmConfig::stream_dedup_replay_buffer_size`
+pub const DEFAULT_STREAM_DEDUP_REPLAY_BUFFER_SIZE: usize = 256;
+
+fn default_stream_dedup_replay_buffer_size() -> usize
+{   DEFAULT_STREAM_DEDUP_REPLAY_BUFFER_SIZE
+}
+
+/// Default `AllmConfig::command_queue_capacity`
+pub const DEFAULT_COMMAND_QUEUE_CAPACITY: usize = 256;
+
+fn default_command_queue_capacity() -> usize
+{   DEFAULT_COMMAND_QUEUE_CAPACITY
+}
+
+/// Default `AllmConfig::context_warning_threshold_percent`
+pub const DEFAULT_CONTEXT_WARNING_THRESHOLD_PERCENT: f32 = 80.0;
+
+fn default_context_warning_threshold_percent() -> f32
+{   DEFAULT_CONTEXT_WARNING_THRESHOLD_PERCENT
+}
+
+fn default_lazy_init() -> bool
+{   true
+}
+
+fn default_strict_capability_validation() -> bool
+{   true
+}
 
 /// Provider configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -9,10 +52,154 @@ pub struct ProviderConfig
     pub name: String
   , /// API base URL (if custom)
     pub api_base: Option<String>
-  , /// Request timeout in seconds
+  , /// Request timeout in seconds. A streaming request has no total
+    /// limit: this is the longest it may wait for its next chunk.
     pub timeout_secs: Option<u64>
-  , /// Enable detailed logging
+  , /// Longest wait, in seconds, for a connection to the provider
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u64>
+  , /// Enable detailed logging: `log_level` `Trace` when true,
+    /// `Debug` otherwise
     pub verbose: Option<bool>
+  , /// Level the provider's actor logs at (target
+    /// `allm::providers::<name>`), overriding `verbose`
+    #[serde(default)]
+    pub log_level: Option<crate::utils::logger::LogLevel>
+  , /// Master API key for the provider
+    pub api_key: Option<String>
+  , /// Account identifier (Cloudflare Workers AI)
+    pub account_id: Option<String>
+  , /// Requests per minute allowed to this provider
+    pub rate_limit_rpm: Option<u32>
+  , /// Estimated prompt tokens per minute allowed to this provider
+    pub rate_limit_tpm: Option<u32>
+  , /// Delay between polls of asynchronous jobs (Replicate,
+    /// OpenAI assistant runs and batches)
+    pub poll_interval_ms: Option<u64>
+  , /// Give up polling an asynchronous job after this long
+    pub poll_timeout_secs: Option<u64>
+  , /// Polls of an OpenAI assistant run before giving up
+    #[serde(default)]
+    pub max_poll_attempts: Option<u32>
+  , /// Model used when the backend picks one itself (warm-up);
+    /// `providers::default_model` if unset
+    pub default_model: Option<String>
+  , /// Route OpenAI requests to an Azure deployment instead
+    #[serde(default)]
+    pub azure: Option<AzureConfig>
+  , /// Create the client on the first request routed to it rather
+    /// than at startup (see `AllmBackend::preload_providers`)
+    #[serde(default = "default_lazy_init")]
+    pub lazy_init: bool
+  , /// Headers added to every request to this provider, e.g. a
+    /// gateway's tenant id; checked when the client is built
+    #[serde(default)]
+    pub extra_headers: Option<HashMap<String, String>>
+  , /// PEM file of a CA certificate trusted on top of the system
+    /// roots, e.g. for a gateway signed by a private CA
+    #[serde(default)]
+    pub ca_cert_path: Option<String>
+  , /// INSECURE: accept any certificate, expired, self-signed or for
+    /// another host, leaving the connection open to interception.
+    /// Only for local testing; prefer `ca_cert_path`.
+    #[serde(default)]
+    pub danger_accept_invalid_certs: Option<bool>
+  , /// PKCS#12 (`.p12`/`.pfx`) file with the client certificate and
+    /// key presented to gateways requiring mutual TLS
+    #[serde(default)]
+    pub client_identity_path: Option<String>
+  , /// Password of `client_identity_path`
+    #[serde(default)]
+    pub client_identity_password: Option<String>
+  , /// Sign every request with HMAC-SHA256, for endpoints that
+    /// check a signature (see `utils::auth::HmacSha256Signer`)
+    #[serde(default)]
+    pub hmac_signing: Option<HmacSigningConfig>
+  , /// Largest prompt, in bytes, one request may carry; see
+    /// `AllmConfig::large_prompt_strategy`
+    #[serde(default)]
+    pub max_request_size_bytes: Option<usize>
+  , /// Most estimated prompt tokens in one request, for models that
+    /// take fewer than their context window suggests
+    #[serde(default)]
+    pub max_single_request_tokens: Option<usize>
+}
+
+impl Default for ProviderConfig
+{   fn default() -> Self
+    {   ProviderConfig
+        {   name: String::new()
+          , api_base: None
+          , timeout_secs: None
+          , connect_timeout_secs: None
+          , verbose: None
+          , log_level: None
+          , api_key: None
+          , account_id: None
+          , rate_limit_rpm: None
+          , rate_limit_tpm: None
+          , poll_interval_ms: None
+          , poll_timeout_secs: None
+          , max_poll_attempts: None
+          , default_model: None
+          , azure: None
+          , lazy_init: default_lazy_init()
+          , extra_headers: None
+          , ca_cert_path: None
+          , danger_accept_invalid_certs: None
+          , client_identity_path: None
+          , client_identity_password: None
+          , hmac_signing: None
+          , max_request_size_bytes: None
+          , max_single_request_tokens: None
+        }
+    }
+}
+
+/// Azure OpenAI deployment (`https://{resource}.openai.azure.com`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AzureConfig
+{   /// Azure resource name
+    pub resource: String
+  , /// Deployment name; it selects the model, so the request's
+    /// model field is ignored by Azure
+    pub deployment: String
+  , /// Value of the `api-version` query parameter
+    pub api_version: String
+}
+
+/// Key a provider's requests are signed with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HmacSigningConfig
+{   /// Sent as `X-Signature-KeyId`
+    pub key_id: String
+  , pub secret: String
+}
+
+impl ProviderConfig
+{   /// Resolve `name` to a provider variant
+    pub fn provider(&self) -> Option<crate::Provider>
+    {   crate::Provider::from_name(&self.name)
+    }
+}
+
+/// Settings shared by every provider's HTTP client
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HttpClientConfig
+{   /// `User-Agent` for all requests; `DEFAULT_USER_AGENT` if unset
+    #[serde(default)]
+    pub user_agent: Option<String>
+  , /// Per-provider `User-Agent`, sent as a header on each request
+    /// to that provider in place of the default
+    #[serde(default)]
+    pub provider_specific_user_agents: HashMap<crate::Provider, String>
+}
+
+impl HttpClientConfig
+{   /// `User-Agent` configured for every client
+    pub fn user_agent(&self) -> &str
+    {   self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT)
+    }
 }
 
 /// Failover configuration
@@ -26,6 +213,14 @@ pub struct FailoverConfig
     pub backoff_multiplier: f32
   , /// Initial backoff duration in milliseconds
     pub initial_backoff_ms: u64
+  , /// Errors that move a prompt to its next fallback; none when
+    /// empty. Rate limits and overloads are retried on the same model
+    /// first either way.
+    #[serde(default = "crate::failover::FailoverTrigger::defaults")]
+    pub failover_on: Vec<crate::failover::FailoverTrigger>
+  , /// Which remaining fallback a failed prompt moves to
+    #[serde(default)]
+    pub strategy: crate::failover::FailoverStrategy
 }
 
 impl Default for FailoverConfig
@@ -35,6 +230,133 @@ impl Default for FailoverConfig
           , max_retries: 3
           , backoff_multiplier: 2.0
           , initial_backoff_ms: 100
+          , failover_on: crate::failover::FailoverTrigger::defaults()
+          , strategy: Default::default()
+        }
+    }
+}
+
+/// Coalescing of identical prompts
+///
+/// A `send_prompt` with the same provider, model, system message and
+/// prompt as one accepted less than `window_ms` ago, and still
+/// unanswered, waits for that prompt's reply instead of sending a
+/// request of its own. Streams started less than `window_ms` apart
+/// with the same provider, model and prompt share one provider stream
+/// (see `utils::stream_share`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeduplicationConfig
+{   #[serde(default)]
+    pub enabled: bool
+  , /// How long after acceptance a prompt takes on duplicates
+    #[serde(default = "default_dedup_window_ms")]
+    pub window_ms: u64
+  , /// Prompts tracked at once; past this, new prompts are sent
+    /// without deduplication
+    #[serde(default = "default_dedup_max_pending")]
+    pub max_pending: usize
+}
+
+fn default_dedup_window_ms() -> u64
+{   2_000
+}
+
+fn default_dedup_max_pending() -> usize
+{   256
+}
+
+impl Default for DeduplicationConfig
+{   fn default() -> Self
+    {   DeduplicationConfig
+        {   enabled: false
+          , window_ms: default_dedup_window_ms()
+          , max_pending: default_dedup_max_pending()
+        }
+    }
+}
+
+/// Resuming of `CheckpointMode::Enabled` prompts that time out
+///
+/// Such prompts are streamed and their text kept as it arrives. When
+/// the provider times out mid-reply, the prompt is resent with
+/// `Continue from: {last 512 characters}` ahead of its system message,
+/// up to `max_resume_attempts` times.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointConfig
+{   #[serde(default = "default_true")]
+    pub enabled: bool
+  , #[serde(default = "default_max_resume_attempts")]
+    pub max_resume_attempts: usize
+}
+
+fn default_max_resume_attempts() -> usize
+{   2
+}
+
+impl Default for CheckpointConfig
+{   fn default() -> Self
+    {   CheckpointConfig
+        {   enabled: default_true()
+          , max_resume_attempts: default_max_resume_attempts()
+        }
+    }
+}
+
+/// Restarting of crashed provider actors (see `supervisor`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SupervisorConfig
+{   #[serde(default = "default_true")]
+    pub enabled: bool
+  , /// Restarts allowed within `window_secs` before the provider is
+    /// marked failed
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32
+  , #[serde(default = "default_restart_window_secs")]
+    pub window_secs: u64
+  , /// Wait before the first restart; doubled for each further crash
+    /// within the window
+    #[serde(default = "default_restart_backoff_ms")]
+    pub initial_backoff_ms: u64
+  , #[serde(default = "default_max_restart_backoff_ms")]
+    pub max_backoff_ms: u64
+  , /// How often clients are checked for a dead actor
+    #[serde(default = "default_check_interval_ms")]
+    pub check_interval_ms: u64
+}
+
+fn default_true() -> bool
+{   true
+}
+
+fn default_max_restarts() -> u32
+{   5
+}
+
+fn default_restart_window_secs() -> u64
+{   60
+}
+
+fn default_restart_backoff_ms() -> u64
+{   100
+}
+
+fn default_max_restart_backoff_ms() -> u64
+{   10_000
+}
+
+fn default_check_interval_ms() -> u64
+{   1_000
+}
+
+impl Default for SupervisorConfig
+{   fn default() -> Self
+    {   SupervisorConfig
+        {   enabled: default_true()
+          , max_restarts: default_max_restarts()
+          , window_secs: default_restart_window_secs()
+          , initial_backoff_ms: default_restart_backoff_ms()
+          , max_backoff_ms: default_max_restart_backoff_ms()
+          , check_interval_ms: default_check_interval_ms()
         }
     }
 }
@@ -46,6 +368,114 @@ pub struct AllmConfig
     pub providers: Vec<ProviderConfig>
   , /// Failover configuration
     pub failover: FailoverConfig
+  , /// Resend refused prompts to the next fallback model
+    #[serde(default)]
+    pub retry_on_refusal: bool
+  , /// HTTP client settings
+    #[serde(default)]
+    pub http_client_config: HttpClientConfig
+  , /// Run `AllmBackend::warm_up` before `new_with_config` returns
+    #[serde(default)]
+    pub warm_up_on_start: bool
+  , /// Reject prompts asking a catalogued model for tools, logprobs
+    /// or a seed it does not support (see
+    /// `PromptRequest::validate_for_model`)
+    #[serde(default = "default_strict_capability_validation")]
+    pub strict_capability_validation: bool
+  , /// On `ContextWindowExceeded`, retry on a same-family catalog
+    /// model with a larger context window before failing
+    #[serde(default)]
+    pub context_overflow_upgrade: bool
+  , /// Stream chunks buffered for a consumer before the provider
+    /// waits for it to catch up
+    #[serde(default = "default_stream_buffer_size")]
+    pub stream_buffer_size: usize
+  , /// Chunks of a deduplicated stream kept for identical streams
+    /// joining late; once more have arrived, it takes no more
+    #[serde(default = "default_stream_dedup_replay_buffer_size")]
+    pub stream_dedup_replay_buffer_size: usize
+  , /// Commands queued on each backend channel before senders wait
+    /// for the backend to take one
+    #[serde(default = "default_command_queue_capacity")]
+    pub command_queue_capacity: usize
+  , /// Prompts accepted and not yet answered at once; past this,
+    /// `send_prompt` and `send_request` fail with "queue full".
+    /// `None` accepts any number.
+    #[serde(default)]
+    pub max_queued_prompts: Option<usize>
+  , /// Coalescing of identical prompts, off by default
+    #[serde(default)]
+    pub deduplication: DeduplicationConfig
+  , /// Restarting of crashed provider actors
+    #[serde(default)]
+    pub supervisor: SupervisorConfig
+  , /// Resuming of checkpointed prompts after a timeout
+    #[serde(default)]
+    pub checkpoint: CheckpointConfig
+  , /// Serve `/health`, `/metrics` and `/providers` on this port
+    /// (`health-server` feature)
+    #[serde(default)]
+    pub health_server_port: Option<u16>
+  , /// Catalog entries replacing the built-in ones with the same
+    /// provider and name, or adding models allm does not know yet
+    #[serde(default)]
+    pub model_overrides: Vec<crate::ModelInfo>
+  , /// JSON price file (see `utils::cost`) whose prices win over
+    /// the catalog's
+    #[serde(default)]
+    pub price_table_path: Option<String>
+  , /// JSON file of the model catalog (see `export_model_registry`),
+    /// merged in at startup when present and rewritten on `shutdown`
+    #[serde(default)]
+    pub model_registry_cache_path: Option<String>
+  , /// Have Mistral send the version a floating name such as
+    /// `mistral-small-latest` pointed at on its first use, for
+    /// every later request (see `utils::version`)
+    #[serde(default)]
+    pub pin_model_versions: bool
+  , /// Screen each prompt with OpenAI moderation before sending it;
+    /// flagged prompts fail with `Error::ContentFlagged`. Needs an
+    /// OpenAI provider; streams are not screened.
+    #[serde(default)]
+    pub moderate_before_send: bool
+  , /// Receives a `ContextUsageWarning` for each reply whose prompt
+    /// filled `context_warning_threshold_percent` of the context
+    #[serde(skip)]
+    pub context_warning_tx: Option<
+      tokio::sync::mpsc::UnboundedSender<crate::ContextUsageWarning>
+    >
+  , #[serde(default = "default_context_warning_threshold_percent")]
+    pub context_warning_threshold_percent: f32
+  , /// Whitespace and opener cleanup of every successful reply,
+    /// off by default
+    #[serde(default)]
+    pub response_normalization: crate::utils::normalize::NormalizationRules
+  , /// Prompts above `ProviderConfig::max_request_size_bytes` or
+    /// `max_single_request_tokens` fail, are truncated, or are split
+    #[serde(default)]
+    pub large_prompt_strategy: crate::utils::prompt_splitter::LargePromptStrategy
+  , /// Only these providers may be used, when set
+    #[serde(default)]
+    pub allowed_providers: Option<Vec<crate::Provider>>
+  , /// Providers that may never be used, whatever `allowed_providers`
+    /// says
+    #[serde(default)]
+    pub blocked_providers: Vec<crate::Provider>
+  , /// A prompt for a provider the policy forbids fails with
+    /// `InvalidConfiguration` instead of going to the first allowed
+    /// fallback
+    #[serde(default)]
+    pub strict_provider_policy: bool
+  , /// Most a prompt may cost in USD on a fallback: models whose
+    /// input price (from `price_table_path` or the catalog) puts the
+    /// prompt (by `estimate_tokens`) above it are skipped during
+    /// failover
+    #[serde(default)]
+    pub failover_budget_constraint_usd: Option<f64>
+  , /// Longest a provider's `retry-after` is waited for, by retries
+    /// and `AllmBackend::wait_for_rate_limit_reset`; no cap when `None`
+    #[serde(default)]
+    pub max_retry_after_secs: Option<u64>
 }
 
 impl Default for AllmConfig
@@ -53,6 +483,244 @@ impl Default for AllmConfig
     {   AllmConfig
         {   providers: vec![]
           , failover: FailoverConfig::default()
+          , retry_on_refusal: false
+          , http_client_config: HttpClientConfig::default()
+          , warm_up_on_start: false
+          , strict_capability_validation: default_strict_capability_validation()
+          , context_overflow_upgrade: false
+          , stream_buffer_size: DEFAULT_STREAM_BUFFER_SIZE
+          , stream_dedup_replay_buffer_size: DEFAULT_STREAM_DEDUP_REPLAY_BUFFER_SIZE
+          , command_queue_capacity: DEFAULT_COMMAND_QUEUE_CAPACITY
+          , max_queued_prompts: None
+          , deduplication: DeduplicationConfig::default()
+          , supervisor: SupervisorConfig::default()
+          , checkpoint: CheckpointConfig::default()
+          , health_server_port: None
+          , model_overrides: vec![]
+          , price_table_path: None
+          , model_registry_cache_path: None
+          , pin_model_versions: false
+          , moderate_before_send: false
+          , context_warning_tx: None
+          , context_warning_threshold_percent
+              : DEFAULT_CONTEXT_WARNING_THRESHOLD_PERCENT
+          , response_normalization: Default::default()
+          , large_prompt_strategy: Default::default()
+          , allowed_providers: None
+          , blocked_providers: vec![]
+          , strict_provider_policy: false
+          , failover_budget_constraint_usd: None
+          , max_retry_after_secs: None
         }
     }
-}
\ No newline at end of file
+}
+
+impl AllmConfig
+{   /// Whether `allowed_providers` and `blocked_providers` let
+    /// prompts go to `provider`
+    pub fn allows_provider(&self, provider: &crate::Provider) -> bool
+    {   !self.blocked_providers.contains(provider)
+          && self.allowed_providers.as_ref()
+            .is_none_or(|allowed| allowed.contains(provider))
+    }
+
+    /// `InvalidConfiguration` naming the first of `providers` the
+    /// policy forbids
+    pub fn check_provider_policy<'a>(
+      &self
+    , providers: impl IntoIterator<Item = &'a crate::Provider>
+    ) -> Result<(), crate::error::Error>
+    {   match providers.into_iter().find(|p| !self.allows_provider(p))
+        {   Some(provider) => Err(crate::error::Error::InvalidConfiguration(
+              format!("{:?} is not allowed by the provider policy", provider)
+            ))
+          , None => Ok(())
+        }
+    }
+}
+
+/// Prefix of the variables read by `AllmConfig::from_env`
+pub const ENV_PREFIX: &str = "ALLM_";
+
+impl AllmConfig
+{   /// Defaults plus every provider found in the environment:
+    /// `ALLM_<NAME>_API_KEY` (`<NAME>` being the upper-cased config
+    /// name, e.g. `ALLM_OPENAI_API_KEY`) configures a provider, with
+    /// `ALLM_<NAME>_API_BASE` and `ALLM_<NAME>_ACCOUNT_ID` if set.
+    /// `ALLM_OLLAMA_URL` (e.g. `http://localhost:11434`) configures
+    /// the local provider. Keys of providers allm has no client for
+    /// are ignored.
+    pub fn from_env() -> AllmConfig
+    {   let var = |name: String| std::env::var(name).ok()
+          .filter(|value| !value.is_empty());
+        let mut providers = vec![];
+        for provider in crate::Provider::ALL
+        {   let prefix = format!(
+              "{}{}_", ENV_PREFIX, provider.name().to_uppercase()
+            );
+            let Some(api_key) = var(format!("{}API_KEY", prefix)) else
+            {   continue;
+            };
+            if !crate::providers::is_supported(&provider)
+            {   log::warn!(
+                  "Ignoring {}API_KEY: {:?} is not supported", prefix, provider
+                );
+                continue;
+            }
+            let config = ProviderConfig
+            {   name: provider.name().to_string()
+              , api_key: Some(api_key)
+              , api_base: var(format!("{}API_BASE", prefix))
+              , account_id: var(format!("{}ACCOUNT_ID", prefix))
+              , ..Default::default()
+            };
+            if provider == crate::Provider::CloudflareAi
+              && config.account_id.is_none()
+            {   log::warn!(
+                  "Ignoring {}API_KEY without {}ACCOUNT_ID", prefix, prefix
+                );
+                continue;
+            }
+            providers.push(config);
+        }
+        if let Some(url) = var(format!("{}OLLAMA_URL", ENV_PREFIX))
+        {   providers.retain(|p| p.provider() != Some(crate::Provider::Local));
+            providers.push(ProviderConfig
+            {   name: crate::Provider::Local.name().to_string()
+              , api_base: Some(format!("{}/v1", url.trim_end_matches('/')))
+              , api_key: var(format!("{}LOCAL_API_KEY", ENV_PREFIX))
+              , ..Default::default()
+            });
+        }
+        log::info!(
+          "Providers found in the environment: {:?}",
+          providers.iter().map(|p| p.name.as_str()).collect::<Vec<_>>()
+        );
+        AllmConfig
+        {   providers
+          , ..Default::default()
+        }
+    }
+}
+
+impl AllmConfig
+{   /// JSON Schema (draft 7) of a configuration file, for editors to
+    /// complete and check JSON or TOML configs against
+    ///
+    /// Worked out from the defaults, with one default provider as the
+    /// sample `providers` entry: each field gets the type of its
+    /// default value and is required when deserializing fails without
+    /// it. Fields that default to `null`, and enums (string defaults
+    /// that not every string can replace, such as
+    /// `large_prompt_strategy`, whose `split` variant is an object),
+    /// accept any value; deserializing checks them.
+    pub fn json_schema() -> serde_json::Value
+    {   let sample = AllmConfig
+        {   providers: vec![ProviderConfig::default()]
+          , ..Default::default()
+        };
+        let sample = serde_json::to_value(&sample)
+          .expect("the default config serializes");
+        let mut schema = schema_of(&sample, &sample, "");
+        schema["$schema"] = "http://json-schema.org/draft-07/schema#".into();
+        schema["title"] = "AllmConfig".into();
+        schema
+    }
+
+    /// Check `config_json` against `json_schema`, then that it
+    /// deserializes; every mismatched top-level field is reported
+    #[cfg(feature = "schema-validation")]
+    pub fn validate_with_schema(config_json: &str) -> Result<(), Vec<String>>
+    {   let config: serde_json::Value = serde_json::from_str(config_json)
+          .map_err(|e| vec![format!("not JSON: {}", e)])?;
+        let Some(fields) = config.as_object() else
+        {   return Err(vec!["$: expected object".to_string()]);
+        };
+        let schema = AllmConfig::json_schema();
+        let mut errors: Vec<String> = schema["required"].as_array()
+          .into_iter()
+          .flatten()
+          .filter_map(serde_json::Value::as_str)
+          .filter(|name| !fields.contains_key(*name))
+          .map(|name| format!("$: missing field {}", name))
+          .collect();
+        for (name, field) in fields
+        {   let Some(field_schema) = schema["properties"].get(name) else
+            {   continue;
+            };
+            if let Err(e) = crate::utils::json_schema::validate(field_schema, field)
+            {   errors.push(e.replacen('$', &format!("$.{}", name), 1));
+            }
+        }
+        if errors.is_empty()
+        {   if let Err(e) = serde_json::from_value::<AllmConfig>(config)
+            {   errors.push(e.to_string());
+            }
+        }
+        match errors.is_empty()
+        {   true => Ok(())
+          , false => Err(errors)
+        }
+    }
+}
+
+/// Schema of `value`, found at JSON pointer `at` in the serialized
+/// sample config `sample`
+fn schema_of(sample: &serde_json::Value, value: &serde_json::Value, at: &str)
+  -> serde_json::Value
+{   use serde_json::{json, Value};
+
+    match value
+    {   Value::Object(fields) => {
+          let properties: serde_json::Map<String, Value> = fields.iter()
+            .map(|(name, field)| {
+              let field_at = format!("{}/{}", at, name);
+              (name.clone(), schema_of(sample, field, &field_at))
+            })
+            .collect();
+          let required: Vec<&String> = fields.keys()
+            .filter(|name| !optional_in_sample(sample, at, name))
+            .collect();
+          json!({ "type": "object", "properties": properties, "required": required })
+        }
+      , Value::Array(items) => match items.first()
+        {   Some(item) => json!({
+              "type": "array",
+              "items": schema_of(sample, item, &format!("{}/0", at))
+            })
+          , None => json!({ "type": "array" })
+        }
+      , Value::String(_) if any_string_in_sample(sample, at) => {
+          json!({ "type": "string" })
+        }
+      , // An enum, whose other variants may not be strings
+        Value::String(_) => json!({})
+      , Value::Bool(_) => json!({ "type": "boolean" })
+      , Value::Number(number) if number.is_f64() => json!({ "type": "number" })
+      , Value::Number(number) if number.is_u64() => {
+          json!({ "type": "integer", "minimum": 0 })
+        }
+      , Value::Number(_) => json!({ "type": "integer" })
+      , Value::Null => json!({})
+    }
+}
+
+/// Whether `sample` still deserializes with the string at `at`
+/// replaced by an arbitrary one, which an enum's does not
+fn any_string_in_sample(sample: &serde_json::Value, at: &str) -> bool
+{   let mut probed = sample.clone();
+    if let Some(value) = probed.pointer_mut(at)
+    {   *value = "\u{1}allm schema probe".into();
+    }
+    serde_json::from_value::<AllmConfig>(probed).is_ok()
+}
+
+/// Whether `sample` still deserializes with field `name` of the
+/// object at `at` left out
+fn optional_in_sample(sample: &serde_json::Value, at: &str, name: &str) -> bool
+{   let mut trimmed = sample.clone();
+    if let Some(fields) = trimmed.pointer_mut(at).and_then(|o| o.as_object_mut())
+    {   fields.remove(name);
+    }
+    serde_json::from_value::<AllmConfig>(trimmed).is_ok()
+}
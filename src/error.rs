@@ -1,8 +1,14 @@
+use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::time::Duration;
 
 /// Custom error type for ALLM operations
 /// Implements Clone for sending through channels
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// Serialized with a `type` tag, e.g. `{"type":"timeout"}` or
+/// `{"type":"api_error","message":"..."}` (see `ErrorRepr`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "ErrorRepr", from = "ErrorRepr")]
 pub enum Error
 {   /// API key is missing for a provider
     MissingApiKey(String)
@@ -12,20 +18,40 @@ pub enum Error
     HttpError(String)
   , /// API returned an error response
     ApiError(String)
+  , /// Provider error response, its code mapped to `ProviderErrorCode`
+    ProviderApiError
+    {   provider: crate::Provider
+      , code: ProviderErrorCode
+      , message: String
+      , /// From the response's `retry-after` header
+        retry_after: Option<Duration>
+    }
   , /// Failed to parse API response
     ParseError(String)
   , /// No choices in API response
     NoChoicesInResponse
   , /// Prompt not found in queue
     PromptNotFound(usize)
+  , /// No conversation session with this id
+    SessionNotFound(crate::utils::uuid::Uuid)
+  , /// No configured provider has this model
+    ModelNotFound(String)
   , /// Rate limit exceeded
     RateLimitExceeded
   , /// Context window exceeded
     ContextWindowExceeded
+  , /// Prompt above its provider's `max_request_size_bytes` or
+    /// `max_single_request_tokens`
+    RequestTooLarge(String)
   , /// Invalid configuration
     InvalidConfiguration(String)
+  , /// Prompt flagged by moderation, with the flagged categories
+    ContentFlagged(Vec<String>)
   , /// Timeout error
     Timeout
+  , /// A prompt's `total_deadline` ran out before its next retry or
+    /// failover; holds the last attempt's error
+    DeadlineExceeded(Box<Error>)
   , /// Generic error
     Other(String)
 }
@@ -49,6 +75,9 @@ impl fmt::Display for Error
           , Error::ApiError(msg) => {
               write!(f, "API error: {}", msg)
             }
+          , Error::ProviderApiError { provider, code, message, .. } => {
+              write!(f, "{:?} API error ({}): {}", provider, code, message)
+            }
           , Error::ParseError(msg) => {
               write!(f, "Parse error: {}", msg)
             }
@@ -58,6 +87,12 @@ impl fmt::Display for Error
           , Error::PromptNotFound(id) => {
               write!(f, "Prompt not found in queue: {}", id)
             }
+          , Error::SessionNotFound(id) => {
+              write!(f, "Session not found: {}", id)
+            }
+          , Error::ModelNotFound(model) => {
+              write!(f, "Model not found: {}", model)
+            }
           , Error::RateLimitExceeded => {
               write!(f, "API rate limit exceeded")
             }
@@ -66,12 +101,21 @@ impl fmt::Display for Error
                 "Request exceeds model context window"
               )
             }
+          , Error::RequestTooLarge(msg) => {
+              write!(f, "Request too large: {}", msg)
+            }
           , Error::InvalidConfiguration(msg) => {
               write!(f, "Invalid configuration: {}", msg)
             }
+          , Error::ContentFlagged(categories) => {
+              write!(f, "Content flagged: {}", categories.join(", "))
+            }
           , Error::Timeout => {
               write!(f, "Request timed out")
             }
+          , Error::DeadlineExceeded(last) => {
+              write!(f, "Deadline exceeded, last error: {}", last)
+            }
           , Error::Other(msg) => {
               write!(f, "Error: {}", msg)
             }
@@ -81,6 +125,279 @@ impl fmt::Display for Error
 
 impl std::error::Error for Error {}
 
+impl Error
+{   /// Kind of failover trigger this error is, if any
+    pub fn failover_trigger(&self) -> Option<crate::failover::FailoverTrigger>
+    {   use crate::failover::FailoverTrigger;
+        match self
+        {   Error::ProviderApiError { code, .. } => match code
+            {   ProviderErrorCode::RateLimitRequests
+                  | ProviderErrorCode::RateLimitTokens => {
+                  Some(FailoverTrigger::RateLimit)
+                }
+              , ProviderErrorCode::Overloaded => Some(FailoverTrigger::ServerError)
+              , ProviderErrorCode::ContextLengthExceeded => {
+                  Some(FailoverTrigger::ContextWindow)
+                }
+              , ProviderErrorCode::InvalidApiKey => {
+                  Some(FailoverTrigger::InvalidApiKey)
+                }
+              , ProviderErrorCode::ModelNotFound => {
+                  Some(FailoverTrigger::ModelNotFound)
+                }
+              , ProviderErrorCode::ContentFiltered => None
+              , ProviderErrorCode::Unknown(status) => status.parse::<u16>().ok()
+                  .filter(|status| (500..600).contains(status))
+                  .map(|_| FailoverTrigger::ServerError)
+            }
+          , Error::RateLimitExceeded => Some(FailoverTrigger::RateLimit)
+          , Error::Timeout => Some(FailoverTrigger::Timeout)
+          , Error::ContextWindowExceeded => Some(FailoverTrigger::ContextWindow)
+          , _ => None
+        }
+    }
+
+    /// Whether this error should move a prompt to its next fallback
+    /// under `failover_on` (see `FailoverConfig::failover_on`)
+    pub fn is_failover_eligible(
+      &self
+    , failover_on: &[crate::failover::FailoverTrigger]
+    ) -> bool
+    {   self.failover_trigger()
+          .is_some_and(|trigger| failover_on.contains(&trigger))
+    }
+}
+
+/// Wire form of `Error`: every variant an object tagged by `type`
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ErrorRepr
+{   MissingApiKey { provider: String }
+  , ProviderNotImplemented { provider: String }
+  , HttpError { message: String }
+  , ApiError { message: String }
+  , ProviderApiError
+    {   provider: crate::Provider
+      , code: ProviderErrorCode
+      , message: String
+      , #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>
+    }
+  , ParseError { message: String }
+  , NoChoicesInResponse
+  , PromptNotFound { id: usize }
+  , SessionNotFound { id: crate::utils::uuid::Uuid }
+  , ModelNotFound { model: String }
+  , RateLimitExceeded
+  , ContextWindowExceeded
+  , RequestTooLarge { message: String }
+  , InvalidConfiguration { message: String }
+  , ContentFlagged { categories: Vec<String> }
+  , Timeout
+  , DeadlineExceeded { last_error: Box<Error> }
+  , Other { message: String }
+}
+
+impl From<Error> for ErrorRepr
+{   fn from(error: Error) -> Self
+    {   match error
+        {   Error::MissingApiKey(provider) => {
+              ErrorRepr::MissingApiKey { provider }
+            }
+          , Error::ProviderNotImplemented(provider) => {
+              ErrorRepr::ProviderNotImplemented { provider }
+            }
+          , Error::HttpError(message) => ErrorRepr::HttpError { message }
+          , Error::ApiError(message) => ErrorRepr::ApiError { message }
+          , Error::ProviderApiError { provider, code, message, retry_after } => {
+              ErrorRepr::ProviderApiError
+              {   provider
+                , code
+                , message
+                , retry_after_ms: retry_after.map(|d| d.as_millis() as u64)
+              }
+            }
+          , Error::ParseError(message) => ErrorRepr::ParseError { message }
+          , Error::NoChoicesInResponse => ErrorRepr::NoChoicesInResponse
+          , Error::PromptNotFound(id) => ErrorRepr::PromptNotFound { id }
+          , Error::SessionNotFound(id) => ErrorRepr::SessionNotFound { id }
+          , Error::ModelNotFound(model) => ErrorRepr::ModelNotFound { model }
+          , Error::RateLimitExceeded => ErrorRepr::RateLimitExceeded
+          , Error::ContextWindowExceeded => ErrorRepr::ContextWindowExceeded
+          , Error::RequestTooLarge(message) => {
+              ErrorRepr::RequestTooLarge { message }
+            }
+          , Error::InvalidConfiguration(message) => {
+              ErrorRepr::InvalidConfiguration { message }
+            }
+          , Error::ContentFlagged(categories) => {
+              ErrorRepr::ContentFlagged { categories }
+            }
+          , Error::Timeout => ErrorRepr::Timeout
+          , Error::DeadlineExceeded(last_error) => {
+              ErrorRepr::DeadlineExceeded { last_error }
+            }
+          , Error::Other(message) => ErrorRepr::Other { message }
+        }
+    }
+}
+
+impl From<ErrorRepr> for Error
+{   fn from(repr: ErrorRepr) -> Self
+    {   match repr
+        {   ErrorRepr::MissingApiKey { provider } => {
+              Error::MissingApiKey(provider)
+            }
+          , ErrorRepr::ProviderNotImplemented { provider } => {
+              Error::ProviderNotImplemented(provider)
+            }
+          , ErrorRepr::HttpError { message } => Error::HttpError(message)
+          , ErrorRepr::ApiError { message } => Error::ApiError(message)
+          , ErrorRepr::ProviderApiError
+            {   provider, code, message, retry_after_ms
+            } => Error::ProviderApiError
+            {   provider
+              , code
+              , message
+              , retry_after: retry_after_ms.map(Duration::from_millis)
+            }
+          , ErrorRepr::ParseError { message } => Error::ParseError(message)
+          , ErrorRepr::NoChoicesInResponse => Error::NoChoicesInResponse
+          , ErrorRepr::PromptNotFound { id } => Error::PromptNotFound(id)
+          , ErrorRepr::SessionNotFound { id } => Error::SessionNotFound(id)
+          , ErrorRepr::ModelNotFound { model } => Error::ModelNotFound(model)
+          , ErrorRepr::RateLimitExceeded => Error::RateLimitExceeded
+          , ErrorRepr::ContextWindowExceeded => Error::ContextWindowExceeded
+          , ErrorRepr::RequestTooLarge { message } => {
+              Error::RequestTooLarge(message)
+            }
+          , ErrorRepr::InvalidConfiguration { message } => {
+              Error::InvalidConfiguration(message)
+            }
+          , ErrorRepr::ContentFlagged { categories } => {
+              Error::ContentFlagged(categories)
+            }
+          , ErrorRepr::Timeout => Error::Timeout
+          , ErrorRepr::DeadlineExceeded { last_error } => {
+              Error::DeadlineExceeded(last_error)
+            }
+          , ErrorRepr::Other { message } => Error::Other(message)
+        }
+    }
+}
+
+/// Error codes of every provider mapped onto one set
+///
+/// Serialized as its `Display` name (`"rate_limit_requests"`, or the
+/// provider's own code for `Unknown`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", from = "String")]
+pub enum ProviderErrorCode
+{   /// Too many requests in the provider's window
+    RateLimitRequests
+  , /// Too many tokens in the provider's window
+    RateLimitTokens
+  , /// Provider temporarily out of capacity
+    Overloaded
+  , ContextLengthExceeded
+  , InvalidApiKey
+  , ModelNotFound
+  , /// Prompt or reply blocked by the provider's moderation
+    ContentFiltered
+  , /// Any other code, as sent (the HTTP status if none was)
+    Unknown(String)
+}
+
+impl ProviderErrorCode
+{   /// Map an error body's `code` and `type` fields, falling back on
+    /// the HTTP status. Covers OpenAI (`rate_limit_exceeded` with
+    /// type `tokens`), Anthropic (`overloaded_error`), Mistral and
+    /// the OpenAI-compatible providers.
+    pub fn classify(status: u16, code: Option<&str>, kind: Option<&str>)
+      -> Self
+    {   let names: Vec<String> = [code, kind].into_iter()
+          .flatten()
+          .map(|n| n.to_ascii_lowercase())
+          .collect();
+        let named = |markers: &[&str]| names.iter()
+          .any(|n| markers.iter().any(|m| n.contains(m)));
+        if named(&["context_length"])
+        {   ProviderErrorCode::ContextLengthExceeded
+        } else if named(&["rate_limit", "too_many_requests"]) || status == 429
+        {   if named(&["token"])
+            {   ProviderErrorCode::RateLimitTokens
+            } else
+            {   ProviderErrorCode::RateLimitRequests
+            }
+        } else if named(&["overloaded", "capacity"])
+          || status == 503 || status == 529
+        {   ProviderErrorCode::Overloaded
+        } else if named(&["api_key", "authentication", "permission"])
+          || status == 401 || status == 403
+        {   ProviderErrorCode::InvalidApiKey
+        } else if named(&["model_not_found", "not_found"]) || status == 404
+        {   ProviderErrorCode::ModelNotFound
+        } else if named(&["content_filter", "content_policy", "safety"])
+        {   ProviderErrorCode::ContentFiltered
+        } else
+        {   ProviderErrorCode::Unknown(
+              code.or(kind)
+                .map(|c| c.to_string())
+                .unwrap_or_else(|| status.to_string())
+            )
+        }
+    }
+
+    /// The same request may succeed on the same model after a pause
+    pub fn is_transient(&self) -> bool
+    {   matches!(
+          self,
+          ProviderErrorCode::RateLimitRequests
+            | ProviderErrorCode::RateLimitTokens
+            | ProviderErrorCode::Overloaded
+        )
+    }
+}
+
+impl fmt::Display for ProviderErrorCode
+{   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {   let name = match self
+        {   ProviderErrorCode::RateLimitRequests => "rate_limit_requests"
+          , ProviderErrorCode::RateLimitTokens => "rate_limit_tokens"
+          , ProviderErrorCode::Overloaded => "overloaded"
+          , ProviderErrorCode::ContextLengthExceeded => "context_length_exceeded"
+          , ProviderErrorCode::InvalidApiKey => "invalid_api_key"
+          , ProviderErrorCode::ModelNotFound => "model_not_found"
+          , ProviderErrorCode::ContentFiltered => "content_filtered"
+          , ProviderErrorCode::Unknown(code) => code.as_str()
+        };
+        f.write_str(name)
+    }
+}
+
+impl From<ProviderErrorCode> for String
+{   fn from(code: ProviderErrorCode) -> Self
+    {   code.to_string()
+    }
+}
+
+impl From<String> for ProviderErrorCode
+{   fn from(name: String) -> Self
+    {   match name.as_str()
+        {   "rate_limit_requests" => ProviderErrorCode::RateLimitRequests
+          , "rate_limit_tokens" => ProviderErrorCode::RateLimitTokens
+          , "overloaded" => ProviderErrorCode::Overloaded
+          , "context_length_exceeded" => {
+              ProviderErrorCode::ContextLengthExceeded
+            }
+          , "invalid_api_key" => ProviderErrorCode::InvalidApiKey
+          , "model_not_found" => ProviderErrorCode::ModelNotFound
+          , "content_filtered" => ProviderErrorCode::ContentFiltered
+          , _ => ProviderErrorCode::Unknown(name)
+        }
+    }
+}
+
 impl From<String> for Error
 {   fn from(s: String) -> Self
     {   Error::Other(s)
@@ -91,4 +408,61 @@ impl From<&str> for Error
 {   fn from(s: &str) -> Self
     {   Error::Other(s.to_string())
     }
-}
\ No newline at end of file
+}
+
+impl From<reqwest::Error> for Error
+{   /// Status codes with a variant of their own map to it; the rest
+    /// of the errors become `HttpError`
+    fn from(e: reqwest::Error) -> Self
+    {   match e.status().map(|status| status.as_u16())
+        {   Some(401) => {
+              let host = e.url()
+                .and_then(|url| url.host_str())
+                .unwrap_or("unknown host");
+              Error::MissingApiKey(host.to_string())
+            }
+          , Some(429) => Error::RateLimitExceeded
+          , Some(408) | Some(504) => Error::Timeout
+          , _ if e.is_timeout() => Error::Timeout
+          , _ if e.is_decode() => Error::ParseError(e.to_string())
+          , _ => Error::HttpError(e.to_string())
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error
+{   fn from(e: serde_json::Error) -> Self
+    {   Error::ParseError(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error
+{   fn from(e: std::io::Error) -> Self
+    {   Error::Other(e.to_string())
+    }
+}
+
+impl From<Error> for std::io::Error
+{   /// `TimedOut` for timeouts, `Other` otherwise
+    fn from(e: Error) -> Self
+    {   let kind = match e
+        {   Error::Timeout | Error::DeadlineExceeded(_) => {
+              std::io::ErrorKind::TimedOut
+            }
+          , _ => std::io::ErrorKind::Other
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+impl From<tokio::time::error::Elapsed> for Error
+{   fn from(_: tokio::time::error::Elapsed) -> Self
+    {   Error::Timeout
+    }
+}
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for Error
+{   fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self
+    {   Error::Other("channel closed".to_string())
+    }
+}
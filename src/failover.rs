@@ -1,7 +1,127 @@
 //! Failover and retry logic for provider fallbacks
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use log::debug;
+use serde::{Deserialize, Serialize};
+
+/// Kind of error that moves a prompt to its next fallback model;
+/// see `FailoverConfig::failover_on` and `Error::is_failover_eligible`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailoverTrigger
+{   /// Request or token rate limit
+    RateLimit
+  , Timeout
+  , /// Overloaded provider or any other 5xx response
+    ServerError
+  , /// Prompt too long for the model, once `context_overflow_upgrade`
+    /// found no larger model of its family
+    ContextWindow
+  , /// Key rejected by the provider
+    InvalidApiKey
+  , /// Model unknown to the provider
+    ModelNotFound
+}
+
+impl FailoverTrigger
+{   /// Triggers of `FailoverConfig::default`: rate limits, server
+    /// errors, rejected keys and unknown models
+    pub fn defaults() -> Vec<FailoverTrigger>
+    {   vec!
+        [   FailoverTrigger::RateLimit
+          , FailoverTrigger::ServerError
+          , FailoverTrigger::InvalidApiKey
+          , FailoverTrigger::ModelNotFound
+        ]
+    }
+}
+
+/// Which remaining fallback a failed prompt moves to; see
+/// `FailoverConfig::strategy`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailoverStrategy
+{   /// The next fallback preference
+    #[default]
+    Ordered
+  , /// The cheapest one, see `CostAwareFailoverStrategy`
+    CostMinimizing
+}
+
+/// One model a prompt went to, see `PromptResponse::failover_chain`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FailoverHop
+{   pub provider: crate::Provider
+  , pub model: String
+  , /// Why the prompt left this model; `None` for the one that
+    /// answered
+    pub error: Option<crate::error::Error>
+  , /// From the first request to this model until it answered or
+    /// was given up, retries included
+    pub latency: Duration
+}
+
+/// Fallback prices from the price table, else the model catalog.
+/// Entries are ranked by their price per million input tokens; those
+/// with no input price rank after every priced one, in their order.
+pub struct CostAwareFailoverStrategy<'a>
+{   pub catalog: &'a [crate::ModelInfo]
+  , /// Prices overriding the catalog's, see
+    /// `AllmConfig::price_table_path`
+    pub prices: &'a crate::utils::cost::PriceTable
+  , /// Most a prompt may cost in USD, see
+    /// `AllmConfig::failover_budget_constraint_usd`
+    pub budget_usd: Option<f64>
+}
+
+impl CostAwareFailoverStrategy<'_>
+{   /// USD per million input tokens of `entry`, when known
+    fn input_price(&self, entry: &ProviderEntry) -> Option<f64>
+    {   let price = match self.catalog.iter()
+          .find(|m| m.provider == entry.provider && m.name == entry.model)
+        {   Some(info) => crate::utils::cost::price_for(self.prices, info)
+              .map(|(input, _)| input)
+              .or(info.cost_per_million_input_tokens)
+          , None => self.prices
+              .get(&(entry.provider.clone(), entry.model.clone()))
+              .map(|(input, _)| *input)
+        };
+        price.map(f64::from)
+    }
+
+    /// False when `input_tokens` sent to `entry` would cost more than
+    /// the budget; entries without a price always fit
+    pub fn within_budget(&self, entry: &ProviderEntry, input_tokens: usize)
+      -> bool
+    {   match (self.budget_usd, self.input_price(entry))
+        {   (Some(budget), Some(price)) => {
+              let cost = price * input_tokens as f64 / 1_000_000.0;
+              if cost > budget
+              {   debug!(
+                    "{:?}/{} over the failover budget: ${:.6}",
+                    entry.provider, entry.model, cost
+                  );
+              }
+              cost <= budget
+            }
+          , _ => true
+        }
+    }
+
+    /// Cheapest of `candidates`; the first of equally priced ones
+    pub fn cheapest<'e>(
+      &self
+    , candidates: impl IntoIterator<Item = &'e ProviderEntry>
+    ) -> Option<&'e ProviderEntry>
+    {   candidates.into_iter()
+          .enumerate()
+          .min_by(|(a_index, a), (b_index, b)| {
+            let rank = |entry| self.input_price(entry).unwrap_or(f64::INFINITY);
+            rank(a).total_cmp(&rank(b)).then(a_index.cmp(b_index))
+          })
+          .map(|(_, entry)| entry)
+    }
+}
 
 /// Retry policy for failed requests
 #[derive(Debug, Clone)]
@@ -48,10 +168,65 @@ impl Default for RetryPolicy
     }
 }
 
+/// Family of a model name: its first `-`-separated part, so
+/// `mistral-small-latest` and `mistral-large-latest` are both
+/// `mistral`
+pub fn model_family(model: &str) -> &str
+{   model.split('-').next().unwrap_or(model)
+}
+
+/// Catalog model of the same provider and family as `model` with
+/// the smallest context window larger than its own, skipping
+/// `tried`. `None` when `model` is not in the catalog.
+pub fn larger_context_model<'a>(
+  catalog: &'a [crate::ModelInfo]
+, provider: &crate::Provider
+, model: &str
+, tried: &[(crate::Provider, String)]
+) -> Option<&'a crate::ModelInfo>
+{   let current = catalog.iter()
+      .find(|m| m.provider == *provider && m.name == model)?;
+    catalog.iter()
+      .filter(|m| m.provider == *provider
+        && model_family(&m.name) == model_family(model)
+        && m.max_context_tokens > current.max_context_tokens
+        && m.is_available
+        && !tried.iter().any(|(p, n)| p == provider && *n == m.name))
+      .min_by_key(|m| m.max_context_tokens)
+}
+
+/// How often the backend calls `FailoverSequence::smart_reset`
+pub const SMART_RESET_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Cool-off of a rate-limited fallback whose provider sent no
+/// `retry-after`
+pub const DEFAULT_RATE_LIMIT_COOL_OFF: Duration = Duration::from_secs(60);
+
+/// One model of a `FailoverSequence`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProviderEntry
+{   pub provider: crate::Provider
+  , pub model: String
+  , /// Set by `mark_rate_limited`; the entry is skipped until a
+    /// `smart_reset` after this instant clears it
+    pub rate_limited_until: Option<Instant>
+}
+
+impl ProviderEntry
+{   pub fn new(provider: crate::Provider, model: String) -> Self
+    {   ProviderEntry { provider, model, rate_limited_until: None }
+    }
+
+    /// Still cooling off from a rate limit
+    pub fn is_rate_limited(&self) -> bool
+    {   self.rate_limited_until.is_some_and(|until| until > Instant::now())
+    }
+}
+
 /// Failover provider sequence
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct FailoverSequence
-{   pub providers: Vec<(crate::Provider, String)>
+{   pub providers: Vec<ProviderEntry>
   , pub current_index: usize
 }
 
@@ -65,26 +240,47 @@ impl FailoverSequence
           providers.len()
         );
         FailoverSequence
-        {   providers
+        {   providers: providers.into_iter()
+              .map(|(provider, model)| ProviderEntry::new(provider, model))
+              .collect()
           , current_index: 0
         }
     }
 
-    /// Get the current provider
-    pub fn current(&self) 
-      -> Option<&(crate::Provider, String)>
-    {   self.providers.get(self.current_index)
+    /// Index of the first entry from `current_index` on that is not
+    /// rate-limited
+    fn current_position(&self) -> Option<usize>
+    {   self.providers.iter()
+          .enumerate()
+          .skip(self.current_index)
+          .find(|(_, entry)| !entry.is_rate_limited())
+          .map(|(index, _)| index)
     }
 
-    /// Move to the next provider
-    pub fn next(&mut self) -> Option<&(crate::Provider, String)>
-    {   self.current_index += 1;
+    /// Get the current provider, skipping rate-limited ones
+    pub fn current(&self) -> Option<&ProviderEntry>
+    {   self.current_position().map(|index| &self.providers[index])
+    }
+
+    /// Move to the next provider that is not rate-limited
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<&ProviderEntry>
+    {   self.current_index = self.current_position()
+          .map_or(self.providers.len(), |index| index + 1);
         self.current()
     }
 
-    /// Check if we have more providers to try
+    /// Check if we have more providers to try that are not
+    /// rate-limited
     pub fn has_next(&self) -> bool
-    {   self.current_index + 1 < self.providers.len()
+    {   self.current_position().is_some_and(|index| {
+          self.providers[index + 1..].iter().any(|e| !e.is_rate_limited())
+        })
+    }
+
+    /// Entries that are not rate-limited, in order
+    pub fn available(&self) -> impl Iterator<Item = &ProviderEntry>
+    {   self.providers.iter().filter(|entry| !entry.is_rate_limited())
     }
 
     /// Reset to the first provider
@@ -92,4 +288,34 @@ impl FailoverSequence
     {   debug!("Resetting failover sequence");
         self.current_index = 0;
     }
-}
\ No newline at end of file
+
+    /// Clear the cool-offs that have passed at `now`, then reset to
+    /// the first entry that is not rate-limited. Entries are back in
+    /// the sequence once their cool-off passes either way.
+    pub fn smart_reset(&mut self, now: Instant)
+    {   for entry in &mut self.providers
+        {   if entry.rate_limited_until.is_some_and(|until| until <= now)
+            {   debug!(
+                  "{:?}/{} cooled off, back in the failover sequence",
+                  entry.provider, entry.model
+                );
+                entry.rate_limited_until = None;
+            }
+        }
+        self.reset();
+        self.current_index = self.current_position()
+          .unwrap_or(self.providers.len());
+    }
+
+    /// Skip every entry of `provider` for `duration` from now
+    pub fn mark_rate_limited(&mut self, provider: &crate::Provider, duration: Duration)
+    {   let until = Instant::now() + duration;
+        for entry in self.providers.iter_mut().filter(|e| e.provider == *provider)
+        {   debug!(
+              "{:?}/{} rate-limited for {:?}",
+              entry.provider, entry.model, duration
+            );
+            entry.rate_limited_until = Some(until);
+        }
+    }
+}
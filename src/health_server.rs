@@ -0,0 +1,120 @@
+// allm/src/health_server.rs
+
+//! HTTP endpoints for monitoring allm running as a service
+//! (`health-server` feature)
+//!
+//! - `GET /health`: 200 `{"status":"ok"}`, 503 once shutting down
+//! - `GET /metrics`: `MetricsSnapshot` as Prometheus text
+//! - `GET /providers`: JSON array of `ProviderStatus`
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{debug, error, info};
+
+use crate::client::AllmBackendRef;
+
+/// Health server task; stopped when dropped
+pub struct AllmHealthServer
+{   addr: SocketAddr
+  , task: tokio::task::JoinHandle<()>
+}
+
+impl AllmHealthServer
+{   /// Serve on `0.0.0.0:port` (`0` picks a free port, see `addr`)
+    pub fn spawn(backend: AllmBackendRef, port: u16)
+      -> Result<Self, crate::error::Error>
+    {   let bind_addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let builder = Server::try_bind(&bind_addr).map_err(|e| {
+          error!("Health server cannot bind {}: {}", bind_addr, e);
+          crate::error::Error::InvalidConfiguration(
+            format!("health server port {}: {}", port, e)
+          )
+        })?;
+        let make_service = make_service_fn(move |_conn| {
+          let backend = backend.clone();
+          async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+              handle(backend.clone(), request)
+            }))
+          }
+        });
+        let server = builder.serve(make_service);
+        let addr = server.local_addr();
+        info!("Health server listening on {}", addr);
+        let task = tokio::spawn(async move {
+          if let Err(e) = server.await
+          {   error!("Health server failed: {}", e);
+          }
+        });
+        Ok(AllmHealthServer { addr, task })
+    }
+
+    /// Address the server is bound to
+    pub fn addr(&self) -> SocketAddr
+    {   self.addr
+    }
+}
+
+impl Drop for AllmHealthServer
+{   fn drop(&mut self)
+    {   debug!("Stopping health server on {}", self.addr);
+        self.task.abort();
+    }
+}
+
+async fn handle(backend: AllmBackendRef, request: Request<Body>)
+  -> Result<Response<Body>, Infallible>
+{   debug!("Health server {} {}", request.method(), request.uri().path());
+    if request.method() != Method::GET
+    {   return Ok(respond(StatusCode::METHOD_NOT_ALLOWED, "text/plain", ""));
+    }
+    let response = match request.uri().path()
+    {   "/health" if backend.is_alive() => respond(
+          StatusCode::OK, "application/json", r#"{"status":"ok"}"#
+        )
+      , "/health" => respond(
+          StatusCode::SERVICE_UNAVAILABLE,
+          "application/json",
+          r#"{"status":"shutting_down"}"#
+        )
+      , "/metrics" => match backend.metrics().await
+        {   Ok(metrics) => respond(
+              StatusCode::OK,
+              "text/plain; version=0.0.4",
+              metrics.to_prometheus_text()
+            )
+          , Err(e) => unavailable(e)
+        }
+      , "/providers" => match backend.metrics().await
+        {   Ok(metrics) => respond(
+              StatusCode::OK,
+              "application/json",
+              serde_json::to_string(&metrics.providers)
+                .unwrap_or_else(|_| "[]".to_string())
+            )
+          , Err(e) => unavailable(e)
+        }
+      , _ => respond(StatusCode::NOT_FOUND, "text/plain", "not found")
+    };
+    Ok(response)
+}
+
+fn unavailable(e: crate::error::Error) -> Response<Body>
+{   respond(StatusCode::SERVICE_UNAVAILABLE, "text/plain", e.to_string())
+}
+
+fn respond(
+  status: StatusCode
+, content_type: &str
+, body: impl Into<Body>
+) -> Response<Body>
+{   let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    if let Ok(value) = content_type.parse()
+    {   response.headers_mut().insert(hyper::header::CONTENT_TYPE, value);
+    }
+    response
+}
@@ -1,13 +1,27 @@
+// The house brace style puts `{` on the line after `else`.
+#![allow(clippy::suspicious_else_formatting)]
+
 pub mod error;
+pub mod auth;
 pub mod config;
 pub mod providers;
 pub mod request;
 pub mod failover;
 pub mod client;
+pub mod utils;
+pub mod middleware;
+pub mod session;
+pub mod metrics;
+pub mod supervisor;
+pub mod capabilities;
+#[cfg(feature = "health-server")]
+pub mod health_server;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 use serde::{Deserialize, Serialize};
 
 // Re-export for convenience
-pub use client::AllmBackend;
+pub use client::{AllmBackend, AllmBackendRef};
 pub use error::Error;
 
 
@@ -42,10 +56,24 @@ allm/
 
 */
 
-/// ALLM API INTERFACE:
+// ALLM API INTERFACE:
 
 // ===== SendPrompt =====
 
+/// Correlation id of a request (`PromptRequest::request_id`)
+pub type RequestId = String;
+
+/// Whether a prompt's reply is checkpointed (see
+/// `config::CheckpointConfig`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CheckpointMode
+{   #[default]
+    Disabled
+  , /// Stream the reply and keep what arrived, so that a request
+    /// timing out is resumed from there
+    Enabled
+}
+
 pub type SendPromptReply = Result<String, crate::error::Error>;
 pub type SendPromptReplySender 
   = tokio::sync::mpsc::UnboundedSender<SendPromptReply>;
@@ -53,12 +81,203 @@ pub type SendPromptReplySender
 pub struct SendPromptArgs 
 {   pub prompt: String
   , pub model: String
+  , /// Target provider; `None` routes to the current model's provider
+    pub provider: Option<crate::Provider>
+  , pub system_message: Option<String>
+  , /// Provider default when `None`
+    pub max_tokens: Option<usize>
+  , /// Provider default when `None`
+    pub temperature: Option<f32>
+  , /// See `PromptRequest::extra_params`
+    pub extra_params: Option<serde_json::Map<String, serde_json::Value>>
+  , /// Generated when `None` and the prompt is checkpointed
+    pub request_id: Option<RequestId>
+  , /// See `PromptRequest::stop_sequences`
+    pub stop_sequences: Option<Vec<String>>
+  , /// See `PromptRequest::total_deadline`
+    pub total_deadline: Option<std::time::Duration>
+  , /// See `PromptRequest::json_schema`
+    pub json_schema: Option<crate::request::JsonSchemaRetry>
+  , /// See `PromptRequest::prefill`
+    pub prefill: Option<String>
+  , /// With no `provider`, route to the configured provider whose
+    /// catalog has `model`; see `AllmBackend::ask_by_model`
+    pub auto_detect_provider: bool
+  , pub checkpoint: CheckpointMode
+  , /// Sent the prompt's id once accepted, for `GetPromptStatus`
+    pub accepted: Option<PromptIdSender>
+  , /// Place among prompts waiting for the provider's rate limit
+    pub priority: crate::request::Priority
+  , /// See `PromptRequest::no_failover`
+    pub no_failover: bool
+  , /// See `PromptRequest::few_shot`
+    pub few_shot: Option<crate::request::FewShot>
+  , /// See `AllmBackend::send_prompt_cancellable`
+    pub cancel: Option<tokio_util::sync::CancellationToken>
+  , /// Headers for this prompt, over the provider's
+    /// `ProviderConfig::extra_headers` of the same name
+    pub extra_headers: Option<std::collections::HashMap<String, String>>
   , pub reply: SendPromptReplySender
 }
 
+impl SendPromptArgs
+{   /// The same prompt settings for `prompt` answered on `reply`,
+    /// e.g. for a chunk of a split prompt; without request id or
+    /// acceptance notice
+    pub fn with_prompt(&self, prompt: String, reply: SendPromptReplySender)
+      -> Self
+    {   SendPromptArgs
+        {   prompt
+          , model: self.model.clone()
+          , provider: self.provider.clone()
+          , system_message: self.system_message.clone()
+          , max_tokens: self.max_tokens
+          , temperature: self.temperature
+          , extra_params: self.extra_params.clone()
+          , request_id: None
+          , stop_sequences: self.stop_sequences.clone()
+          , total_deadline: self.total_deadline
+          , json_schema: self.json_schema.clone()
+          , prefill: self.prefill.clone()
+          , auto_detect_provider: self.auto_detect_provider
+          , checkpoint: self.checkpoint
+          , accepted: None
+          , priority: self.priority
+          , no_failover: self.no_failover
+          , few_shot: self.few_shot.clone()
+          , cancel: self.cancel.clone()
+          , extra_headers: self.extra_headers.clone()
+          , reply
+        }
+    }
+}
+
+/// Receives the id of an accepted prompt; an identical prompt it
+/// joined gives the id of that one
+pub type PromptIdSender = tokio::sync::mpsc::UnboundedSender<usize>;
+
+// ===== SendRequest =====
+
+pub type SendRequestReply
+  = Result<crate::request::PromptResponse, crate::error::Error>;
+pub type SendRequestReplySender
+  = tokio::sync::mpsc::UnboundedSender<SendRequestReply>;
+
+/// A prompt whose reply is a `PromptResponse` rather than its text
+pub struct SendRequestArgs
+{   pub request: crate::request::PromptRequest
+  , pub reply: SendRequestReplySender
+}
+
+// ===== SendStream =====
+
+/// One piece of a streamed completion
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamChunk
+{   /// Text added by this chunk
+    pub delta: String
+  , pub finish_reason: Option<String>
+  , /// Set on the last chunk of the stream only
+    pub end: Option<StreamEnd>
+}
+
+impl StreamChunk
+{   /// Empty final chunk carrying the last known `finish_reason`
+    pub fn last(finish_reason: Option<String>, end: StreamEnd) -> Self
+    {   StreamChunk
+        {   delta: String::new()
+          , finish_reason
+          , end: Some(end)
+        }
+    }
+}
+
+/// How a stream ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEnd
+{   /// The provider sent `data: [DONE]`
+    Done
+  , /// The connection closed without `[DONE]`; the response may be
+    /// truncated
+    Eof
+}
+
+pub type StreamReply = Result<StreamChunk, crate::error::Error>;
+/// Bounded (`AllmConfig::stream_buffer_size`) so a slow consumer
+/// holds back the provider instead of buffering without limit
+pub type StreamReplySender
+  = tokio::sync::mpsc::Sender<StreamReply>;
+
+/// A streamed completion, as returned by
+/// `AllmBackend::send_prompt_stream`; use it with `StreamExt` or
+/// `recv` it chunk by chunk. Ends after the chunk with `end` set or
+/// after an `Err`.
+#[derive(Debug)]
+pub struct CompletionStream
+{   rx: tokio::sync::mpsc::Receiver<StreamReply>
+}
+
+impl CompletionStream
+{   pub fn new(rx: tokio::sync::mpsc::Receiver<StreamReply>) -> Self
+    {   CompletionStream { rx }
+    }
+
+    /// Next chunk, or `None` once the stream has ended
+    pub async fn recv(&mut self) -> Option<StreamReply>
+    {   self.rx.recv().await
+    }
+
+    /// Chunks received from the provider and not yet consumed
+    pub fn len(&self) -> usize
+    {   self.rx.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {   self.rx.is_empty()
+    }
+
+    pub fn into_inner(self) -> tokio::sync::mpsc::Receiver<StreamReply>
+    {   self.rx
+    }
+}
+
+impl futures_util::Stream for CompletionStream
+{   type Item = StreamReply;
+
+    fn poll_next(
+      mut self: std::pin::Pin<&mut Self>
+    , cx: &mut std::task::Context<'_>
+    ) -> std::task::Poll<Option<StreamReply>>
+    {   self.rx.poll_recv(cx)
+    }
+}
+
+pub struct SendStreamArgs
+{   pub prompt: String
+  , pub model: String
+  , /// Target provider; `None` routes to the current model's provider
+    pub provider: Option<crate::Provider>
+  , pub reply: StreamReplySender
+}
+
+// ===== DryRun =====
+
+pub type DryRunReply = Result<serde_json::Value, crate::error::Error>;
+pub type DryRunReplySender
+  = tokio::sync::mpsc::UnboundedSender<DryRunReply>;
+
+pub struct DryRunArgs
+{   pub request: crate::request::PromptRequest
+  , pub reply: DryRunReplySender
+}
+
 // ===== SetApiKeys =====
 
-pub type SetApiKeysReply = Result<(), crate::error::Error>;
+/// Every key with its outcome, in the order given
+pub type SetApiKeysReply = Result<
+    Vec<(ApiKeySpec, Result<(), crate::error::Error>)>,
+    crate::error::Error
+  >;
 pub type SetApiKeysReplySender 
   = tokio::sync::mpsc::UnboundedSender<SetApiKeysReply>;
 
@@ -73,6 +292,30 @@ pub struct ApiKeySpec
   , pub key: String
 }
 
+/// Debug output leaves the key out
+impl std::fmt::Debug for ApiKeySpec
+{   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {   f.debug_struct("ApiKeySpec")
+          .field("provider", &self.provider)
+          .field("model", &self.model)
+          .finish_non_exhaustive()
+    }
+}
+
+// ===== SetKeyProvider =====
+
+pub type SetKeyProviderReply = Result<(), crate::error::Error>;
+pub type SetKeyProviderReplySender
+  = tokio::sync::mpsc::UnboundedSender<SetKeyProviderReply>;
+
+pub struct SetKeyProviderArgs
+{   pub provider: crate::Provider
+  , /// `None` replaces the provider's master key
+    pub model: Option<String>
+  , pub key_provider: crate::auth::SharedKeyProvider
+  , pub reply: SetKeyProviderReplySender
+}
+
 // ===== GetModelLists =====
 
 pub type GetModelListsReply 
@@ -81,7 +324,9 @@ pub type GetModelListsReplySender
   = tokio::sync::mpsc::UnboundedSender<GetModelListsReply>;
 
 pub struct GetModelListsArgs 
-{   pub reply: GetModelListsReplySender
+{   /// Keeps only the models whose `ModelInfo` matches
+    pub filter: Option<crate::capabilities::ModelFilter>
+  , pub reply: GetModelListsReplySender
 }
 
 // ===== KillProcess =====
@@ -107,39 +352,589 @@ pub struct SetModelFallbackPreferenceArgs
   , pub reply: SetModelFallbackPreferenceSender
 }
 
+// ===== SetModelAliases =====
+
+pub type SetModelAliasesReply = Result<(), crate::error::Error>;
+pub type SetModelAliasesReplySender
+  = tokio::sync::mpsc::UnboundedSender<SetModelAliasesReply>;
+
+pub struct SetModelAliasesArgs
+{   /// Friendly name -> real provider and model; replaces the
+    /// current table
+    pub aliases: std::collections::HashMap<String, (crate::Provider, String)>
+  , pub reply: SetModelAliasesReplySender
+}
+
+// ===== AddResponseValidator =====
+
+pub type AddResponseValidatorReply = Result<(), crate::error::Error>;
+pub type AddResponseValidatorSender
+  = tokio::sync::mpsc::UnboundedSender<AddResponseValidatorReply>;
+
+pub struct AddResponseValidatorArgs
+{   pub validator: Box<
+      dyn crate::middleware::ContentValidator + Send + Sync
+    >
+  , pub reply: AddResponseValidatorSender
+}
+
+// ===== AddRequestInterceptor =====
+
+pub type AddRequestInterceptorReply = Result<(), crate::error::Error>;
+pub type AddRequestInterceptorSender
+  = tokio::sync::mpsc::UnboundedSender<AddRequestInterceptorReply>;
+
+pub struct AddRequestInterceptorArgs
+{   pub interceptor: Box<
+      dyn crate::middleware::RequestInterceptor + Send + Sync
+    >
+  , pub reply: AddRequestInterceptorSender
+}
+
+// ===== RegisterModels =====
+
+pub type RegisterModelsReply = Result<(), crate::error::Error>;
+pub type RegisterModelsReplySender
+  = tokio::sync::mpsc::UnboundedSender<RegisterModelsReply>;
+
+pub struct RegisterModelsArgs
+{   /// Added to the catalog, replacing entries with the same
+    /// provider and name
+    pub models: Vec<ModelInfo>
+  , pub reply: RegisterModelsReplySender
+}
+
+// ===== ExportModelRegistry =====
+
+pub type ExportModelRegistryReply = Result<String, crate::error::Error>;
+pub type ExportModelRegistryReplySender
+  = tokio::sync::mpsc::UnboundedSender<ExportModelRegistryReply>;
+
+pub struct ExportModelRegistryArgs
+{   pub reply: ExportModelRegistryReplySender
+}
+
+// ===== ImportModelRegistry =====
+
+pub type ImportModelRegistryReply = Result<usize, crate::error::Error>;
+pub type ImportModelRegistryReplySender
+  = tokio::sync::mpsc::UnboundedSender<ImportModelRegistryReply>;
+
+pub struct ImportModelRegistryArgs
+{   /// `export_model_registry` output
+    pub json: String
+  , pub reply: ImportModelRegistryReplySender
+}
+
+// ===== GetMetrics =====
+
+pub type GetMetricsReply
+  = Result<crate::metrics::MetricsSnapshot, crate::error::Error>;
+pub type GetMetricsReplySender
+  = tokio::sync::mpsc::UnboundedSender<GetMetricsReply>;
+
+pub struct GetMetricsArgs
+{   pub reply: GetMetricsReplySender
+}
+
+// ===== CreateSession =====
+
+pub type CreateSessionReply
+  = Result<crate::utils::uuid::Uuid, crate::error::Error>;
+pub type CreateSessionReplySender
+  = tokio::sync::mpsc::UnboundedSender<CreateSessionReply>;
+
+pub struct CreateSessionArgs
+{   pub max_messages: usize
+  , pub pruning: crate::session::PruningStrategy
+  , pub reply: CreateSessionReplySender
+}
+
+// ===== SendSessionPrompt =====
+
+pub struct SendSessionPromptArgs
+{   pub session_id: crate::utils::uuid::Uuid
+  , pub prompt: String
+  , pub model: String
+  , /// Target provider; `None` routes to the current model's provider
+    pub provider: Option<crate::Provider>
+  , pub reply: SendPromptReplySender
+}
+
+// ===== SummarizeSession =====
+
+pub type SummarizeSessionReply = Result<String, crate::error::Error>;
+pub type SummarizeSessionReplySender
+  = tokio::sync::mpsc::UnboundedSender<SummarizeSessionReply>;
+
+pub struct SummarizeSessionArgs
+{   pub session_id: crate::utils::uuid::Uuid
+  , /// Model writing the summary
+    pub model: (crate::Provider, String)
+  , pub reply: SummarizeSessionReplySender
+}
+
+// ===== PinSessionModel =====
+
+pub type PinSessionModelReply = Result<(), crate::error::Error>;
+pub type PinSessionModelReplySender
+  = tokio::sync::mpsc::UnboundedSender<PinSessionModelReply>;
+
+pub struct PinSessionModelArgs
+{   pub session_id: crate::utils::uuid::Uuid
+  , pub provider: crate::Provider
+  , pub model: String
+  , pub reply: PinSessionModelReplySender
+}
+
+// ===== SetSessionSummarization =====
+
+pub type SetSessionSummarizationReply = Result<(), crate::error::Error>;
+pub type SetSessionSummarizationReplySender
+  = tokio::sync::mpsc::UnboundedSender<SetSessionSummarizationReply>;
+
+pub struct SetSessionSummarizationArgs
+{   pub session_id: crate::utils::uuid::Uuid
+  , /// `None` turns the rolling summary off
+    pub strategy: Option<crate::session::SummarizationStrategy>
+  , pub reply: SetSessionSummarizationReplySender
+}
+
+// ===== GetSession =====
+
+pub type GetSessionReply
+  = Result<crate::session::ConversationSession, crate::error::Error>;
+pub type GetSessionReplySender
+  = tokio::sync::mpsc::UnboundedSender<GetSessionReply>;
+
+pub struct GetSessionArgs
+{   pub session_id: crate::utils::uuid::Uuid
+  , pub reply: GetSessionReplySender
+}
+
+// ===== ExportSession =====
+
+pub type ExportSessionReply = Result<String, crate::error::Error>;
+pub type ExportSessionReplySender
+  = tokio::sync::mpsc::UnboundedSender<ExportSessionReply>;
+
+pub struct ExportSessionArgs
+{   pub session_id: crate::utils::uuid::Uuid
+  , pub reply: ExportSessionReplySender
+}
+
+// ===== ImportSession =====
+
+pub type ImportSessionReply
+  = Result<crate::utils::uuid::Uuid, crate::error::Error>;
+pub type ImportSessionReplySender
+  = tokio::sync::mpsc::UnboundedSender<ImportSessionReply>;
+
+pub struct ImportSessionArgs
+{   /// `ConversationSession::to_json` output
+    pub json: String
+  , pub reply: ImportSessionReplySender
+}
+
+// ===== PreloadProviders =====
+
+pub type PreloadProvidersReply = Result<(), crate::error::Error>;
+pub type PreloadProvidersReplySender
+  = tokio::sync::mpsc::UnboundedSender<PreloadProvidersReply>;
+
+pub struct PreloadProvidersArgs
+{   pub providers: Vec<Provider>
+  , pub reply: PreloadProvidersReplySender
+}
+
+// ===== GetPartialResponse =====
+
+/// Last characters received for a checkpointed request, `None` if
+/// none were kept or the request has completed
+pub type GetPartialResponseReply = Option<String>;
+pub type GetPartialResponseReplySender
+  = tokio::sync::mpsc::UnboundedSender<GetPartialResponseReply>;
+
+pub struct GetPartialResponseArgs
+{   pub request_id: RequestId
+  , pub reply: GetPartialResponseReplySender
+}
+
+// ===== GetCapabilityMatrix =====
+
+pub type GetCapabilityMatrixReply = Result<
+  crate::capabilities::CapabilityMatrix,
+  crate::error::Error
+>;
+pub type GetCapabilityMatrixReplySender
+  = tokio::sync::mpsc::UnboundedSender<GetCapabilityMatrixReply>;
+
+pub struct GetCapabilityMatrixArgs
+{   pub reply: GetCapabilityMatrixReplySender
+}
+
+// ===== SplitPrompt =====
+
+pub type SplitPromptReply = Result<Vec<String>, crate::error::Error>;
+pub type SplitPromptReplySender
+  = tokio::sync::mpsc::UnboundedSender<SplitPromptReply>;
+
+/// Chunks `prompt` would be split into for `model`'s provider
+pub struct SplitPromptArgs
+{   pub prompt: String
+  , pub model: String
+  , pub reply: SplitPromptReplySender
+}
+
+// ===== WouldUseProvider =====
+
+pub type WouldUseProviderReply
+  = Result<(Provider, String), crate::error::Error>;
+pub type WouldUseProviderReplySender
+  = tokio::sync::mpsc::UnboundedSender<WouldUseProviderReply>;
+
+/// First fallback a request meeting `requirements` would go to
+pub struct WouldUseProviderArgs
+{   pub requirements: Option<crate::capabilities::ModelRequirements>
+  , pub reply: WouldUseProviderReplySender
+}
+
+// ===== GetModelInfo =====
+
+pub type GetModelInfoReply = Result<ModelInfo, crate::error::Error>;
+pub type GetModelInfoReplySender
+  = tokio::sync::mpsc::UnboundedSender<GetModelInfoReply>;
+
+pub struct GetModelInfoArgs
+{   pub provider: Provider
+  , pub model: String
+  , pub reply: GetModelInfoReplySender
+}
+
+// ===== ComputePerplexity =====
+
+pub type ComputePerplexityReply = Result<f64, crate::error::Error>;
+pub type ComputePerplexityReplySender
+  = tokio::sync::mpsc::UnboundedSender<ComputePerplexityReply>;
+
+pub struct ComputePerplexityArgs
+{   pub text: String
+  , pub provider: Provider
+  , pub model: String
+  , pub reply: ComputePerplexityReplySender
+}
+
+// ===== CountTokens =====
+
+pub type CountTokensReply = Result<Vec<usize>, crate::error::Error>;
+pub type CountTokensReplySender
+  = tokio::sync::mpsc::UnboundedSender<CountTokensReply>;
+
+pub struct CountTokensArgs
+{   pub prompts: Vec<String>
+  , /// Picks the provider, as for a prompt to this model
+    pub model: String
+  , pub reply: CountTokensReplySender
+}
+
+// ===== SendCompletion =====
+
+/// A raw prompt for the completion endpoint, continued as text
+/// rather than answered as a chat message
+pub struct SendCompletionArgs
+{   pub prompt: String
+  , /// Picks the provider, as for a prompt to this model
+    pub model: String
+  , pub max_tokens: Option<usize>
+  , pub temperature: Option<f32>
+  , pub stop: Option<Vec<String>>
+  , pub reply: tokio::sync::mpsc::UnboundedSender<SendPromptReply>
+}
+
+// ===== GetPromptStatus =====
+
+/// Where a prompt accepted by the backend is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PromptStatus
+{   /// Waiting for a rate limit, moderation or a retry
+    Queued
+  , /// Sent to its provider, reply not yet in
+    InFlight
+  , /// Reply sent to the caller
+    Done
+}
+
+/// `Error::PromptNotFound` for an id the backend never gave out
+pub type GetPromptStatusReply = Result<PromptStatus, crate::error::Error>;
+pub type GetPromptStatusReplySender
+  = tokio::sync::mpsc::UnboundedSender<GetPromptStatusReply>;
+
+pub struct GetPromptStatusArgs
+{   pub id: usize
+  , pub reply: GetPromptStatusReplySender
+}
+
+// ===== WatchProviderStatus =====
+
+pub type WatchProviderStatusReply = Result<
+  tokio::sync::watch::Receiver<crate::metrics::ProviderStatus>,
+  crate::error::Error
+>;
+pub type WatchProviderStatusReplySender
+  = tokio::sync::mpsc::UnboundedSender<WatchProviderStatusReply>;
+
+pub struct WatchProviderStatusArgs
+{   pub provider: Provider
+  , pub reply: WatchProviderStatusReplySender
+}
+
+// ===== SubscribeEvents =====
+
+/// Sent once the subscriber receives events
+pub type SubscribeEventsReply = Result<(), crate::error::Error>;
+pub type SubscribeEventsReplySender
+  = tokio::sync::mpsc::UnboundedSender<SubscribeEventsReply>;
+
+pub struct SubscribeEventsArgs
+{   /// Dropped from the subscribers once its receiver is gone
+    pub subscriber: tokio::sync::mpsc::UnboundedSender<AllmEvent>
+  , pub reply: SubscribeEventsReplySender
+}
+
+// ===== GetRateLimitStatus =====
+
+pub type GetRateLimitStatusReply = Result<
+  crate::utils::rate_limiter::RateLimitStatus,
+  crate::error::Error
+>;
+pub type GetRateLimitStatusReplySender
+  = tokio::sync::mpsc::UnboundedSender<GetRateLimitStatusReply>;
+
+pub struct GetRateLimitStatusArgs
+{   pub provider: Provider
+  , pub model: String
+  , pub reply: GetRateLimitStatusReplySender
+}
+
+// ===== GetRateLimitReset =====
+
+pub type GetRateLimitResetReply
+  = Result<std::time::Duration, crate::error::Error>;
+pub type GetRateLimitResetReplySender
+  = tokio::sync::mpsc::UnboundedSender<GetRateLimitResetReply>;
+
+/// See `AllmBackend::wait_for_rate_limit_reset`
+pub struct GetRateLimitResetArgs
+{   pub provider: Provider
+  , pub model: String
+  , pub reply: GetRateLimitResetReplySender
+}
+
+// ===== GetSupervisionStatus =====
+
+pub type GetSupervisionStatusReply = Result<
+  Vec<crate::supervisor::ProviderSupervision>,
+  crate::error::Error
+>;
+pub type GetSupervisionStatusReplySender
+  = tokio::sync::mpsc::UnboundedSender<GetSupervisionStatusReply>;
+
+pub struct GetSupervisionStatusArgs
+{   pub reply: GetSupervisionStatusReplySender
+}
+
+// ===== OpenAIBatch =====
+
+/// Results of a batch in the order of its items
+pub type BatchReply = Result<
+  Vec<crate::providers::openai::BatchResult>,
+  crate::error::Error
+>;
+pub type BatchReplySender
+  = tokio::sync::mpsc::UnboundedSender<BatchReply>;
+
+pub struct OpenAIBatchArgs
+{   pub requests: Vec<crate::providers::openai::BatchItem>
+  , pub reply: BatchReplySender
+}
+
 // ===== AllmHand (sender side) =====
 
 pub struct AllmHand 
 {   pub send_prompt_tx
-      : tokio::sync::mpsc::UnboundedSender<SendPromptArgs>
+      : tokio::sync::mpsc::Sender<SendPromptArgs>
+  , pub send_request_tx
+      : tokio::sync::mpsc::Sender<SendRequestArgs>
+  , pub send_stream_tx
+      : tokio::sync::mpsc::Sender<SendStreamArgs>
+  , pub dry_run_tx
+      : tokio::sync::mpsc::Sender<DryRunArgs>
   , pub set_api_keys_tx
-      : tokio::sync::mpsc::UnboundedSender<SetApiKeysArgs>
+      : tokio::sync::mpsc::Sender<SetApiKeysArgs>
+  , pub set_key_provider_tx
+      : tokio::sync::mpsc::Sender<SetKeyProviderArgs>
   , pub get_model_lists_tx
-      : tokio::sync::mpsc::UnboundedSender<GetModelListsArgs>
+      : tokio::sync::mpsc::Sender<GetModelListsArgs>
   , pub kill_process_tx
-      : tokio::sync::mpsc::UnboundedSender<KillProcessArgs>
+      : tokio::sync::mpsc::Sender<KillProcessArgs>
   , pub set_model_fallback_preference_tx
-      : tokio::sync::mpsc::UnboundedSender
+      : tokio::sync::mpsc::Sender
         <SetModelFallbackPreferenceArgs>
+  , pub set_model_aliases_tx
+      : tokio::sync::mpsc::Sender<SetModelAliasesArgs>
+  , pub add_response_validator_tx
+      : tokio::sync::mpsc::Sender<AddResponseValidatorArgs>
+  , pub add_request_interceptor_tx
+      : tokio::sync::mpsc::Sender<AddRequestInterceptorArgs>
+  , pub register_models_tx
+      : tokio::sync::mpsc::Sender<RegisterModelsArgs>
+  , pub export_model_registry_tx
+      : tokio::sync::mpsc::Sender<ExportModelRegistryArgs>
+  , pub import_model_registry_tx
+      : tokio::sync::mpsc::Sender<ImportModelRegistryArgs>
+  , pub get_metrics_tx
+      : tokio::sync::mpsc::Sender<GetMetricsArgs>
+  , pub create_session_tx
+      : tokio::sync::mpsc::Sender<CreateSessionArgs>
+  , pub send_session_prompt_tx
+      : tokio::sync::mpsc::Sender<SendSessionPromptArgs>
+  , pub summarize_session_tx
+      : tokio::sync::mpsc::Sender<SummarizeSessionArgs>
+  , pub pin_session_model_tx
+      : tokio::sync::mpsc::Sender<PinSessionModelArgs>
+  , pub set_session_summarization_tx
+      : tokio::sync::mpsc::Sender<SetSessionSummarizationArgs>
+  , pub get_session_tx
+      : tokio::sync::mpsc::Sender<GetSessionArgs>
+  , pub export_session_tx
+      : tokio::sync::mpsc::Sender<ExportSessionArgs>
+  , pub import_session_tx
+      : tokio::sync::mpsc::Sender<ImportSessionArgs>
+  , pub preload_providers_tx
+      : tokio::sync::mpsc::Sender<PreloadProvidersArgs>
+  , pub openai_tx
+      : tokio::sync::mpsc::Sender<crate::providers::openai::OpenAICommand>
+  , pub get_supervision_status_tx
+      : tokio::sync::mpsc::Sender<GetSupervisionStatusArgs>
+  , pub get_partial_response_tx
+      : tokio::sync::mpsc::Sender<GetPartialResponseArgs>
+  , pub get_capability_matrix_tx
+      : tokio::sync::mpsc::Sender<GetCapabilityMatrixArgs>
+  , pub get_model_info_tx
+      : tokio::sync::mpsc::Sender<GetModelInfoArgs>
+  , pub split_prompt_tx
+      : tokio::sync::mpsc::Sender<SplitPromptArgs>
+  , pub would_use_provider_tx
+      : tokio::sync::mpsc::Sender<WouldUseProviderArgs>
+  , pub get_rate_limit_status_tx
+      : tokio::sync::mpsc::Sender<GetRateLimitStatusArgs>
+  , pub get_rate_limit_reset_tx
+      : tokio::sync::mpsc::Sender<GetRateLimitResetArgs>
+  , pub count_tokens_tx
+      : tokio::sync::mpsc::Sender<CountTokensArgs>
+  , pub get_prompt_status_tx
+      : tokio::sync::mpsc::Sender<GetPromptStatusArgs>
+  , pub watch_provider_status_tx
+      : tokio::sync::mpsc::Sender<WatchProviderStatusArgs>
+  , pub subscribe_events_tx
+      : tokio::sync::mpsc::Sender<SubscribeEventsArgs>
+  , pub completion_tx
+      : tokio::sync::mpsc::Sender<SendCompletionArgs>
+  , pub compute_perplexity_tx
+      : tokio::sync::mpsc::Sender<ComputePerplexityArgs>
+  , pub openai_batch_tx
+      : tokio::sync::mpsc::Sender<OpenAIBatchArgs>
 }
 
 // ===== AllmFoot (receiver side) =====
 
 pub struct AllmFoot 
 {   pub send_prompt_rx
-      : tokio::sync::mpsc::UnboundedReceiver<SendPromptArgs>
+      : tokio::sync::mpsc::Receiver<SendPromptArgs>
+  , pub send_request_rx
+      : tokio::sync::mpsc::Receiver<SendRequestArgs>
+  , pub send_stream_rx
+      : tokio::sync::mpsc::Receiver<SendStreamArgs>
+  , pub dry_run_rx
+      : tokio::sync::mpsc::Receiver<DryRunArgs>
   , pub set_api_keys_rx
-      : tokio::sync::mpsc::UnboundedReceiver<SetApiKeysArgs>
+      : tokio::sync::mpsc::Receiver<SetApiKeysArgs>
+  , pub set_key_provider_rx
+      : tokio::sync::mpsc::Receiver<SetKeyProviderArgs>
   , pub get_model_lists_rx
-      : tokio::sync::mpsc::UnboundedReceiver<GetModelListsArgs>
+      : tokio::sync::mpsc::Receiver<GetModelListsArgs>
   , pub kill_process_rx
-      : tokio::sync::mpsc::UnboundedReceiver<KillProcessArgs>
+      : tokio::sync::mpsc::Receiver<KillProcessArgs>
   , pub set_model_fallback_preference_rx
-      : tokio::sync::mpsc::UnboundedReceiver
+      : tokio::sync::mpsc::Receiver
         <SetModelFallbackPreferenceArgs>
+  , pub set_model_aliases_rx
+      : tokio::sync::mpsc::Receiver<SetModelAliasesArgs>
+  , pub add_response_validator_rx
+      : tokio::sync::mpsc::Receiver<AddResponseValidatorArgs>
+  , pub add_request_interceptor_rx
+      : tokio::sync::mpsc::Receiver<AddRequestInterceptorArgs>
+  , pub register_models_rx
+      : tokio::sync::mpsc::Receiver<RegisterModelsArgs>
+  , pub export_model_registry_rx
+      : tokio::sync::mpsc::Receiver<ExportModelRegistryArgs>
+  , pub import_model_registry_rx
+      : tokio::sync::mpsc::Receiver<ImportModelRegistryArgs>
+  , pub get_metrics_rx
+      : tokio::sync::mpsc::Receiver<GetMetricsArgs>
+  , pub create_session_rx
+      : tokio::sync::mpsc::Receiver<CreateSessionArgs>
+  , pub send_session_prompt_rx
+      : tokio::sync::mpsc::Receiver<SendSessionPromptArgs>
+  , pub summarize_session_rx
+      : tokio::sync::mpsc::Receiver<SummarizeSessionArgs>
+  , pub pin_session_model_rx
+      : tokio::sync::mpsc::Receiver<PinSessionModelArgs>
+  , pub set_session_summarization_rx
+      : tokio::sync::mpsc::Receiver<SetSessionSummarizationArgs>
+  , pub get_session_rx
+      : tokio::sync::mpsc::Receiver<GetSessionArgs>
+  , pub export_session_rx
+      : tokio::sync::mpsc::Receiver<ExportSessionArgs>
+  , pub import_session_rx
+      : tokio::sync::mpsc::Receiver<ImportSessionArgs>
+  , pub preload_providers_rx
+      : tokio::sync::mpsc::Receiver<PreloadProvidersArgs>
+  , pub openai_rx
+      : tokio::sync::mpsc::Receiver<crate::providers::openai::OpenAICommand>
+  , pub get_supervision_status_rx
+      : tokio::sync::mpsc::Receiver<GetSupervisionStatusArgs>
+  , pub get_partial_response_rx
+      : tokio::sync::mpsc::Receiver<GetPartialResponseArgs>
+  , pub get_capability_matrix_rx
+      : tokio::sync::mpsc::Receiver<GetCapabilityMatrixArgs>
+  , pub get_model_info_rx
+      : tokio::sync::mpsc::Receiver<GetModelInfoArgs>
+  , pub split_prompt_rx
+      : tokio::sync::mpsc::Receiver<SplitPromptArgs>
+  , pub would_use_provider_rx
+      : tokio::sync::mpsc::Receiver<WouldUseProviderArgs>
+  , pub get_rate_limit_status_rx
+      : tokio::sync::mpsc::Receiver<GetRateLimitStatusArgs>
+  , pub get_rate_limit_reset_rx
+      : tokio::sync::mpsc::Receiver<GetRateLimitResetArgs>
+  , pub count_tokens_rx
+      : tokio::sync::mpsc::Receiver<CountTokensArgs>
+  , pub get_prompt_status_rx
+      : tokio::sync::mpsc::Receiver<GetPromptStatusArgs>
+  , pub watch_provider_status_rx
+      : tokio::sync::mpsc::Receiver<WatchProviderStatusArgs>
+  , pub subscribe_events_rx
+      : tokio::sync::mpsc::Receiver<SubscribeEventsArgs>
+  , pub completion_rx
+      : tokio::sync::mpsc::Receiver<SendCompletionArgs>
+  , pub compute_perplexity_rx
+      : tokio::sync::mpsc::Receiver<ComputePerplexityArgs>
+  , pub openai_batch_rx
+      : tokio::sync::mpsc::Receiver<OpenAIBatchArgs>
 }
 
-/// ALLM STRUCTURES:
+// ALLM STRUCTURES:
 
 /// Enum representing all targeted supported LLM providers.
 /// Each variant corresponds to a public API or platform.
@@ -204,8 +999,77 @@ pub enum Provider
   Local
 }
 
+impl Provider
+{   /// Every provider, in declaration order
+    pub const ALL: [Provider; 18] =
+    [ Provider::MistralAi, Provider::OpenAI, Provider::Anthropic
+    , Provider::Google, Provider::Meta, Provider::PerplexityAi
+    , Provider::Xai, Provider::Ai21Studio, Provider::Alibaba
+    , Provider::HuggingFaceInterface, Provider::Groq
+    , Provider::CloudflareAi, Provider::TogetherAi, Provider::Cerebras
+    , Provider::OpenRouter, Provider::FireworksAi, Provider::Replicate
+    , Provider::Local
+    ];
+
+    /// Parse a provider from a config name such as `"mistral"`,
+    /// `"cloudflare"` or the variant name itself (case-insensitive)
+    pub fn from_name(name: &str) -> Option<Provider>
+    {   let normalized: String = name
+          .chars()
+          .filter(|c| c.is_ascii_alphanumeric())
+          .collect::<String>()
+          .to_ascii_lowercase();
+        match normalized.as_str()
+        {   "mistral" | "mistralai" => Some(Provider::MistralAi)
+          , "openai" => Some(Provider::OpenAI)
+          , "anthropic" => Some(Provider::Anthropic)
+          , "google" => Some(Provider::Google)
+          , "meta" => Some(Provider::Meta)
+          , "perplexity" | "perplexityai" => Some(Provider::PerplexityAi)
+          , "xai" => Some(Provider::Xai)
+          , "ai21" | "ai21studio" => Some(Provider::Ai21Studio)
+          , "alibaba" => Some(Provider::Alibaba)
+          , "huggingface" | "huggingfaceinterface"
+              => Some(Provider::HuggingFaceInterface)
+          , "groq" => Some(Provider::Groq)
+          , "cloudflare" | "cloudflareai" => Some(Provider::CloudflareAi)
+          , "together" | "togetherai" => Some(Provider::TogetherAi)
+          , "cerebras" => Some(Provider::Cerebras)
+          , "openrouter" => Some(Provider::OpenRouter)
+          , "fireworks" | "fireworksai" => Some(Provider::FireworksAi)
+          , "replicate" => Some(Provider::Replicate)
+          , "local" | "ollama" => Some(Provider::Local)
+          , _ => None
+        }
+    }
+
+    /// Short config name, accepted by `from_name`
+    pub fn name(&self) -> &'static str
+    {   match self
+        {   Provider::MistralAi => "mistral"
+          , Provider::OpenAI => "openai"
+          , Provider::Anthropic => "anthropic"
+          , Provider::Google => "google"
+          , Provider::Meta => "meta"
+          , Provider::PerplexityAi => "perplexity"
+          , Provider::Xai => "xai"
+          , Provider::Ai21Studio => "ai21"
+          , Provider::Alibaba => "alibaba"
+          , Provider::HuggingFaceInterface => "huggingface"
+          , Provider::Groq => "groq"
+          , Provider::CloudflareAi => "cloudflare"
+          , Provider::TogetherAi => "together"
+          , Provider::Cerebras => "cerebras"
+          , Provider::OpenRouter => "openrouter"
+          , Provider::FireworksAi => "fireworks"
+          , Provider::Replicate => "replicate"
+          , Provider::Local => "local"
+        }
+    }
+}
+
 /// Information about a model's capabilities and limits.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ModelInfo 
 {   /// Human-readable name (e.g., "mistral-tiny", "gpt-4")
     pub name: String
@@ -221,6 +1085,17 @@ pub struct ModelInfo
     pub supports_streaming: bool
   , /// Whether the model supports function/tool calling
     pub supports_tools: bool
+  , /// Whether the model returns token log probabilities
+    /// (`logprobs`)
+    #[serde(default)]
+    pub supports_logprobs: bool
+  , /// Whether a `seed` makes the model's sampling repeatable
+    #[serde(default)]
+    pub supports_reproducible_outputs: bool
+  , /// Whether the model takes raw prompts at the completion
+    /// endpoint (see `AllmBackend::complete`)
+    #[serde(default)]
+    pub supports_raw_completion: bool
   , /// Provider of the model
     pub provider: crate::Provider
   , /// Default system prompt or behavior instructions
@@ -235,8 +1110,154 @@ pub struct ModelInfo
     pub is_available: bool
 }
 
-/// Represents a single input modality
-#[derive(Debug, Clone, PartialEq)]
+impl ModelInfo
+{   /// A model a provider lists but allm knows nothing else about:
+    /// text input, no context size, tools or prices
+    pub fn listed(provider: Provider, name: String) -> Self
+    {   ModelInfo
+        {   name
+          , max_context_tokens: 0
+          , max_response_tokens: 0
+          , can_save_context: false
+          , input_modalities: ModelModalities
+            {   supported: vec![InputModality::Single(BaseModality::Text)]
+            }
+          , supports_streaming: false
+          , supports_tools: false
+          , supports_logprobs: false
+          , supports_reproducible_outputs: false
+          , supports_raw_completion: false
+          , provider
+          , default_system_prompt: None
+          , supported_file_extensions: None
+          , cost_per_million_input_tokens: None
+          , cost_per_million_output_tokens: None
+          , is_available: true
+        }
+    }
+
+    /// Whether the model takes `modality` input, alone or combined
+    /// with others
+    pub fn accepts(&self, modality: &BaseModality) -> bool
+    {   self.input_modalities.supported.iter()
+          .any(|supported| match supported
+          {   InputModality::Single(base) => base == modality
+            , InputModality::Combined(combined) => {
+                combined.modalities.contains(modality)
+              }
+          })
+    }
+
+    /// One-paragraph summary of the model's limits, tools and image
+    /// support, for logs and UIs
+    pub fn to_human_description(&self) -> String
+    {   let template = crate::request::PromptTemplate::new(
+          "{{name}} by {{provider}} supports up to {{max_context_tokens}} \
+           input tokens and {{max_response_tokens}} output tokens. It \
+           {{tools}} support tools and {{images}} support image input."
+        );
+        let tokens = |count: usize| match count
+        {   0 => "an unknown number of".to_string()
+          , count => count.to_string()
+        };
+        let does = |yes: bool| if yes { "does" } else { "doesn't" }.to_string();
+        let vars = std::collections::HashMap::from(
+        [   ("name".to_string(), self.name.clone())
+          , ("provider".to_string(), format!("{:?}", self.provider))
+          , ("max_context_tokens".to_string(), tokens(self.max_context_tokens))
+          , ("max_response_tokens".to_string(), tokens(self.max_response_tokens))
+          , ("tools".to_string(), does(self.supports_tools))
+          , ("images".to_string(), does(self.accepts(&BaseModality::Image)))
+        ]);
+        template.render(&vars)
+          .expect("every description variable is set")
+    }
+}
+
+/// What the backend reports to `AllmBackend::subscribe_events`
+/// subscribers as it happens
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AllmEvent
+{   /// A prompt was sent to `model`; retries and failovers send it
+    /// again
+    RequestStarted
+    {   request_id: RequestId
+      , provider: Provider
+      , model: String
+    }
+  , /// A prompt was answered. Token counts the provider does not
+    /// report are estimated; `cost_usd` is `None` for unpriced models.
+    RequestCompleted
+    {   request_id: RequestId
+      , tokens_in: usize
+      , tokens_out: usize
+      , cost_usd: Option<f32>
+      , /// Time spent on every model the prompt went to
+        latency: std::time::Duration
+    }
+  , /// A prompt failed for good
+    RequestFailed
+    {   request_id: RequestId
+      , error: crate::error::Error
+    }
+  , /// A prompt moved on to its next fallback, as `FailoverEvent`
+    Failover
+    {   from: (Provider, String)
+      , to: (Provider, String)
+      , reason: String
+    }
+  , /// A model answered with a rate limit and is out of routing until
+    /// it resets
+    CircuitBreakerOpened
+    {   provider: Provider
+      , model: String
+    }
+  , /// A rate-limited model has reset
+    CircuitBreakerClosed
+    {   provider: Provider
+      , model: String
+    }
+  , /// A provider's `ProviderStatus::health` changed
+    ProviderStatusChanged
+    {   provider: Provider
+      , from: crate::metrics::ProviderStatus
+      , to: crate::metrics::ProviderStatus
+    }
+}
+
+/// Sent on `AllmConfig::context_warning_tx` when a reply shows the
+/// prompt filled at least `context_warning_threshold_percent` of the
+/// model's context window
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ContextUsageWarning
+{   pub provider: Provider
+  , pub model: String
+  , /// Prompt tokens, as reported by the provider or estimated
+    pub tokens_used: usize
+  , /// Context window of the model
+    pub tokens_available: usize
+  , pub percent_full: f32
+}
+
+/// Passed to the `AllmBackend::with_failover_callback` callback each
+/// time a prompt moves on to its next fallback preference
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FailoverEvent
+{   /// Provider and model that failed
+    pub from: (Provider, String)
+  , /// Provider and model the prompt goes to next
+    pub to: (Provider, String)
+  , /// Error, or rejected reply, that caused the switch
+    pub reason: crate::error::Error
+  , /// Dispatches of the prompt so far, retries included
+    pub attempt: usize
+}
+
+/// Represents a single input modality; serialized as `"text"`,
+/// `"image"`, ...
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BaseModality 
 {   Text
   , Image
@@ -245,20 +1266,23 @@ pub enum BaseModality
 }
 
 /// Represents a combination of modalities (e.g., Text + Image)
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CombinedModality 
 {   pub modalities: Vec<BaseModality>
 }
 
-/// Represents a single or combined input modality
-#[derive(Debug, Clone, PartialEq)]
+/// Represents a single or combined input modality; serialized as
+/// `{"type": "single", "value": "text"}` or
+/// `{"type": "combined", "value": {"modalities": [...]}}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
 pub enum InputModality 
 {   Single(BaseModality)
   , Combined(CombinedModality)
 }
 
 /// All possible input modalities a model supports
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ModelModalities 
 {   pub supported: Vec<InputModality>
 }
\ No newline at end of file
@@ -0,0 +1,113 @@
+//! Counters the backend keeps per provider, for monitoring
+//!
+//! `AllmBackendRef::get_metrics` returns a `MetricsSnapshot`, which
+//! renders as Prometheus text or serializes to JSON.
+
+use serde::Serialize;
+use std::fmt::Write;
+
+/// Running totals for one provider
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProviderCounters
+{   /// Prompts handed to the provider, retries included
+    pub requests: u64
+  , pub succeeded: u64
+  , pub failed: u64
+  , pub last_error: Option<String>
+  , /// Failures since the last success
+    pub consecutive_failures: u64
+}
+
+/// How a provider is doing, as `AllmBackend::watch_provider_status`
+/// reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderHealth
+{   /// Running, and its last request, if any, succeeded
+    Healthy
+  , /// Its last request failed, or its client is being restarted
+    Degraded
+  , /// Crashed too often; the supervisor gave up on it
+    Unavailable
+}
+
+/// State of one provider client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProviderStatus
+{   pub provider: crate::Provider
+  , pub default_model: Option<String>
+  , /// False while a lazily created client waits for its first
+    /// request
+    pub initialized: bool
+  , /// Pending prompts currently routed to the provider
+    pub in_flight: usize
+  , pub requests: u64
+  , pub succeeded: u64
+  , pub failed: u64
+  , pub last_error: Option<String>
+  , pub health: ProviderHealth
+}
+
+/// Point-in-time view of the backend
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MetricsSnapshot
+{   /// Prompts accepted and not yet answered
+    pub pending_prompts: usize
+  , pub sessions: usize
+  , pub providers: Vec<ProviderStatus>
+}
+
+impl MetricsSnapshot
+{   /// Prometheus text exposition format (version 0.0.4)
+    pub fn to_prometheus_text(&self) -> String
+    {   let mut out = String::new();
+        gauge(
+          &mut out, "allm_pending_prompts",
+          "Prompts accepted and not yet answered",
+          self.pending_prompts as u64
+        );
+        gauge(
+          &mut out, "allm_sessions",
+          "Open conversation sessions",
+          self.sessions as u64
+        );
+        let series: [(&str, &str, &str, ProviderValue); 4] =
+        [ ( "allm_provider_in_flight", "gauge"
+          , "Pending prompts routed to the provider"
+          , |p| p.in_flight as u64
+          )
+        , ( "allm_provider_requests_total", "counter"
+          , "Prompts sent to the provider"
+          , |p| p.requests
+          )
+        , ( "allm_provider_succeeded_total", "counter"
+          , "Provider replies received"
+          , |p| p.succeeded
+          )
+        , ( "allm_provider_failed_total", "counter"
+          , "Provider errors received"
+          , |p| p.failed
+          )
+        ];
+        for (name, kind, help, value) in series
+        {   let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for status in &self.providers
+            {   let _ = writeln!(
+                  out, "{}{{provider=\"{:?}\"}} {}",
+                  name, status.provider, value(status)
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Value of a per-provider series
+type ProviderValue = fn(&ProviderStatus) -> u64;
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64)
+{   let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
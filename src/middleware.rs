@@ -0,0 +1,329 @@
+//! Hooks that rewrite requests before they are sent and inspect
+//! responses before they reach the caller
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Why a validator rejected a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationCode
+{   /// The model declined to answer
+    RefusalDetected
+  , /// The response looks unreliable
+    HallucinationRisk
+  , /// The response contains personal data
+    PiiDetected
+  , /// Application-specific reason
+    Custom(String)
+}
+
+impl fmt::Display for ValidationCode
+{   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {   match self
+        {   ValidationCode::RefusalDetected => {
+              write!(f, "refusal_detected")
+            }
+          , ValidationCode::HallucinationRisk => {
+              write!(f, "hallucination_risk")
+            }
+          , ValidationCode::PiiDetected => {
+              write!(f, "pii_detected")
+            }
+          , ValidationCode::Custom(code) => {
+              write!(f, "{}", code)
+            }
+        }
+    }
+}
+
+/// Rejection produced by a `ContentValidator`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError
+{   pub code: ValidationCode
+  , pub message: String
+}
+
+impl fmt::Display for ValidationError
+{   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {   write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Data middleware attaches to a request, returned with its reply
+/// in `PromptResponse::metadata`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestMetadata
+{   /// Placeholder to the value it replaced, see
+    /// `PiiRedactor::unredact`
+    #[serde(default)]
+    pub redactions: HashMap<String, String>
+}
+
+/// Checks a response; run by the backend on every successful reply
+pub trait ContentValidator
+{   fn validate(&self, response: &str) -> Result<(), ValidationError>;
+
+    /// Replacement for a response that passed every validator;
+    /// `None` keeps it as is
+    fn rewrite(&self, response: &str, metadata: &mut RequestMetadata)
+      -> Option<String>
+    {   let _ = (response, metadata);
+        None
+    }
+}
+
+/// Rewrites a request before it is sent; run by the backend on
+/// every prompt it accepts
+pub trait RequestInterceptor
+{   fn intercept(&self, request: &mut crate::providers::ChatRequest);
+}
+
+/// Phrases that open the typical LLM refusal
+const DEFAULT_REFUSAL_PHRASES: &[&str] = &[
+  "i cannot",
+  "i can't",
+  "i can not",
+  "i'm unable",
+  "i am unable",
+  "i'm not able to",
+  "i am not able to",
+  "as an ai",
+];
+
+/// Flags responses containing common refusal phrases
+/// (case-insensitive)
+#[derive(Debug, Clone)]
+pub struct RefusalDetector
+{   phrases: Vec<String>
+}
+
+impl RefusalDetector
+{   /// Detector using the built-in phrase list
+    pub fn new() -> Self
+    {   RefusalDetector::with_phrases(
+          DEFAULT_REFUSAL_PHRASES.iter().map(|p| p.to_string()).collect()
+        )
+    }
+
+    /// Detector using a custom phrase list
+    pub fn with_phrases(phrases: Vec<String>) -> Self
+    {   RefusalDetector
+        {   phrases: phrases
+              .into_iter()
+              .map(|p| p.to_lowercase())
+              .collect()
+        }
+    }
+}
+
+impl Default for RefusalDetector
+{   fn default() -> Self
+    {   RefusalDetector::new()
+    }
+}
+
+impl ContentValidator for RefusalDetector
+{   fn validate(&self, response: &str) -> Result<(), ValidationError>
+    {   // Curly apostrophes are common in model output
+        let text = response.to_lowercase().replace('\u{2019}', "'");
+        match self.phrases.iter().find(|p| text.contains(p.as_str()))
+        {   Some(phrase) => Err(ValidationError
+            {   code: ValidationCode::RefusalDetected
+              , message: format!("response contains \"{}\"", phrase)
+            })
+          , None => Ok(())
+        }
+    }
+}
+
+/// Which kinds of personal data `PiiRedactor` replaces
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PiiConfig
+{   #[serde(default = "default_true")]
+    pub redact_emails: bool
+  , /// US and European numbers, with or without country code
+    #[serde(default = "default_true")]
+    pub redact_phones: bool
+  , #[serde(default = "default_true")]
+    pub redact_ssns: bool
+  , /// Digit runs passing the Luhn check
+    #[serde(default = "default_true")]
+    pub redact_cards: bool
+  , /// IPv4 and full-form IPv6 addresses
+    #[serde(default = "default_true")]
+    pub redact_ips: bool
+  , /// Further regexes; matches become `[CUSTOM_n]`
+    #[serde(default)]
+    pub custom_patterns: Vec<String>
+}
+
+fn default_true() -> bool
+{   true
+}
+
+impl Default for PiiConfig
+{   fn default() -> Self
+    {   PiiConfig
+        {   redact_emails: true
+          , redact_phones: true
+          , redact_ssns: true
+          , redact_cards: true
+          , redact_ips: true
+          , custom_patterns: vec![]
+        }
+    }
+}
+
+const EMAIL_PATTERN: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}";
+const SSN_PATTERN: &str = r"\b\d{3}-\d{2}-\d{4}\b";
+const CARD_PATTERN: &str = r"\b\d(?:[ -]?\d){12,18}\b";
+const IP_PATTERN: &str = concat!(
+  r"\b(?:(?:25[0-5]|2[0-4]\d|1?\d?\d)\.){3}(?:25[0-5]|2[0-4]\d|1?\d?\d)\b",
+  r"|\b(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}\b"
+);
+/// `+1 (555) 123-4567`, `555.123.4567`; `+49 30 1234567`,
+/// `+33 1 23 45 67 89`
+const PHONE_PATTERN: &str = concat!(
+  r"(?:\+1[ .-]?)?(?:\(\d{3}\)|\b\d{3})[ .-]?\d{3}[ .-]\d{4}\b",
+  r"|\+[34]\d(?:[ .-]?\d){6,12}\b"
+);
+
+/// Replaces personal data with numbered placeholders (`[EMAIL_1]`,
+/// `[PHONE_2]`, ...) in prompts before they leave the process and in
+/// replies before they reach the caller.
+///
+/// Register it both as a request interceptor and as a response
+/// validator. The same value always gets the same placeholder within
+/// a request; `PromptResponse::metadata` maps the placeholders back to
+/// the values.
+#[derive(Debug, Clone)]
+pub struct PiiRedactor
+{   /// Label and pattern, applied in order
+    patterns: Vec<(&'static str, Regex)>
+}
+
+impl PiiRedactor
+{   /// Redactor for the kinds enabled in `config`; a custom pattern
+    /// that is not a valid regex is an `InvalidConfiguration`
+    pub fn new(config: PiiConfig) -> Result<Self, crate::error::Error>
+    {   let compile = |pattern: &str| Regex::new(pattern).map_err(|e| {
+          crate::error::Error::InvalidConfiguration(
+            format!("PII pattern {}: {}", pattern, e)
+          )
+        });
+        let mut patterns = vec![];
+        // Emails first, their digits must not be taken for phones;
+        // SSNs and cards before the looser phone pattern
+        let builtin = [
+          (config.redact_emails, "EMAIL", EMAIL_PATTERN),
+          (config.redact_ssns, "SSN", SSN_PATTERN),
+          (config.redact_cards, "CARD", CARD_PATTERN),
+          (config.redact_ips, "IP", IP_PATTERN),
+          (config.redact_phones, "PHONE", PHONE_PATTERN),
+        ];
+        for (enabled, label, pattern) in builtin
+        {   if enabled
+            {   patterns.push((label, compile(pattern)?));
+            }
+        }
+        for pattern in &config.custom_patterns
+        {   patterns.push(("CUSTOM", compile(pattern)?));
+        }
+        Ok(PiiRedactor { patterns })
+    }
+
+    /// Replace the personal data in `text`, adding new placeholders to
+    /// `redactions`
+    pub fn redact(
+      &self
+    , text: &str
+    , redactions: &mut HashMap<String, String>
+    ) -> String
+    {   let mut text = text.to_string();
+        for (label, pattern) in &self.patterns
+        {   let mut redacted = String::with_capacity(text.len());
+            let mut last = 0;
+            for found in pattern.find_iter(&text)
+            {   if *label == "CARD" && !luhn_valid(found.as_str())
+                {   continue;
+                }
+                redacted.push_str(&text[last..found.start()]);
+                redacted.push_str(&placeholder(label, found.as_str(), redactions));
+                last = found.end();
+            }
+            redacted.push_str(&text[last..]);
+            text = redacted;
+        }
+        text
+    }
+
+    /// Put the original values back in place of their placeholders
+    pub fn unredact(text: &str, map: &HashMap<String, String>) -> String
+    {   let mut text = text.to_string();
+        for (placeholder, value) in map
+        {   text = text.replace(placeholder.as_str(), value);
+        }
+        text
+    }
+}
+
+/// Placeholder of `value`, reusing the one it already has
+fn placeholder(
+  label: &str
+, value: &str
+, redactions: &mut HashMap<String, String>
+) -> String
+{   if let Some((existing, _)) = redactions.iter().find(|(_, v)| *v == value)
+    {   return existing.clone();
+    }
+    let prefix = format!("[{}_", label);
+    let n = redactions.keys().filter(|k| k.starts_with(&prefix)).count() + 1;
+    let placeholder = format!("{}{}]", prefix, n);
+    redactions.insert(placeholder.clone(), value.to_string());
+    placeholder
+}
+
+/// Luhn checksum of the digits of `candidate`, telling card numbers
+/// from other long digit runs
+fn luhn_valid(candidate: &str) -> bool
+{   let digits: Vec<u32> = candidate.chars()
+      .filter_map(|c| c.to_digit(10))
+      .collect();
+    let sum: u32 = digits.iter()
+      .rev()
+      .enumerate()
+      .map(|(i, d)| match i % 2
+      {   0 => *d
+        , _ if *d > 4 => d * 2 - 9
+        , _ => d * 2
+      })
+      .sum();
+    sum.is_multiple_of(10)
+}
+
+impl RequestInterceptor for PiiRedactor
+{   fn intercept(&self, request: &mut crate::providers::ChatRequest)
+    {   for message in &mut request.messages
+        {   message.content = self.redact(
+              &message.content,
+              &mut request.metadata.redactions
+            );
+        }
+    }
+}
+
+impl ContentValidator for PiiRedactor
+{   fn validate(&self, _response: &str) -> Result<(), ValidationError>
+    {   Ok(())
+    }
+
+    fn rewrite(&self, response: &str, metadata: &mut RequestMetadata)
+      -> Option<String>
+    {   let redacted = self.redact(response, &mut metadata.redactions);
+        (redacted != response).then_some(redacted)
+    }
+}
@@ -0,0 +1,174 @@
+// allm/src/providers/cerebras.rs
+
+//! Cerebras Inference provider
+//!
+//! Cerebras serves an OpenAI-compatible API on wafer-scale hardware,
+//! which makes it a good low-latency primary in a failover sequence.
+
+use tokio::sync::mpsc;
+use log::debug;
+
+use super::ProviderClient;
+use super::openai_compat::{
+  EndpointAuth, OpenAiCompatClient, OpenAiCompatEndpoint
+};
+
+const CEREBRAS_API_BASE: &str
+  = "https://api.cerebras.ai/v1";
+
+/// Public Cerebras client interface
+pub struct CerebrasClient
+{   inner: OpenAiCompatClient
+}
+
+impl CerebrasClient
+{   /// Create and spawn a new Cerebras client
+    pub fn new(
+      api_key: Option<String>
+    , api_base: Option<String>
+    , http_client: impl Into<super::ProviderHttp>
+    ) -> Self
+    {   debug!("Creating CerebrasClient");
+        let api_base = api_base
+          .unwrap_or_else(|| CEREBRAS_API_BASE.to_string());
+        let api_base = api_base.trim_end_matches('/');
+        let endpoint = OpenAiCompatEndpoint
+        {   provider: crate::Provider::Cerebras
+          , chat_url: format!("{}/chat/completions", api_base)
+          , models_url: Some(format!("{}/models", api_base))
+          , auth: EndpointAuth::Bearer
+        };
+        CerebrasClient
+        {   inner: OpenAiCompatClient::new(
+              endpoint, api_key, http_client
+            )
+        }
+    }
+
+    /// Create a client from its provider configuration
+    pub fn from_config(
+      config: &crate::config::ProviderConfig
+    , http_config: &crate::config::HttpClientConfig
+    ) -> Result<Self, crate::error::Error>
+    {   let http_client
+          = super::build_http_client(Some(config), http_config)?;
+        Ok(CerebrasClient::new(
+          config.api_key.clone(),
+          config.api_base.clone(),
+          http_client
+        ))
+    }
+
+    /// Shutdown the client
+    pub async fn shutdown(self)
+      -> Result<(), crate::error::Error>
+    {   self.inner.shutdown().await
+    }
+}
+
+impl ProviderClient for CerebrasClient
+{   fn provider(&self) -> crate::Provider
+    {   crate::Provider::Cerebras
+    }
+
+    fn is_alive(&self) -> bool
+    {   self.inner.is_alive()
+    }
+
+    fn rate_limit_status(&self) -> crate::utils::rate_limiter::RateLimitStatus
+    {   self.inner.rate_limit_status()
+    }
+
+    fn send_chat(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::SendPromptReplySender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat(request, reply)
+    }
+
+    fn send_chat_response(
+      &self
+    , request: super::ChatRequest
+    , reply: super::ChatResponseSender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat_response(request, reply)
+    }
+
+    fn send_chat_stream(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::StreamReplySender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat_stream(request, reply)
+    }
+
+    fn request_body(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<serde_json::Value, crate::error::Error>
+    {   self.inner.request_body(request)
+    }
+
+    fn get_available_models(
+      &self
+    , reply: mpsc::UnboundedSender<
+        Result<Vec<String>, crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.get_available_models(reply)
+    }
+
+    fn set_api_key(
+      &self
+    , model: Option<String>
+    , key: String
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.set_api_key(model, key, reply)
+    }
+
+    fn set_key_provider(
+      &self
+    , model: Option<String>
+    , key_provider: crate::auth::SharedKeyProvider
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.set_key_provider(model, key_provider, reply)
+    }
+}
+
+/// Default model info for Cerebras.
+///
+/// `llama3.1-8b` generates on the order of 2000 tokens/s, so it is
+/// the cheapest way to get a fast first answer.
+pub fn default_model_info() -> crate::ModelInfo
+{   crate::ModelInfo
+    {   name: "llama3.1-8b".to_string()
+      , max_context_tokens: 8192
+      , max_response_tokens: 8192
+      , can_save_context: false
+      , input_modalities: crate::ModelModalities
+        {   supported: vec![
+              crate::InputModality::Single(
+                crate::BaseModality::Text
+              )
+            ]
+        }
+      , supports_streaming: true
+      , supports_tools: true
+      , supports_logprobs: true
+      , supports_reproducible_outputs: true
+      , supports_raw_completion: false
+      , provider: crate::Provider::Cerebras
+      , default_system_prompt: None
+      , supported_file_extensions: None
+      , cost_per_million_input_tokens: Some(0.10)
+      , cost_per_million_output_tokens: Some(0.10)
+      , is_available: true
+    }
+}
@@ -0,0 +1,174 @@
+// allm/src/providers/cloudflare.rs
+
+//! Cloudflare Workers AI provider
+//!
+//! Workers AI exposes an OpenAI-compatible endpoint scoped to an
+//! account, so requests need both an account ID and an API token.
+
+use tokio::sync::mpsc;
+use log::{debug, error};
+
+use super::ProviderClient;
+use super::openai_compat::{
+  EndpointAuth, OpenAiCompatClient, OpenAiCompatEndpoint
+};
+
+const CLOUDFLARE_API_BASE: &str
+  = "https://api.cloudflare.com/client/v4";
+
+/// Build the account-scoped chat completions URL
+pub fn chat_url(api_base: &str, account_id: &str) -> String
+{   format!(
+      "{}/accounts/{}/ai/v1/chat/completions",
+      api_base.trim_end_matches('/'),
+      account_id
+    )
+}
+
+/// Public Cloudflare Workers AI client interface
+pub struct CloudflareClient
+{   account_id: String
+  , inner: OpenAiCompatClient
+}
+
+impl CloudflareClient
+{   /// Create and spawn a new Cloudflare client
+    pub fn new(
+      account_id: String
+    , api_token: Option<String>
+    , api_base: Option<String>
+    , http_client: impl Into<super::ProviderHttp>
+    ) -> Self
+    {   debug!("Creating CloudflareClient for account {}", account_id);
+        let api_base = api_base
+          .unwrap_or_else(|| CLOUDFLARE_API_BASE.to_string());
+        let endpoint = OpenAiCompatEndpoint
+        {   provider: crate::Provider::CloudflareAi
+          , chat_url: chat_url(&api_base, &account_id)
+          , models_url: None
+          , auth: EndpointAuth::Bearer
+        };
+        CloudflareClient
+        {   account_id
+          , inner: OpenAiCompatClient::new(
+              endpoint, api_token, http_client
+            )
+        }
+    }
+
+    /// Create a client from its provider configuration.
+    /// Fails if `account_id` is not set.
+    pub fn from_config(
+      config: &crate::config::ProviderConfig
+    , http_config: &crate::config::HttpClientConfig
+    ) -> Result<Self, crate::error::Error>
+    {   let account_id = required_account_id(config)?;
+        let http_client
+          = super::build_http_client(Some(config), http_config)?;
+        Ok(CloudflareClient::new(
+          account_id,
+          config.api_key.clone(),
+          config.api_base.clone(),
+          http_client
+        ))
+    }
+
+    /// Account the client is scoped to
+    pub fn account_id(&self) -> &str
+    {   &self.account_id
+    }
+
+    /// Shutdown the client
+    pub async fn shutdown(self)
+      -> Result<(), crate::error::Error>
+    {   self.inner.shutdown().await
+    }
+}
+
+/// `account_id` of a Cloudflare configuration, which is required
+pub(crate) fn required_account_id(
+  config: &crate::config::ProviderConfig
+) -> Result<String, crate::error::Error>
+{   config.account_id.clone().ok_or_else(|| {
+      error!("Cloudflare config without account_id");
+      crate::error::Error::InvalidConfiguration(
+        "Cloudflare requires account_id".to_string()
+      )
+    })
+}
+
+impl ProviderClient for CloudflareClient
+{   fn provider(&self) -> crate::Provider
+    {   crate::Provider::CloudflareAi
+    }
+
+    fn is_alive(&self) -> bool
+    {   self.inner.is_alive()
+    }
+
+    fn rate_limit_status(&self) -> crate::utils::rate_limiter::RateLimitStatus
+    {   self.inner.rate_limit_status()
+    }
+
+    fn send_chat(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::SendPromptReplySender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat(request, reply)
+    }
+
+    fn send_chat_response(
+      &self
+    , request: super::ChatRequest
+    , reply: super::ChatResponseSender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat_response(request, reply)
+    }
+
+    fn send_chat_stream(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::StreamReplySender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat_stream(request, reply)
+    }
+
+    fn request_body(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<serde_json::Value, crate::error::Error>
+    {   self.inner.request_body(request)
+    }
+
+    fn get_available_models(
+      &self
+    , reply: mpsc::UnboundedSender<
+        Result<Vec<String>, crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.get_available_models(reply)
+    }
+
+    fn set_api_key(
+      &self
+    , model: Option<String>
+    , key: String
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.set_api_key(model, key, reply)
+    }
+
+    fn set_key_provider(
+      &self
+    , model: Option<String>
+    , key_provider: crate::auth::SharedKeyProvider
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.set_key_provider(model, key_provider, reply)
+    }
+}
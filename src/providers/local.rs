@@ -0,0 +1,71 @@
+// allm/src/providers/local.rs
+
+//! Local and self-hosted models (Ollama, LM Studio, vLLM)
+//!
+//! These servers expose an OpenAI-compatible API, so the shared
+//! actor serves them as is. They rarely check API keys; when none is
+//! configured a placeholder is sent. A secured deployment can check
+//! signed requests instead (see `new_with_signing`).
+
+use log::debug;
+
+use super::openai_compat::{
+  EndpointAuth, OpenAiCompatClient, OpenAiCompatEndpoint
+};
+
+/// Ollama's OpenAI-compatible API on its default port
+pub const LOCAL_API_BASE: &str = "http://localhost:11434/v1";
+
+/// Key sent when the configuration has none
+const PLACEHOLDER_KEY: &str = "local";
+
+/// Create and spawn a client for the server at `api_base`
+/// (`LOCAL_API_BASE` if unset)
+pub fn new_client(
+  api_key: Option<String>
+, api_base: Option<String>
+, http_client: impl Into<super::ProviderHttp>
+) -> OpenAiCompatClient
+{   let api_base = api_base
+      .unwrap_or_else(|| LOCAL_API_BASE.to_string());
+    let api_base = api_base.trim_end_matches('/');
+    debug!("Creating local client for {}", api_base);
+    let endpoint = OpenAiCompatEndpoint
+    {   provider: crate::Provider::Local
+      , chat_url: format!("{}/chat/completions", api_base)
+      , models_url: Some(format!("{}/models", api_base))
+      , auth: EndpointAuth::Bearer
+    };
+    OpenAiCompatClient::new(
+      endpoint,
+      Some(api_key.unwrap_or_else(|| PLACEHOLDER_KEY.to_string())),
+      http_client
+    )
+}
+
+/// Create and spawn a client for a secured self-hosted server at
+/// `api_base`, whose every request `signer` signs
+pub fn new_with_signing(
+  api_base: &str
+, signer: crate::utils::auth::SharedRequestSigner
+) -> OpenAiCompatClient
+{   let http_client = super::ProviderHttp
+    {   signer: Some(signer)
+      , ..Default::default()
+    };
+    new_client(None, Some(api_base.to_string()), http_client)
+}
+
+/// Create a client from its provider configuration
+pub fn from_config(
+  config: &crate::config::ProviderConfig
+, http_config: &crate::config::HttpClientConfig
+) -> Result<OpenAiCompatClient, crate::error::Error>
+{   let http_client
+      = super::build_http_client(Some(config), http_config)?;
+    Ok(new_client(
+      config.api_key.clone(),
+      config.api_base.clone(),
+      http_client
+    ))
+}
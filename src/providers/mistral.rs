@@ -3,20 +3,73 @@
 
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
-use log::{debug, trace, error, info};
+use log::{debug, trace, error, info, warn};
 use std::collections::HashMap;
 
+use crate::auth::{SharedKeyProvider, StaticKeyProvider};
+use crate::utils::logger::ProviderLogger;
+use crate::utils::telemetry::GenAiSpan;
+use crate::utils::version::ModelVersionResolver;
+use tracing::Instrument;
+
 const MISTRAL_API_BASE: &str 
   = "https://api.mistral.ai/v1";
 
 // ===== Message Types =====
 
+/// Author of a chat message, serialized as its lowercase wire name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role
+{   System
+  , User
+  , Assistant
+  , /// Result of a tool call
+    Tool
+}
+
+impl Role
+{   /// Role name on the wire
+    pub fn as_str(&self) -> &'static str
+    {   match self
+        {   Role::System => "system"
+          , Role::User => "user"
+          , Role::Assistant => "assistant"
+          , Role::Tool => "tool"
+        }
+    }
+}
+
+impl std::fmt::Display for Role
+{   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {   f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage
-{   pub role: String
+{   pub role: Role
   , pub content: String
 }
 
+impl ChatMessage
+{   pub fn new(role: Role, content: impl Into<String>) -> Self
+    {   ChatMessage { role, content: content.into() }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self
+    {   ChatMessage::new(Role::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self
+    {   ChatMessage::new(Role::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self
+    {   ChatMessage::new(Role::Assistant, content)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MistralChatRequest
 {   pub model: String
@@ -25,13 +78,54 @@ pub struct MistralChatRequest
     pub max_tokens: Option<usize>
   , #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>
   , #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<bool>
+  , /// Completions to generate, one choice each
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<usize>
+}
+
+/// Body posted to `/completions`: a raw prompt, no `messages`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MistralCompletionRequest
+{   pub model: String
+  , pub prompt: String
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>
+  , pub stream: bool
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MistralCompletionResponse
+{   pub choices: Vec<CompletionChoice>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompletionChoice
+{   pub text: String
+}
+
+/// Name starts of the Mistral models serving raw completions, for
+/// models missing from the catalog
+pub const RAW_COMPLETION_MODELS: &[&str] = &["codestral"];
+
+/// True for a model `RAW_COMPLETION_MODELS` knows to take raw
+/// prompts
+pub fn supports_raw_completion(model: &str) -> bool
+{   RAW_COMPLETION_MODELS.iter().any(|start| model.starts_with(start))
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct MistralChatResponse
 {   pub choices: Vec<Choice>
+  , #[serde(default)]
+    pub usage: Option<super::TokenUsage>
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -52,15 +146,67 @@ pub struct ModelData
     pub owned_by: Option<String>
 }
 
+/// Body posted to `/chat/completions`, with defaults filled in
+pub fn chat_body(request: super::ChatRequest, stream: bool)
+  -> MistralChatRequest
+{   let super::ChatRequest
+    {   messages, model, max_tokens, temperature, stop, n, ..
+    } = request;
+    MistralChatRequest
+    {   model
+      , messages
+      , max_tokens: Some(
+          max_tokens.unwrap_or(super::DEFAULT_MAX_TOKENS)
+        )
+      , temperature: Some(
+          temperature.unwrap_or(super::DEFAULT_TEMPERATURE)
+        )
+      , stop
+      , stream: Some(stream)
+      , n
+    }
+}
+
+/// `chat_body` as posted, with the request's `extra_params`
+/// merged in
+pub fn chat_json(request: super::ChatRequest, stream: bool)
+  -> Result<serde_json::Value, crate::error::Error>
+{   super::check_choice_count(&request)?;
+    let extra = request.extra_params.clone();
+    let prefilled = super::prefill(&request).is_some();
+    let mut body = super::to_json_body(&chat_body(request, stream))?;
+    // Mistral continues an assistant message marked as a prefix
+    if let Some(last) = body.get_mut("messages")
+      .and_then(|messages| messages.as_array_mut())
+      .and_then(|messages| messages.last_mut())
+      .filter(|_| prefilled)
+    {   last["prefix"] = serde_json::Value::Bool(true);
+    }
+    if let Some(fields) = body.as_object_mut()
+    {   super::merge_extra_params(fields, extra);
+    }
+    Ok(body)
+}
+
 // ===== Mistral Client Actor =====
 
 /// Commands for MistralClient actor
 pub enum MistralCommand
 {   SendPrompt
-    {   prompt: String
-      , model: String
+    {   request: super::ChatRequest
       , reply: mpsc::UnboundedSender<crate::SendPromptReply>
     }
+  , SendChat
+    {   request: super::ChatRequest
+      , reply: super::ChatResponseSender
+    }
+  , SendStream
+    {   request: super::ChatRequest
+      , reply: crate::StreamReplySender
+    }
+  , SendCompletion
+    {   args: crate::SendCompletionArgs
+    }
   , GetModels
     {   reply: mpsc::UnboundedSender
         <Result<Vec<String>, crate::error::Error>>
@@ -71,31 +217,119 @@ pub enum MistralCommand
       , reply: mpsc::UnboundedSender
         <Result<(), crate::error::Error>>
     }
+  , SetKeyProvider
+    {   model: Option<String>
+      , key_provider: SharedKeyProvider
+      , reply: mpsc::UnboundedSender
+        <Result<(), crate::error::Error>>
+    }
   , Shutdown
 }
 
 /// Mistral client state
 pub struct MistralClientState
-{   master_key: Option<String>
-  , model_keys: HashMap<String, String>
-  , http_client: reqwest::Client
+{   master_key: Option<SharedKeyProvider>
+  , model_keys: HashMap<String, SharedKeyProvider>
+  , http_client: super::ProviderHttp
+  , api_base: String
+  , logger: ProviderLogger
+  , /// Pins of floating model names, when `pin_model_versions` is on
+    versions: Option<ModelVersionResolver>
 }
 
 impl MistralClientState
 {   pub fn new(master_key: Option<String>) -> Self
+    {   MistralClientState::with_http_client(
+          master_key,
+          None,
+          super::ProviderHttp::default()
+        )
+    }
+
+    /// Create state against a custom API base and HTTP client
+    pub fn with_http_client(
+      master_key: Option<String>
+    , api_base: Option<String>
+    , http_client: impl Into<super::ProviderHttp>
+    ) -> Self
     {   debug!("Creating MistralClientState");
+        let http_client: super::ProviderHttp = http_client.into();
         MistralClientState
-        {   master_key
+        {   master_key: master_key.map(StaticKeyProvider::shared)
           , model_keys: HashMap::new()
-          , http_client: reqwest::Client::new()
+          , logger: ProviderLogger::new(
+              crate::Provider::MistralAi.name(), http_client.log_level
+            )
+          , http_client
+          , api_base: api_base
+              .unwrap_or_else(|| MISTRAL_API_BASE.to_string())
+          , versions: None
+        }
+    }
+
+    /// Pin floating model names (see `utils::version`) on first use
+    pub fn pin_model_versions(mut self) -> Self
+    {   self.versions = Some(ModelVersionResolver::new());
+        self
+    }
+
+    /// `request` with its model pinned, looking a floating name up
+    /// the first time it is used. A failed lookup sends the name as
+    /// given and is retried on the next request.
+    async fn pin_version(&mut self, mut request: super::ChatRequest)
+      -> super::ChatRequest
+    {   let Some(versions) = &self.versions else { return request };
+        if versions.needs_lookup(&request.model)
+        {   match self.fetch_model_id(&request.model).await
+            {   Ok(version) => {
+                  info!("Pinned {} to {}", request.model, version);
+                  if let Some(versions) = &mut self.versions
+                  {   versions.pin(&request.model, &version);
+                  }
+                }
+              , Err(e) => warn!("Cannot pin {}: {}", request.model, e)
+            }
+        }
+        if let Some(versions) = &self.versions
+        {   request.model = versions.resolve(&request.model);
+        }
+        request
+    }
+
+    /// Canonical id of `model`, from `GET /models/{model}`
+    async fn fetch_model_id(&self, model: &str)
+      -> Result<String, crate::error::Error>
+    {   let api_key = self.get_api_key(model).await?;
+        let builder = self.http_client
+          .get(format!("{}/models/{}", self.api_base, model))
+          .header("Authorization", format!("Bearer {}", api_key));
+        let response = self.http_client.sign(builder)?
+          .send()
+          .await
+          .inspect_err(|e| error!("Failed to fetch model {}: {}", model, e))?;
+
+        let status = response.status();
+        if !status.is_success()
+        {   let retry_after = super::retry_after(response.headers());
+            let error_text = response.text().await
+              .unwrap_or_else(|_|
+                "Unknown error".to_string()
+              );
+            return Err(super::api_error(
+              crate::Provider::MistralAi, status.as_u16(), retry_after,
+              &error_text
+            ));
         }
+        let model: ModelData = response.json().await
+          .inspect_err(|e| error!("Parse error: {}", e))?;
+        Ok(model.id)
     }
 
-    fn get_api_key(&self, model: &str) 
+    async fn get_api_key(&self, model: &str) 
       -> Result<String, crate::error::Error>
     {   if let Some(key) = self.model_keys.get(model)
         {   debug!("Using model-specific key for: {}", model);
-            return Ok(key.clone());
+            return key.current_key().await;
         }
         
         if let Some(key) = &self.master_key
@@ -103,7 +337,7 @@ impl MistralClientState
               "Using master key for model: {}", 
               model
             );
-            return Ok(key.clone());
+            return key.current_key().await;
         }
 
         error!("No API key for model: {}", model);
@@ -114,72 +348,193 @@ impl MistralClientState
 
     fn set_master_key(&mut self, key: String)
     {   debug!("Setting master key");
-        self.master_key = Some(key);
+        self.master_key = Some(StaticKeyProvider::shared(key));
     }
 
     fn set_model_key(&mut self, model: String, key: String)
     { log::debug!("Setting model key for: {}", model);
-      self.model_keys.insert(model, key);
+      self.model_keys.insert(model, StaticKeyProvider::shared(key));
     }
 
-    async fn handle_send_prompt(
+    /// Post a chat request; non-success statuses become
+    /// `Error::ApiError`
+    async fn post_chat(
       &self
-    , prompt: String
-    , model: String
-    ) -> Result<String, crate::error::Error>
-    {   debug!("Handling send_prompt for: {}", model);
-        
-        let api_key = self.get_api_key(&model)?;
-
-        let request = MistralChatRequest
-        {   model: model.clone()
-          , messages: vec![
-              ChatMessage
-              {   role: "user".to_string()
-                , content: prompt
-              }
-            ]
-          , max_tokens: Some(1024)
-          , temperature: Some(0.7)
-          , stream: Some(false)
+    , request: super::ChatRequest
+    , stream: bool
+    ) -> Result<reqwest::Response, crate::error::Error>
+    {   let api_key = self.get_api_key(&request.model).await?;
+        let request_id = request.request_id.clone();
+        let extra_headers = request.extra_headers.clone();
+
+        let request = chat_json(request, stream)?;
+
+        self.logger.log_at(
+          log::Level::Trace, format_args!("Mistral request: {:?}", request)
+        );
+
+        let url = format!("{}/chat/completions", self.api_base);
+        let builder = if stream
+        {   self.http_client.post_stream(url)
+        } else
+        {   self.http_client.post(url)
         };
-
-        trace!("Mistral request: {:?}", request);
-
-        let response = self.http_client
-          .post(format!("{}/chat/completions", MISTRAL_API_BASE))
+        let builder = super::with_extra_headers(builder, extra_headers.as_ref())?;
+        let builder = super::with_request_id(builder, request_id.as_deref())
           .header("Authorization", format!("Bearer {}", api_key))
           .header("Content-Type", "application/json")
-          .json(&request)
+          .json(&request);
+        let response = self.http_client.sign(builder)?
           .send()
           .await
-          .map_err(|e| {
-            error!("HTTP error: {}", e);
-            crate::error::Error::HttpError(e.to_string())
-          })?;
+          .inspect_err(|e| error!("HTTP error: {}", e))?;
 
         let status = response.status();
-        trace!("Mistral response status: {}", status);
+        self.http_client.observe_rate_limits(response.headers());
+        self.logger.log_at(
+          log::Level::Trace, format_args!("Mistral response status: {}", status)
+        );
 
         if !status.is_success()
-        {   let error_text = response.text().await
+        {   let retry_after = super::retry_after(response.headers());
+            let error_text = response.text().await
               .unwrap_or_else(|_| 
                 "Unknown error".to_string()
               );
             error!("Mistral API error: {}", error_text);
-            return Err(crate::error::Error::ApiError(
-              format!("Mistral error: {}", error_text)
+            return Err(super::api_error(
+              crate::Provider::MistralAi, status.as_u16(), retry_after,
+              &error_text
             ));
         }
+        Ok(response)
+    }
+
+    async fn handle_send_prompt(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<String, crate::error::Error>
+    {   self.logger.log(
+          format_args!("Handling send_prompt for: {}", request.model)
+        );
+        self.handle_send_chat(request).await.map(|r| r.text)
+    }
+
+    async fn handle_send_chat(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<super::ChatResponse, crate::error::Error>
+    {   let telemetry = GenAiSpan::chat(&crate::Provider::MistralAi, &request);
+        self.exchange_chat(request, &telemetry)
+          .instrument(telemetry.span().clone())
+          .await
+          .inspect_err(|e| telemetry.record_error(e))
+    }
 
-        let chat_response: MistralChatResponse
-          = response.json().await.map_err(|e| {
-            error!("Parse error: {}", e);
-            crate::error::Error::ParseError(e.to_string())
+    async fn exchange_chat(
+      &self
+    , request: super::ChatRequest
+    , telemetry: &GenAiSpan
+    ) -> Result<super::ChatResponse, crate::error::Error>
+    {   let return_raw = request.return_raw;
+        let model = request.model.clone();
+        let logged = self.logger.traces_exchanges()
+          .then(|| chat_json(request.clone(), false).ok())
+          .flatten();
+        let started = std::time::Instant::now();
+        let response = self.post_chat(request, false).await?;
+
+        let body: serde_json::Value = response.json().await
+          .inspect_err(|e| error!("Parse error: {}", e))?;
+        if let Some(logged) = &logged
+        {   self.logger.exchange(&model, logged, &body, started.elapsed());
+        }
+        let raw = return_raw.then(|| body.clone());
+        let chat_response: MistralChatResponse = serde_json::from_value(body)
+          .inspect_err(|e| error!("Parse error: {}", e))?;
+
+        let choices: Vec<String> = chat_response.choices.into_iter()
+          .map(|c| c.message.content)
+          .collect();
+        let text = choices.first().cloned()
+          .ok_or_else(|| {
+            error!("No choices in response");
+            crate::error::Error::NoChoicesInResponse
           })?;
+        telemetry.record_usage(chat_response.usage.as_ref());
+        let input_tokens = chat_response.usage
+          .and_then(|usage| usage.prompt_tokens);
+        Ok(super::ChatResponse
+        {   text, choices, raw, input_tokens, prompt_logprobs: None
+          , thinking: None
+        })
+    }
+
+    async fn handle_send_stream(
+      &self
+    , request: super::ChatRequest
+    , reply: &crate::StreamReplySender
+    )
+    {   debug!("Handling send_stream for: {}", request.model);
+        match self.post_chat(request, true).await
+        {   Ok(response) => super::sse::forward_stream(
+              response, self.http_client.read_timeout, reply
+            ).await
+          , Err(e) => { let _ = reply.send(Err(e)).await; }
+        }
+    }
+
+    /// Post a raw prompt to `/completions` and return the text of
+    /// its first choice
+    async fn handle_send_completion(
+      &self
+    , args: &crate::SendCompletionArgs
+    ) -> Result<String, crate::error::Error>
+    {   debug!("Handling send_completion for: {}", args.model);
+        let api_key = self.get_api_key(&args.model).await?;
+        let request = MistralCompletionRequest
+        {   model: args.model.clone()
+          , prompt: args.prompt.clone()
+          , max_tokens: args.max_tokens
+          , temperature: args.temperature
+          , stop: args.stop.clone()
+          , stream: false
+        };
+        self.logger.log_at(
+          log::Level::Trace,
+          format_args!("Mistral completion request: {:?}", request)
+        );
+
+        let builder = self.http_client
+          .post(format!("{}/completions", self.api_base))
+          .header("Authorization", format!("Bearer {}", api_key))
+          .header("Content-Type", "application/json")
+          .json(&request);
+        let response = self.http_client.sign(builder)?
+          .send()
+          .await
+          .inspect_err(|e| error!("HTTP error: {}", e))?;
+
+        let status = response.status();
+        self.http_client.observe_rate_limits(response.headers());
+        if !status.is_success()
+        {   let retry_after = super::retry_after(response.headers());
+            let error_text = response.text().await
+              .unwrap_or_else(|_| 
+                "Unknown error".to_string()
+              );
+            error!("Mistral API error: {}", error_text);
+            return Err(super::api_error(
+              crate::Provider::MistralAi, status.as_u16(), retry_after,
+              &error_text
+            ));
+        }
 
-        chat_response.choices.first()
-          .map(|c| c.message.content.clone())
+        let completion: MistralCompletionResponse = response.json().await
+          .inspect_err(|e| error!("Parse error: {}", e))?;
+        completion.choices.into_iter()
+          .next()
+          .map(|choice| choice.text)
           .ok_or_else(|| {
             error!("No choices in response");
             crate::error::Error::NoChoicesInResponse
@@ -197,37 +552,36 @@ impl MistralClientState
             crate::error::Error::MissingApiKey(
               "Mistral (master)".to_string()
             )
-          })?;
-
-        let response = self.http_client
-          .get(format!("{}/models", MISTRAL_API_BASE))
-          .header("Authorization", format!("Bearer {}", api_key))
+          })?
+          .current_key()
+          .await?;
+
+        let builder = self.http_client
+          .get(format!("{}/models", self.api_base))
+          .header("Authorization", format!("Bearer {}", api_key));
+        let response = self.http_client.sign(builder)?
           .send()
           .await
-          .map_err(|e| {
-            error!("Failed to fetch models: {}", e);
-            crate::error::Error::HttpError(e.to_string())
-          })?;
+          .inspect_err(|e| error!("Failed to fetch models: {}", e))?;
 
         let status = response.status();
         trace!("Models response status: {}", status);
 
         if !status.is_success()
-        {   let error_text = response.text().await
+        {   let retry_after = super::retry_after(response.headers());
+            let error_text = response.text().await
               .unwrap_or_else(|_|
                 "Unknown error".to_string()
               );
             error!("Failed to get models: {}", error_text);
-            return Err(crate::error::Error::ApiError(
-              error_text
+            return Err(super::api_error(
+              crate::Provider::MistralAi, status.as_u16(), retry_after,
+              &error_text
             ));
         }
 
-        let models_response: MistralModelsResponse
-          = response.json().await.map_err(|e| {
-            error!("Parse error: {}", e);
-            crate::error::Error::ParseError(e.to_string())
-          })?;
+        let models_response: MistralModelsResponse = response.json().await
+          .inspect_err(|e| error!("Parse error: {}", e))?;
 
         let model_names: Vec<String>
           = models_response.data
@@ -255,11 +609,30 @@ impl MistralClientState
       log::debug!("mistral.rs::handle_set_api_key OK EXIT!");
       Ok(())
     }
+
+    fn handle_set_key_provider(
+      &mut self
+    , model_opt: Option<String>
+    , key_provider: SharedKeyProvider
+    ) -> Result<(), crate::error::Error>
+    {   match model_opt
+        {   Some(model) => {
+              debug!("Setting model key provider for: {}", model);
+              self.model_keys.insert(model, key_provider);
+            }
+          , None => {
+              debug!("Setting master key provider");
+              self.master_key = Some(key_provider);
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Public Mistral client interface
 pub struct MistralClient
 {   tx: mpsc::UnboundedSender<MistralCommand>
+  , rate_limits: crate::utils::rate_limiter::SharedRateLimitStatus
   , _task: tokio::task::JoinHandle<()>
 }
 
@@ -271,16 +644,50 @@ impl MistralClient
         crate::error::Error
       >>
     ) -> Self
+    {   MistralClient::spawn(MistralClientState::new(api_key))
+    }
+
+    /// Create a client from its provider configuration
+    pub fn from_config(
+      config: &crate::config::ProviderConfig
+    , http_config: &crate::config::HttpClientConfig
+    ) -> Result<Self, crate::error::Error>
+    {   MistralClient::from_config_with_pinning(config, http_config, false)
+    }
+
+    /// `from_config`, with floating model names pinned on first use
+    /// when `pin_model_versions` is set
+    pub fn from_config_with_pinning(
+      config: &crate::config::ProviderConfig
+    , http_config: &crate::config::HttpClientConfig
+    , pin_model_versions: bool
+    ) -> Result<Self, crate::error::Error>
+    {   let http_client
+          = super::build_http_client(Some(config), http_config)?;
+        let state = MistralClientState::with_http_client(
+          config.api_key.clone(),
+          config.api_base.clone(),
+          http_client
+        );
+        Ok(MistralClient::spawn(match pin_model_versions
+        {   true => state.pin_model_versions()
+          , false => state
+        }))
+    }
+
+    fn spawn(state: MistralClientState) -> Self
     {   debug!("Creating MistralClient");
         let (cmd_tx, cmd_rx)
           = mpsc::unbounded_channel();
+        let rate_limits = state.http_client.rate_limits.clone();
 
         let _task = tokio::spawn(async move {
-          run_mistral_loop(cmd_rx, api_key).await;
+          run_mistral_loop(cmd_rx, state).await;
         });
 
         MistralClient
         {   tx: cmd_tx
+          , rate_limits
           , _task
         }
     }
@@ -295,8 +702,7 @@ impl MistralClient
     {   debug!("send_prompt queued for model: {}", model);
         
         self.tx.send(MistralCommand::SendPrompt {
-          prompt,
-          model,
+          request: super::ChatRequest::from_prompt(prompt, model),
           reply,
         }).map_err(|_| {
           error!("Mistral client disconnected");
@@ -361,25 +767,189 @@ impl MistralClient
     }
 }
 
+impl super::ProviderClient for MistralClient
+{   fn provider(&self) -> crate::Provider
+    {   crate::Provider::MistralAi
+    }
+
+    fn is_alive(&self) -> bool
+    {   !self._task.is_finished()
+    }
+
+    fn rate_limit_status(&self) -> crate::utils::rate_limiter::RateLimitStatus
+    {   self.rate_limits.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    fn send_chat(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::SendPromptReplySender
+    ) -> Result<(), crate::error::Error>
+    {   debug!("send_chat queued for model: {}", request.model);
+        self.tx.send(MistralCommand::SendPrompt {
+          request,
+          reply,
+        }).map_err(|_| {
+          error!("Mistral client disconnected");
+          crate::error::Error::Other(
+            "Mistral client disconnected".to_string()
+          )
+        })
+    }
+
+    fn send_chat_response(
+      &self
+    , request: super::ChatRequest
+    , reply: super::ChatResponseSender
+    ) -> Result<(), crate::error::Error>
+    {   debug!("send_chat_response queued for model: {}", request.model);
+        self.tx.send(MistralCommand::SendChat {
+          request,
+          reply,
+        }).map_err(|_| {
+          error!("Mistral client disconnected");
+          crate::error::Error::Other(
+            "Mistral client disconnected".to_string()
+          )
+        })
+    }
+
+    fn request_body(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<serde_json::Value, crate::error::Error>
+    {   chat_json(request, false)
+    }
+
+    fn send_completion(&self, args: crate::SendCompletionArgs)
+      -> Result<(), crate::error::Error>
+    {   debug!("send_completion queued for model: {}", args.model);
+        self.tx.send(MistralCommand::SendCompletion {
+          args,
+        }).map_err(|_| {
+          error!("Mistral client disconnected");
+          crate::error::Error::Other(
+            "Mistral client disconnected".to_string()
+          )
+        })
+    }
+
+    fn send_chat_stream(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::StreamReplySender
+    ) -> Result<(), crate::error::Error>
+    {   debug!("send_chat_stream queued for model: {}", request.model);
+        self.tx.send(MistralCommand::SendStream {
+          request,
+          reply,
+        }).map_err(|_| {
+          error!("Mistral client disconnected");
+          crate::error::Error::Other(
+            "Mistral client disconnected".to_string()
+          )
+        })
+    }
+
+    fn get_available_models(
+      &self
+    , reply: mpsc::UnboundedSender<
+        Result<Vec<String>, crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   debug!("get_available_models queued");
+        self.tx.send(MistralCommand::GetModels {
+          reply,
+        }).map_err(|_| {
+          error!("Mistral client disconnected");
+          crate::error::Error::Other(
+            "Mistral client disconnected".to_string()
+          )
+        })
+    }
+
+    fn set_api_key(
+      &self
+    , model: Option<String>
+    , key: String
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   debug!("set_api_key queued for model: {:?}", model);
+        self.tx.send(MistralCommand::SetApiKey {
+          model,
+          key,
+          reply,
+        }).map_err(|_| {
+          error!("Mistral client disconnected");
+          crate::error::Error::Other(
+            "Mistral client disconnected".to_string()
+          )
+        })
+    }
+
+    fn set_key_provider(
+      &self
+    , model: Option<String>
+    , key_provider: SharedKeyProvider
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   debug!("set_key_provider queued for model: {:?}", model);
+        self.tx.send(MistralCommand::SetKeyProvider {
+          model,
+          key_provider,
+          reply,
+        }).map_err(|_| {
+          error!("Mistral client disconnected");
+          crate::error::Error::Other(
+            "Mistral client disconnected".to_string()
+          )
+        })
+    }
+}
+
 /// Main mistral event loop
 async fn run_mistral_loop(
   mut cmd_rx: mpsc::UnboundedReceiver<MistralCommand>
-, api_key: Option<String>
+, mut state: MistralClientState
 )
 {   debug!("Starting Mistral client loop");
-    let mut state = MistralClientState::new(api_key);
 
     loop
     { match cmd_rx.recv().await
       {   Some(MistralCommand::SendPrompt {
-            prompt, model, reply
+            request, reply
           }) => {
             debug!("Processing SendPrompt");
+            let request = state.pin_version(request).await;
             let result = state
-              .handle_send_prompt(prompt, model)
+              .handle_send_prompt(request)
               .await;
             let _ = reply.send(result);
           }
+        , Some(MistralCommand::SendChat {
+            request, reply
+          }) => {
+            debug!("Processing SendChat");
+            let request = state.pin_version(request).await;
+            let result = state.handle_send_chat(request).await;
+            let _ = reply.send(result);
+          }
+        , Some(MistralCommand::SendStream {
+            request, reply
+          }) => {
+            debug!("Processing SendStream");
+            let request = state.pin_version(request).await;
+            state.handle_send_stream(request, &reply).await;
+          }
+        , Some(MistralCommand::SendCompletion { args }) => {
+            debug!("Processing SendCompletion");
+            let result = state.handle_send_completion(&args).await;
+            let _ = args.reply.send(result);
+          }
         , Some(MistralCommand::GetModels { reply }) => {
             debug!("Processing GetModels");
             let result = state.handle_get_models().await;
@@ -394,6 +964,13 @@ async fn run_mistral_loop(
               .await;
             let _ = reply.send(result);
           }
+        , Some(MistralCommand::SetKeyProvider {
+            model, key_provider, reply
+          }) => {
+            debug!("Processing SetKeyProvider for: {:?}", model);
+            let result = state.handle_set_key_provider(model, key_provider);
+            let _ = reply.send(result);
+          }
         , Some(MistralCommand::Shutdown) => {
             info!("Mistral client shutting down");
             break;
@@ -422,6 +999,9 @@ pub fn default_model_info() -> crate::ModelInfo
         }
       , supports_streaming: true
       , supports_tools: true
+      , supports_logprobs: false
+      , supports_reproducible_outputs: false
+      , supports_raw_completion: false
       , provider: crate::Provider::MistralAi
       , default_system_prompt: None
       , supported_file_extensions: None
@@ -1,11 +1,793 @@
 //! LLM provider implementations
 
 pub mod mistral;
+pub mod openai_compat;
+pub mod cloudflare;
+pub mod cerebras;
+pub mod replicate;
+pub mod openai;
+pub mod local;
+pub mod together;
+pub mod sse;
+
+use std::collections::HashMap;
+use tokio::sync::mpsc;
 
 // Re-export for convenience
 pub use mistral::MistralClient;
+pub use openai_compat::OpenAiCompatClient;
+pub use cloudflare::CloudflareClient;
+pub use cerebras::CerebrasClient;
+pub use replicate::ReplicateClient;
+pub use openai::OpenAIClient;
+pub use together::TogetherClient;
 
 // Future provider modules:
-// pub mod openai;
 // pub mod anthropic;
-// pub mod google;
\ No newline at end of file
+// pub mod google;
+
+/// Provider-neutral chat call handed to a provider actor.
+/// Unset sampling parameters get the provider's defaults.
+#[derive(Debug, Clone, Default)]
+pub struct ChatRequest
+{   pub messages: Vec<mistral::ChatMessage>
+  , pub model: String
+  , pub max_tokens: Option<usize>
+  , pub temperature: Option<f32>
+  , /// Strings that end generation, see `check_stop_sequences`
+    pub stop: Option<Vec<String>>
+  , /// Keep the provider's response body in `ChatResponse::raw`
+    pub return_raw: bool
+  , /// Body fields passed through as given, see `merge_extra_params`
+    pub extra_params: Option<serde_json::Map<String, serde_json::Value>>
+  , /// Sent as `X-Request-Id` by providers that accept one
+    pub request_id: Option<String>
+  , /// Filled by request interceptors, returned with the reply
+    pub metadata: crate::middleware::RequestMetadata
+  , /// Completions to generate; see `check_choice_count`
+    pub n: Option<usize>
+  , /// Headers for this request alone, see `with_extra_headers`
+    pub extra_headers: Option<HashMap<String, String>>
+}
+
+/// Reply to a chat request sent with `send_chat_response`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatResponse
+{   pub text: String
+  , /// Text of every choice, in order; `text` is the first
+    pub choices: Vec<String>
+  , /// Untouched response body, when `ChatRequest::return_raw`
+    pub raw: Option<serde_json::Value>
+  , /// Prompt tokens counted by the provider, when it reports usage
+    pub input_tokens: Option<usize>
+  , /// Logprobs of the echoed prompt tokens, see `prompt_logprobs`
+    pub prompt_logprobs: Option<Vec<crate::request::TokenLogprob>>
+  , /// Thinking of reasoning models that return it apart from the
+    /// text, see `reasoning_content`
+    pub thinking: Option<String>
+}
+
+impl ChatResponse
+{   /// Single-choice reply without body or usage
+    pub fn from_text(text: String) -> Self
+    {   ChatResponse
+        {   choices: vec![text.clone()]
+          , text
+          , raw: None
+          , input_tokens: None
+          , prompt_logprobs: None
+          , thinking: None
+        }
+    }
+}
+
+/// Thinking of the first choice, for APIs that return it apart from
+/// the answer: DeepSeek's `message.reasoning_content`, or the
+/// `message.reasoning` of gateways such as OpenRouter
+pub fn reasoning_content(body: &serde_json::Value) -> Option<String>
+{   let message = body.get("choices")?.get(0)?.get("message")?;
+    message.get("reasoning_content")
+      .or_else(|| message.get("reasoning"))?
+      .as_str()
+      .filter(|thinking| !thinking.trim().is_empty())
+      .map(str::to_string)
+}
+
+/// Tokens of the prompt a request with `echo: true` and `logprobs`
+/// got back, with their logprobs: Together's
+/// `prompt[0].logprobs.{tokens, token_logprobs}`
+pub fn prompt_logprobs(body: &serde_json::Value)
+  -> Option<Vec<crate::request::TokenLogprob>>
+{   let logprobs = body.get("prompt")?.get(0)?.get("logprobs")?;
+    let tokens = logprobs.get("tokens")?.as_array()?;
+    let token_logprobs = logprobs.get("token_logprobs")?.as_array()?;
+    Some(tokens.iter()
+      .zip(token_logprobs)
+      .map(|(token, logprob)| crate::request::TokenLogprob
+      {   token: token.as_str().unwrap_or_default().to_string()
+        , logprob: logprob.as_f64()
+      })
+      .collect())
+}
+
+/// `usage` object of an OpenAI-style chat completion
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct TokenUsage
+{   #[serde(default)]
+    pub prompt_tokens: Option<usize>
+  , #[serde(default)]
+    pub completion_tokens: Option<usize>
+}
+
+pub type ChatResponseSender = mpsc::UnboundedSender<
+  Result<ChatResponse, crate::error::Error>
+>;
+
+/// Fail with `InvalidConfiguration` when `request.n` asks for no
+/// completion at all
+pub fn check_choice_count(request: &ChatRequest)
+  -> Result<(), crate::error::Error>
+{   match request.n
+    {   Some(0) => Err(crate::error::Error::InvalidConfiguration(
+          "n must be at least 1".to_string()
+        ))
+      , _ => Ok(())
+    }
+}
+
+impl ChatRequest
+{   /// Put `few_shot`'s examples just before the last message, the
+    /// prompt
+    pub fn add_examples(&mut self, few_shot: &crate::request::FewShot)
+    {   let prompt = self.messages.len().saturating_sub(1);
+        self.messages.splice(prompt..prompt, few_shot.messages());
+    }
+
+    /// Single user message
+    pub fn from_prompt(prompt: String, model: String) -> Self
+    {   ChatRequest
+        {   messages: vec![mistral::ChatMessage::user(prompt)]
+          , model
+          , ..Default::default()
+        }
+    }
+
+    /// Whether `extra_params` asks the provider to `echo` the
+    /// prompt, whose logprobs only come with a full `ChatResponse`
+    pub fn echoes_prompt(&self) -> bool
+    {   self.extra_params.as_ref()
+          .and_then(|extra| extra.get("echo"))
+          .and_then(serde_json::Value::as_bool)
+          .unwrap_or(false)
+    }
+}
+
+/// Serialize a provider request body
+pub(crate) fn to_json_body<T: serde::Serialize>(body: &T)
+  -> Result<serde_json::Value, crate::error::Error>
+{   serde_json::to_value(body)
+      .map_err(|e| crate::error::Error::ParseError(e.to_string()))
+}
+
+/// Add `extra` fields to a request body. Fields allm sets (model,
+/// messages, sampling parameters) take precedence; an extra field
+/// only fills a key the body does not have.
+pub(crate) fn merge_extra_params(
+  body: &mut serde_json::Map<String, serde_json::Value>
+, extra: Option<serde_json::Map<String, serde_json::Value>>
+)
+{   for (key, value) in extra.into_iter().flatten()
+    {   if body.contains_key(&key)
+        {   log::warn!("Ignoring extra param {}: set by allm", key);
+            continue;
+        }
+        body.insert(key, value);
+    }
+}
+
+/// Header carrying `ChatRequest::request_id`
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Add the `REQUEST_ID_HEADER` of a request that has an id
+pub(crate) fn with_request_id(
+  builder: reqwest::RequestBuilder
+, request_id: Option<&str>
+) -> reqwest::RequestBuilder
+{   match request_id
+    {   Some(id) => builder.header(REQUEST_ID_HEADER, id)
+      , None => builder
+    }
+}
+
+/// Add a request's `extra_headers`, replacing provider headers of
+/// the same name; a name or value HTTP does not allow is
+/// `Error::InvalidConfiguration`
+pub(crate) fn with_extra_headers(
+  builder: reqwest::RequestBuilder
+, extra_headers: Option<&HashMap<String, String>>
+) -> Result<reqwest::RequestBuilder, crate::error::Error>
+{   match extra_headers
+    {   Some(headers) => Ok(builder.headers(header_map(headers)?))
+      , None => Ok(builder)
+    }
+}
+
+/// `headers` as a `HeaderMap`, failing on the first invalid one
+fn header_map(headers: &HashMap<String, String>)
+  -> Result<reqwest::header::HeaderMap, crate::error::Error>
+{   let mut map = reqwest::header::HeaderMap::new();
+    for (name, value) in headers
+    {   let invalid = |e: &dyn std::fmt::Display| {
+          crate::error::Error::InvalidConfiguration(
+            format!("header {:?}: {}", name, e)
+          )
+        };
+        let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+          .map_err(|e| invalid(&e))?;
+        let value = reqwest::header::HeaderValue::from_str(value)
+          .map_err(|e| invalid(&e))?;
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+/// `max_tokens` sent when a request does not set one
+pub const DEFAULT_MAX_TOKENS: usize = 1024;
+/// `temperature` sent when a request does not set one
+pub const DEFAULT_TEMPERATURE: f32 = 0.7;
+
+/// Stop sequences OpenAI and Mistral accept per request
+pub const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Reject a request with more stop sequences than `provider` accepts
+pub fn check_stop_sequences(
+  provider: &crate::Provider
+, request: &ChatRequest
+) -> Result<(), crate::error::Error>
+{   let limited = matches!(
+      provider,
+      crate::Provider::OpenAI | crate::Provider::MistralAi
+    );
+    let count = request.stop.as_ref().map_or(0, Vec::len);
+    if limited && count > MAX_STOP_SEQUENCES
+    {   log::error!("{} stop sequences for {:?}", count, provider);
+        return Err(crate::error::Error::InvalidConfiguration(format!(
+          "{:?} accepts at most {} stop sequences, got {}",
+          provider, MAX_STOP_SEQUENCES, count
+        )));
+    }
+    Ok(())
+}
+
+/// Content of the trailing assistant message that seeds the reply
+/// (prefill), if the conversation ends with one
+pub fn prefill(request: &ChatRequest) -> Option<&str>
+{   request.messages.last()
+      .filter(|m| m.role == mistral::Role::Assistant)
+      .map(|m| m.content.as_str())
+}
+
+/// Whether `provider` continues a trailing assistant message instead
+/// of answering after it
+pub fn supports_prefill(provider: &crate::Provider) -> bool
+{   matches!(
+      provider,
+      crate::Provider::MistralAi
+        | crate::Provider::Anthropic
+        | crate::Provider::Replicate
+    )
+}
+
+/// Reject a prefilled request for a provider without prefill
+pub fn check_prefill(
+  provider: &crate::Provider
+, request: &ChatRequest
+) -> Result<(), crate::error::Error>
+{   if prefill(request).is_some() && !supports_prefill(provider)
+    {   log::error!("Prefill for {:?}", provider);
+        return Err(crate::error::Error::InvalidConfiguration(format!(
+          "{:?} does not support assistant prefill", provider
+        )));
+    }
+    Ok(())
+}
+
+/// `text` without the stop sequence it ends with; most providers
+/// leave it out already
+pub fn strip_stop_sequence<'a>(text: &'a str, stop: &[String]) -> &'a str
+{   stop.iter()
+      .filter(|sequence| !sequence.is_empty())
+      .find_map(|sequence| text.strip_suffix(sequence.as_str()))
+      .unwrap_or(text)
+}
+
+/// Queueing interface shared by every provider actor.
+///
+/// Each method only hands the command to the actor's channel and
+/// returns; the result arrives later on `reply`.
+pub trait ProviderClient: Send + Sync
+{   /// Provider served by this client
+    fn provider(&self) -> crate::Provider;
+
+    /// False once the actor's task has ended, e.g. by panicking
+    fn is_alive(&self) -> bool
+    {   true
+    }
+
+    /// Queue a conversation; the reply is the next assistant turn
+    fn send_chat(
+      &self
+    , request: ChatRequest
+    , reply: crate::SendPromptReplySender
+    ) -> Result<(), crate::error::Error>;
+
+    /// Queue a conversation whose reply keeps the provider's
+    /// response body when `request.return_raw` is set
+    fn send_chat_response(
+      &self
+    , request: ChatRequest
+    , reply: ChatResponseSender
+    ) -> Result<(), crate::error::Error>
+    {   let _ = (request, reply);
+        Err(crate::error::Error::ProviderNotImplemented(
+          format!("{:?} raw responses", self.provider())
+        ))
+    }
+
+    /// Queue a streamed conversation; chunks arrive on `reply`
+    /// until one ends the stream (see `sse`)
+    fn send_chat_stream(
+      &self
+    , request: ChatRequest
+    , reply: crate::StreamReplySender
+    ) -> Result<(), crate::error::Error>
+    {   let _ = (request, reply);
+        Err(crate::error::Error::ProviderNotImplemented(
+          format!("{:?} streaming", self.provider())
+        ))
+    }
+
+    /// JSON body `send_chat` would post for `request`, built without
+    /// contacting the provider
+    fn request_body(
+      &self
+    , request: ChatRequest
+    ) -> Result<serde_json::Value, crate::error::Error>
+    {   let _ = request;
+        Err(crate::error::Error::ProviderNotImplemented(
+          format!("{:?} dry run", self.provider())
+        ))
+    }
+
+    /// Queue an Assistants API command (OpenAI only). Failures are
+    /// answered on the command's own reply.
+    fn send_openai_command(&self, command: openai::OpenAICommand)
+    {   command.fail(crate::error::Error::ProviderNotImplemented(
+          format!("{:?} assistants", self.provider())
+        ));
+    }
+
+    /// Queue a single user prompt
+    fn send_prompt(
+      &self
+    , prompt: String
+    , model: String
+    , reply: crate::SendPromptReplySender
+    ) -> Result<(), crate::error::Error>
+    {   self.send_chat(ChatRequest::from_prompt(prompt, model), reply)
+    }
+
+    /// Quota from the provider's last response; unknown for
+    /// providers that do not track it
+    fn rate_limit_status(&self) -> crate::utils::rate_limiter::RateLimitStatus
+    {   Default::default()
+    }
+
+    /// Queue a count of the input tokens of each prompt, sent as
+    /// one user message to `model`; for providers with a counting
+    /// endpoint. The backend estimates locally for the others.
+    fn count_tokens(
+      &self
+    , prompts: Vec<String>
+    , model: String
+    , reply: crate::CountTokensReplySender
+    ) -> Result<(), crate::error::Error>
+    {   let _ = (prompts, model, reply);
+        Err(crate::error::Error::ProviderNotImplemented(
+          format!("{:?} token counting", self.provider())
+        ))
+    }
+
+    /// Queue a raw completion, answered with the continued text;
+    /// for providers with a completion endpoint
+    fn send_completion(&self, args: crate::SendCompletionArgs)
+      -> Result<(), crate::error::Error>
+    {   let _ = args;
+        Err(crate::error::Error::ProviderNotImplemented(
+          format!("{:?} raw completion", self.provider())
+        ))
+    }
+
+    /// Queue the perplexity of `text` under `model`; for providers
+    /// returning prompt logprobs
+    fn compute_perplexity(
+      &self
+    , text: String
+    , model: String
+    , reply: crate::ComputePerplexityReplySender
+    ) -> Result<(), crate::error::Error>
+    {   let _ = (text, model, reply);
+        Err(crate::error::Error::ProviderNotImplemented(
+          format!("{:?} perplexity", self.provider())
+        ))
+    }
+
+    /// Queue a model listing request
+    fn get_available_models(
+      &self
+    , reply: mpsc::UnboundedSender<
+        Result<Vec<String>, crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>;
+
+    /// Queue a master (`model == None`) or model-specific key update
+    fn set_api_key(
+      &self
+    , model: Option<String>
+    , key: String
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>;
+
+    /// Queue a master or model-specific key provider, consulted
+    /// before every request in place of a static key
+    fn set_key_provider(
+      &self
+    , model: Option<String>
+    , key_provider: crate::auth::SharedKeyProvider
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>;
+}
+
+/// HTTP client of a provider actor plus headers added to each
+/// of its requests, and the level the actor logs its traffic at
+#[derive(Debug, Clone)]
+pub struct ProviderHttp
+{   pub client: reqwest::Client
+  , /// `client` without the total `timeout_secs`, for streams
+    pub stream_client: reqwest::Client
+  , /// `timeout_secs`, applied to each chunk of a stream
+    pub read_timeout: Option<std::time::Duration>
+  , pub headers: reqwest::header::HeaderMap
+  , /// See `utils::logger::ProviderLogger::from_config`
+    pub log_level: log::Level
+  , /// Quota from the provider's last response headers
+    pub rate_limits: crate::utils::rate_limiter::SharedRateLimitStatus
+  , /// Signs each request when the endpoint requires it, see `sign`
+    pub signer: Option<crate::utils::auth::SharedRequestSigner>
+}
+
+impl ProviderHttp
+{   /// Start a POST request carrying the provider headers
+    pub fn post<U: reqwest::IntoUrl>(&self, url: U)
+      -> reqwest::RequestBuilder
+    {   self.client.post(url).headers(self.headers.clone())
+    }
+
+    /// `post` for a streaming request, through `stream_client`
+    pub fn post_stream<U: reqwest::IntoUrl>(&self, url: U)
+      -> reqwest::RequestBuilder
+    {   self.stream_client.post(url).headers(self.headers.clone())
+    }
+
+    /// Start a GET request carrying the provider headers
+    pub fn get<U: reqwest::IntoUrl>(&self, url: U)
+      -> reqwest::RequestBuilder
+    {   self.client.get(url).headers(self.headers.clone())
+    }
+
+    /// Let `signer` sign a finished request, body included; without
+    /// a signer the request is returned as is
+    pub fn sign(&self, request: reqwest::RequestBuilder)
+      -> Result<reqwest::RequestBuilder, crate::error::Error>
+    {   let Some(signer) = &self.signer else { return Ok(request) };
+        let (client, request) = request.build_split();
+        let mut request = request?;
+        let body = request.body()
+          .and_then(reqwest::Body::as_bytes)
+          .unwrap_or_default()
+          .to_vec();
+        let method = request.method().to_string();
+        let url = request.url().to_string();
+        signer.sign(&method, &url, &body, request.headers_mut())?;
+        Ok(reqwest::RequestBuilder::from_parts(client, request))
+    }
+
+    /// Update `rate_limits` from a response's headers
+    pub fn observe_rate_limits(&self, headers: &reqwest::header::HeaderMap)
+    {   if let Ok(mut status) = self.rate_limits.lock()
+        {   status.observe(headers);
+        }
+    }
+}
+
+impl From<reqwest::Client> for ProviderHttp
+{   fn from(client: reqwest::Client) -> Self
+    {   ProviderHttp
+        {   stream_client: client.clone()
+          , client
+          , read_timeout: None
+          , headers: reqwest::header::HeaderMap::new()
+          , log_level: log::Level::Debug
+          , rate_limits: Default::default()
+          , signer: None
+        }
+    }
+}
+
+impl Default for ProviderHttp
+{   /// Client sending `DEFAULT_USER_AGENT`
+    fn default() -> Self
+    {   build_http_client(
+          None,
+          &crate::config::HttpClientConfig::default()
+        ).unwrap_or_else(|_| reqwest::Client::new().into())
+    }
+}
+
+/// Built-in catalog entry of `provider`'s default model, for
+/// providers that have one
+pub fn default_model_info(provider: &crate::Provider)
+  -> Option<crate::ModelInfo>
+{   match provider
+    {   crate::Provider::MistralAi => Some(mistral::default_model_info())
+      , crate::Provider::Cerebras => Some(cerebras::default_model_info())
+      , _ => None
+    }
+}
+
+/// True for providers allm has a client for
+pub fn is_supported(provider: &crate::Provider) -> bool
+{   matches!(
+      provider,
+      crate::Provider::MistralAi
+        | crate::Provider::OpenAI
+        | crate::Provider::CloudflareAi
+        | crate::Provider::Cerebras
+        | crate::Provider::Replicate
+        | crate::Provider::Local
+        | crate::Provider::TogetherAi
+    )
+}
+
+/// Model the backend uses for `provider` when none is configured
+pub fn default_model(provider: &crate::Provider) -> Option<String>
+{   match provider
+    {   crate::Provider::MistralAi => Some(
+          mistral::default_model_info().name
+        )
+      , crate::Provider::Cerebras => Some(
+          cerebras::default_model_info().name
+        )
+      , crate::Provider::OpenAI => Some("gpt-4o-mini".to_string())
+      , crate::Provider::CloudflareAi => Some(
+          "@cf/meta/llama-3.1-8b-instruct".to_string()
+        )
+      , crate::Provider::Replicate => Some(
+          "meta/meta-llama-3-8b-instruct".to_string()
+        )
+      , _ => None
+    }
+}
+
+/// True when an API error body reports a prompt longer than the
+/// model's context window
+pub fn is_context_overflow(error_text: &str) -> bool
+{   let text = error_text.to_ascii_lowercase();
+    [ "context_length_exceeded"
+    , "context length"
+    , "context window"
+    , "maximum context"
+    ].iter().any(|marker| text.contains(marker))
+}
+
+/// Error for a non-success API response
+pub(crate) fn api_error(
+  provider: crate::Provider
+, status: u16
+, retry_after: Option<std::time::Duration>
+, error_text: &str
+) -> crate::error::Error
+{   let body: Option<serde_json::Value>
+      = serde_json::from_str(error_text).ok();
+    let fields = body.as_ref().map(error_fields).unwrap_or_default();
+    let code = crate::error::ProviderErrorCode::classify(
+      status,
+      fields.code.as_deref(),
+      fields.kind.as_deref()
+    );
+    if code == crate::error::ProviderErrorCode::ContextLengthExceeded
+      || is_context_overflow(error_text)
+    {   return crate::error::Error::ContextWindowExceeded;
+    }
+    crate::error::Error::ProviderApiError
+    {   provider
+      , code
+      , message: fields.message.unwrap_or_else(|| error_text.to_string())
+      , retry_after
+    }
+}
+
+/// `code`, `type` and `message` of an error body
+#[derive(Debug, Default)]
+struct ErrorFields
+{   code: Option<String>
+  , kind: Option<String>
+  , message: Option<String>
+}
+
+/// Find the error fields in the shapes providers use:
+/// `{"error": {...}}` (OpenAI, Anthropic), `{"errors": [{...}]}`
+/// (Cloudflare) or the fields at the top level (Mistral)
+fn error_fields(body: &serde_json::Value) -> ErrorFields
+{   let error = body.get("error")
+      .filter(|e| e.is_object())
+      .or_else(|| body.get("errors").and_then(|e| e.get(0)))
+      .unwrap_or(body);
+    let text = |value: Option<&serde_json::Value>| match value
+    {   Some(serde_json::Value::String(s)) => Some(s.clone())
+      , Some(serde_json::Value::Number(n)) => Some(n.to_string())
+      , _ => None
+    };
+    ErrorFields
+    {   code: text(error.get("code"))
+      , kind: text(error.get("type")).filter(|t| t != "error")
+      , message: text(error.get("message"))
+          .or_else(|| text(body.get("message")))
+          .or_else(|| text(body.get("detail")))
+          .or_else(|| body.get("error").and_then(|e| e.as_str())
+            .map(|e| e.to_string()))
+    }
+}
+
+/// Delay a response asks for in its `retry-after` (seconds) or
+/// `retry-after-ms` header
+pub fn retry_after(headers: &reqwest::header::HeaderMap)
+  -> Option<std::time::Duration>
+{   let header = |name: &str| headers.get(name)
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.trim().parse::<f64>().ok())
+      .filter(|v| v.is_finite() && *v >= 0.0);
+    header("retry-after-ms")
+      .map(|ms| std::time::Duration::from_secs_f64(ms / 1000.0))
+      .or_else(|| header("retry-after").map(std::time::Duration::from_secs_f64))
+}
+
+/// CA certificate in the PEM file at `path`
+fn load_certificate(path: &str)
+  -> Result<reqwest::Certificate, crate::error::Error>
+{   let invalid = |e: &dyn std::fmt::Display| {
+      crate::error::Error::InvalidConfiguration(
+        format!("CA certificate {:?}: {}", path, e)
+      )
+    };
+    let pem = std::fs::read(path).map_err(|e| invalid(&e))?;
+    reqwest::Certificate::from_pem(&pem).map_err(|e| invalid(&e))
+}
+
+/// Client certificate and key in the PKCS#12 file at `path`; a
+/// wrong `password` fails like an unreadable file
+fn load_identity(path: &str, password: &str)
+  -> Result<reqwest::Identity, crate::error::Error>
+{   let invalid = |e: &dyn std::fmt::Display| {
+      crate::error::Error::InvalidConfiguration(
+        format!("client identity {:?}: {}", path, e)
+      )
+    };
+    let der = std::fs::read(path).map_err(|e| invalid(&e))?;
+    reqwest::Identity::from_pkcs12_der(&der, password)
+      .map_err(|e| invalid(&e))
+}
+
+/// Build the HTTP client for a provider from its configuration.
+///
+/// The shared `User-Agent` goes on the client builder; a
+/// provider-specific one becomes a per-request header, which
+/// takes precedence over the builder default. `extra_headers` are
+/// per-request headers too; a name or value HTTP does not allow is
+/// `Error::InvalidConfiguration`.
+///
+/// With the `compression` feature, responses are requested with
+/// `Accept-Encoding: gzip, br` and decompressed before they are
+/// parsed.
+pub(crate) fn build_http_client(
+  config: Option<&crate::config::ProviderConfig>
+, http_config: &crate::config::HttpClientConfig
+) -> Result<ProviderHttp, crate::error::Error>
+{   let read_timeout = config
+      .and_then(|c| c.timeout_secs)
+      .map(std::time::Duration::from_secs);
+    let ca_cert = config
+      .and_then(|c| c.ca_cert_path.as_deref())
+      .map(load_certificate)
+      .transpose()?;
+    let identity = config
+      .and_then(|c| c.client_identity_path.as_deref().map(|path| {
+        load_identity(path, c.client_identity_password.as_deref().unwrap_or(""))
+      }))
+      .transpose()?;
+    let accept_invalid_certs = config
+      .and_then(|c| c.danger_accept_invalid_certs)
+      .unwrap_or(false);
+    if let (true, Some(config)) = (accept_invalid_certs, config)
+    {   log::warn!("Certificate checks are off for {}", config.name);
+    }
+    // The total timeout would cut off long streams, so streams get a
+    // client of their own and `read_timeout` per chunk instead
+    let build = |timeout: Option<std::time::Duration>| {
+      let mut builder = reqwest::Client::builder()
+        .user_agent(http_config.user_agent());
+      if let Some(secs) = config.and_then(|c| c.connect_timeout_secs)
+      {   builder = builder.connect_timeout(
+            std::time::Duration::from_secs(secs)
+          );
+      }
+      if let Some(timeout) = timeout
+      {   builder = builder.timeout(timeout);
+      }
+      if let Some(cert) = &ca_cert
+      {   builder = builder.add_root_certificate(cert.clone());
+      }
+      if let Some(identity) = &identity
+      {   builder = builder.identity(identity.clone());
+      }
+      builder = builder.danger_accept_invalid_certs(accept_invalid_certs);
+      #[cfg(feature = "compression")]
+      {   builder = builder.gzip(true).brotli(true);
+      }
+      builder.build().map_err(|e| {
+        crate::error::Error::InvalidConfiguration(
+          format!("HTTP client: {}", e)
+        )
+      })
+    };
+    let client = build(read_timeout)?;
+    let stream_client = build(None)?;
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    let agent = config
+      .and_then(|c| c.provider())
+      .and_then(|p| http_config.provider_specific_user_agents.get(&p));
+    if let Some(agent) = agent
+    {   let value = reqwest::header::HeaderValue::from_str(agent)
+          .map_err(|e| {
+            crate::error::Error::InvalidConfiguration(
+              format!("User-Agent {:?}: {}", agent, e)
+            )
+          })?;
+        headers.insert(reqwest::header::USER_AGENT, value);
+    }
+    if let Some(extra) = config.and_then(|c| c.extra_headers.as_ref())
+    {   headers.extend(header_map(extra)?);
+    }
+    let log_level = config
+      .map(crate::utils::logger::ProviderLogger::from_config)
+      .unwrap_or_default()
+      .level();
+    Ok(ProviderHttp
+    {   client
+      , stream_client
+      , read_timeout
+      , headers
+      , log_level
+      , rate_limits: Default::default()
+      , signer: config
+          .and_then(|c| c.hmac_signing.as_ref())
+          .map(|signing| crate::utils::auth::HmacSha256Signer::shared(
+            signing.key_id.clone(), signing.secret.clone()
+          ))
+    })
+}
@@ -0,0 +1,912 @@
+// allm/src/providers/openai.rs
+
+//! OpenAI provider, optionally routed to an Azure OpenAI deployment
+//!
+//! Azure serves the same wire format but addresses a deployment
+//! rather than a model, authenticates with an `api-key` header and
+//! requires an `api-version` query parameter.
+//!
+//! Chat goes through the shared OpenAI-compatible actor. The
+//! Assistants API (threads and runs) has its own actor, see
+//! `OpenAICommand`; it is not available through Azure. The same
+//! actor runs batches (`OpenAICommand::Batch`), which OpenAI answers
+//! within 24 hours at half the price, and moderation
+//! (`OpenAICommand::Moderate`).
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use log::{debug, error, info, trace};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::auth::{SharedKeyProvider, StaticKeyProvider};
+
+use super::ProviderClient;
+use super::openai_compat::{
+  EndpointAuth, OpenAiCompatClient, OpenAiCompatEndpoint
+};
+use crate::config::AzureConfig;
+
+const OPENAI_API_BASE: &str
+  = "https://api.openai.com/v1";
+
+/// `OpenAI-Beta` header value the Assistants API requires
+const ASSISTANTS_BETA: &str = "assistants=v2";
+
+const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+const DEFAULT_MAX_POLL_ATTEMPTS: u32 = 60;
+
+const DEFAULT_BATCH_POLL_INTERVAL_SECS: u64 = 10;
+/// OpenAI's completion window for a batch
+const DEFAULT_BATCH_TIMEOUT_SECS: u64 = 24 * 60 * 60;
+
+/// Endpoint every line of a batch input file targets
+const BATCH_ENDPOINT: &str = "/v1/chat/completions";
+
+/// Boundary of the batch file upload. Lines of a JSONL file escape
+/// their line breaks, so the file cannot contain `\r\n--<boundary>`.
+const UPLOAD_BOUNDARY: &str = "allm-batch-upload";
+
+impl AzureConfig
+{   /// Default host of the Azure resource
+    pub fn resource_base(&self) -> String
+    {   format!("https://{}.openai.azure.com", self.resource)
+    }
+
+    /// Chat completions URL for the deployment under `api_base`
+    /// (the resource host unless overridden)
+    pub fn chat_url(&self, api_base: Option<&str>) -> String
+    {   let base = api_base
+          .map(|b| b.to_string())
+          .unwrap_or_else(|| self.resource_base());
+        format!(
+          "{}/openai/deployments/{}/chat/completions?api-version={}",
+          base.trim_end_matches('/'),
+          self.deployment,
+          self.api_version
+        )
+    }
+}
+
+// ===== Assistants Types =====
+
+/// Conversation thread of the Assistants API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpenAIThread
+{   pub id: String
+}
+
+/// Run of an assistant on a thread
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpenAIRun
+{   pub id: String
+  , pub status: RunStatus
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus
+{   Queued
+  , InProgress
+  , RequiresAction
+  , Cancelling
+  , Cancelled
+  , Failed
+  , Completed
+  , Incomplete
+  , Expired
+}
+
+impl RunStatus
+{   /// True once the run will not change any more. `RequiresAction`
+    /// counts as terminal: allm does not submit tool outputs.
+    pub fn is_terminal(&self) -> bool
+    {   !matches!(
+          self,
+          RunStatus::Queued | RunStatus::InProgress | RunStatus::Cancelling
+        )
+    }
+}
+
+/// How `GetRunResult` waits for a run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunPolling
+{   pub interval: Duration
+  , pub max_attempts: u32
+}
+
+impl Default for RunPolling
+{   fn default() -> Self
+    {   RunPolling
+        {   interval: Duration::from_millis(DEFAULT_POLL_INTERVAL_MS)
+          , max_attempts: DEFAULT_MAX_POLL_ATTEMPTS
+        }
+    }
+}
+
+// ===== Moderation Types =====
+
+/// Verdict of the moderation endpoint on one input
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModerationResult
+{   /// True when any category is flagged
+    pub flagged: bool
+  , pub categories: HashMap<String, bool>
+  , /// Confidence per category, 0 to 1
+    pub category_scores: HashMap<String, f32>
+}
+
+impl ModerationResult
+{   /// Names of the flagged categories, sorted
+    pub fn flagged_categories(&self) -> Vec<String>
+    {   let mut flagged: Vec<String> = self.categories.iter()
+          .filter(|(_, flagged)| **flagged)
+          .map(|(category, _)| category.clone())
+          .collect();
+        flagged.sort();
+        flagged
+    }
+}
+
+// ===== Batch Types =====
+
+/// One prompt of an OpenAI batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchItem
+{   /// Caller's identifier, returned with the result
+    pub custom_id: String
+  , pub prompt: String
+  , /// The provider's default model if unset
+    pub model: Option<String>
+}
+
+/// Outcome of one `BatchItem`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchResult
+{   pub custom_id: String
+  , pub response: Result<String, crate::error::Error>
+}
+
+/// How `OpenAICommand::Batch` waits for a batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchPolling
+{   pub interval: Duration
+  , pub timeout: Duration
+}
+
+impl Default for BatchPolling
+{   fn default() -> Self
+    {   BatchPolling
+        {   interval: Duration::from_secs(DEFAULT_BATCH_POLL_INTERVAL_SECS)
+          , timeout: Duration::from_secs(DEFAULT_BATCH_TIMEOUT_SECS)
+        }
+    }
+}
+
+/// Batch object of the API, as far as allm reads it
+#[derive(Debug, Clone, Deserialize)]
+struct OpenAIBatch
+{   id: String
+  , status: String
+  , output_file_id: Option<String>
+  , error_file_id: Option<String>
+  , #[serde(default)]
+    errors: serde_json::Value
+}
+
+// ===== Assistants Actor =====
+
+/// Commands for the Assistants actor of an `OpenAIClient`
+pub enum OpenAICommand
+{   CreateThread
+    {   reply: mpsc::UnboundedSender
+        <Result<OpenAIThread, crate::error::Error>>
+    }
+  , /// Add a user message to a thread
+    AddThreadMessage
+    {   thread_id: String
+      , content: String
+      , reply: mpsc::UnboundedSender<Result<(), crate::error::Error>>
+    }
+  , CreateRun
+    {   thread_id: String
+      , assistant_id: String
+      , reply: mpsc::UnboundedSender
+        <Result<OpenAIRun, crate::error::Error>>
+    }
+  , /// Poll a run until it ends; the reply is the newest message of
+    /// the thread
+    GetRunResult
+    {   thread_id: String
+      , run_id: String
+      , reply: mpsc::UnboundedSender<Result<String, crate::error::Error>>
+    }
+  , /// Screen `input` with the moderation endpoint
+    Moderate
+    {   input: String
+      , reply: mpsc::UnboundedSender
+        <Result<ModerationResult, crate::error::Error>>
+    }
+  , /// Run `requests`, keyed by custom id, as one batch and wait
+    /// for all of them; results come in the order of `requests`
+    Batch
+    {   requests: Vec<(String, super::ChatRequest)>
+      , reply: crate::BatchReplySender
+    }
+  , SetKeyProvider
+    {   key_provider: SharedKeyProvider
+      , reply: mpsc::UnboundedSender<Result<(), crate::error::Error>>
+    }
+  , Shutdown
+}
+
+impl OpenAICommand
+{   /// Answer the command with `error` instead of running it
+    pub fn fail(self, error: crate::error::Error)
+    {   match self
+        {   OpenAICommand::CreateThread { reply } => {
+              let _ = reply.send(Err(error));
+            }
+          , OpenAICommand::AddThreadMessage { reply, .. }
+          | OpenAICommand::SetKeyProvider { reply, .. } => {
+              let _ = reply.send(Err(error));
+            }
+          , OpenAICommand::CreateRun { reply, .. } => {
+              let _ = reply.send(Err(error));
+            }
+          , OpenAICommand::GetRunResult { reply, .. } => {
+              let _ = reply.send(Err(error));
+            }
+          , OpenAICommand::Moderate { reply, .. } => {
+              let _ = reply.send(Err(error));
+            }
+          , OpenAICommand::Batch { reply, .. } => {
+              let _ = reply.send(Err(error));
+            }
+          , OpenAICommand::Shutdown => {}
+        }
+    }
+}
+
+/// Assistants actor state
+#[derive(Clone)]
+pub struct AssistantsState
+{   master_key: Option<SharedKeyProvider>
+  , http_client: super::ProviderHttp
+  , api_base: String
+  , polling: RunPolling
+  , batch_polling: BatchPolling
+}
+
+impl AssistantsState
+{   async fn request(
+      &self
+    , request: reqwest::RequestBuilder
+    ) -> Result<serde_json::Value, crate::error::Error>
+    {   self.send(request).await?.json().await.map_err(|e| {
+          error!("Parse error: {}", e);
+          crate::error::Error::ParseError(e.to_string())
+        })
+    }
+
+    /// Authenticate and send `request`, failing on an error status
+    async fn send(
+      &self
+    , request: reqwest::RequestBuilder
+    ) -> Result<reqwest::Response, crate::error::Error>
+    {   let Some(key) = &self.master_key else
+        {   error!("No OpenAI API key for assistants");
+            return Err(crate::error::Error::MissingApiKey(
+              "OpenAI:assistants".to_string()
+            ));
+        };
+        let api_key = key.current_key().await?;
+        let builder = request
+          .header("Authorization", format!("Bearer {}", api_key))
+          .header("OpenAI-Beta", ASSISTANTS_BETA);
+        let response = self.http_client.sign(builder)?
+          .send()
+          .await
+          .map_err(|e| {
+            error!("HTTP error: {}", e);
+            crate::error::Error::HttpError(e.to_string())
+          })?;
+        let status = response.status();
+        trace!("OpenAI assistants response status: {}", status);
+        if !status.is_success()
+        {   let retry_after = super::retry_after(response.headers());
+            let error_text = response.text().await
+              .unwrap_or_else(|_| "Unknown error".to_string());
+            error!("OpenAI assistants API error: {}", error_text);
+            return Err(super::api_error(
+              crate::Provider::OpenAI, status.as_u16(), retry_after,
+              &error_text
+            ));
+        }
+        Ok(response)
+    }
+
+    async fn post<T: serde::de::DeserializeOwned>(
+      &self
+    , path: &str
+    , body: serde_json::Value
+    ) -> Result<T, crate::error::Error>
+    {   let url = format!("{}{}", self.api_base, path);
+        let value = self.request(self.http_client.post(url).json(&body))
+          .await?;
+        parse(value)
+    }
+
+    async fn get(&self, path: &str)
+      -> Result<serde_json::Value, crate::error::Error>
+    {   let url = format!("{}{}", self.api_base, path);
+        self.request(self.http_client.get(url)).await
+    }
+
+    /// Body of a file, as text
+    async fn get_text(&self, path: &str)
+      -> Result<String, crate::error::Error>
+    {   let url = format!("{}{}", self.api_base, path);
+        self.send(self.http_client.get(url)).await?
+          .text()
+          .await
+          .map_err(|e| {
+            error!("HTTP error: {}", e);
+            crate::error::Error::HttpError(e.to_string())
+          })
+    }
+
+    async fn handle_create_thread(&self)
+      -> Result<OpenAIThread, crate::error::Error>
+    {   self.post("/threads", serde_json::json!({})).await
+    }
+
+    async fn handle_add_message(
+      &self
+    , thread_id: &str
+    , content: String
+    ) -> Result<(), crate::error::Error>
+    {   let _: serde_json::Value = self.post(
+          &format!("/threads/{}/messages", thread_id),
+          serde_json::json!({ "role": "user", "content": content })
+        ).await?;
+        Ok(())
+    }
+
+    async fn handle_create_run(
+      &self
+    , thread_id: &str
+    , assistant_id: String
+    ) -> Result<OpenAIRun, crate::error::Error>
+    {   self.post(
+          &format!("/threads/{}/runs", thread_id),
+          serde_json::json!({ "assistant_id": assistant_id })
+        ).await
+    }
+
+    async fn handle_get_run_result(
+      &self
+    , thread_id: &str
+    , run_id: &str
+    ) -> Result<String, crate::error::Error>
+    {   for attempt in 1..=self.polling.max_attempts
+        {   let value = self.get(
+              &format!("/threads/{}/runs/{}", thread_id, run_id)
+            ).await?;
+            let run: OpenAIRun = parse(value.clone())?;
+            debug!("Run {} status: {:?}", run.id, run.status);
+            match run.status
+            {   RunStatus::Completed => return self.latest_message(thread_id).await
+              , status if status.is_terminal() => {
+                  let reason = value["last_error"]["message"].as_str()
+                    .unwrap_or("no details");
+                  error!("Run {} ended {:?}: {}", run.id, status, reason);
+                  return Err(crate::error::Error::ApiError(format!(
+                    "OpenAI run {:?}: {}", status, reason
+                  )));
+                }
+              , _ => {}
+            }
+            if attempt < self.polling.max_attempts
+            {   tokio::time::sleep(self.polling.interval).await;
+            }
+        }
+        error!("Run {} still running after {} polls", run_id, self.polling.max_attempts);
+        Err(crate::error::Error::Timeout)
+    }
+
+    /// Text of the newest message of the thread
+    async fn latest_message(&self, thread_id: &str)
+      -> Result<String, crate::error::Error>
+    {   let list = self.get(
+          &format!("/threads/{}/messages?order=desc&limit=1", thread_id)
+        ).await?;
+        let parts = list["data"][0]["content"].as_array()
+          .ok_or(crate::error::Error::NoChoicesInResponse)?;
+        Ok(parts.iter()
+          .filter_map(|part| part["text"]["value"].as_str())
+          .collect::<Vec<_>>()
+          .join("\n"))
+    }
+
+    async fn handle_moderate(&self, input: String)
+      -> Result<ModerationResult, crate::error::Error>
+    {   let response: serde_json::Value = self.post(
+          "/moderations",
+          serde_json::json!({ "input": input })
+        ).await?;
+        match response["results"].get(0)
+        {   Some(result) => parse(result.clone())
+          , None => {
+              error!("Moderation response without results");
+              Err(crate::error::Error::ParseError(
+                "moderation response without results".to_string()
+              ))
+            }
+        }
+    }
+
+    async fn handle_batch(
+      &self
+    , requests: Vec<(String, super::ChatRequest)>
+    ) -> Result<Vec<BatchResult>, crate::error::Error>
+    {   let mut custom_ids = Vec::with_capacity(requests.len());
+        let mut input = String::new();
+        for (custom_id, request) in requests
+        {   let line = serde_json::json!({
+              "custom_id": custom_id,
+              "method": "POST",
+              "url": BATCH_ENDPOINT,
+              "body": super::openai_compat::chat_json(request, false)?
+            });
+            input.push_str(&line.to_string());
+            input.push('\n');
+            custom_ids.push(custom_id);
+        }
+        let input_file_id = self.upload_batch_input(input).await?;
+        let batch: OpenAIBatch = self.post("/batches", serde_json::json!({
+          "input_file_id": input_file_id,
+          "endpoint": BATCH_ENDPOINT,
+          "completion_window": "24h"
+        })).await?;
+        info!("OpenAI batch {} created for {} requests", batch.id, custom_ids.len());
+        let batch = self.wait_for_batch(batch).await?;
+
+        let mut results = HashMap::new();
+        for file_id in [&batch.output_file_id, &batch.error_file_id]
+          .into_iter()
+          .flatten()
+        {   let content = self.get_text(&format!("/files/{}/content", file_id))
+              .await?;
+            for line in content.lines().filter(|line| !line.trim().is_empty())
+            {   let value: serde_json::Value = serde_json::from_str(line)
+                  .map_err(|e| {
+                    error!("Parse error: {}", e);
+                    crate::error::Error::ParseError(e.to_string())
+                  })?;
+                if let Some(custom_id) = value["custom_id"].as_str()
+                {   results.insert(custom_id.to_string(), batch_line_result(&value));
+                }
+            }
+        }
+        Ok(custom_ids.into_iter().map(|custom_id| {
+          let response = results.remove(&custom_id).unwrap_or_else(|| {
+            error!("OpenAI batch {} has no result for {}", batch.id, custom_id);
+            Err(crate::error::Error::ApiError(format!(
+              "OpenAI batch {} {}: no result", batch.id, batch.status
+            )))
+          });
+          BatchResult { custom_id, response }
+        }).collect())
+    }
+
+    /// Upload a batch input file; the reply is its file id
+    async fn upload_batch_input(&self, jsonl: String)
+      -> Result<String, crate::error::Error>
+    {   let body = format!(
+          "--{b}\r\n\
+           Content-Disposition: form-data; name=\"purpose\"\r\n\r\n\
+           batch\r\n\
+           --{b}\r\n\
+           Content-Disposition: form-data; name=\"file\"; \
+           filename=\"batch.jsonl\"\r\n\
+           Content-Type: application/jsonl\r\n\r\n\
+           {jsonl}\r\n\
+           --{b}--\r\n",
+          b = UPLOAD_BOUNDARY,
+          jsonl = jsonl
+        );
+        let url = format!("{}/files", self.api_base);
+        let file = self.request(
+          self.http_client.post(url)
+            .header(
+              "Content-Type",
+              format!("multipart/form-data; boundary={}", UPLOAD_BOUNDARY)
+            )
+            .body(body)
+        ).await?;
+        file["id"].as_str().map(str::to_string).ok_or_else(|| {
+          error!("OpenAI file upload returned no id");
+          crate::error::Error::ParseError(
+            "OpenAI file upload returned no id".to_string()
+          )
+        })
+    }
+
+    /// Poll a batch until it ends; an expired batch still carries the
+    /// requests it completed
+    async fn wait_for_batch(&self, mut batch: OpenAIBatch)
+      -> Result<OpenAIBatch, crate::error::Error>
+    {   let deadline = tokio::time::Instant::now()
+          + self.batch_polling.timeout;
+        loop
+        {   debug!("Batch {} status: {}", batch.id, batch.status);
+            match batch.status.as_str()
+            {   "completed" | "expired" => return Ok(batch)
+              , "failed" | "cancelled" => {
+                  let reason = batch.errors["data"][0]["message"].as_str()
+                    .unwrap_or("no details");
+                  error!("Batch {} ended {}: {}", batch.id, batch.status, reason);
+                  return Err(crate::error::Error::ApiError(format!(
+                    "OpenAI batch {}: {}", batch.status, reason
+                  )));
+                }
+              , _ => {}
+            }
+            if tokio::time::Instant::now() + self.batch_polling.interval
+              > deadline
+            {   error!(
+                  "Batch {} still {} after {:?}",
+                  batch.id, batch.status, self.batch_polling.timeout
+                );
+                return Err(crate::error::Error::Timeout);
+            }
+            tokio::time::sleep(self.batch_polling.interval).await;
+            batch = parse(self.get(&format!("/batches/{}", batch.id)).await?)?;
+        }
+    }
+}
+
+/// Reply text of one line of a batch output or error file
+fn batch_line_result(line: &serde_json::Value)
+  -> Result<String, crate::error::Error>
+{   if let Some(message) = line["error"]["message"].as_str()
+    {   return Err(crate::error::Error::ApiError(message.to_string()));
+    }
+    let response = &line["response"];
+    let status = response["status_code"].as_u64().unwrap_or(200) as u16;
+    if !(200..300).contains(&status)
+    {   return Err(super::api_error(
+          crate::Provider::OpenAI, status, None, &response["body"].to_string()
+        ));
+    }
+    response["body"]["choices"][0]["message"]["content"].as_str()
+      .map(str::to_string)
+      .ok_or(crate::error::Error::NoChoicesInResponse)
+}
+
+fn parse<T: serde::de::DeserializeOwned>(value: serde_json::Value)
+  -> Result<T, crate::error::Error>
+{   serde_json::from_value(value).map_err(|e| {
+      error!("Parse error: {}", e);
+      crate::error::Error::ParseError(e.to_string())
+    })
+}
+
+/// Assistants event loop; a run being polled holds up the commands
+/// behind it, a batch is polled on its own task
+async fn run_assistants_loop(
+  mut cmd_rx: mpsc::UnboundedReceiver<OpenAICommand>
+, mut state: AssistantsState
+)
+{   debug!("Starting OpenAI assistants loop");
+
+    loop
+    { match cmd_rx.recv().await
+      {   Some(OpenAICommand::CreateThread { reply }) => {
+            debug!("Processing CreateThread");
+            let _ = reply.send(state.handle_create_thread().await);
+          }
+        , Some(OpenAICommand::AddThreadMessage {
+            thread_id, content, reply
+          }) => {
+            debug!("Processing AddThreadMessage for {}", thread_id);
+            let result = state.handle_add_message(&thread_id, content).await;
+            let _ = reply.send(result);
+          }
+        , Some(OpenAICommand::CreateRun {
+            thread_id, assistant_id, reply
+          }) => {
+            debug!("Processing CreateRun for {}", thread_id);
+            let result = state
+              .handle_create_run(&thread_id, assistant_id)
+              .await;
+            let _ = reply.send(result);
+          }
+        , Some(OpenAICommand::GetRunResult {
+            thread_id, run_id, reply
+          }) => {
+            debug!("Processing GetRunResult for {}", run_id);
+            let result = state
+              .handle_get_run_result(&thread_id, &run_id)
+              .await;
+            let _ = reply.send(result);
+          }
+        , Some(OpenAICommand::Moderate { input, reply }) => {
+            debug!("Processing Moderate");
+            let _ = reply.send(state.handle_moderate(input).await);
+          }
+        , Some(OpenAICommand::Batch { requests, reply }) => {
+            debug!("Processing Batch of {} requests", requests.len());
+            let batch_state = state.clone();
+            tokio::spawn(async move {
+              let _ = reply.send(batch_state.handle_batch(requests).await);
+            });
+          }
+        , Some(OpenAICommand::SetKeyProvider { key_provider, reply }) => {
+            debug!("Processing SetKeyProvider");
+            state.master_key = Some(key_provider);
+            let _ = reply.send(Ok(()));
+          }
+        , Some(OpenAICommand::Shutdown) => {
+            info!("OpenAI assistants shutting down");
+            break;
+          }
+        , None => {
+            debug!("Command channel closed");
+            break;
+          }
+      }
+    }
+}
+
+/// Public OpenAI client interface
+pub struct OpenAIClient
+{   azure: Option<AzureConfig>
+  , inner: OpenAiCompatClient
+  , /// Assistants actor; not spawned for Azure
+    assistants: Option<mpsc::UnboundedSender<OpenAICommand>>
+}
+
+impl OpenAIClient
+{   /// Create and spawn a new OpenAI client.
+    ///
+    /// With `azure` set, `api_base` overrides the resource host
+    /// (e.g. a private endpoint); otherwise it overrides the
+    /// standard OpenAI base URL.
+    pub fn new(
+      api_key: Option<String>
+    , api_base: Option<String>
+    , azure: Option<AzureConfig>
+    , http_client: impl Into<super::ProviderHttp>
+    ) -> Self
+    {   OpenAIClient::with_polling(
+          api_key, api_base, azure, http_client,
+          RunPolling::default(), BatchPolling::default()
+        )
+    }
+
+    /// `new` with the polling of assistant runs and batches set
+    pub fn with_polling(
+      api_key: Option<String>
+    , api_base: Option<String>
+    , azure: Option<AzureConfig>
+    , http_client: impl Into<super::ProviderHttp>
+    , polling: RunPolling
+    , batch_polling: BatchPolling
+    ) -> Self
+    {   let http_client: super::ProviderHttp = http_client.into();
+        let assistants = azure.is_none().then(|| {
+          let api_base = api_base.as_deref()
+            .unwrap_or(OPENAI_API_BASE)
+            .trim_end_matches('/')
+            .to_string();
+          spawn_assistants(AssistantsState
+          {   master_key: api_key.clone().map(StaticKeyProvider::shared)
+            , http_client: http_client.clone()
+            , api_base
+            , polling
+            , batch_polling
+          })
+        });
+        let endpoint = match &azure
+        {   Some(azure) => {
+              debug!(
+                "Creating OpenAIClient for Azure deployment {}/{}",
+                azure.resource, azure.deployment
+              );
+              OpenAiCompatEndpoint
+              {   provider: crate::Provider::OpenAI
+                , chat_url: azure.chat_url(api_base.as_deref())
+                , models_url: None
+                , auth: EndpointAuth::Header("api-key".to_string())
+              }
+            }
+          , None => {
+              debug!("Creating OpenAIClient");
+              let api_base = api_base
+                .unwrap_or_else(|| OPENAI_API_BASE.to_string());
+              let api_base = api_base.trim_end_matches('/');
+              OpenAiCompatEndpoint
+              {   provider: crate::Provider::OpenAI
+                , chat_url: format!("{}/chat/completions", api_base)
+                , models_url: Some(format!("{}/models", api_base))
+                , auth: EndpointAuth::Bearer
+              }
+            }
+        };
+        OpenAIClient
+        {   azure
+          , inner: OpenAiCompatClient::new(
+              endpoint, api_key, http_client
+            )
+          , assistants
+        }
+    }
+
+    /// Create a client from its provider configuration
+    pub fn from_config(
+      config: &crate::config::ProviderConfig
+    , http_config: &crate::config::HttpClientConfig
+    ) -> Result<Self, crate::error::Error>
+    {   let http_client
+          = super::build_http_client(Some(config), http_config)?;
+        let mut polling = RunPolling::default();
+        let mut batch_polling = BatchPolling::default();
+        if let Some(ms) = config.poll_interval_ms
+        {   polling.interval = Duration::from_millis(ms);
+            batch_polling.interval = Duration::from_millis(ms);
+        }
+        if let Some(attempts) = config.max_poll_attempts
+        {   polling.max_attempts = attempts.max(1);
+        }
+        if let Some(secs) = config.poll_timeout_secs
+        {   batch_polling.timeout = Duration::from_secs(secs);
+        }
+        Ok(OpenAIClient::with_polling(
+          config.api_key.clone(),
+          config.api_base.clone(),
+          config.azure.clone(),
+          http_client,
+          polling,
+          batch_polling
+        ))
+    }
+
+    /// Azure deployment the client targets, if any
+    pub fn azure(&self) -> Option<&AzureConfig>
+    {   self.azure.as_ref()
+    }
+
+    /// Shutdown the client
+    pub async fn shutdown(self)
+      -> Result<(), crate::error::Error>
+    {   if let Some(assistants) = &self.assistants
+        {   let _ = assistants.send(OpenAICommand::Shutdown);
+        }
+        self.inner.shutdown().await
+    }
+
+    /// Tell the Assistants actor about a new master key; the caller's
+    /// reply comes from the chat actor
+    fn update_assistants_key(&self, key_provider: SharedKeyProvider)
+    {   if let Some(assistants) = &self.assistants
+        {   let (reply, _) = mpsc::unbounded_channel();
+            let _ = assistants.send(OpenAICommand::SetKeyProvider {
+              key_provider,
+              reply,
+            });
+        }
+    }
+}
+
+fn spawn_assistants(state: AssistantsState)
+  -> mpsc::UnboundedSender<OpenAICommand>
+{   let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+    // The loop ends when the client drops its sender
+    tokio::spawn(async move {
+      run_assistants_loop(cmd_rx, state).await;
+    });
+    cmd_tx
+}
+
+impl ProviderClient for OpenAIClient
+{   fn provider(&self) -> crate::Provider
+    {   crate::Provider::OpenAI
+    }
+
+    fn is_alive(&self) -> bool
+    {   self.inner.is_alive()
+    }
+
+    fn rate_limit_status(&self) -> crate::utils::rate_limiter::RateLimitStatus
+    {   self.inner.rate_limit_status()
+    }
+
+    fn send_chat(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::SendPromptReplySender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat(request, reply)
+    }
+
+    fn send_chat_response(
+      &self
+    , request: super::ChatRequest
+    , reply: super::ChatResponseSender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat_response(request, reply)
+    }
+
+    fn send_chat_stream(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::StreamReplySender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat_stream(request, reply)
+    }
+
+    fn request_body(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<serde_json::Value, crate::error::Error>
+    {   self.inner.request_body(request)
+    }
+
+    fn get_available_models(
+      &self
+    , reply: mpsc::UnboundedSender<
+        Result<Vec<String>, crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.get_available_models(reply)
+    }
+
+    fn set_api_key(
+      &self
+    , model: Option<String>
+    , key: String
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   if model.is_none()
+        {   self.update_assistants_key(StaticKeyProvider::shared(key.clone()));
+        }
+        self.inner.set_api_key(model, key, reply)
+    }
+
+    fn set_key_provider(
+      &self
+    , model: Option<String>
+    , key_provider: crate::auth::SharedKeyProvider
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   if model.is_none()
+        {   self.update_assistants_key(key_provider.clone());
+        }
+        self.inner.set_key_provider(model, key_provider, reply)
+    }
+
+    fn send_openai_command(&self, command: OpenAICommand)
+    {   match &self.assistants
+        {   Some(assistants) => {
+              if let Err(mpsc::error::SendError(command))
+                = assistants.send(command)
+              {   error!("OpenAI assistants disconnected");
+                  command.fail(crate::error::Error::Other(
+                    "OpenAI assistants disconnected".to_string()
+                  ));
+              }
+            }
+          , None => command.fail(
+              crate::error::Error::ProviderNotImplemented(
+                "Azure OpenAI assistants".to_string()
+              )
+            )
+        }
+    }
+}
@@ -0,0 +1,681 @@
+// allm/src/providers/openai_compat.rs
+
+//! Actor shared by providers exposing an OpenAI-compatible
+//! `chat/completions` API (Cloudflare, Cerebras, ...)
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use log::{debug, trace, error, info};
+use std::collections::HashMap;
+
+use crate::auth::{SharedKeyProvider, StaticKeyProvider};
+use crate::utils::logger::ProviderLogger;
+use crate::utils::telemetry::GenAiSpan;
+use tracing::Instrument;
+
+use super::mistral::ChatMessage;
+
+// ===== Message Types =====
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatCompletionRequest
+{   pub model: String
+  , pub messages: Vec<ChatMessage>
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>
+  , /// Completions to generate, one choice each
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<usize>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatCompletionResponse
+{   pub choices: Vec<ChatCompletionChoice>
+  , #[serde(default)]
+    pub usage: Option<super::TokenUsage>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatCompletionChoice
+{   pub message: ChatMessage
+  , pub finish_reason: Option<String>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelsResponse
+{   pub data: Vec<ModelData>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelData
+{   pub id: String
+}
+
+/// Body posted to the chat endpoint, with defaults filled in
+pub fn chat_body(request: super::ChatRequest, stream: bool)
+  -> ChatCompletionRequest
+{   let super::ChatRequest
+    {   messages, model, max_tokens, temperature, stop, n, ..
+    } = request;
+    ChatCompletionRequest
+    {   model
+      , messages
+      , max_tokens: Some(
+          max_tokens.unwrap_or(super::DEFAULT_MAX_TOKENS)
+        )
+      , temperature: Some(
+          temperature.unwrap_or(super::DEFAULT_TEMPERATURE)
+        )
+      , stop
+      , stream: Some(stream)
+      , n
+    }
+}
+
+/// `chat_body` as posted, with the request's `extra_params`
+/// merged in
+pub fn chat_json(request: super::ChatRequest, stream: bool)
+  -> Result<serde_json::Value, crate::error::Error>
+{   super::check_choice_count(&request)?;
+    let extra = request.extra_params.clone();
+    let mut body = super::to_json_body(&chat_body(request, stream))?;
+    if let Some(fields) = body.as_object_mut()
+    {   super::merge_extra_params(fields, extra);
+    }
+    Ok(body)
+}
+
+// ===== Endpoint Description =====
+
+/// How the API key is presented to the endpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EndpointAuth
+{   /// `Authorization: Bearer <key>`
+    Bearer
+  , /// The raw key in the named header (Azure uses `api-key`)
+    Header(String)
+}
+
+/// Where and how an OpenAI-compatible provider is reached
+#[derive(Debug, Clone)]
+pub struct OpenAiCompatEndpoint
+{   /// Provider served by this endpoint
+    pub provider: crate::Provider
+  , /// Full URL of the chat completions endpoint
+    pub chat_url: String
+  , /// Full URL of the model listing endpoint, if any
+    pub models_url: Option<String>
+  , pub auth: EndpointAuth
+}
+
+impl OpenAiCompatEndpoint
+{   /// Attach the API key to a request the way the endpoint expects
+    fn authorize(
+      &self
+    , request: reqwest::RequestBuilder
+    , api_key: &str
+    ) -> reqwest::RequestBuilder
+    {   match &self.auth
+        {   EndpointAuth::Bearer => request
+              .header("Authorization", format!("Bearer {}", api_key))
+          , EndpointAuth::Header(name) => request
+              .header(name.as_str(), api_key)
+        }
+    }
+}
+
+// ===== Client Actor =====
+
+/// Commands for OpenAiCompatClient actor
+pub enum OpenAiCompatCommand
+{   SendPrompt
+    {   request: super::ChatRequest
+      , reply: mpsc::UnboundedSender<crate::SendPromptReply>
+    }
+  , SendChat
+    {   request: super::ChatRequest
+      , reply: super::ChatResponseSender
+    }
+  , SendStream
+    {   request: super::ChatRequest
+      , reply: crate::StreamReplySender
+    }
+  , GetModels
+    {   reply: mpsc::UnboundedSender
+        <Result<Vec<String>, crate::error::Error>>
+    }
+  , SetApiKey
+    {   model: Option<String>
+      , key: String
+      , reply: mpsc::UnboundedSender
+        <Result<(), crate::error::Error>>
+    }
+  , SetKeyProvider
+    {   model: Option<String>
+      , key_provider: SharedKeyProvider
+      , reply: mpsc::UnboundedSender
+        <Result<(), crate::error::Error>>
+    }
+  , Shutdown
+}
+
+/// OpenAI-compatible client state
+pub struct OpenAiCompatState
+{   endpoint: OpenAiCompatEndpoint
+  , master_key: Option<SharedKeyProvider>
+  , model_keys: HashMap<String, SharedKeyProvider>
+  , http_client: super::ProviderHttp
+  , logger: ProviderLogger
+}
+
+impl OpenAiCompatState
+{   pub fn new(
+      endpoint: OpenAiCompatEndpoint
+    , master_key: Option<String>
+    , http_client: impl Into<super::ProviderHttp>
+    ) -> Self
+    {   debug!(
+          "Creating OpenAiCompatState for {:?}",
+          endpoint.provider
+        );
+        let http_client: super::ProviderHttp = http_client.into();
+        OpenAiCompatState
+        {   logger: ProviderLogger::new(
+              endpoint.provider.name(), http_client.log_level
+            )
+          , endpoint
+          , master_key: master_key.map(StaticKeyProvider::shared)
+          , model_keys: HashMap::new()
+          , http_client
+        }
+    }
+
+    async fn get_api_key(&self, model: &str)
+      -> Result<String, crate::error::Error>
+    {   if let Some(key) = self.model_keys.get(model)
+        {   debug!("Using model-specific key for: {}", model);
+            return key.current_key().await;
+        }
+
+        if let Some(key) = &self.master_key
+        {   debug!("Using master key for model: {}", model);
+            return key.current_key().await;
+        }
+
+        error!("No API key for model: {}", model);
+        Err(crate::error::Error::MissingApiKey(
+          format!("{:?}:{}", self.endpoint.provider, model)
+        ))
+    }
+
+    /// Post a chat request; non-success statuses become
+    /// `Error::ApiError`
+    async fn post_chat(
+      &self
+    , request: super::ChatRequest
+    , stream: bool
+    ) -> Result<reqwest::Response, crate::error::Error>
+    {   let api_key = self.get_api_key(&request.model).await?;
+        let request_id = request.request_id.clone();
+        let extra_headers = request.extra_headers.clone();
+
+        let request = chat_json(request, stream)?;
+
+        self.logger.log_at(
+          log::Level::Trace,
+          format_args!("{:?} request: {:?}", self.endpoint.provider, request)
+        );
+
+        let builder = if stream
+        {   self.http_client.post_stream(&self.endpoint.chat_url)
+        } else
+        {   self.http_client.post(&self.endpoint.chat_url)
+        };
+        let builder = super::with_extra_headers(builder, extra_headers.as_ref())?;
+        let builder = super::with_request_id(builder, request_id.as_deref());
+        let builder = self.endpoint
+          .authorize(builder, &api_key)
+          .header("Content-Type", "application/json")
+          .json(&request);
+        let response = self.http_client.sign(builder)?
+          .send()
+          .await
+          .map_err(|e| {
+            error!("HTTP error: {}", e);
+            crate::error::Error::HttpError(e.to_string())
+          })?;
+
+        let status = response.status();
+        self.http_client.observe_rate_limits(response.headers());
+        self.logger.log_at(
+          log::Level::Trace,
+          format_args!("{:?} response status: {}", self.endpoint.provider, status)
+        );
+
+        if !status.is_success()
+        {   let retry_after = super::retry_after(response.headers());
+            let error_text = response.text().await
+              .unwrap_or_else(|_|
+                "Unknown error".to_string()
+              );
+            error!("{:?} API error: {}", self.endpoint.provider, error_text);
+            return Err(super::api_error(
+              self.endpoint.provider.clone(), status.as_u16(), retry_after,
+              &error_text
+            ));
+        }
+        Ok(response)
+    }
+
+    async fn handle_send_prompt(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<String, crate::error::Error>
+    {   self.logger.log(format_args!(
+          "Handling send_prompt for {:?}: {}",
+          self.endpoint.provider, request.model
+        ));
+        self.handle_send_chat(request).await.map(|r| r.text)
+    }
+
+    async fn handle_send_chat(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<super::ChatResponse, crate::error::Error>
+    {   let telemetry = GenAiSpan::chat(&self.endpoint.provider, &request);
+        self.exchange_chat(request, &telemetry)
+          .instrument(telemetry.span().clone())
+          .await
+          .inspect_err(|e| telemetry.record_error(e))
+    }
+
+    async fn exchange_chat(
+      &self
+    , request: super::ChatRequest
+    , telemetry: &GenAiSpan
+    ) -> Result<super::ChatResponse, crate::error::Error>
+    {   let return_raw = request.return_raw;
+        let model = request.model.clone();
+        let logged = self.logger.traces_exchanges()
+          .then(|| chat_json(request.clone(), false).ok())
+          .flatten();
+        let started = std::time::Instant::now();
+        let response = self.post_chat(request, false).await?;
+
+        let body: serde_json::Value
+          = response.json().await.map_err(|e| {
+            error!("Parse error: {}", e);
+            crate::error::Error::ParseError(e.to_string())
+          })?;
+        if let Some(logged) = &logged
+        {   self.logger.exchange(&model, logged, &body, started.elapsed());
+        }
+        let raw = return_raw.then(|| body.clone());
+        let prompt_logprobs = super::prompt_logprobs(&body);
+        let thinking = super::reasoning_content(&body);
+        let chat_response: ChatCompletionResponse
+          = serde_json::from_value(body).map_err(|e| {
+            error!("Parse error: {}", e);
+            crate::error::Error::ParseError(e.to_string())
+          })?;
+
+        let choices: Vec<String> = chat_response.choices.into_iter()
+          .map(|c| c.message.content)
+          .collect();
+        let text = choices.first().cloned()
+          .ok_or_else(|| {
+            error!("No choices in response");
+            crate::error::Error::NoChoicesInResponse
+          })?;
+        telemetry.record_usage(chat_response.usage.as_ref());
+        let input_tokens = chat_response.usage
+          .and_then(|usage| usage.prompt_tokens);
+        Ok(super::ChatResponse
+        {   text, choices, raw, input_tokens, prompt_logprobs, thinking
+        })
+    }
+
+    async fn handle_send_stream(
+      &self
+    , request: super::ChatRequest
+    , reply: &crate::StreamReplySender
+    )
+    {   debug!(
+          "Handling send_stream for {:?}: {}",
+          self.endpoint.provider, request.model
+        );
+        match self.post_chat(request, true).await
+        {   Ok(response) => super::sse::forward_stream(
+              response, self.http_client.read_timeout, reply
+            ).await
+          , Err(e) => { let _ = reply.send(Err(e)).await; }
+        }
+    }
+
+    async fn handle_get_models(
+      &self
+    ) -> Result<Vec<String>, crate::error::Error>
+    {   debug!("Handling get_models for {:?}", self.endpoint.provider);
+
+        let models_url = self.endpoint.models_url.as_ref()
+          .ok_or_else(|| {
+            crate::error::Error::ProviderNotImplemented(
+              format!("{:?} model listing", self.endpoint.provider)
+            )
+          })?;
+
+        let api_key = self.master_key.as_ref()
+          .ok_or_else(|| {
+            error!("No master key");
+            crate::error::Error::MissingApiKey(
+              format!("{:?} (master)", self.endpoint.provider)
+            )
+          })?
+          .current_key()
+          .await?;
+
+        let builder = self.endpoint
+          .authorize(self.http_client.get(models_url), &api_key);
+        let response = self.http_client.sign(builder)?
+          .send()
+          .await
+          .map_err(|e| {
+            error!("Failed to fetch models: {}", e);
+            crate::error::Error::HttpError(e.to_string())
+          })?;
+
+        let status = response.status();
+        trace!("Models response status: {}", status);
+
+        if !status.is_success()
+        {   let retry_after = super::retry_after(response.headers());
+            let error_text = response.text().await
+              .unwrap_or_else(|_|
+                "Unknown error".to_string()
+              );
+            error!("Failed to get models: {}", error_text);
+            return Err(super::api_error(
+              self.endpoint.provider.clone(), status.as_u16(), retry_after,
+              &error_text
+            ));
+        }
+
+        let models_response: ModelsResponse
+          = response.json().await.map_err(|e| {
+            error!("Parse error: {}", e);
+            crate::error::Error::ParseError(e.to_string())
+          })?;
+
+        let model_names: Vec<String>
+          = models_response.data
+            .iter()
+            .map(|m| m.id.clone())
+            .collect();
+
+        debug!("Retrieved {} models", model_names.len());
+        Ok(model_names)
+    }
+
+    fn handle_set_api_key(
+      &mut self
+    , model_opt: Option<String>
+    , key: String
+    ) -> Result<(), crate::error::Error>
+    {   match model_opt
+        {   Some(model) => {
+              debug!("Setting model key for: {}", model);
+              self.model_keys.insert(model, StaticKeyProvider::shared(key));
+            }
+          , None => {
+              debug!("Setting master key");
+              self.master_key = Some(StaticKeyProvider::shared(key));
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_set_key_provider(
+      &mut self
+    , model_opt: Option<String>
+    , key_provider: SharedKeyProvider
+    ) -> Result<(), crate::error::Error>
+    {   match model_opt
+        {   Some(model) => {
+              debug!("Setting model key provider for: {}", model);
+              self.model_keys.insert(model, key_provider);
+            }
+          , None => {
+              debug!("Setting master key provider");
+              self.master_key = Some(key_provider);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Public OpenAI-compatible client interface
+pub struct OpenAiCompatClient
+{   provider: crate::Provider
+  , tx: mpsc::UnboundedSender<OpenAiCompatCommand>
+  , rate_limits: crate::utils::rate_limiter::SharedRateLimitStatus
+  , _task: tokio::task::JoinHandle<()>
+}
+
+impl OpenAiCompatClient
+{   /// Create and spawn a new client for `endpoint`
+    pub fn new(
+      endpoint: OpenAiCompatEndpoint
+    , api_key: Option<String>
+    , http_client: impl Into<super::ProviderHttp>
+    ) -> Self
+    {   debug!("Creating OpenAiCompatClient for {:?}", endpoint.provider);
+        let provider = endpoint.provider.clone();
+        let (cmd_tx, cmd_rx)
+          = mpsc::unbounded_channel();
+
+        let state
+          = OpenAiCompatState::new(endpoint, api_key, http_client);
+        let rate_limits = state.http_client.rate_limits.clone();
+        let _task = tokio::spawn(async move {
+          run_openai_compat_loop(cmd_rx, state).await;
+        });
+
+        OpenAiCompatClient
+        {   provider
+          , tx: cmd_tx
+          , rate_limits
+          , _task
+        }
+    }
+
+    fn queue(
+      &self
+    , cmd: OpenAiCompatCommand
+    ) -> Result<(), crate::error::Error>
+    {   self.tx.send(cmd).map_err(|_| {
+          error!("{:?} client disconnected", self.provider);
+          crate::error::Error::Other(
+            format!("{:?} client disconnected", self.provider)
+          )
+        })
+    }
+
+    /// Shutdown the client
+    pub async fn shutdown(self)
+      -> Result<(), crate::error::Error>
+    {   debug!("Shutting down {:?} client", self.provider);
+        self.tx.send(OpenAiCompatCommand::Shutdown)
+          .map_err(|_| {
+            crate::error::Error::Other(
+              "Client already shutdown".to_string()
+            )
+          })
+    }
+}
+
+impl super::ProviderClient for OpenAiCompatClient
+{   fn provider(&self) -> crate::Provider
+    {   self.provider.clone()
+    }
+
+    fn is_alive(&self) -> bool
+    {   !self._task.is_finished()
+    }
+
+    fn rate_limit_status(&self) -> crate::utils::rate_limiter::RateLimitStatus
+    {   self.rate_limits.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    fn send_chat(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::SendPromptReplySender
+    ) -> Result<(), crate::error::Error>
+    {   debug!("send_chat queued for model: {}", request.model);
+        self.queue(OpenAiCompatCommand::SendPrompt {
+          request,
+          reply,
+        })
+    }
+
+    fn send_chat_response(
+      &self
+    , request: super::ChatRequest
+    , reply: super::ChatResponseSender
+    ) -> Result<(), crate::error::Error>
+    {   debug!("send_chat_response queued for model: {}", request.model);
+        self.queue(OpenAiCompatCommand::SendChat {
+          request,
+          reply,
+        })
+    }
+
+    fn request_body(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<serde_json::Value, crate::error::Error>
+    {   chat_json(request, false)
+    }
+
+    fn send_chat_stream(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::StreamReplySender
+    ) -> Result<(), crate::error::Error>
+    {   debug!("send_chat_stream queued for model: {}", request.model);
+        self.queue(OpenAiCompatCommand::SendStream {
+          request,
+          reply,
+        })
+    }
+
+    fn get_available_models(
+      &self
+    , reply: mpsc::UnboundedSender<
+        Result<Vec<String>, crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   debug!("get_available_models queued");
+        self.queue(OpenAiCompatCommand::GetModels { reply })
+    }
+
+    fn set_api_key(
+      &self
+    , model: Option<String>
+    , key: String
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   debug!("set_api_key queued for model: {:?}", model);
+        self.queue(OpenAiCompatCommand::SetApiKey {
+          model,
+          key,
+          reply,
+        })
+    }
+
+    fn set_key_provider(
+      &self
+    , model: Option<String>
+    , key_provider: SharedKeyProvider
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   debug!("set_key_provider queued for model: {:?}", model);
+        self.queue(OpenAiCompatCommand::SetKeyProvider {
+          model,
+          key_provider,
+          reply,
+        })
+    }
+}
+
+/// Main OpenAI-compatible event loop
+async fn run_openai_compat_loop(
+  mut cmd_rx: mpsc::UnboundedReceiver<OpenAiCompatCommand>
+, mut state: OpenAiCompatState
+)
+{   debug!("Starting {:?} client loop", state.endpoint.provider);
+
+    loop
+    { match cmd_rx.recv().await
+      {   Some(OpenAiCompatCommand::SendPrompt {
+            request, reply
+          }) => {
+            debug!("Processing SendPrompt");
+            let result = state
+              .handle_send_prompt(request)
+              .await;
+            let _ = reply.send(result);
+          }
+        , Some(OpenAiCompatCommand::SendChat {
+            request, reply
+          }) => {
+            debug!("Processing SendChat");
+            let result = state.handle_send_chat(request).await;
+            let _ = reply.send(result);
+          }
+        , Some(OpenAiCompatCommand::SendStream {
+            request, reply
+          }) => {
+            debug!("Processing SendStream");
+            state.handle_send_stream(request, &reply).await;
+          }
+        , Some(OpenAiCompatCommand::GetModels { reply }) => {
+            debug!("Processing GetModels");
+            let result = state.handle_get_models().await;
+            let _ = reply.send(result);
+          }
+        , Some(OpenAiCompatCommand::SetApiKey {
+            model, key, reply
+          }) => {
+            debug!("Processing SetApiKey for: {:?}", model);
+            let result = state.handle_set_api_key(model, key);
+            let _ = reply.send(result);
+          }
+        , Some(OpenAiCompatCommand::SetKeyProvider {
+            model, key_provider, reply
+          }) => {
+            debug!("Processing SetKeyProvider for: {:?}", model);
+            let result = state.handle_set_key_provider(model, key_provider);
+            let _ = reply.send(result);
+          }
+        , Some(OpenAiCompatCommand::Shutdown) => {
+            info!("{:?} client shutting down", state.endpoint.provider);
+            break;
+          }
+        , None => {
+            debug!("Command channel closed");
+            break;
+          }
+      }
+    }
+}
@@ -0,0 +1,599 @@
+// allm/src/providers/replicate.rs
+
+//! Replicate provider
+//!
+//! Replicate runs models as asynchronous predictions: a POST creates
+//! the prediction, then its `urls.get` endpoint is polled until the
+//! status is terminal.
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use log::{debug, trace, error, info};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::auth::{SharedKeyProvider, StaticKeyProvider};
+use crate::utils::logger::ProviderLogger;
+
+use super::ProviderClient;
+use super::mistral::{ChatMessage, Role};
+
+const REPLICATE_API_BASE: &str
+  = "https://api.replicate.com/v1";
+
+const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+const DEFAULT_POLL_TIMEOUT_SECS: u64 = 300;
+
+// ===== Message Types =====
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PredictionInput
+{   pub prompt: String
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_new_tokens: Option<usize>
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatePredictionRequest
+{   /// Only set for `owner/name:version` model references
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>
+  , pub input: PredictionInput
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PredictionUrls
+{   pub get: String
+  , #[serde(default)]
+    pub cancel: Option<String>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Prediction
+{   pub id: String
+  , pub status: PredictionStatus
+  , #[serde(default)]
+    pub output: Option<serde_json::Value>
+  , #[serde(default)]
+    pub error: Option<serde_json::Value>
+  , pub urls: PredictionUrls
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PredictionStatus
+{   Starting
+  , Processing
+  , Succeeded
+  , Failed
+  , Canceled
+}
+
+impl Prediction
+{   /// Language models stream output as an array of string pieces;
+    /// other models may return a single string
+    pub fn output_text(&self) -> Option<String>
+    {   match &self.output
+        {   Some(serde_json::Value::String(s)) => Some(s.clone())
+          , Some(serde_json::Value::Array(parts)) => Some(
+              parts.iter()
+                .filter_map(|p| p.as_str())
+                .collect::<String>()
+            )
+          , _ => None
+        }
+    }
+}
+
+// ===== Replicate Client Actor =====
+
+/// Commands for ReplicateClient actor
+pub enum ReplicateCommand
+{   SendPrompt
+    {   request: Box<super::ChatRequest>
+      , reply: mpsc::UnboundedSender<crate::SendPromptReply>
+    }
+  , SetApiKey
+    {   model: Option<String>
+      , key: String
+      , reply: mpsc::UnboundedSender
+        <Result<(), crate::error::Error>>
+    }
+  , SetKeyProvider
+    {   model: Option<String>
+      , key_provider: SharedKeyProvider
+      , reply: mpsc::UnboundedSender
+        <Result<(), crate::error::Error>>
+    }
+  , Shutdown
+}
+
+/// Replicate models take a single prompt: system messages become
+/// `system_prompt` and a multi-turn conversation is written out as
+/// a transcript ending in an open assistant turn
+fn flatten_messages(
+  messages: Vec<ChatMessage>
+) -> (Option<String>, String)
+{   let (system, turns): (Vec<_>, Vec<_>) = messages
+      .into_iter()
+      .partition(|m| m.role == Role::System);
+    let system_prompt = (!system.is_empty()).then(|| {
+      system.into_iter()
+        .map(|m| m.content)
+        .collect::<Vec<_>>()
+        .join("\n\n")
+    });
+    let prompt = match turns.as_slice()
+    {   [only] if only.role == Role::User => only.content.clone()
+      , _ => {
+          let mut transcript: String = turns.iter()
+            .map(|m| format!("{}: {}\n", m.role, m.content))
+            .collect();
+          match turns.last()
+          {   // A trailing assistant turn is a prefill to continue
+              Some(last) if last.role == Role::Assistant => {
+                transcript.pop();
+              }
+            , _ => transcript.push_str("assistant:")
+          }
+          transcript
+        }
+    };
+    (system_prompt, prompt)
+}
+
+/// Body creating a prediction; `owner/name:version` models carry
+/// their version in it
+pub fn prediction_body(request: super::ChatRequest)
+  -> CreatePredictionRequest
+{   let super::ChatRequest
+    {   messages, model, max_tokens, temperature, ..
+    } = request;
+    let (system_prompt, prompt) = flatten_messages(messages);
+    CreatePredictionRequest
+    {   version: model.split_once(':')
+          .map(|(_, version)| version.to_string())
+      , input: PredictionInput
+        {   prompt
+          , system_prompt
+          , max_new_tokens: Some(
+              max_tokens.unwrap_or(super::DEFAULT_MAX_TOKENS)
+            )
+          , temperature: Some(
+              temperature.unwrap_or(super::DEFAULT_TEMPERATURE)
+            )
+        }
+    }
+}
+
+/// `prediction_body` as posted; the request's `extra_params` are
+/// model inputs, so they are merged into `input`
+pub fn prediction_json(request: super::ChatRequest)
+  -> Result<serde_json::Value, crate::error::Error>
+{   let extra = request.extra_params.clone();
+    let mut body = super::to_json_body(&prediction_body(request))?;
+    if let Some(input) = body.get_mut("input").and_then(|i| i.as_object_mut())
+    {   super::merge_extra_params(input, extra);
+    }
+    Ok(body)
+}
+
+/// Replicate client state
+pub struct ReplicateClientState
+{   master_key: Option<SharedKeyProvider>
+  , model_keys: HashMap<String, SharedKeyProvider>
+  , http_client: super::ProviderHttp
+  , api_base: String
+  , poll_interval: Duration
+  , poll_timeout: Duration
+  , logger: ProviderLogger
+}
+
+impl ReplicateClientState
+{   pub fn new(
+      master_key: Option<String>
+    , api_base: Option<String>
+    , http_client: impl Into<super::ProviderHttp>
+    ) -> Self
+    {   debug!("Creating ReplicateClientState");
+        let http_client: super::ProviderHttp = http_client.into();
+        ReplicateClientState
+        {   master_key: master_key.map(StaticKeyProvider::shared)
+          , model_keys: HashMap::new()
+          , logger: ProviderLogger::new(
+              crate::Provider::Replicate.name(), http_client.log_level
+            )
+          , http_client
+          , api_base: api_base
+              .unwrap_or_else(|| REPLICATE_API_BASE.to_string())
+          , poll_interval: Duration::from_millis(
+              DEFAULT_POLL_INTERVAL_MS
+            )
+          , poll_timeout: Duration::from_secs(
+              DEFAULT_POLL_TIMEOUT_SECS
+            )
+        }
+    }
+
+    async fn get_api_key(&self, model: &str)
+      -> Result<String, crate::error::Error>
+    {   if let Some(key) = self.model_keys.get(model)
+        {   debug!("Using model-specific key for: {}", model);
+            return key.current_key().await;
+        }
+
+        if let Some(key) = &self.master_key
+        {   debug!("Using master key for model: {}", model);
+            return key.current_key().await;
+        }
+
+        error!("No API key for model: {}", model);
+        Err(crate::error::Error::MissingApiKey(
+          format!("Replicate:{}", model)
+        ))
+    }
+
+    /// `owner/name` models use the model endpoint; `owner/name:version`
+    /// references post to `/predictions` with an explicit version
+    fn create_request(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<(String, serde_json::Value), crate::error::Error>
+    {   let url = match request.model.split_once(':')
+        {   Some(_) => format!("{}/predictions", self.api_base)
+          , None => format!(
+              "{}/models/{}/predictions", self.api_base, request.model
+            )
+        };
+        Ok((url, prediction_json(request)?))
+    }
+
+    async fn read_prediction(
+      response: reqwest::Response
+    ) -> Result<Prediction, crate::error::Error>
+    {   let status = response.status();
+        trace!("Replicate response status: {}", status);
+
+        if !status.is_success()
+        {   let retry_after = super::retry_after(response.headers());
+            let error_text = response.text().await
+              .unwrap_or_else(|_|
+                "Unknown error".to_string()
+              );
+            error!("Replicate API error: {}", error_text);
+            return Err(super::api_error(
+              crate::Provider::Replicate, status.as_u16(), retry_after,
+              &error_text
+            ));
+        }
+
+        response.json().await.map_err(|e| {
+          error!("Parse error: {}", e);
+          crate::error::Error::ParseError(e.to_string())
+        })
+    }
+
+    async fn handle_send_prompt(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<String, crate::error::Error>
+    {   self.logger.log(
+          format_args!("Handling send_prompt for: {}", request.model)
+        );
+
+        let api_key = self.get_api_key(&request.model).await?;
+        let request_id = request.request_id.clone();
+        let extra_headers = request.extra_headers.clone();
+        let (url, request) = self.create_request(request)?;
+        self.logger.log_at(
+          log::Level::Trace, format_args!("Replicate request: {:?}", request)
+        );
+
+        let builder = self.http_client.post(url);
+        let builder = super::with_extra_headers(builder, extra_headers.as_ref())?;
+        let builder = super::with_request_id(builder, request_id.as_deref())
+          .header("Authorization", format!("Bearer {}", api_key))
+          .header("Content-Type", "application/json")
+          .json(&request);
+        let response = self.http_client.sign(builder)?
+          .send()
+          .await
+          .map_err(|e| {
+            error!("HTTP error: {}", e);
+            crate::error::Error::HttpError(e.to_string())
+          })?;
+        let mut prediction
+          = ReplicateClientState::read_prediction(response).await?;
+
+        let deadline = tokio::time::Instant::now() + self.poll_timeout;
+        loop
+        {   debug!(
+              "Prediction {} status: {:?}",
+              prediction.id, prediction.status
+            );
+            match prediction.status
+            {   PredictionStatus::Succeeded => {
+                  return prediction.output_text().ok_or_else(|| {
+                    error!("Prediction {} has no output", prediction.id);
+                    crate::error::Error::NoChoicesInResponse
+                  });
+                }
+              , PredictionStatus::Failed => {
+                  let reason = prediction.error
+                    .map(|e| e.to_string())
+                    .unwrap_or_else(|| "unknown".to_string());
+                  error!("Prediction {} failed: {}", prediction.id, reason);
+                  return Err(crate::error::Error::ApiError(
+                    format!("Replicate prediction failed: {}", reason)
+                  ));
+                }
+              , PredictionStatus::Canceled => {
+                  error!("Prediction {} was canceled", prediction.id);
+                  return Err(crate::error::Error::ApiError(
+                    "Replicate prediction canceled".to_string()
+                  ));
+                }
+              , PredictionStatus::Starting
+              | PredictionStatus::Processing => {}
+            }
+
+            if tokio::time::Instant::now() + self.poll_interval > deadline
+            {   error!("Prediction {} timed out", prediction.id);
+                return Err(crate::error::Error::Timeout);
+            }
+            tokio::time::sleep(self.poll_interval).await;
+
+            let builder = self.http_client
+              .get(&prediction.urls.get)
+              .header("Authorization", format!("Bearer {}", api_key));
+            let response = self.http_client.sign(builder)?
+              .send()
+              .await
+              .map_err(|e| {
+                error!("HTTP error: {}", e);
+                crate::error::Error::HttpError(e.to_string())
+              })?;
+            prediction
+              = ReplicateClientState::read_prediction(response).await?;
+        }
+    }
+
+    fn handle_set_api_key(
+      &mut self
+    , model_opt: Option<String>
+    , key: String
+    ) -> Result<(), crate::error::Error>
+    {   match model_opt
+        {   Some(model) => {
+              debug!("Setting model key for: {}", model);
+              self.model_keys.insert(model, StaticKeyProvider::shared(key));
+            }
+          , None => {
+              debug!("Setting master key");
+              self.master_key = Some(StaticKeyProvider::shared(key));
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_set_key_provider(
+      &mut self
+    , model_opt: Option<String>
+    , key_provider: SharedKeyProvider
+    ) -> Result<(), crate::error::Error>
+    {   match model_opt
+        {   Some(model) => {
+              debug!("Setting model key provider for: {}", model);
+              self.model_keys.insert(model, key_provider);
+            }
+          , None => {
+              debug!("Setting master key provider");
+              self.master_key = Some(key_provider);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Public Replicate client interface
+pub struct ReplicateClient
+{   tx: mpsc::UnboundedSender<ReplicateCommand>
+  , _task: tokio::task::JoinHandle<()>
+}
+
+impl ReplicateClient
+{   /// Create and spawn a new Replicate client
+    pub fn new(
+      api_key: Option<String>
+    , api_base: Option<String>
+    , http_client: impl Into<super::ProviderHttp>
+    ) -> Self
+    {   ReplicateClient::spawn(ReplicateClientState::new(
+          api_key, api_base, http_client
+        ))
+    }
+
+    /// Create a client from its provider configuration
+    pub fn from_config(
+      config: &crate::config::ProviderConfig
+    , http_config: &crate::config::HttpClientConfig
+    ) -> Result<Self, crate::error::Error>
+    {   let http_client
+          = super::build_http_client(Some(config), http_config)?;
+        let mut state = ReplicateClientState::new(
+          config.api_key.clone(),
+          config.api_base.clone(),
+          http_client
+        );
+        if let Some(ms) = config.poll_interval_ms
+        {   state.poll_interval = Duration::from_millis(ms);
+        }
+        if let Some(secs) = config.poll_timeout_secs
+        {   state.poll_timeout = Duration::from_secs(secs);
+        }
+        Ok(ReplicateClient::spawn(state))
+    }
+
+    fn spawn(state: ReplicateClientState) -> Self
+    {   debug!("Creating ReplicateClient");
+        let (cmd_tx, cmd_rx)
+          = mpsc::unbounded_channel();
+
+        let _task = tokio::spawn(async move {
+          run_replicate_loop(cmd_rx, state).await;
+        });
+
+        ReplicateClient
+        {   tx: cmd_tx
+          , _task
+        }
+    }
+
+    fn queue(
+      &self
+    , cmd: ReplicateCommand
+    ) -> Result<(), crate::error::Error>
+    {   self.tx.send(cmd).map_err(|_| {
+          error!("Replicate client disconnected");
+          crate::error::Error::Other(
+            "Replicate client disconnected".to_string()
+          )
+        })
+    }
+
+    /// Shutdown the client
+    pub async fn shutdown(self)
+      -> Result<(), crate::error::Error>
+    {   debug!("Shutting down ReplicateClient");
+        self.tx.send(ReplicateCommand::Shutdown)
+          .map_err(|_| {
+            crate::error::Error::Other(
+              "Client already shutdown".to_string()
+            )
+          })
+    }
+}
+
+impl ProviderClient for ReplicateClient
+{   fn provider(&self) -> crate::Provider
+    {   crate::Provider::Replicate
+    }
+
+    fn is_alive(&self) -> bool
+    {   !self._task.is_finished()
+    }
+
+    fn send_chat(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::SendPromptReplySender
+    ) -> Result<(), crate::error::Error>
+    {   debug!("send_chat queued for model: {}", request.model);
+        self.queue(ReplicateCommand::SendPrompt {
+          request: Box::new(request),
+          reply,
+        })
+    }
+
+    fn request_body(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<serde_json::Value, crate::error::Error>
+    {   prediction_json(request)
+    }
+
+    /// Replicate hosts far too many models to list usefully
+    fn get_available_models(
+      &self
+    , reply: mpsc::UnboundedSender<
+        Result<Vec<String>, crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   let _ = reply.send(Err(
+          crate::error::Error::ProviderNotImplemented(
+            "Replicate model listing".to_string()
+          )
+        ));
+        Ok(())
+    }
+
+    fn set_api_key(
+      &self
+    , model: Option<String>
+    , key: String
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   debug!("set_api_key queued for model: {:?}", model);
+        self.queue(ReplicateCommand::SetApiKey {
+          model,
+          key,
+          reply,
+        })
+    }
+
+    fn set_key_provider(
+      &self
+    , model: Option<String>
+    , key_provider: SharedKeyProvider
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   debug!("set_key_provider queued for model: {:?}", model);
+        self.queue(ReplicateCommand::SetKeyProvider {
+          model,
+          key_provider,
+          reply,
+        })
+    }
+}
+
+/// Main replicate event loop
+async fn run_replicate_loop(
+  mut cmd_rx: mpsc::UnboundedReceiver<ReplicateCommand>
+, mut state: ReplicateClientState
+)
+{   debug!("Starting Replicate client loop");
+
+    loop
+    { match cmd_rx.recv().await
+      {   Some(ReplicateCommand::SendPrompt {
+            request, reply
+          }) => {
+            debug!("Processing SendPrompt");
+            let result = state
+              .handle_send_prompt(*request)
+              .await;
+            let _ = reply.send(result);
+          }
+        , Some(ReplicateCommand::SetApiKey {
+            model, key, reply
+          }) => {
+            debug!("Processing SetApiKey for: {:?}", model);
+            let result = state.handle_set_api_key(model, key);
+            let _ = reply.send(result);
+          }
+        , Some(ReplicateCommand::SetKeyProvider {
+            model, key_provider, reply
+          }) => {
+            debug!("Processing SetKeyProvider for: {:?}", model);
+            let result = state.handle_set_key_provider(model, key_provider);
+            let _ = reply.send(result);
+          }
+        , Some(ReplicateCommand::Shutdown) => {
+            info!("Replicate client shutting down");
+            break;
+          }
+        , None => {
+            debug!("Command channel closed");
+            break;
+          }
+      }
+    }
+}
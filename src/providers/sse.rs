@@ -0,0 +1,193 @@
+// allm/src/providers/sse.rs
+
+//! Server-sent events of OpenAI-style streaming chat completions
+//!
+//! A stream has three possible endings:
+//! - `data: [DONE]`: clean end, the last chunk is `StreamEnd::Done`
+//! - a frame whose JSON holds an `error` object: `Err` on the stream
+//! - EOF without `[DONE]`: complete but possibly truncated, the last
+//!   chunk is `StreamEnd::Eof`
+//!
+//! Either final chunk repeats the last `finish_reason` seen.
+
+use log::{debug, error, trace, warn};
+use serde::Deserialize;
+
+use crate::{StreamChunk, StreamEnd};
+
+/// Payload marking the clean end of a stream
+pub const DONE_SENTINEL: &str = "[DONE]";
+
+#[derive(Debug, Clone, Deserialize)]
+struct StreamFrameBody
+{   #[serde(default)]
+    choices: Vec<StreamChoice>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct StreamChoice
+{   #[serde(default)]
+    delta: StreamDelta
+  , #[serde(default)]
+    finish_reason: Option<String>
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct StreamDelta
+{   #[serde(default)]
+    content: Option<String>
+}
+
+/// One decoded `data:` payload
+#[derive(Debug, Clone, PartialEq)]
+pub enum SseFrame
+{   Chunk(StreamChunk)
+  , Done
+}
+
+/// Splits a byte stream into the `data` payloads of its events
+#[derive(Debug, Default)]
+pub struct SseParser
+{   buffer: String
+  , data: Vec<String>
+}
+
+impl SseParser
+{   pub fn new() -> Self
+    {   SseParser::default()
+    }
+
+    /// Feed received bytes; returns the payloads of the events they
+    /// complete
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String>
+    {   self.buffer.push_str(&String::from_utf8_lossy(bytes));
+        let mut events = vec![];
+        while let Some(end) = self.buffer.find('\n')
+        {   let line: String = self.buffer.drain(..=end).collect();
+            self.line(line.trim_end_matches(['\n', '\r']), &mut events);
+        }
+        events
+    }
+
+    /// Payload of an event cut off by the end of the stream
+    pub fn finish(&mut self) -> Option<String>
+    {   let rest = std::mem::take(&mut self.buffer);
+        let mut events = vec![];
+        self.line(rest.trim_end_matches('\r'), &mut events);
+        self.line("", &mut events);
+        events.pop()
+    }
+
+    fn line(&mut self, line: &str, events: &mut Vec<String>)
+    {   if line.is_empty()
+        {   if !self.data.is_empty()
+            {   events.push(self.data.join("\n"));
+                self.data.clear();
+            }
+        } else if let Some(value) = line.strip_prefix("data:")
+        {   self.data.push(
+              value.strip_prefix(' ').unwrap_or(value).to_string()
+            );
+        }
+        // `event:`, `id:`, `retry:` and `:` comments carry nothing
+        // the chat streams use
+    }
+}
+
+/// Decode one event payload. A JSON `error` object becomes
+/// `Error::ApiError`.
+pub fn parse_frame(data: &str) -> Result<SseFrame, crate::error::Error>
+{   if data.trim() == DONE_SENTINEL
+    {   return Ok(SseFrame::Done);
+    }
+    let value: serde_json::Value = serde_json::from_str(data)
+      .map_err(|e| crate::error::Error::ParseError(e.to_string()))?;
+    if let Some(err) = value.get("error").filter(|e| !e.is_null())
+    {   let message = err.get("message")
+          .and_then(|m| m.as_str())
+          .map(|m| m.to_string())
+          .unwrap_or_else(|| err.to_string());
+        return Err(crate::error::Error::ApiError(message));
+    }
+    let body: StreamFrameBody = serde_json::from_value(value)
+      .map_err(|e| crate::error::Error::ParseError(e.to_string()))?;
+    let choice = body.choices.into_iter().next();
+    Ok(SseFrame::Chunk(StreamChunk
+    {   delta: choice.as_ref()
+          .and_then(|c| c.delta.content.clone())
+          .unwrap_or_default()
+      , finish_reason: choice.and_then(|c| c.finish_reason)
+      , end: None
+    }))
+}
+
+/// Forward a successful streaming response to `reply` until one of
+/// the three endings, or `Error::Timeout` once a chunk takes longer
+/// than `read_timeout`
+pub(crate) async fn forward_stream(
+  mut response: reqwest::Response
+, read_timeout: Option<std::time::Duration>
+, reply: &crate::StreamReplySender
+)
+{   let mut parser = SseParser::new();
+    let mut finish_reason: Option<String> = None;
+    loop
+    {   let next = match read_timeout
+        {   Some(limit) => tokio::time::timeout(limit, response.chunk()).await
+          , None => Ok(response.chunk().await)
+        };
+        let Ok(next) = next else
+        {   error!("Stream read timed out");
+            let _ = reply.send(Err(crate::error::Error::Timeout)).await;
+            return;
+        };
+        let (events, eof) = match next
+        {   Ok(Some(bytes)) => (parser.push(&bytes), false)
+          , Ok(None) => (parser.finish().into_iter().collect(), true)
+          , Err(e) => {
+              error!("Stream read error: {}", e);
+              let error = if e.is_timeout()
+              {   crate::error::Error::Timeout
+              } else
+              {   crate::error::Error::HttpError(e.to_string())
+              };
+              let _ = reply.send(Err(error)).await;
+              return;
+            }
+        };
+        for data in events
+        {   trace!("Stream event: {}", data);
+            match parse_frame(&data)
+            {   Ok(SseFrame::Chunk(chunk)) => {
+                  if chunk.finish_reason.is_some()
+                  {   finish_reason = chunk.finish_reason.clone();
+                  }
+                  // Waits while the consumer's buffer is full
+                  if reply.send(Ok(chunk)).await.is_err()
+                  {   debug!("Stream receiver dropped, aborting stream");
+                      return;
+                  }
+                }
+              , Ok(SseFrame::Done) => {
+                  debug!("Stream finished with [DONE]");
+                  let _ = reply.send(Ok(StreamChunk::last(
+                    finish_reason, StreamEnd::Done
+                  ))).await;
+                  return;
+                }
+              , Err(e) => {
+                  error!("Stream error: {}", e);
+                  let _ = reply.send(Err(e)).await;
+                  return;
+                }
+            }
+        }
+        if eof
+        {   warn!("Stream ended without [DONE]");
+            let _ = reply.send(Ok(StreamChunk::last(
+              finish_reason, StreamEnd::Eof
+            ))).await;
+            return;
+        }
+    }
+}
@@ -0,0 +1,375 @@
+// allm/src/providers/together.rs
+
+//! Together AI provider
+//!
+//! Chat goes through Together's OpenAI-compatible API. On top of it,
+//! Together's `/completions` endpoint can `echo` the prompt back with
+//! the log probability of each of its tokens, which scores how likely
+//! a model finds an arbitrary text (see `compute_text_perplexity`).
+
+use std::collections::HashMap;
+
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::ProviderClient;
+use super::openai_compat::{
+  EndpointAuth, OpenAiCompatClient, OpenAiCompatEndpoint
+};
+use crate::auth::{SharedKeyProvider, StaticKeyProvider};
+use crate::request::TokenLogprob;
+
+const TOGETHER_API_BASE: &str
+  = "https://api.together.xyz/v1";
+
+/// Body posted to `/completions`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TogetherCompletionRequest
+{   pub model: String
+  , pub prompt: String
+  , #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>
+  , /// Return the prompt's tokens, with their logprobs, in `prompt`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo: Option<bool>
+  , /// Top logprobs returned per token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<u8>
+}
+
+// ===== Together Client Actor =====
+
+/// Commands for the Together actor; chat goes to the
+/// OpenAI-compatible actor instead
+pub enum TogetherCommand
+{   ComputePerplexity
+    {   text: String
+      , model: String
+      , reply: crate::ComputePerplexityReplySender
+    }
+  , SetApiKey
+    {   model: Option<String>
+      , key: String
+    }
+  , SetKeyProvider
+    {   model: Option<String>
+      , key_provider: SharedKeyProvider
+    }
+  , Shutdown
+}
+
+/// Together client state for the requests beyond chat
+pub struct TogetherClientState
+{   master_key: Option<SharedKeyProvider>
+  , model_keys: HashMap<String, SharedKeyProvider>
+  , http_client: super::ProviderHttp
+  , api_base: String
+}
+
+impl TogetherClientState
+{   pub fn new(
+      master_key: Option<String>
+    , api_base: String
+    , http_client: super::ProviderHttp
+    ) -> Self
+    {   TogetherClientState
+        {   master_key: master_key.map(StaticKeyProvider::shared)
+          , model_keys: HashMap::new()
+          , http_client
+          , api_base
+        }
+    }
+
+    async fn get_api_key(&self, model: &str)
+      -> Result<String, crate::error::Error>
+    {   match self.model_keys.get(model).or(self.master_key.as_ref())
+        {   Some(key) => key.current_key().await
+          , None => {
+              error!("No API key for model: {}", model);
+              Err(crate::error::Error::MissingApiKey(
+                format!("{:?}:{}", crate::Provider::TogetherAi, model)
+              ))
+            }
+        }
+    }
+
+    /// Post `request` to `/completions` and return the response body;
+    /// non-success statuses become `Error::ApiError`
+    pub async fn send_completion(&self, request: &TogetherCompletionRequest)
+      -> Result<serde_json::Value, crate::error::Error>
+    {   let api_key = self.get_api_key(&request.model).await?;
+        let builder = self.http_client
+          .post(format!("{}/completions", self.api_base))
+          .header("Authorization", format!("Bearer {}", api_key))
+          .header("Content-Type", "application/json")
+          .json(request);
+        let response = self.http_client.sign(builder)?
+          .send()
+          .await
+          .inspect_err(|e| error!("HTTP error: {}", e))?;
+
+        let status = response.status();
+        self.http_client.observe_rate_limits(response.headers());
+        if !status.is_success()
+        {   let retry_after = super::retry_after(response.headers());
+            let error_text = response.text().await
+              .unwrap_or_else(|_| "Unknown error".to_string());
+            error!("Together API error: {}", error_text);
+            return Err(super::api_error(
+              crate::Provider::TogetherAi, status.as_u16(), retry_after,
+              &error_text
+            ));
+        }
+        response.json().await.map_err(|e| {
+          error!("Parse error: {}", e);
+          crate::error::Error::ParseError(e.to_string())
+        })
+    }
+
+    /// Perplexity of `text` under `model`: the text is echoed back
+    /// (`echo: true, logprobs: 1`) and scored by `perplexity`
+    pub async fn compute_text_perplexity(&self, text: &str, model: &str)
+      -> Result<f64, crate::error::Error>
+    {   let request = TogetherCompletionRequest
+        {   model: model.to_string()
+          , prompt: text.to_string()
+          , max_tokens: Some(1)
+          , echo: Some(true)
+          , logprobs: Some(1)
+        };
+        let body = self.send_completion(&request).await?;
+        super::prompt_logprobs(&body)
+          .and_then(|logprobs| perplexity(&logprobs))
+          .ok_or_else(|| crate::error::Error::ParseError(
+            "no prompt logprobs in response".to_string()
+          ))
+    }
+
+    fn handle_set_api_key(&mut self, model: Option<String>, key: String)
+    {   let key = StaticKeyProvider::shared(key);
+        match model
+        {   Some(model) => { self.model_keys.insert(model, key); }
+          , None => self.master_key = Some(key)
+        }
+    }
+
+    fn handle_set_key_provider(
+      &mut self
+    , model: Option<String>
+    , key_provider: SharedKeyProvider
+    )
+    {   match model
+        {   Some(model) => { self.model_keys.insert(model, key_provider); }
+          , None => self.master_key = Some(key_provider)
+        }
+    }
+}
+
+/// `exp` of the negated mean logprob of the tokens that have one (a
+/// text's first token has none); `None` when no token has one
+pub fn perplexity(logprobs: &[TokenLogprob]) -> Option<f64>
+{   let scored: Vec<f64> = logprobs.iter()
+      .filter_map(|token| token.logprob)
+      .collect();
+    if scored.is_empty()
+    {   return None;
+    }
+    let mean = scored.iter().sum::<f64>() / scored.len() as f64;
+    Some((-mean).exp())
+}
+
+async fn run_together_loop(
+  mut cmd_rx: mpsc::UnboundedReceiver<TogetherCommand>
+, mut state: TogetherClientState
+)
+{   debug!("Starting Together client loop");
+    loop
+    { match cmd_rx.recv().await
+      {   Some(TogetherCommand::ComputePerplexity {
+            text, model, reply
+          }) => {
+            debug!("Processing ComputePerplexity for: {}", model);
+            let result = state.compute_text_perplexity(&text, &model).await;
+            let _ = reply.send(result);
+          }
+        , Some(TogetherCommand::SetApiKey { model, key }) => {
+            state.handle_set_api_key(model, key);
+          }
+        , Some(TogetherCommand::SetKeyProvider { model, key_provider }) => {
+            state.handle_set_key_provider(model, key_provider);
+          }
+        , Some(TogetherCommand::Shutdown) => {
+            info!("Together client shutting down");
+            break;
+          }
+        , None => {
+            debug!("Command channel closed");
+            break;
+          }
+      }
+    }
+}
+
+/// Public Together client interface
+pub struct TogetherClient
+{   inner: OpenAiCompatClient
+  , tx: mpsc::UnboundedSender<TogetherCommand>
+  , _task: tokio::task::JoinHandle<()>
+}
+
+impl TogetherClient
+{   /// Create and spawn a new Together client
+    pub fn new(
+      api_key: Option<String>
+    , api_base: Option<String>
+    , http_client: impl Into<super::ProviderHttp>
+    ) -> Self
+    {   debug!("Creating TogetherClient");
+        let http_client: super::ProviderHttp = http_client.into();
+        let api_base = api_base
+          .unwrap_or_else(|| TOGETHER_API_BASE.to_string());
+        let api_base = api_base.trim_end_matches('/').to_string();
+        let endpoint = OpenAiCompatEndpoint
+        {   provider: crate::Provider::TogetherAi
+          , chat_url: format!("{}/chat/completions", api_base)
+          , models_url: Some(format!("{}/models", api_base))
+          , auth: EndpointAuth::Bearer
+        };
+        let state = TogetherClientState::new(
+          api_key.clone(), api_base, http_client.clone()
+        );
+        let (tx, cmd_rx) = mpsc::unbounded_channel();
+        let _task = tokio::spawn(run_together_loop(cmd_rx, state));
+        TogetherClient
+        {   inner: OpenAiCompatClient::new(endpoint, api_key, http_client)
+          , tx
+          , _task
+        }
+    }
+
+    /// Create a client from its provider configuration
+    pub fn from_config(
+      config: &crate::config::ProviderConfig
+    , http_config: &crate::config::HttpClientConfig
+    ) -> Result<Self, crate::error::Error>
+    {   let http_client
+          = super::build_http_client(Some(config), http_config)?;
+        Ok(TogetherClient::new(
+          config.api_key.clone(),
+          config.api_base.clone(),
+          http_client
+        ))
+    }
+
+    fn send(&self, command: TogetherCommand)
+      -> Result<(), crate::error::Error>
+    {   self.tx.send(command).map_err(|_| {
+          error!("Together client disconnected");
+          crate::error::Error::Other(
+            "Together client disconnected".to_string()
+          )
+        })
+    }
+
+    /// Shutdown the client
+    pub async fn shutdown(self)
+      -> Result<(), crate::error::Error>
+    {   let _ = self.tx.send(TogetherCommand::Shutdown);
+        self.inner.shutdown().await
+    }
+}
+
+impl ProviderClient for TogetherClient
+{   fn provider(&self) -> crate::Provider
+    {   crate::Provider::TogetherAi
+    }
+
+    fn is_alive(&self) -> bool
+    {   self.inner.is_alive() && !self._task.is_finished()
+    }
+
+    fn rate_limit_status(&self) -> crate::utils::rate_limiter::RateLimitStatus
+    {   self.inner.rate_limit_status()
+    }
+
+    fn send_chat(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::SendPromptReplySender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat(request, reply)
+    }
+
+    fn send_chat_response(
+      &self
+    , request: super::ChatRequest
+    , reply: super::ChatResponseSender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat_response(request, reply)
+    }
+
+    fn send_chat_stream(
+      &self
+    , request: super::ChatRequest
+    , reply: crate::StreamReplySender
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.send_chat_stream(request, reply)
+    }
+
+    fn request_body(
+      &self
+    , request: super::ChatRequest
+    ) -> Result<serde_json::Value, crate::error::Error>
+    {   self.inner.request_body(request)
+    }
+
+    fn compute_perplexity(
+      &self
+    , text: String
+    , model: String
+    , reply: crate::ComputePerplexityReplySender
+    ) -> Result<(), crate::error::Error>
+    {   debug!("compute_perplexity queued for model: {}", model);
+        self.send(TogetherCommand::ComputePerplexity { text, model, reply })
+    }
+
+    fn get_available_models(
+      &self
+    , reply: mpsc::UnboundedSender<
+        Result<Vec<String>, crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   self.inner.get_available_models(reply)
+    }
+
+    fn set_api_key(
+      &self
+    , model: Option<String>
+    , key: String
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   self.send(TogetherCommand::SetApiKey
+        {   model: model.clone()
+          , key: key.clone()
+        })?;
+        self.inner.set_api_key(model, key, reply)
+    }
+
+    fn set_key_provider(
+      &self
+    , model: Option<String>
+    , key_provider: SharedKeyProvider
+    , reply: mpsc::UnboundedSender<
+        Result<(), crate::error::Error>
+      >
+    ) -> Result<(), crate::error::Error>
+    {   self.send(TogetherCommand::SetKeyProvider
+        {   model: model.clone()
+          , key_provider: key_provider.clone()
+        })?;
+        self.inner.set_key_provider(model, key_provider, reply)
+    }
+}
@@ -1,5 +1,7 @@
 //! Unified request and response types for ALLM
 
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 /// Unified prompt request
@@ -17,6 +19,302 @@ pub struct PromptRequest
     pub max_tokens: Option<usize>
   , /// Temperature for sampling
     pub temperature: Option<f32>
+  , /// Keep the provider's response body in `PromptResponse::raw`
+    #[serde(default)]
+    pub return_raw: bool
+  , /// Extra body fields for parameters allm does not model
+    /// (`logit_bias`, `parallel_tool_calls`, ...). Fields allm sets
+    /// itself win over these.
+    #[serde(default)]
+    pub extra_params: Option<serde_json::Map<String, serde_json::Value>>
+  , /// Correlation id for logs, the provider's `X-Request-Id` header
+    /// and `PromptResponse::request_id`; a UUID when `None`
+    #[serde(default)]
+    pub request_id: Option<String>
+  , /// Strings that end generation; the reply leaves them out.
+    /// OpenAI and Mistral accept at most four.
+    #[serde(default)]
+    pub stop_sequences: Option<Vec<String>>
+  , /// Cap on the whole operation, retries and failover included.
+    /// Once the next attempt would start past it, the prompt fails
+    /// with `Error::DeadlineExceeded`.
+    #[serde(default)]
+    pub total_deadline: Option<std::time::Duration>
+  , /// Validate the reply as JSON against a schema, asking the model
+    /// to correct mismatches
+    #[serde(default)]
+    pub json_schema: Option<JsonSchemaRetry>
+  , /// Start of the assistant's reply, sent as a trailing assistant
+    /// message for the model to continue. Providers without prefill
+    /// (see `providers::supports_prefill`) reject it.
+    #[serde(default)]
+    pub prefill: Option<String>
+  , /// Completions to generate, returned in `PromptResponse::choices`;
+    /// must be at least 1
+    #[serde(default)]
+    pub n: Option<usize>
+  , /// Place among prompts waiting for the provider's rate limit
+    #[serde(default)]
+    pub priority: Priority
+  , /// Only ever send to `provider`/`model`: no retries, fallbacks
+    /// or context upgrades, whatever `FailoverConfig::enabled` says.
+    /// The first error is the reply.
+    #[serde(default)]
+    pub no_failover: bool
+  , /// Example exchanges sent before the prompt
+    #[serde(default)]
+    pub few_shot: Option<FewShot>
+}
+
+/// Example input/output pairs for in-context learning, sent as
+/// alternating user and assistant messages after the system message
+/// and before the prompt
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FewShot
+{   /// (user, assistant) pairs, in order
+    pub examples: Vec<(String, String)>
+}
+
+impl FewShot
+{   pub fn new(examples: Vec<(String, String)>) -> Self
+    {   FewShot { examples }
+    }
+
+    /// Fail with `InvalidConfiguration` when there are no examples
+    pub fn validate(&self) -> Result<(), crate::error::Error>
+    {   if self.examples.is_empty()
+        {   return Err(crate::error::Error::InvalidConfiguration(
+              "few_shot needs at least one example".to_string()
+            ));
+        }
+        Ok(())
+    }
+
+    /// The examples as chat messages, each pair a user message then
+    /// an assistant message
+    pub fn messages(&self) -> Vec<crate::providers::mistral::ChatMessage>
+    {   use crate::providers::mistral::ChatMessage;
+
+        self.examples.iter()
+          .flat_map(|(user, assistant)| [
+            ChatMessage::user(user.clone()),
+            ChatMessage::assistant(assistant.clone())
+          ])
+          .collect()
+    }
+}
+
+/// Schema a reply must match (see `utils::json_schema`). A mismatch
+/// is sent back to the model with a correction message, up to
+/// `max_attempts` replies in all; then the prompt fails with
+/// `Error::ParseError`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonSchemaRetry
+{   pub schema: serde_json::Value
+  , pub max_attempts: usize
+}
+
+impl JsonSchemaRetry
+{   pub fn new(schema: serde_json::Value, max_attempts: usize) -> Self
+    {   JsonSchemaRetry { schema, max_attempts }
+    }
+}
+
+/// `InvalidConfiguration("model {name} does not support {feature}")`
+/// for the first of these `extra_params` fields set for a model
+/// without the feature: `tools`, `tool_choice` or `functions`
+/// (tools), `logprobs` or `top_logprobs` (logprobs), `seed` or
+/// `random_seed` (seed)
+pub fn check_capabilities(
+  extra_params: Option<&serde_json::Map<String, serde_json::Value>>
+, info: &crate::ModelInfo
+) -> Result<(), crate::error::Error>
+{   let Some(params) = extra_params else { return Ok(()) };
+    let features: [(&[&str], &str, bool); 3] =
+    [ (&["tools", "tool_choice", "functions"], "tools", info.supports_tools)
+    , (&["logprobs", "top_logprobs"], "logprobs", info.supports_logprobs)
+    , (&["seed", "random_seed"], "seed", info.supports_reproducible_outputs)
+    ];
+    let asked = |name: &&str| {
+      params.get(*name)
+        .is_some_and(|v| !v.is_null() && *v != serde_json::Value::Bool(false))
+    };
+    match features.iter().find(|(names, _, supported)| !supported && names.iter().any(asked))
+    {   Some((_, feature, _)) => Err(crate::error::Error::InvalidConfiguration(
+          format!("model {} does not support {}", info.name, feature)
+        ))
+      , None => Ok(())
+    }
+}
+
+/// `response_format` of an OpenAI-style chat request, asking the
+/// model for JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ResponseFormat
+{   /// Any JSON object
+    JsonObject
+  , /// JSON matching this schema
+    JsonSchema(serde_json::Value)
+}
+
+impl ResponseFormat
+{   /// `JsonSchema` when there is a schema, else `JsonObject`
+    pub fn for_schema(schema: Option<serde_json::Value>) -> Self
+    {   match schema
+        {   Some(schema) => ResponseFormat::JsonSchema(schema)
+          , None => ResponseFormat::JsonObject
+        }
+    }
+
+    /// Value of the request body's `response_format` field
+    pub fn to_param(&self) -> serde_json::Value
+    {   match self
+        {   ResponseFormat::JsonObject => serde_json::json!({ "type": "json_object" })
+          , ResponseFormat::JsonSchema(schema) => serde_json::json!(
+            {   "type": "json_schema"
+              , "json_schema": { "name": "response", "schema": schema, "strict": true }
+            })
+        }
+    }
+}
+
+/// Which prompt waiting on a rate-limited provider goes first;
+/// prompts of the same priority go in the order they arrived
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+  Serialize, Deserialize
+)]
+pub enum Priority
+{   /// Interactive prompts, ahead of everything else
+    High
+  , #[default]
+    Normal
+  , /// Background work, once nothing else waits
+    Low
+}
+
+/// Prompt text with `{{name}}` placeholders, filled by `render`.
+/// `\{{` and `\}}` stand for literal `{{` and `}}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PromptTemplate
+{   pub template: String
+}
+
+impl PromptTemplate
+{   pub fn new(template: impl Into<String>) -> Self
+    {   PromptTemplate { template: template.into() }
+    }
+
+    /// Names of the placeholders, in order of first use
+    pub fn variables(&self) -> Vec<String>
+    {   let mut names = vec![];
+        let _ = self.substitute(|name| {
+          if !names.iter().any(|n| n == name)
+          {   names.push(name.to_string());
+          }
+          Some(String::new())
+        });
+        names
+    }
+
+    /// The template with every placeholder replaced by its value in
+    /// `vars`. Fails with `InvalidConfiguration` naming the variables
+    /// `vars` lacks, or on an unclosed `{{`.
+    pub fn render(&self, vars: &HashMap<String, String>)
+      -> Result<String, crate::error::Error>
+    {   let mut missing: Vec<String> = vec![];
+        let rendered = self.substitute(|name| {
+          let value = vars.get(name).cloned();
+          if value.is_none() && !missing.iter().any(|n| n == name)
+          {   missing.push(name.to_string());
+          }
+          value
+        })?;
+        if !missing.is_empty()
+        {   return Err(crate::error::Error::InvalidConfiguration(
+              format!("missing template variables: {}", missing.join(", "))
+            ));
+        }
+        Ok(rendered)
+    }
+
+    /// Walk the template, asking `value` for each placeholder
+    fn substitute(&self, mut value: impl FnMut(&str) -> Option<String>)
+      -> Result<String, crate::error::Error>
+    {   let mut out = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(at) = rest.find(['\\', '{'])
+        {   out.push_str(&rest[..at]);
+            rest = &rest[at..];
+            if let Some(escaped) = ["\\{{", "\\}}"].iter()
+              .find(|e| rest.starts_with(**e))
+            {   out.push_str(&escaped[1..]);
+                rest = &rest[escaped.len()..];
+            } else if let Some(open) = rest.strip_prefix("{{")
+            {   let close = open.find("}}").ok_or_else(|| {
+                  crate::error::Error::InvalidConfiguration(
+                    "unclosed {{ in prompt template".to_string()
+                  )
+                })?;
+                let name = open[..close].trim();
+                out.push_str(&value(name).unwrap_or_default());
+                rest = &open[close + 2..];
+            } else
+            {   let c = rest.chars().next().unwrap_or_default();
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+impl PromptRequest
+{   /// Request with provider defaults for everything optional
+    pub fn new(
+      provider: crate::Provider
+    , model: String
+    , prompt: String
+    ) -> Self
+    {   PromptRequest
+        {   prompt
+          , provider
+          , model
+          , system_message: None
+          , max_tokens: None
+          , temperature: None
+          , return_raw: false
+          , extra_params: None
+          , request_id: None
+          , stop_sequences: None
+          , total_deadline: None
+          , json_schema: None
+          , prefill: None
+          , n: None
+          , priority: Priority::Normal
+          , no_failover: false
+          , few_shot: None
+        }
+    }
+
+    /// Check the request asks `info` for nothing it lacks; see
+    /// `check_capabilities`
+    pub fn validate_for_model(&self, info: &crate::ModelInfo)
+      -> Result<(), crate::error::Error>
+    {   check_capabilities(self.extra_params.as_ref(), info)
+    }
+
+    /// `new` with the prompt rendered from `template`
+    pub fn from_template(
+      provider: crate::Provider
+    , model: String
+    , template: &PromptTemplate
+    , vars: &HashMap<String, String>
+    ) -> Result<Self, crate::error::Error>
+    {   Ok(PromptRequest::new(provider, model, template.render(vars)?))
+    }
 }
 
 /// Unified prompt response
@@ -30,6 +328,106 @@ pub struct PromptResponse
     pub model: String
   , /// Tokens used
     pub tokens_used: Option<usize>
+  , /// Untouched provider response body, when `return_raw` was set.
+    /// Holds fields allm does not model (logprobs,
+    /// system_fingerprint, provider extras).
+    #[serde(default)]
+    pub raw: Option<serde_json::Value>
+  , /// `PromptRequest::request_id`, or the id generated for it
+    #[serde(default)]
+    pub request_id: String
+  , /// What middleware recorded, e.g. `PiiRedactor` placeholders
+    #[serde(default)]
+    pub metadata: crate::middleware::RequestMetadata
+  , /// Times the prompt was rephrased after a refusal, see
+    /// `AllmBackend::ask_with_rephrasing`
+    #[serde(default)]
+    pub rephrase_attempts: u8
+  , /// Text of every choice, in order; `text` is the first
+    #[serde(default)]
+    pub choices: Vec<String>
+  , /// Logprobs of the prompt's tokens, when the request asked the
+    /// provider to `echo` them (Together: `echo: true` and `logprobs`
+    /// in `extra_params`)
+    #[serde(default)]
+    pub prompt_logprobs: Option<Vec<TokenLogprob>>
+  , /// Chain-of-thought of a reasoning model: the provider's own
+    /// field (DeepSeek's `reasoning_content`), else a `<think>` block
+    /// taken off the front of `text` (see `utils::thinking`)
+    #[serde(default)]
+    pub thinking: Option<String>
+  , /// Every model the prompt went to, in order; the last is the one
+    /// that answered
+    #[serde(default)]
+    pub failover_chain: Vec<crate::failover::FailoverHop>
+}
+
+impl PromptResponse
+{   /// True when the prompt failed over before this reply
+    pub fn was_failover(&self) -> bool
+    {   self.failover_chain.len() > 1
+    }
+
+    pub fn failover_hops(&self) -> &[crate::failover::FailoverHop]
+    {   &self.failover_chain
+    }
+}
+
+/// Reply of a reasoning model such as `deepseek-reasoner`, split into
+/// its chain-of-thought and the answer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeepSeekResponse
+{   /// `None` when the model showed no thinking
+    pub thinking: Option<String>
+  , pub answer: String
+}
+
+impl From<PromptResponse> for DeepSeekResponse
+{   fn from(response: PromptResponse) -> Self
+    {   DeepSeekResponse { thinking: response.thinking, answer: response.text }
+    }
+}
+
+/// One token and its log probability; `None` where the provider
+/// gives none, e.g. for a text's first token
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenLogprob
+{   pub token: String
+  , pub logprob: Option<f64>
+}
+
+/// How `AllmBackend::ask_with_rephrasing` rewrites a refused prompt
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RephrasingStrategy
+{   /// Ask the same model to reword the last prompt it refused
+    #[default]
+    AutoRephrase
+  , /// Resend the original prompt with this text in front
+    CustomPrefix(String)
+  , /// Ask this model to reword the last refused prompt
+    AlternativeModel(crate::Provider, String)
+}
+
+/// How `AllmBackend::map_over_texts_with` works through its texts
+pub struct MapConfig
+{   /// Prompts waiting for a reply at once
+    pub concurrency: usize
+  , /// End the stream after the first error instead of going on
+    /// with the other texts
+    pub fail_fast: bool
+  , /// Called with (texts done, texts in all) after each reply
+    pub progress_callback: Option<Box<dyn Fn(usize, usize) + Send>>
+}
+
+impl Default for MapConfig
+{   /// Four at a time, past any errors
+    fn default() -> Self
+    {   MapConfig
+        {   concurrency: 4
+          , fail_fast: false
+          , progress_callback: None
+        }
+    }
 }
 
 /// Unified error response
@@ -0,0 +1,239 @@
+//! Multi-turn conversations kept by the backend
+//!
+//! A session stores the message history sent with each of its
+//! prompts. Once the history grows past `max_messages` the
+//! session's `PruningStrategy` shortens it; a session with a
+//! `SummarizationStrategy` also folds its oldest turns into a summary
+//! as the history nears its model's context window. Sessions
+//! serialize to JSON so they can outlive the process.
+
+use serde::{Deserialize, Serialize};
+
+use crate::providers::mistral::{ChatMessage, Role};
+use crate::utils::uuid::Uuid;
+
+/// Prefix of the system message holding a session summary
+pub const SUMMARY_PREFIX: &str = "[Previous conversation summary]:";
+
+/// Instruction sent with the transcript when summarizing
+pub const SUMMARY_INSTRUCTION: &str
+  = "Summarize this conversation in 3-5 sentences";
+
+/// Author of a conversation message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageRole
+{   System
+  , User
+  , Assistant
+}
+
+impl MessageRole
+{   /// Role name on the wire
+    pub fn as_str(&self) -> &'static str
+    {   match self
+        {   MessageRole::System => "system"
+          , MessageRole::User => "user"
+          , MessageRole::Assistant => "assistant"
+        }
+    }
+}
+
+impl From<MessageRole> for Role
+{   fn from(role: MessageRole) -> Self
+    {   match role
+        {   MessageRole::System => Role::System
+          , MessageRole::User => Role::User
+          , MessageRole::Assistant => Role::Assistant
+        }
+    }
+}
+
+/// One message of a session's history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConversationMessage
+{   pub role: MessageRole
+  , pub content: String
+}
+
+impl ConversationMessage
+{   pub fn new(role: MessageRole, content: String) -> Self
+    {   ConversationMessage { role, content }
+    }
+}
+
+/// How a session shortens its history past `max_messages`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PruningStrategy
+{   /// Drop the oldest user/assistant messages
+    DropOldest
+  , /// Replace the user/assistant turns with a summary written by
+    /// `model`
+    Summary
+    {   model: (crate::Provider, String)
+    }
+}
+
+/// Share of the context window a history may fill before a
+/// `SummarizationStrategy` summarizes it, by default
+pub const DEFAULT_SUMMARY_TRIGGER_PERCENT: f32 = 80.0;
+
+/// Rolling summary of a long session: once the estimated history
+/// fills `trigger_percent` of the context window of the model that
+/// answered the latest turn, its oldest `oldest_messages`
+/// user/assistant messages are replaced with a summary from `model`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SummarizationStrategy
+{   /// Model writing the summary, best a cheap one
+    pub model: (crate::Provider, String)
+  , pub oldest_messages: usize
+  , pub trigger_percent: f32
+}
+
+impl SummarizationStrategy
+{   /// Summarize `oldest_messages` at the default trigger
+    pub fn new(model: (crate::Provider, String), oldest_messages: usize)
+      -> Self
+    {   SummarizationStrategy
+        {   model
+          , oldest_messages
+          , trigger_percent: DEFAULT_SUMMARY_TRIGGER_PERCENT
+        }
+    }
+}
+
+/// Conversation history and its pruning settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationSession
+{   pub id: Uuid
+  , pub messages: Vec<ConversationMessage>
+  , /// History length that triggers pruning
+    pub max_messages: usize
+  , pub pruning: PruningStrategy
+  , /// Latest summary of the pruned turns, if summarized
+    pub summary: Option<String>
+  , /// Model every turn goes to; a pinned session never fails over
+    #[serde(default)]
+    pub pinned_model: Option<(crate::Provider, String)>
+  , /// Model that answered the latest turn
+    #[serde(default)]
+    pub last_used_model: Option<(crate::Provider, String)>
+  , /// Rolling summary near the context window, when set
+    #[serde(default)]
+    pub summarization: Option<SummarizationStrategy>
+}
+
+impl ConversationSession
+{   pub fn new(max_messages: usize, pruning: PruningStrategy) -> Self
+    {   ConversationSession
+        {   id: Uuid::new_v4()
+          , messages: vec![]
+          , max_messages
+          , pruning
+          , summary: None
+          , pinned_model: None
+          , last_used_model: None
+          , summarization: None
+        }
+    }
+
+    /// Session as JSON, for `from_json` after a restart
+    pub fn to_json(&self) -> Result<String, serde_json::Error>
+    {   serde_json::to_string(self)
+    }
+
+    pub fn from_json(s: &str) -> Result<Self, serde_json::Error>
+    {   serde_json::from_str(s)
+    }
+
+    /// True once the history is longer than `max_messages`
+    pub fn needs_pruning(&self) -> bool
+    {   self.messages.len() > self.max_messages
+    }
+
+    pub fn push(&mut self, role: MessageRole, content: String)
+    {   self.messages.push(ConversationMessage::new(role, content));
+    }
+
+    /// History in provider wire format
+    pub fn chat_messages(&self) -> Vec<ChatMessage>
+    {   self.messages.iter()
+          .map(|m| ChatMessage::new(m.role.into(), m.content.clone()))
+          .collect()
+    }
+
+    fn is_summary(message: &ConversationMessage) -> bool
+    {   message.role == MessageRole::System
+          && message.content.starts_with(SUMMARY_PREFIX)
+    }
+
+    /// Remove the oldest user/assistant messages until the history
+    /// fits `max_messages`; system messages are kept
+    pub fn drop_oldest(&mut self)
+    {   while self.needs_pruning()
+        {   match self.messages.iter()
+              .position(|m| m.role != MessageRole::System)
+            {   Some(index) => { self.messages.remove(index); }
+              , None => break
+            }
+        }
+    }
+
+    /// Estimated tokens of the whole history
+    pub fn estimated_tokens(&self) -> usize
+    {   self.messages.iter()
+          .map(|m| crate::utils::tokens::estimate_tokens(&m.content))
+          .sum()
+    }
+
+    /// Number of leading messages that holds the oldest `count`
+    /// user/assistant messages
+    pub fn oldest_turns_end(&self, count: usize) -> usize
+    {   self.messages.iter()
+          .enumerate()
+          .filter(|(_, m)| m.role != MessageRole::System)
+          .nth(count.saturating_sub(1))
+          .map_or(self.messages.len(), |(index, _)| index + 1)
+    }
+
+    /// Prompt asking a model to summarize the user/assistant turns
+    /// (and any earlier summary)
+    pub fn summary_prompt(&self) -> String
+    {   self.summary_prompt_of(self.messages.len())
+    }
+
+    /// `summary_prompt` over the first `summarized` messages only
+    pub fn summary_prompt_of(&self, summarized: usize) -> String
+    {   let mut prompt = format!("{}:\n", SUMMARY_INSTRUCTION);
+        if let Some(summary) = &self.summary
+        {   prompt.push_str(&format!("\nEarlier summary: {}\n", summary));
+        }
+        for message in self.messages.iter().take(summarized)
+        {   let speaker = match message.role
+            {   MessageRole::User => "User"
+              , MessageRole::Assistant => "Assistant"
+              , MessageRole::System => continue
+            };
+            prompt.push_str(&format!("\n{}: {}", speaker, message.content));
+        }
+        prompt
+    }
+
+    /// Replace the user/assistant turns among the first `summarized`
+    /// messages, and any earlier summary, with a single system
+    /// message holding `summary`. Messages added after the summary
+    /// prompt was built are kept.
+    pub fn apply_summary(&mut self, summary: String, summarized: usize)
+    {   let summarized = summarized.min(self.messages.len());
+        let newer = self.messages.split_off(summarized);
+        self.messages.retain(|m| {
+          m.role == MessageRole::System && !Self::is_summary(m)
+        });
+        self.messages.push(ConversationMessage::new(
+          MessageRole::System,
+          format!("{} {}", SUMMARY_PREFIX, summary)
+        ));
+        self.messages.extend(newer);
+        self.summary = Some(summary);
+    }
+}
@@ -0,0 +1,143 @@
+// allm/src/supervisor.rs
+
+//! Restart policy for provider actors
+//!
+//! The backend checks its clients every `check_interval_ms`. A client
+//! whose actor task has ended is restarted after an exponential
+//! backoff, until it has crashed `max_restarts` times within
+//! `window_secs`; then it is marked failed and taken out of routing.
+
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::config::SupervisorConfig;
+
+/// Where a provider client stands with the supervisor
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SupervisionState
+{   Running
+  , /// Crashed, restart pending
+    Restarting
+    {   /// Crashes within the window, this one included
+        attempt: u32
+      , /// Wait before the restart
+        backoff_ms: u64
+    }
+  , /// Crashed too often; no longer routed to
+    Failed
+}
+
+/// Supervision record of one provider, from `get_supervision_status`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProviderSupervision
+{   pub provider: crate::Provider
+  , pub state: SupervisionState
+  , /// Restarts since the backend started
+    pub restarts: u32
+}
+
+#[derive(Debug, Default)]
+struct Record
+{   crashes: VecDeque<Instant>
+  , restarts: u32
+  , restart_at: Option<Instant>
+  , failed: bool
+}
+
+/// Crash bookkeeping for every supervised provider
+#[derive(Debug, Default)]
+pub struct Supervisor
+{   config: SupervisorConfig
+  , records: HashMap<crate::Provider, Record>
+}
+
+impl Supervisor
+{   pub fn new(config: SupervisorConfig) -> Self
+    {   Supervisor { config, records: HashMap::new() }
+    }
+
+    /// Record that the client of `provider` died at `now`; returns
+    /// what happens to it next
+    pub fn crashed(&mut self, provider: &crate::Provider, now: Instant)
+      -> SupervisionState
+    {   let window = Duration::from_secs(self.config.window_secs);
+        let record = self.records.entry(provider.clone()).or_default();
+        record.crashes.push_back(now);
+        while record.crashes.front()
+          .is_some_and(|t| now.duration_since(*t) > window)
+        {   record.crashes.pop_front();
+        }
+        let attempt = record.crashes.len() as u32;
+        if attempt > self.config.max_restarts
+        {   record.failed = true;
+            record.restart_at = None;
+            return SupervisionState::Failed;
+        }
+        let backoff = self.config.initial_backoff_ms
+          .saturating_mul(1 << (attempt - 1).min(20))
+          .min(self.config.max_backoff_ms);
+        record.restart_at = Some(now + Duration::from_millis(backoff));
+        SupervisionState::Restarting { attempt, backoff_ms: backoff }
+    }
+
+    /// Providers whose restart is due at `now`
+    pub fn due(&self, now: Instant) -> Vec<crate::Provider>
+    {   self.records.iter()
+          .filter(|(_, r)| r.restart_at.is_some_and(|at| at <= now))
+          .map(|(provider, _)| provider.clone())
+          .collect()
+    }
+
+    /// The client of `provider` is running again
+    pub fn restarted(&mut self, provider: &crate::Provider)
+    {   if let Some(record) = self.records.get_mut(provider)
+        {   record.restart_at = None;
+            record.restarts += 1;
+        }
+    }
+
+    /// Interval between liveness checks
+    pub fn check_interval(&self) -> Duration
+    {   Duration::from_millis(self.config.check_interval_ms.max(1))
+    }
+
+    pub fn enabled(&self) -> bool
+    {   self.config.enabled
+    }
+
+    /// State of `provider`, `Running` if it never crashed
+    pub fn state(&self, provider: &crate::Provider) -> SupervisionState
+    {   let Some(record) = self.records.get(provider) else
+        {   return SupervisionState::Running;
+        };
+        if record.failed
+        {   return SupervisionState::Failed;
+        }
+        match record.restart_at
+        {   Some(at) => SupervisionState::Restarting
+            {   attempt: record.crashes.len() as u32
+              , backoff_ms: at.saturating_duration_since(Instant::now())
+                  .as_millis() as u64
+            }
+          , None => SupervisionState::Running
+        }
+    }
+
+    /// Records of `providers`, ordered by name
+    pub fn status(&self, providers: impl IntoIterator<Item = crate::Provider>)
+      -> Vec<ProviderSupervision>
+    {   let mut status: Vec<ProviderSupervision> = providers.into_iter()
+          .map(|provider| ProviderSupervision
+          {   state: self.state(&provider)
+            , restarts: self.records.get(&provider)
+                .map(|r| r.restarts)
+                .unwrap_or(0)
+            , provider
+          })
+          .collect();
+        status.sort_by_key(|s| format!("{:?}", s.provider));
+        status
+    }
+}
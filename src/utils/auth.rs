@@ -0,0 +1,182 @@
+//! Request signing for endpoints that authenticate each request by
+//! a signature over it (some enterprise gateways, self-hosted model
+//! servers) instead of, or on top of, a bearer key
+//!
+//! A provider's `ProviderHttp` holds the signer and applies it to a
+//! request once its body is final (see `ProviderHttp::sign`).
+
+use std::sync::Arc;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// Signer shared between a provider's HTTP clients
+pub type SharedRequestSigner = Arc<dyn RequestSigner>;
+
+/// Adds authentication headers computed from a request
+pub trait RequestSigner: Send + Sync
+{   /// Sign a `method` request to `url` with `body`, adding the
+    /// signature to `headers`
+    fn sign(
+      &self
+    , method: &str
+    , url: &str
+    , body: &[u8]
+    , headers: &mut HeaderMap
+    ) -> Result<(), crate::error::Error>;
+}
+
+impl std::fmt::Debug for dyn RequestSigner
+{   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {   f.write_str("RequestSigner")
+    }
+}
+
+/// Signs `METHOD\npath\nhex(sha256(body))` with HMAC-SHA256 of
+/// `secret`, sent hex-encoded as `X-Signature` next to
+/// `X-Signature-KeyId`
+#[derive(Clone)]
+pub struct HmacSha256Signer
+{   pub key_id: String
+  , pub secret: String
+}
+
+impl std::fmt::Debug for HmacSha256Signer
+{   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {   f.debug_struct("HmacSha256Signer")
+          .field("key_id", &self.key_id)
+          .finish_non_exhaustive()
+    }
+}
+
+impl HmacSha256Signer
+{   pub fn new(key_id: String, secret: String) -> Self
+    {   HmacSha256Signer { key_id, secret }
+    }
+
+    /// Shared signer for `key_id` and `secret`
+    pub fn shared(key_id: String, secret: String) -> SharedRequestSigner
+    {   Arc::new(HmacSha256Signer::new(key_id, secret))
+    }
+
+    /// Hex signature of a `method` request to `path` with `body`
+    pub fn signature(&self, method: &str, path: &str, body: &[u8]) -> String
+    {   let message = format!("{}\n{}\n{}", method, path, hex(&sha256(body)));
+        hex(&hmac_sha256(self.secret.as_bytes(), message.as_bytes()))
+    }
+}
+
+impl RequestSigner for HmacSha256Signer
+{   fn sign(
+      &self
+    , method: &str
+    , url: &str
+    , body: &[u8]
+    , headers: &mut HeaderMap
+    ) -> Result<(), crate::error::Error>
+    {   let url = reqwest::Url::parse(url).map_err(|e| {
+          crate::error::Error::InvalidConfiguration(
+            format!("signed URL {:?}: {}", url, e)
+          )
+        })?;
+        let key_id = HeaderValue::from_str(&self.key_id).map_err(|e| {
+          crate::error::Error::InvalidConfiguration(
+            format!("signing key id {:?}: {}", self.key_id, e)
+          )
+        })?;
+        let signature = self.signature(method, url.path(), body);
+        headers.insert(HeaderName::from_static("x-signature-keyid"), key_id);
+        headers.insert(
+          HeaderName::from_static("x-signature"),
+          HeaderValue::from_str(&signature).expect("hex is a valid header")
+        );
+        Ok(())
+    }
+}
+
+/// Lowercase hex of `bytes`
+pub fn hex(bytes: &[u8]) -> String
+{   bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// HMAC (RFC 2104) of `message` with SHA-256
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32]
+{   let mut block = [0u8; 64];
+    if key.len() > block.len()
+    {   block[..32].copy_from_slice(&sha256(key));
+    } else
+    {   block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+    let mut inner = pad(0x36);
+    inner.extend_from_slice(message);
+    let mut outer = pad(0x5c);
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+const K: [u32; 64] =
+[   0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5
+  , 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174
+  , 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da
+  , 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967
+  , 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85
+  , 0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070
+  , 0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3
+  , 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+/// SHA-256 (FIPS 180-4) digest of `data`
+pub fn sha256(data: &[u8]) -> [u8; 32]
+{   let mut h: [u32; 8] =
+    [   0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a
+      , 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+    ];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56
+    {   padded.push(0);
+    }
+    padded.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in padded.chunks_exact(64)
+    {   let mut w = [0u32; 64];
+        for (i, word) in chunk.chunks_exact(4).enumerate()
+        {   w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64
+        {   let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18)
+              ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19)
+              ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7])
+              .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64
+        {   let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i])
+              .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh])
+        {   *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(h)
+    {   bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
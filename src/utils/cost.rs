@@ -0,0 +1,92 @@
+// allm/src/utils/cost.rs
+
+//! Token prices, from a JSON price file or the model catalog
+//!
+//! The file maps provider names to models to USD prices per million
+//! tokens, so prices can change without a new crate release:
+//!
+//! ```json
+//! { "mistral": { "mistral-small-latest": { "input": 0.1, "output": 0.3 } } }
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use log::{debug, error};
+use serde::Deserialize;
+
+/// USD per million input and output tokens
+pub type Price = (f32, f32);
+
+/// Prices by provider and model name
+pub type PriceTable = HashMap<(crate::Provider, String), Price>;
+
+#[derive(Deserialize)]
+struct PriceEntry
+{   input: f32
+  , output: f32
+}
+
+/// Read a price file (see the module docs)
+pub fn load_price_table(path: impl AsRef<Path>)
+  -> Result<PriceTable, crate::error::Error>
+{   let path = path.as_ref();
+    let json = std::fs::read_to_string(path).map_err(|e| {
+      error!("Cannot read price table {}: {}", path.display(), e);
+      crate::error::Error::InvalidConfiguration(
+        format!("price table {}: {}", path.display(), e)
+      )
+    })?;
+    parse_price_table(&json)
+}
+
+/// `load_price_table` from the file's contents
+pub fn parse_price_table(json: &str)
+  -> Result<PriceTable, crate::error::Error>
+{   let file: HashMap<String, HashMap<String, PriceEntry>>
+      = serde_json::from_str(json).map_err(|e| {
+        error!("Invalid price table: {}", e);
+        crate::error::Error::ParseError(e.to_string())
+      })?;
+    let mut table = PriceTable::new();
+    for (name, models) in file
+    {   let provider = crate::Provider::from_name(&name)
+          .ok_or_else(|| {
+            error!("Unknown provider in price table: {}", name);
+            crate::error::Error::InvalidConfiguration(
+              format!("unknown provider in price table: {}", name)
+            )
+          })?;
+        for (model, price) in models
+        {   table.insert((provider.clone(), model), (price.input, price.output));
+        }
+    }
+    debug!("Loaded {} prices", table.len());
+    Ok(table)
+}
+
+/// Price of a catalog model: the table's if it has one, otherwise
+/// the catalog's when both its costs are known
+pub fn price_for(table: &PriceTable, info: &crate::ModelInfo)
+  -> Option<Price>
+{   table.get(&(info.provider.clone(), info.name.clone()))
+      .copied()
+      .or_else(|| Some((
+        info.cost_per_million_input_tokens?,
+        info.cost_per_million_output_tokens?
+      )))
+}
+
+/// USD cost of a call to `info` with the given token counts
+pub fn estimate_cost(
+  table: &PriceTable
+, info: &crate::ModelInfo
+, input_tokens: usize
+, output_tokens: usize
+) -> Option<f32>
+{   let (input, output) = price_for(table, info)?;
+    Some(
+      (input * input_tokens as f32 + output * output_tokens as f32)
+        / 1_000_000.0
+    )
+}
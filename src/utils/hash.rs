@@ -0,0 +1,17 @@
+//! Content hashes for recognizing identical requests
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Hash of `parts` in order. `("ab", "c")` and `("a", "bc")` hash
+/// differently.
+///
+/// `DefaultHasher::new` is stable within a process, which is all the
+/// in-memory deduplication needs; do not persist the value.
+pub fn content_hash(parts: &[&str]) -> u64
+{   let mut hasher = DefaultHasher::new();
+    for part in parts
+    {   part.hash(&mut hasher);
+    }
+    hasher.finish()
+}
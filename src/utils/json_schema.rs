@@ -0,0 +1,146 @@
+// allm/src/utils/json_schema.rs
+
+//! Validation of model output against a JSON Schema
+//!
+//! Covers the keywords structured outputs rely on: `type`, `enum`,
+//! `const`, `properties`, `required`, `additionalProperties`,
+//! `items`, `minItems`/`maxItems`, `minLength`/`maxLength` and
+//! `minimum`/`maximum`. Other keywords are ignored.
+
+use serde_json::Value;
+
+/// `text` parsed as JSON, without the Markdown code fence models
+/// like to wrap it in
+pub fn parse_output(text: &str) -> Result<Value, String>
+{   let text = text.trim();
+    let text = text.strip_prefix("```json")
+      .or_else(|| text.strip_prefix("```"))
+      .and_then(|rest| rest.strip_suffix("```"))
+      .unwrap_or(text);
+    serde_json::from_str(text.trim()).map_err(|e| format!("not JSON: {}", e))
+}
+
+/// Check `value` against `schema`; the error names the first
+/// mismatch and where it is, e.g. `$.tags[1]: expected string`
+pub fn validate(schema: &Value, value: &Value) -> Result<(), String>
+{   check(schema, value, "$")
+}
+
+fn check(schema: &Value, value: &Value, path: &str) -> Result<(), String>
+{   let Some(schema) = schema.as_object() else { return Ok(()) };
+    if let Some(expected) = schema.get("type")
+    {   let types: Vec<&str> = match expected
+        {   Value::String(name) => vec![name.as_str()]
+          , Value::Array(names) => {
+              names.iter().filter_map(Value::as_str).collect()
+            }
+          , _ => vec![]
+        };
+        if !types.is_empty() && !types.iter().any(|t| has_type(value, t))
+        {   return Err(format!("{}: expected {}", path, types.join(" or ")));
+        }
+    }
+    if let Some(options) = schema.get("enum").and_then(Value::as_array)
+    {   if !options.contains(value)
+        {   let options = Value::from(options.clone());
+            return Err(format!("{}: not one of {}", path, options));
+        }
+    }
+    if let Some(expected) = schema.get("const")
+    {   if expected != value
+        {   return Err(format!("{}: expected {}", path, expected));
+        }
+    }
+    match value
+    {   Value::Object(fields) => {
+          let properties = schema.get("properties").and_then(Value::as_object);
+          for name in schema.get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+          {   if !fields.contains_key(name)
+              {   return Err(format!("{}: missing field {}", path, name));
+              }
+          }
+          for (name, field) in fields
+          {   let field_path = format!("{}.{}", path, name);
+              match properties.and_then(|p| p.get(name))
+              {   Some(field_schema) => check(field_schema, field, &field_path)?
+                , None => match schema.get("additionalProperties")
+                  {   Some(Value::Bool(false)) => {
+                        return Err(format!("{}: unexpected field", field_path));
+                      }
+                    , Some(extra) => check(extra, field, &field_path)?
+                    , None => {}
+                  }
+              }
+          }
+        }
+      , Value::Array(items) => {
+          bound(schema, "minItems", "maxItems", items.len(), path, "items")?;
+          if let Some(item_schema) = schema.get("items")
+          {   for (i, item) in items.iter().enumerate()
+              {   check(item_schema, item, &format!("{}[{}]", path, i))?;
+              }
+          }
+        }
+      , Value::String(text) => {
+          let length = text.chars().count();
+          bound(schema, "minLength", "maxLength", length, path, "characters")?;
+        }
+      , Value::Number(number) => {
+          let number = number.as_f64().unwrap_or_default();
+          if let Some(min) = schema.get("minimum").and_then(Value::as_f64)
+          {   if number < min
+              {   return Err(format!("{}: below minimum {}", path, min));
+              }
+          }
+          if let Some(max) = schema.get("maximum").and_then(Value::as_f64)
+          {   if number > max
+              {   return Err(format!("{}: above maximum {}", path, max));
+              }
+          }
+        }
+      , _ => {}
+    }
+    Ok(())
+}
+
+fn has_type(value: &Value, name: &str) -> bool
+{   match name
+    {   "object" => value.is_object()
+      , "array" => value.is_array()
+      , "string" => value.is_string()
+      , "number" => value.is_number()
+      , "integer" => {
+          value.is_i64() || value.is_u64()
+            || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+      , "boolean" => value.is_boolean()
+      , "null" => value.is_null()
+      , _ => true
+    }
+}
+
+/// Check a length against the schema's `min` and `max` keywords
+fn bound(
+  schema: &serde_json::Map<String, Value>
+, min: &str
+, max: &str
+, length: usize
+, path: &str
+, unit: &str
+) -> Result<(), String>
+{   if let Some(min) = schema.get(min).and_then(Value::as_u64)
+    {   if (length as u64) < min
+        {   return Err(format!("{}: fewer than {} {}", path, min, unit));
+        }
+    }
+    if let Some(max) = schema.get(max).and_then(Value::as_u64)
+    {   if (length as u64) > max
+        {   return Err(format!("{}: more than {} {}", path, max, unit));
+        }
+    }
+    Ok(())
+}
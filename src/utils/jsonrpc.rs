@@ -0,0 +1,264 @@
+//! JSON-RPC 2.0 over newline-delimited JSON, for programs running
+//! allm as a subprocess (`jsonrpc_server` feature)
+//!
+//! Each input line is one request, each output line one response:
+//!
+//! ```text
+//! -> {"jsonrpc":"2.0","method":"send_prompt","params":{"prompt":"Hi","model":"mistral-small-latest"},"id":1}
+//! <- {"jsonrpc":"2.0","result":"Hello!","id":1}
+//! ```
+//!
+//! | Method | Params | Result |
+//! |--------|--------|--------|
+//! | `send_prompt` | `prompt`, `model` | reply text |
+//! | `set_api_keys` | `keys`: `[{provider, model, key}]` | `null` |
+//! | `get_model_lists` | none | `[{provider, model}]` |
+//! | `set_fallback_preferences` | `preferences`: `[{provider, model}]` | `null` |
+//! | `shutdown` | none | `null`, then the server stops |
+//!
+//! Providers are named as in the configuration (`"mistral"`,
+//! `"openai"`, ...). Requests are served concurrently, so responses
+//! may come out of order; match them by `id`. A backend error has
+//! code `-32000`, its message, and the serialized `Error` as `data`.
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use log::{debug, error, info};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::client::AllmBackend;
+
+/// Invalid JSON
+pub const PARSE_ERROR: i64 = -32700;
+/// JSON that is not a request object
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The backend reported an `Error`
+pub const BACKEND_ERROR: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+struct Request
+{   jsonrpc: String
+  , method: String
+  , #[serde(default)]
+    params: Value
+  , /// Absent for notifications, which get no response
+    id: Option<Value>
+}
+
+/// Error object of a response
+#[derive(Debug)]
+struct RpcError
+{   code: i64
+  , message: String
+  , data: Option<Value>
+}
+
+impl RpcError
+{   fn new(code: i64, message: impl Into<String>) -> Self
+    {   RpcError { code, message: message.into(), data: None }
+    }
+
+    fn invalid_params(e: impl std::fmt::Display) -> Self
+    {   RpcError::new(INVALID_PARAMS, e.to_string())
+    }
+}
+
+impl From<crate::error::Error> for RpcError
+{   fn from(e: crate::error::Error) -> Self
+    {   RpcError
+        {   code: BACKEND_ERROR
+          , message: e.to_string()
+          , data: serde_json::to_value(&e).ok()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SendPromptParams
+{   prompt: String
+  , model: String
+}
+
+#[derive(Deserialize)]
+struct KeyParams
+{   provider: String
+  , #[serde(default)]
+    model: String
+  , key: String
+}
+
+#[derive(Deserialize)]
+struct SetApiKeysParams
+{   keys: Vec<KeyParams>
+}
+
+#[derive(Deserialize)]
+struct ModelParams
+{   provider: String
+  , model: String
+}
+
+#[derive(Deserialize)]
+struct SetFallbackPreferencesParams
+{   preferences: Vec<ModelParams>
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError>
+{   serde_json::from_value(params).map_err(RpcError::invalid_params)
+}
+
+fn provider(name: &str) -> Result<crate::Provider, RpcError>
+{   crate::Provider::from_name(name).ok_or_else(|| {
+      RpcError::invalid_params(format!("unknown provider {:?}", name))
+    })
+}
+
+/// Wait for the single reply of a queued command
+async fn reply<T>(
+  rx: Result<
+    tokio::sync::mpsc::UnboundedReceiver<Result<T, crate::error::Error>>,
+    crate::error::Error
+  >
+) -> Result<T, RpcError>
+{   rx?.recv().await
+      .unwrap_or_else(|| Err(crate::error::Error::Other(
+        "Backend disconnected".to_string()
+      )))
+      .map_err(RpcError::from)
+}
+
+/// Run a request's method on `backend`
+async fn call(backend: &AllmBackend, method: &str, args: Value)
+  -> Result<Value, RpcError>
+{   match method
+    {   "send_prompt" => {
+          let args: SendPromptParams = params(args)?;
+          let text = backend.ask(&args.prompt, &args.model).await?;
+          Ok(Value::String(text))
+        }
+      , "set_api_keys" => {
+          let args: SetApiKeysParams = params(args)?;
+          let keys = args.keys.into_iter()
+            .map(|k| Ok(crate::ApiKeySpec
+            {   provider: provider(&k.provider)?
+              , model: k.model
+              , key: k.key
+            }))
+            .collect::<Result<Vec<_>, RpcError>>()?;
+          reply(backend.set_api_keys(keys).await).await?;
+          Ok(Value::Null)
+        }
+      , "get_model_lists" => {
+          let models = reply(backend.get_model_lists().await).await?;
+          Ok(models.into_iter()
+            .map(|(provider, model)| json!({
+              "provider": provider, "model": model
+            }))
+            .collect())
+        }
+      , "set_fallback_preferences" => {
+          let args: SetFallbackPreferencesParams = params(args)?;
+          let preferences = args.preferences.into_iter()
+            .map(|p| Ok((provider(&p.provider)?, p.model)))
+            .collect::<Result<Vec<_>, RpcError>>()?;
+          reply(backend.set_model_fallback_preference(preferences).await)
+            .await?;
+          Ok(Value::Null)
+        }
+      , "shutdown" => Ok(Value::Null)
+      , _ => Err(RpcError::new(
+          METHOD_NOT_FOUND, format!("method {:?} not found", method)
+        ))
+    }
+}
+
+/// Response line for `id`
+fn response(id: Value, result: Result<Value, RpcError>) -> String
+{   let body = match result
+    {   Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id })
+      , Err(e) => {
+          let mut error = json!({ "code": e.code, "message": e.message });
+          if let Some(data) = e.data
+          {   error["data"] = data;
+          }
+          json!({ "jsonrpc": "2.0", "error": error, "id": id })
+        }
+    };
+    body.to_string()
+}
+
+/// Parsed request, or the response rejecting the line
+fn parse(line: &str) -> Result<Request, String>
+{   let value: Value = serde_json::from_str(line).map_err(|e| {
+      response(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string())))
+    })?;
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    serde_json::from_value::<Request>(value)
+      .map_err(|e| e.to_string())
+      .and_then(|request| match request.jsonrpc.as_str()
+      {   "2.0" => Ok(request)
+        , other => Err(format!("unsupported jsonrpc version {:?}", other))
+      })
+      .map_err(|e| response(id, Err(RpcError::new(INVALID_REQUEST, e))))
+}
+
+/// Serve requests read from `input` on `backend`, writing responses
+/// to `output`, until `input` ends or a `shutdown` request has been
+/// answered. Requests still running at a `shutdown` are answered
+/// first.
+pub async fn serve<R, W>(backend: &AllmBackend, input: R, mut output: W)
+  -> Result<(), crate::error::Error>
+where
+  R: AsyncBufRead + Unpin
+, W: AsyncWrite + Unpin
+{   let io_error = |e: std::io::Error| {
+      error!("JSON-RPC I/O error: {}", e);
+      crate::error::Error::Other(format!("JSON-RPC I/O: {}", e))
+    };
+    let mut lines = input.lines();
+    let mut running = FuturesUnordered::new();
+    let mut reading = true;
+    info!("JSON-RPC server started");
+    while reading || !running.is_empty()
+    {   let line = tokio::select!
+        {   line = lines.next_line(), if reading => line.map_err(io_error)?
+          , Some(done) = running.next() => {
+              let (id, result): (Option<Value>, _) = done;
+              if let Some(id) = id
+              {   output.write_all(response(id, result).as_bytes()).await
+                    .map_err(io_error)?;
+                  output.write_all(b"\n").await.map_err(io_error)?;
+                  output.flush().await.map_err(io_error)?;
+              }
+              continue;
+            }
+        };
+        let Some(line) = line else {
+          debug!("JSON-RPC input closed");
+          reading = false;
+          continue;
+        };
+        if line.trim().is_empty()
+        {   continue;
+        }
+        match parse(&line)
+        {   Ok(request) => {
+              debug!("JSON-RPC {}", request.method);
+              reading = request.method != "shutdown";
+              running.push(async move {
+                (request.id, call(backend, &request.method, request.params).await)
+              });
+            }
+          , Err(rejection) => {
+              output.write_all(rejection.as_bytes()).await.map_err(io_error)?;
+              output.write_all(b"\n").await.map_err(io_error)?;
+              output.flush().await.map_err(io_error)?;
+            }
+        }
+    }
+    info!("JSON-RPC server stopped");
+    Ok(())
+}
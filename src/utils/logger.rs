@@ -0,0 +1,200 @@
+// allm/src/utils/logger.rs
+
+//! Per-provider logging
+//!
+//! Each provider actor logs through a `ProviderLogger` under the
+//! target `allm::providers::<name>`, at the level its
+//! `ProviderConfig::log_level` (or `verbose`) asks for. At
+//! `LogLevel::Trace` it also logs every request/response pair as one
+//! JSON event (see `ProviderLogger::exchange`).
+//!
+//! `init_json` is an opt-in `env_logger` setup writing every record,
+//! the crate's and the application's, as one JSON object per line.
+
+use std::fmt;
+use std::io::Write;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Verbosity of a provider's logs; serialized lowercase (`"trace"`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel
+{   Error
+  , Warn
+  , Info
+  , Debug
+  , Trace
+}
+
+impl From<LogLevel> for log::Level
+{   fn from(level: LogLevel) -> Self
+    {   match level
+        {   LogLevel::Error => log::Level::Error
+          , LogLevel::Warn => log::Level::Warn
+          , LogLevel::Info => log::Level::Info
+          , LogLevel::Debug => log::Level::Debug
+          , LogLevel::Trace => log::Level::Trace
+        }
+    }
+}
+
+/// `log::logger()` with a provider prefix and level
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderLogger
+{   name: String
+  , target: String
+  , level: log::Level
+}
+
+impl Default for ProviderLogger
+{   /// Unnamed provider at `Debug`
+    fn default() -> Self
+    {   ProviderLogger::new("provider", log::Level::Debug)
+    }
+}
+
+impl ProviderLogger
+{   pub fn new(name: &str, level: log::Level) -> Self
+    {   ProviderLogger
+        {   name: name.to_string()
+          , target: format!("allm::providers::{}", name)
+          , level
+        }
+    }
+
+    /// `log_level` if set, else `Trace` when `verbose` and `Debug`
+    /// otherwise
+    pub fn from_config(config: &crate::config::ProviderConfig) -> Self
+    {   let level = match (config.log_level, config.verbose)
+        {   (Some(level), _) => level.into()
+          , (None, Some(true)) => log::Level::Trace
+          , (None, _) => log::Level::Debug
+        };
+        let name = config.provider()
+          .map(|p| p.name().to_string())
+          .unwrap_or_else(|| config.name.clone());
+        ProviderLogger::new(&name, level)
+    }
+
+    /// Level of `log`, and the most verbose `log_at` lets through
+    pub fn level(&self) -> log::Level
+    {   self.level
+    }
+
+    /// `allm::providers::<name>`
+    pub fn target(&self) -> &str
+    {   &self.target
+    }
+
+    /// True when a message at `level` would be written
+    pub fn enabled(&self, level: log::Level) -> bool
+    {   level <= self.level
+          && level <= log::max_level()
+          && log::logger().enabled(
+            &log::Metadata::builder()
+              .level(level)
+              .target(&self.target)
+              .build()
+          )
+    }
+
+    /// Log at the provider's level
+    pub fn log(&self, args: fmt::Arguments<'_>)
+    {   self.log_at(self.level, args);
+    }
+
+    /// Log at `level`, dropped when more verbose than the provider's
+    pub fn log_at(&self, level: log::Level, args: fmt::Arguments<'_>)
+    {   if !self.enabled(level)
+        {   return;
+        }
+        log::logger().log(
+          &log::Record::builder()
+            .level(level)
+            .target(&self.target)
+            .args(format_args!("[{}] {}", self.name, args))
+            .build()
+        );
+    }
+
+    /// True when request/response pairs are logged
+    pub fn traces_exchanges(&self) -> bool
+    {   self.enabled(log::Level::Trace)
+    }
+
+    /// Log one request/response pair as an `exchange_event`, when
+    /// tracing
+    pub fn exchange(
+      &self
+    , model: &str
+    , request: &serde_json::Value
+    , response: &serde_json::Value
+    , elapsed: Duration
+    )
+    {   if self.traces_exchanges()
+        {   let event = self.exchange_event(model, request, response, elapsed);
+            self.log_at(log::Level::Trace, format_args!("{}", event));
+        }
+    }
+
+    /// `{"event":"exchange","provider":..,"model":..,"elapsed_ms":..,
+    /// "request":..,"response":..}`
+    pub fn exchange_event(
+      &self
+    , model: &str
+    , request: &serde_json::Value
+    , response: &serde_json::Value
+    , elapsed: Duration
+    ) -> serde_json::Value
+    {   serde_json::json!(
+        {   "event": "exchange"
+          , "provider": self.name
+          , "model": model
+          , "elapsed_ms": elapsed.as_millis() as u64
+          , "request": request
+          , "response": response
+        })
+    }
+}
+
+/// `{"level":..,"target":..,"provider":..,"model":..,"message":..}`
+/// for `record`. `provider` comes from an `allm::providers::<name>`
+/// target, whose `[name]` prefix leaves the message; `model` from an
+/// exchange event. Both are `null` otherwise.
+pub fn json_record(record: &log::Record<'_>) -> serde_json::Value
+{   let target = record.target();
+    let provider = target.strip_prefix("allm::providers::")
+      .map(|rest| rest.split("::").next().unwrap_or(rest));
+    let mut message = record.args().to_string();
+    if let Some(name) = provider
+    {   if let Some(rest) = message.strip_prefix(&format!("[{}] ", name))
+        {   message = rest.to_string();
+        }
+    }
+    let model = serde_json::from_str::<serde_json::Value>(&message).ok()
+      .filter(|event| event["event"] == "exchange")
+      .and_then(|event| event["model"].as_str().map(str::to_string));
+    serde_json::json!(
+    {   "level": record.level().as_str()
+      , "target": target
+      , "provider": provider
+      , "model": model
+      , "message": message
+    })
+}
+
+/// `env_logger` builder formatting records with `json_record`,
+/// filtered by `RUST_LOG` like `env_logger::init`
+pub fn json_logger_builder() -> env_logger::Builder
+{   let mut builder = env_logger::Builder::from_default_env();
+    builder.format(|buf, record| writeln!(buf, "{}", json_record(record)));
+    builder
+}
+
+/// Install a JSON-lines logger on stderr (see `json_record`); fails
+/// when the application already set one
+pub fn init_json() -> Result<(), log::SetLoggerError>
+{   json_logger_builder().try_init()
+}
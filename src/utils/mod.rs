@@ -0,0 +1,19 @@
+//! Helper modules shared by the backend and providers
+
+pub mod auth;
+pub mod cost;
+pub mod hash;
+#[cfg(feature = "jsonrpc_server")]
+pub mod jsonrpc;
+pub mod json_schema;
+pub mod logger;
+pub mod normalize;
+pub mod prompt_splitter;
+pub mod rate_limiter;
+pub mod secrets;
+pub mod stream_share;
+pub mod telemetry;
+pub mod thinking;
+pub mod tokens;
+pub mod uuid;
+pub mod version;
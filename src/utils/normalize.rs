@@ -0,0 +1,134 @@
+// allm/src/utils/normalize.rs
+
+//! Provider-agnostic cleanup of reply text
+//!
+//! Providers disagree on the whitespace around a reply: OpenAI may
+//! open with a space, Ollama may end with a run of newlines, and most
+//! models like to open with "Certainly! ". `AllmConfig::
+//! response_normalization` picks which of these the backend evens out
+//! before a reply reaches the caller; the default changes nothing.
+
+use serde::{Deserialize, Serialize};
+
+/// Openers stripped by `strip_leading_ai_prefixes`, matched
+/// case-insensitively; longer entries come before their prefixes
+const AI_PREFIXES: &[&str] = &[
+  "as an ai language model, ",
+  "as an ai assistant, ",
+  "as an ai, ",
+  "certainly! ",
+  "certainly, ",
+  "of course! ",
+  "of course, ",
+  "absolutely! ",
+  "great question! ",
+  "sure thing! ",
+  "sure! ",
+  "sure, ",
+];
+
+/// Which cleanups `ResponseNormalizer::normalize` applies
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NormalizationRules
+{   /// Remove whitespace at both ends
+    pub trim_whitespace: bool
+  , /// Turn runs of spaces and tabs inside a line into one space;
+    /// indentation and line breaks are kept
+    pub collapse_internal_whitespace: bool
+  , /// Remove openers like "As an AI assistant, " or "Certainly! "
+    pub strip_leading_ai_prefixes: bool
+  , /// Line breaks kept at the end; without `trim_whitespace`, a
+    /// longer run is cut to this many
+    pub max_trailing_newlines: usize
+}
+
+impl Default for NormalizationRules
+{   /// Leaves every reply as the provider sent it
+    fn default() -> Self
+    {   NormalizationRules
+        {   trim_whitespace: false
+          , collapse_internal_whitespace: false
+          , strip_leading_ai_prefixes: false
+          , max_trailing_newlines: usize::MAX
+        }
+    }
+}
+
+/// Applies `NormalizationRules` to reply text
+pub struct ResponseNormalizer;
+
+impl ResponseNormalizer
+{   /// `text` with `rules` applied: prefixes first, then internal
+    /// whitespace, then the ends
+    pub fn normalize(text: &str, rules: &NormalizationRules) -> String
+    {   let mut text = text.to_string();
+        if rules.strip_leading_ai_prefixes
+        {   text = strip_ai_prefixes(&text);
+        }
+        if rules.collapse_internal_whitespace
+        {   text = collapse_whitespace(&text);
+        }
+        if rules.trim_whitespace
+        {   text = text.trim().to_string();
+        } else
+        {   limit_trailing_newlines(&mut text, rules.max_trailing_newlines);
+        }
+        text
+    }
+}
+
+/// Remove every leading `AI_PREFIXES` entry, keeping the whitespace
+/// before the first
+fn strip_ai_prefixes(text: &str) -> String
+{   let body = text.trim_start();
+    let indent = &text[..text.len() - body.len()];
+    let mut rest = body;
+    while let Some(prefix) = AI_PREFIXES.iter().find(|prefix| {
+      rest.get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    })
+    {   rest = rest[prefix.len()..].trim_start();
+    }
+    format!("{}{}", indent, rest)
+}
+
+/// One space for each run of spaces and tabs after a line's first
+/// non-blank character
+fn collapse_whitespace(text: &str) -> String
+{   let mut out = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate()
+    {   if i > 0
+        {   out.push('\n');
+        }
+        let body = line.trim_start_matches([' ', '\t']);
+        out.push_str(&line[..line.len() - body.len()]);
+        let mut in_run = false;
+        for c in body.chars()
+        {   match c
+            {   ' ' | '\t' => {
+                  if !in_run
+                  {   out.push(' ');
+                  }
+                  in_run = true;
+                }
+              , c => {
+                  out.push(c);
+                  in_run = false;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Cut trailing whitespace holding more than `max` line breaks down
+/// to exactly `max` of them
+fn limit_trailing_newlines(text: &mut String, max: usize)
+{   let kept = text.trim_end().len();
+    let newlines = text[kept..].matches('\n').count();
+    if newlines > max
+    {   text.truncate(kept);
+        text.push_str(&"\n".repeat(max));
+    }
+}
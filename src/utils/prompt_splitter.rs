@@ -0,0 +1,215 @@
+// allm/src/utils/prompt_splitter.rs
+
+//! Prompts larger than a provider accepts
+//!
+//! `ProviderConfig::max_request_size_bytes` and
+//! `max_single_request_tokens` cap the prompt text of one request;
+//! `AllmConfig::large_prompt_strategy` says what happens to a prompt
+//! above them. Sizes are the prompt's own, not the request body's,
+//! and tokens are `utils::tokens::estimate_tokens`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::tokens::estimate_tokens;
+
+/// What to do with a prompt above its provider's limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LargePromptStrategy
+{   /// Reply with `Error::RequestTooLarge`
+    #[default]
+    Fail
+  , /// Send what fits, dropping the rest of the prompt
+    Truncate
+  , /// Send each `PromptLimits::split` chunk as its own prompt and
+    /// answer with the replies merged; a chunk still above the limits
+    /// fails with `Error::RequestTooLarge`
+    Split
+    {   #[serde(default)]
+        merge_strategy: ChunkMergeStrategy
+    }
+}
+
+/// How the replies to a split prompt's chunks become one reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkMergeStrategy
+{   /// Replies in chunk order, separated by a blank line
+    #[default]
+    Concatenate
+  , /// Ask the same model to merge the replies (see `merge_prompt`);
+    /// concatenated when that prompt would be too large itself
+    LlmMerge
+}
+
+/// Most a provider takes in one prompt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptLimits
+{   pub max_bytes: Option<usize>
+  , pub max_tokens: Option<usize>
+}
+
+impl PromptLimits
+{   /// Limits of `config`, `None` when it sets neither
+    pub fn from_config(config: &crate::config::ProviderConfig) -> Option<Self>
+    {   let limits = PromptLimits
+        {   max_bytes: config.max_request_size_bytes
+          , max_tokens: config.max_single_request_tokens
+        };
+        (limits.max_bytes.is_some() || limits.max_tokens.is_some())
+          .then_some(limits)
+    }
+
+    /// Whether `text` is within both limits
+    pub fn fits(&self, text: &str) -> bool
+    {   self.max_bytes.is_none_or(|max| text.len() <= max)
+          && self.max_tokens.is_none_or(|max| estimate_tokens(text) <= max)
+    }
+
+    /// `text` in `split_by_sentences` chunks that are each within
+    /// both limits
+    pub fn split(&self, text: &str) -> Vec<String>
+    {   pack_sentences(
+          text,
+          self.max_tokens.map_or(usize::MAX, chars_for_tokens),
+          self.max_bytes.unwrap_or(usize::MAX)
+        )
+    }
+
+    /// Longest start of `text` within both limits
+    pub fn truncate<'a>(&self, text: &'a str) -> &'a str
+    {   let mut end = text.len();
+        if let Some(max) = self.max_tokens
+        {   end = end.min(
+              text.char_indices().nth(max.saturating_mul(4))
+                .map_or(text.len(), |(index, _)| index)
+            );
+        }
+        if let Some(max) = self.max_bytes
+        {   end = end.min(max);
+        }
+        while !text.is_char_boundary(end)
+        {   end -= 1;
+        }
+        &text[..end]
+    }
+
+    /// Why `text` does not fit, for `Error::RequestTooLarge`
+    pub fn describe_excess(&self, text: &str) -> String
+    {   format!(
+          "prompt of {} bytes and ~{} tokens, limits {} bytes and {} tokens",
+          text.len(),
+          estimate_tokens(text),
+          self.max_bytes.map_or("unlimited".to_string(), |m| m.to_string()),
+          self.max_tokens.map_or("unlimited".to_string(), |m| m.to_string())
+        )
+    }
+}
+
+/// Sentences of `text`, each ending after a `.`, `!` or `?` that is
+/// followed by whitespace, with that whitespace
+fn sentences(text: &str) -> Vec<&str>
+{   let mut sentences = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next()
+    {   if !matches!(c, '.' | '!' | '?')
+        {   continue;
+        }
+        let mut end = None;
+        while let Some(&(index, next)) = chars.peek()
+        {   if !next.is_whitespace()
+            {   break;
+            }
+            end = Some(index + next.len_utf8());
+            chars.next();
+        }
+        if let Some(end) = end
+        {   sentences.push(&text[start..end]);
+            start = end;
+        }
+    }
+    if start < text.len()
+    {   sentences.push(&text[start..]);
+    }
+    sentences
+}
+
+/// Characters that estimate to at most `max_tokens` tokens
+fn chars_for_tokens(max_tokens: usize) -> usize
+{   max_tokens.max(1).saturating_mul(4)
+}
+
+/// Pieces of `text` of at most `max_chars` characters and `max_bytes`
+/// bytes, broken after whitespace where there is some and never
+/// inside a character; one wider than `max_bytes` is a piece alone
+fn split_long(text: &str, max_chars: usize, max_bytes: usize) -> Vec<&str>
+{   let mut pieces = vec![];
+    let mut rest = text;
+    loop
+    {   let mut limit = rest.char_indices().nth(max_chars)
+          .map_or(rest.len(), |(index, _)| index)
+          .min(max_bytes);
+        if limit >= rest.len()
+        {   break;
+        }
+        while !rest.is_char_boundary(limit)
+        {   limit -= 1;
+        }
+        if limit == 0
+        {   limit = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        let end = rest[..limit].char_indices()
+          .rfind(|(_, c)| c.is_whitespace())
+          .map_or(limit, |(index, c)| index + c.len_utf8());
+        pieces.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    if !rest.is_empty()
+    {   pieces.push(rest);
+    }
+    pieces
+}
+
+/// `text` in chunks of whole sentences, each at most `max_tokens`
+/// estimated tokens. A sentence longer than that is broken between
+/// words, or anywhere when a word is too. Chunks are trimmed; empty
+/// text gives no chunks.
+pub fn split_by_sentences(text: &str, max_tokens: usize) -> Vec<String>
+{   pack_sentences(text, chars_for_tokens(max_tokens), usize::MAX)
+}
+
+/// `split_by_sentences` with chunks of at most `max_chars` characters
+/// and `max_bytes` bytes
+fn pack_sentences(text: &str, max_chars: usize, max_bytes: usize) -> Vec<String>
+{   let mut chunks = vec![];
+    let mut current = String::new();
+    for sentence in sentences(text)
+    {   for piece in split_long(sentence, max_chars, max_bytes)
+        {   if current.chars().count() + piece.chars().count() > max_chars
+              || current.len() + piece.len() > max_bytes
+            {   chunks.push(current.trim().to_string());
+                current.clear();
+            }
+            current.push_str(piece);
+        }
+    }
+    chunks.push(current.trim().to_string());
+    chunks.retain(|chunk| !chunk.is_empty());
+    chunks
+}
+
+/// Prompt asking a model to merge the `replies` to the chunks of one
+/// prompt into a single answer
+pub fn merge_prompt(replies: &[String]) -> String
+{   let parts: Vec<String> = replies.iter()
+      .enumerate()
+      .map(|(i, reply)| format!("Part {}:\n{}", i + 1, reply))
+      .collect();
+    format!(
+      "The following are answers to consecutive parts of one long \
+       prompt. Merge them into a single coherent answer, without \
+       mentioning the parts.\n\n{}",
+      parts.join("\n\n")
+    )
+}
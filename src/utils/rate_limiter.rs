@@ -0,0 +1,155 @@
+//! Token bucket rate limiting
+
+use std::time::Duration;
+use tokio::time::Instant;
+use log::trace;
+
+/// Token bucket of `capacity` tokens refilled continuously at
+/// `refill_rate` tokens per second.
+///
+/// `acquire` always takes the tokens, letting the bucket go into
+/// debt, and returns how long the caller must wait before using
+/// them. Later callers therefore queue up behind earlier ones.
+#[derive(Debug, Clone)]
+pub struct TokenBucketRateLimiter
+{   pub capacity: u32
+  , pub refill_rate: f64
+  , pub current_tokens: f64
+  , pub last_refill: Instant
+}
+
+impl TokenBucketRateLimiter
+{   /// Create a full bucket
+    pub fn new(capacity: u32, refill_rate: f64) -> Self
+    {   TokenBucketRateLimiter
+        {   capacity
+          , refill_rate
+          , current_tokens: capacity as f64
+          , last_refill: Instant::now()
+        }
+    }
+
+    /// Bucket allowing `per_minute` tokens per minute
+    pub fn per_minute(per_minute: u32) -> Self
+    {   TokenBucketRateLimiter::new(
+          per_minute,
+          per_minute as f64 / 60.0
+        )
+    }
+
+    fn refill(&mut self)
+    {   let now = Instant::now();
+        let elapsed = now
+          .saturating_duration_since(self.last_refill)
+          .as_secs_f64();
+        self.current_tokens = (
+          self.current_tokens + elapsed * self.refill_rate
+        ).min(self.capacity as f64);
+        self.last_refill = now;
+    }
+
+    /// Consume `tokens` and return how long to wait before they
+    /// may be used (zero when the bucket had enough)
+    pub async fn acquire(&mut self, tokens: u32) -> Duration
+    {   self.refill();
+        let requested = tokens as f64;
+        let wait = if self.current_tokens >= requested
+        {   Duration::ZERO
+        } else if self.refill_rate <= 0.0
+        {   Duration::MAX
+        } else
+        {   Duration::from_secs_f64(
+              (requested - self.current_tokens) / self.refill_rate
+            )
+        };
+        self.current_tokens -= requested;
+        trace!(
+          "Rate limiter acquired {} tokens, {:.2} left, wait {:?}",
+          tokens, self.current_tokens, wait
+        );
+        wait
+    }
+
+    /// Tokens currently available (negative while in debt)
+    pub fn available(&mut self) -> f64
+    {   self.refill();
+        self.current_tokens
+    }
+}
+
+/// `RateLimitStatus` shared by a provider actor, which updates it
+/// from each response, and the handles reading it
+pub type SharedRateLimitStatus = std::sync::Arc<std::sync::Mutex<RateLimitStatus>>;
+
+/// Quota a provider reported in the `x-ratelimit-*` headers of its
+/// last response; `None` until a header has said otherwise
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct RateLimitStatus
+{   /// `x-ratelimit-remaining-requests`
+    pub remaining_requests: Option<u64>
+  , /// `x-ratelimit-remaining-tokens` (Mistral:
+    /// `x-ratelimit-remaining-tokens-minute`)
+    pub remaining_tokens: Option<u64>
+  , /// When the request quota refills (`x-ratelimit-reset-requests`),
+    /// else the token quota (`x-ratelimit-reset-tokens`)
+    pub reset_at: Option<std::time::SystemTime>
+  , /// Set by the backend for a model answered with a 429 whose
+    /// `retry-after` has not passed, or whose quota is used up
+    /// until `reset_at`
+    pub is_rate_limited: bool
+}
+
+impl RateLimitStatus
+{   /// Take the quota headers `headers` carries, keeping earlier
+    /// values for those it lacks
+    pub fn observe(&mut self, headers: &reqwest::header::HeaderMap)
+    {   let header = |name: &str| headers.get(name)
+          .and_then(|v| v.to_str().ok())
+          .map(str::trim);
+        let count = |name: &str| header(name).and_then(|v| v.parse().ok());
+        if let Some(remaining) = count("x-ratelimit-remaining-requests")
+        {   self.remaining_requests = Some(remaining);
+        }
+        if let Some(remaining) = count("x-ratelimit-remaining-tokens")
+          .or_else(|| count("x-ratelimit-remaining-tokens-minute"))
+        {   self.remaining_tokens = Some(remaining);
+        }
+        if let Some(reset) = header("x-ratelimit-reset-requests")
+          .or_else(|| header("x-ratelimit-reset-tokens"))
+          .and_then(parse_reset)
+        {   self.reset_at = Some(std::time::SystemTime::now() + reset);
+        }
+        trace!("Rate limit status now {:?}", self);
+    }
+}
+
+/// Reset delay of an `x-ratelimit-reset-*` header: seconds (`"20"`,
+/// `"1.5"`) or a Go-style duration (`"6m0s"`, `"20ms"`, `"1h2m"`)
+pub fn parse_reset(value: &str) -> Option<Duration>
+{   if let Ok(seconds) = value.parse::<f64>()
+    {   return Duration::try_from_secs_f64(seconds).ok();
+    }
+    if value.is_empty()
+    {   return None;
+    }
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty()
+    {   let digits = rest
+          .find(|c: char| !c.is_ascii_digit() && c != '.')
+          .filter(|&end| end > 0)?;
+        let number: f64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit_end = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let seconds = match &rest[..unit_end]
+        {   "h" => 3600.0
+          , "m" => 60.0
+          , "s" => 1.0
+          , "ms" => 0.001
+          , _ => return None
+        };
+        total += number * seconds;
+        rest = &rest[unit_end..];
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
@@ -0,0 +1,186 @@
+// allm/src/utils/secrets.rs
+
+//! Where the backend keeps API keys
+//!
+//! Keys are addressed by `secret_id`: the provider's config name for
+//! its master key (`"mistral"`), `provider/model` for a model key.
+//! `SecretStoreKeyProvider` lets provider actors look their master
+//! key up in a store before every request.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+
+use crate::auth::{ApiKeyProvider, KeyFuture, SharedKeyProvider};
+
+/// Future returned by `SecretStore` methods
+pub type SecretFuture<'a, T> = Pin<Box<
+  dyn Future<Output = Result<T, crate::error::Error>> + Send + 'a
+>>;
+
+/// Store shared between the backend and its provider actors
+pub type SharedSecretStore = Arc<dyn SecretStore + Send + Sync>;
+
+/// Reads and writes API keys by id.
+///
+/// Like `ApiKeyProvider`, the futures are boxed so stores can be
+/// trait objects; implement them as `Box::pin(async move { ... })`.
+pub trait SecretStore: Send + Sync
+{   /// Secret stored under `id`; `Error::MissingApiKey` if none
+    fn get<'a>(&'a self, id: &'a str) -> SecretFuture<'a, String>;
+
+    fn set<'a>(&'a self, id: &'a str, value: &'a str)
+      -> SecretFuture<'a, ()>;
+}
+
+/// Id of the master key of `provider` (`model == None`) or of one
+/// of its models
+pub fn secret_id(provider: &crate::Provider, model: Option<&str>) -> String
+{   match model
+    {   Some(model) => format!("{}/{}", provider.name(), model)
+      , None => provider.name().to_string()
+    }
+}
+
+/// Plaintext keys in memory, the default store
+#[derive(Debug, Default)]
+pub struct MemorySecretStore
+{   secrets: Mutex<HashMap<String, String>>
+}
+
+impl MemorySecretStore
+{   pub fn new() -> Self
+    {   MemorySecretStore::default()
+    }
+
+    pub fn shared() -> SharedSecretStore
+    {   Arc::new(MemorySecretStore::new())
+    }
+}
+
+impl SecretStore for MemorySecretStore
+{   fn get<'a>(&'a self, id: &'a str) -> SecretFuture<'a, String>
+    {   Box::pin(async move {
+          self.secrets.lock()
+            .map_err(|_| crate::error::Error::Other(
+              "secret store poisoned".to_string()
+            ))?
+            .get(id)
+            .cloned()
+            .ok_or_else(|| crate::error::Error::MissingApiKey(id.to_string()))
+        })
+    }
+
+    fn set<'a>(&'a self, id: &'a str, value: &'a str)
+      -> SecretFuture<'a, ()>
+    {   Box::pin(async move {
+          debug!("Storing secret {}", id);
+          self.secrets.lock()
+            .map_err(|_| crate::error::Error::Other(
+              "secret store poisoned".to_string()
+            ))?
+            .insert(id.to_string(), value.to_string());
+          Ok(())
+        })
+    }
+}
+
+/// Keys in environment variables: `mistral` is read from
+/// `ALLM_MISTRAL_KEY`, `mistral/mistral-large` from
+/// `ALLM_MISTRAL_MISTRAL_LARGE_KEY`. The process environment is only
+/// read; keys `set` are kept in memory and shadow it.
+#[derive(Debug, Clone)]
+pub struct EnvVarSecretStore
+{   prefix: String
+  , /// Keys `set`, by variable name
+    overrides: Arc<Mutex<HashMap<String, String>>>
+}
+
+impl EnvVarSecretStore
+{   /// Variables named `ALLM_<ID>_KEY`
+    pub fn new() -> Self
+    {   EnvVarSecretStore::with_prefix("ALLM")
+    }
+
+    /// Variables named `<prefix>_<ID>_KEY`
+    pub fn with_prefix(prefix: &str) -> Self
+    {   EnvVarSecretStore
+        {   prefix: prefix.to_string()
+          , overrides: Default::default()
+        }
+    }
+
+    /// Environment variable holding the secret `id`
+    pub fn var_name(&self, id: &str) -> String
+    {   let id: String = id.chars()
+          .map(|c| match c.is_ascii_alphanumeric()
+          {   true => c.to_ascii_uppercase()
+            , false => '_'
+          })
+          .collect();
+        format!("{}_{}_KEY", self.prefix, id)
+    }
+}
+
+impl Default for EnvVarSecretStore
+{   fn default() -> Self
+    {   EnvVarSecretStore::new()
+    }
+}
+
+impl SecretStore for EnvVarSecretStore
+{   fn get<'a>(&'a self, id: &'a str) -> SecretFuture<'a, String>
+    {   Box::pin(async move {
+          let name = self.var_name(id);
+          let set = self.overrides.lock()
+            .map_err(|_| crate::error::Error::Other(
+              "secret store poisoned".to_string()
+            ))?
+            .get(&name)
+            .cloned();
+          match set
+          {   Some(value) => Ok(value)
+            , None => std::env::var(&name).map_err(|_| {
+                crate::error::Error::MissingApiKey(name)
+              })
+          }
+        })
+    }
+
+    /// Keeps the key in memory, ahead of the variable: changing the
+    /// environment while other threads may read it is not sound
+    fn set<'a>(&'a self, id: &'a str, value: &'a str)
+      -> SecretFuture<'a, ()>
+    {   Box::pin(async move {
+          let name = self.var_name(id);
+          debug!("Storing secret {} over {}", id, name);
+          self.overrides.lock()
+            .map_err(|_| crate::error::Error::Other(
+              "secret store poisoned".to_string()
+            ))?
+            .insert(name, value.to_string());
+          Ok(())
+        })
+    }
+}
+
+/// Key provider reading one secret from a store on every request
+pub struct SecretStoreKeyProvider
+{   store: SharedSecretStore
+  , id: String
+}
+
+impl SecretStoreKeyProvider
+{   pub fn shared(store: SharedSecretStore, id: String) -> SharedKeyProvider
+    {   Arc::new(SecretStoreKeyProvider { store, id })
+    }
+}
+
+impl ApiKeyProvider for SecretStoreKeyProvider
+{   fn current_key(&self) -> KeyFuture<'_>
+    {   self.store.get(&self.id)
+    }
+}
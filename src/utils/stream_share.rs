@@ -0,0 +1,126 @@
+// allm/src/utils/stream_share.rs
+
+//! One provider stream read by several identical `SendStream`s
+//!
+//! With `DeduplicationConfig::enabled`, a stream of the same
+//! provider, model and prompt as one started less than `window_ms`
+//! ago subscribes to that stream instead of opening a connection of
+//! its own. Chunks go out on a `tokio::sync::broadcast` channel, and
+//! the first `AllmConfig::stream_dedup_replay_buffer_size` are kept so
+//! a late subscriber still gets the whole reply, starting with the
+//! chunks it missed. Once more chunks than that have gone out, the
+//! stream takes no more subscribers.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{StreamReply, StreamReplySender};
+
+struct Fanout
+{   /// Every item sent so far, while there are no more than
+    /// `replay_size` of them
+    replay: VecDeque<StreamReply>
+  , replay_size: usize
+  , /// Items sent so far
+    sent: usize
+  , failed: bool
+  , /// `None` once the provider stream ended
+    tx: Option<broadcast::Sender<StreamReply>>
+}
+
+/// Handle on a provider stream subscribers can join
+#[derive(Clone)]
+pub struct SharedStream
+{   inner: Arc<Mutex<Fanout>>
+}
+
+impl SharedStream
+{   /// A stream keeping its first `replay_size` items for late
+    /// subscribers; a subscriber more than `channel_size` items
+    /// behind gets an error and nothing more
+    pub fn new(replay_size: usize, channel_size: usize) -> Self
+    {   let (tx, _) = broadcast::channel(channel_size.max(1));
+        SharedStream
+        {   inner: Arc::new(Mutex::new(Fanout
+            {   replay: VecDeque::new()
+              , replay_size
+              , sent: 0
+              , failed: false
+              , tx: Some(tx)
+            }))
+        }
+    }
+
+    /// Send every item of the stream to `reply`, the ones already
+    /// sent first. Hands `reply` back when the stream failed or its
+    /// replay buffer no longer holds everything sent.
+    pub fn join(&self, reply: StreamReplySender) -> Result<(), StreamReplySender>
+    {   let (missed, rx) =
+        {   let fanout = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+            if fanout.failed || fanout.sent > fanout.replay_size
+            {   return Err(reply);
+            }
+            let missed: Vec<StreamReply> = fanout.replay.iter().cloned().collect();
+            (missed, fanout.tx.as_ref().map(broadcast::Sender::subscribe))
+        };
+        tokio::spawn(relay(missed, rx, reply));
+        Ok(())
+    }
+
+    /// Read the provider stream from `rx` and send each item to the
+    /// subscribers, until the provider closes it
+    pub async fn pump(self, mut rx: mpsc::Receiver<StreamReply>)
+    {   while let Some(item) = rx.recv().await
+        {   self.publish(item);
+        }
+        let mut fanout = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        fanout.tx = None;
+    }
+
+    fn publish(&self, item: StreamReply)
+    {   let mut fanout = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        fanout.sent += 1;
+        fanout.failed |= item.is_err();
+        if fanout.sent <= fanout.replay_size
+        {   fanout.replay.push_back(item.clone());
+        } else
+        {   fanout.replay.clear();
+        }
+        if let Some(tx) = &fanout.tx
+        {   // No receivers is fine: a subscriber may join later
+            let _ = tx.send(item);
+        }
+    }
+}
+
+/// Send `missed`, then whatever `rx` receives, to one subscriber
+async fn relay(
+  missed: Vec<StreamReply>
+, rx: Option<broadcast::Receiver<StreamReply>>
+, reply: StreamReplySender
+)
+{   for item in missed
+    {   if reply.send(item).await.is_err()
+        {   return;
+        }
+    }
+    let Some(mut rx) = rx else { return };
+    loop
+    {   match rx.recv().await
+        {   Ok(item) => {
+              if reply.send(item).await.is_err()
+              {   return;
+              }
+            }
+          , Err(broadcast::error::RecvError::Closed) => return
+          , Err(broadcast::error::RecvError::Lagged(skipped)) => {
+              let _ = reply.send(Err(crate::error::Error::Other(format!(
+                "Shared stream reader fell {} chunks behind", skipped
+              )))).await;
+              return;
+            }
+        }
+    }
+}
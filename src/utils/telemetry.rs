@@ -0,0 +1,99 @@
+// allm/src/utils/telemetry.rs
+
+//! `tracing` spans named after the OpenTelemetry GenAI semantic
+//! conventions
+//!
+//! Each chat request a provider actor sends runs in a `gen_ai.chat`
+//! span carrying `gen_ai.system`, `gen_ai.request.model` and the
+//! sampling parameters, and records `gen_ai.usage.input_tokens` and
+//! `gen_ai.usage.output_tokens` once the reply is in (see
+//! `GenAiSpan`). With `tracing-opentelemetry` installed as a layer
+//! these become OTel span attributes as they are; without a
+//! subscriber the spans cost next to nothing.
+
+use tracing::field::Empty;
+
+/// Provider name, e.g. `"mistral_ai"`
+pub const SYSTEM: &str = "gen_ai.system";
+/// Always `"chat"` for the spans of this module
+pub const OPERATION_NAME: &str = "gen_ai.operation.name";
+pub const REQUEST_MODEL: &str = "gen_ai.request.model";
+pub const REQUEST_MAX_TOKENS: &str = "gen_ai.request.max_tokens";
+pub const REQUEST_TEMPERATURE: &str = "gen_ai.request.temperature";
+pub const USAGE_INPUT_TOKENS: &str = "gen_ai.usage.input_tokens";
+pub const USAGE_OUTPUT_TOKENS: &str = "gen_ai.usage.output_tokens";
+/// `Error` variant of a failed request, e.g. `"RateLimitExceeded"`
+pub const ERROR_TYPE: &str = "error.type";
+
+/// `gen_ai.system` value of `provider`: the well-known OTel name
+/// when there is one, else `Provider::name`
+pub fn system_name(provider: &crate::Provider) -> &'static str
+{   match provider
+    {   crate::Provider::MistralAi => "mistral_ai"
+      , crate::Provider::Google => "gcp.gemini"
+      , provider => provider.name()
+    }
+}
+
+/// Span around one chat request; closed when dropped
+pub struct GenAiSpan
+{   span: tracing::Span
+}
+
+impl GenAiSpan
+{   /// Open the `gen_ai.chat` span of `request` sent to `provider`
+    pub fn chat(
+      provider: &crate::Provider
+    , request: &crate::providers::ChatRequest
+    ) -> Self
+    {   let span = tracing::info_span!(
+          "gen_ai.chat",
+          otel.name = %format!("chat {}", request.model),
+          otel.kind = "client",
+          gen_ai.system = system_name(provider),
+          gen_ai.operation.name = "chat",
+          gen_ai.request.model = %request.model,
+          gen_ai.request.max_tokens = request.max_tokens.map(|t| t as u64),
+          gen_ai.request.temperature = request.temperature.map(f64::from),
+          gen_ai.usage.input_tokens = Empty,
+          gen_ai.usage.output_tokens = Empty,
+          error.type = Empty,
+        );
+        GenAiSpan { span }
+    }
+
+    /// Record the token counts the provider reported, and emit them
+    /// as a `gen_ai.usage` event within the span
+    pub fn record_usage(&self, usage: Option<&crate::providers::TokenUsage>)
+    {   let input = usage.and_then(|u| u.prompt_tokens).map(|t| t as u64);
+        let output = usage.and_then(|u| u.completion_tokens).map(|t| t as u64);
+        if let Some(input) = input
+        {   self.span.record(USAGE_INPUT_TOKENS, input);
+        }
+        if let Some(output) = output
+        {   self.span.record(USAGE_OUTPUT_TOKENS, output);
+        }
+        tracing::info!(
+          parent: &self.span,
+          gen_ai.usage.input_tokens = input,
+          gen_ai.usage.output_tokens = output,
+          "gen_ai.usage"
+        );
+    }
+
+    /// Record why the request failed
+    pub fn record_error(&self, error: &crate::error::Error)
+    {   self.span.record(ERROR_TYPE, error_type(error));
+    }
+
+    /// The underlying span, e.g. to enter it or parent other spans
+    pub fn span(&self) -> &tracing::Span
+    {   &self.span
+    }
+}
+
+/// Variant name of `error`, without its fields
+fn error_type(error: &crate::error::Error) -> String
+{   let debug = format!("{:?}", error);
+    debug.split(['(', ' ', '{']).next().unwrap_or_default().to_string()
+}
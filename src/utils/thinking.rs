@@ -0,0 +1,65 @@
+// allm/src/utils/thinking.rs
+
+//! Chain-of-thought embedded in reply text
+//!
+//! Reasoning models served without a dedicated field (DeepSeek-R1 on
+//! Together or Groq, Qwen3 and others on Ollama) open their reply
+//! with the thinking in `<think>...</think>`. `ExtractThinking` splits
+//! it off so `PromptResponse::text` holds only the answer.
+
+const OPEN: &str = "<think>";
+const CLOSE: &str = "</think>";
+
+/// Splits a `<think>` prefix off reply text
+pub struct ExtractThinking;
+
+impl ExtractThinking
+{   /// Thinking and answer of `response`. The thinking is the text
+    /// of a `<think>` block opening the reply, nested blocks included,
+    /// or everything before a lone `</think>` (templates that open the
+    /// block in the prompt); `None` when empty or absent. Text with an
+    /// unclosed `<think>`, or with tags only further in, is returned
+    /// whole as the answer.
+    pub fn extract(response: &str) -> (Option<String>, String)
+    {   let trimmed = response.trim_start();
+        let split = match trimmed.strip_prefix(OPEN)
+        {   Some(inner) => Self::matching_close(inner)
+              .map(|end| (&inner[..end], &inner[end + CLOSE.len()..]))
+          , None => trimmed.find(CLOSE)
+              .filter(|&end| !trimmed[..end].contains(OPEN))
+              .map(|end| (&trimmed[..end], &trimmed[end + CLOSE.len()..]))
+        };
+        match split
+        {   Some((thinking, answer)) => {
+              let thinking = thinking.trim();
+              ( (!thinking.is_empty()).then(|| thinking.to_string())
+              , answer.trim_start().to_string()
+              )
+            }
+          , None => (None, response.to_string())
+        }
+    }
+
+    /// Byte offset in `inner`, the text after an opening tag, of the
+    /// `</think>` that closes it
+    fn matching_close(inner: &str) -> Option<usize>
+    {   let mut depth = 0usize;
+        let mut offset = 0;
+        while offset < inner.len()
+        {   let rest = &inner[offset..];
+            if rest.starts_with(OPEN)
+            {   depth += 1;
+                offset += OPEN.len();
+            } else if rest.starts_with(CLOSE)
+            {   if depth == 0
+                {   return Some(offset);
+                }
+                depth -= 1;
+                offset += CLOSE.len();
+            } else
+            {   offset += rest.chars().next().map_or(1, char::len_utf8);
+            }
+        }
+        None
+    }
+}
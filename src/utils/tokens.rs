@@ -0,0 +1,7 @@
+//! Cheap local token estimation
+
+/// Rough token count for `text`: about four characters per token,
+/// which is close enough for rate limiting and budgeting.
+pub fn estimate_tokens(text: &str) -> usize
+{   text.chars().count().div_ceil(4)
+}
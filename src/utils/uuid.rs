@@ -0,0 +1,113 @@
+//! Random (version 4) UUIDs for sessions and requests
+//!
+//! Randomness comes from the standard library's per-process hash
+//! seeds mixed with a clock and a counter; unique enough for ids,
+//! not for anything cryptographic.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// 128-bit identifier printed in the usual hyphenated form
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Uuid(u128);
+
+impl Uuid
+{   /// New random version 4 UUID
+    pub fn new_v4() -> Self
+    {   let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let nanos = std::time::SystemTime::now()
+          .duration_since(std::time::UNIX_EPOCH)
+          .map(|d| d.as_nanos())
+          .unwrap_or_default();
+        let half = |salt: u64| -> u64 {
+          let mut hasher = RandomState::new().build_hasher();
+          hasher.write_u64(salt);
+          hasher.write_u64(count);
+          hasher.write_u128(nanos);
+          hasher.finish()
+        };
+        let bits = ((half(0) as u128) << 64) | half(1) as u128;
+        // Version 4, RFC 4122 variant
+        let bits = (bits & !(0xf << 76)) | (0x4 << 76);
+        let bits = (bits & !(0x3 << 62)) | (0x2 << 62);
+        Uuid(bits)
+    }
+
+    /// The all-zero UUID
+    pub const fn nil() -> Self
+    {   Uuid(0)
+    }
+
+    pub const fn from_u128(value: u128) -> Self
+    {   Uuid(value)
+    }
+
+    pub const fn as_u128(&self) -> u128
+    {   self.0
+    }
+}
+
+impl fmt::Display for Uuid
+{   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {   let v = self.0;
+        write!(
+          f,
+          "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+          (v >> 96) as u32,
+          (v >> 80) as u16,
+          (v >> 64) as u16,
+          (v >> 48) as u16,
+          v & 0xffff_ffff_ffff
+        )
+    }
+}
+
+/// Rejected UUID text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseUuidError(pub String);
+
+impl fmt::Display for ParseUuidError
+{   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {   write!(f, "invalid UUID: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseUuidError {}
+
+impl FromStr for Uuid
+{   type Err = ParseUuidError;
+
+    /// Accepts the hyphenated or the plain 32-digit hex form
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {   let hex: String = s.chars().filter(|c| *c != '-').collect();
+        let hyphens_ok = s.len() == 32
+          || (s.len() == 36
+              && [8, 13, 18, 23].iter().all(|&i| s.as_bytes()[i] == b'-'));
+        if hex.len() != 32 || !hyphens_ok
+        {   return Err(ParseUuidError(s.to_string()));
+        }
+        u128::from_str_radix(&hex, 16)
+          .map(Uuid)
+          .map_err(|_| ParseUuidError(s.to_string()))
+    }
+}
+
+impl serde::Serialize for Uuid
+{   fn serialize<S: serde::Serializer>(&self, serializer: S)
+      -> Result<S::Ok, S::Error>
+    {   serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Uuid
+{   fn deserialize<D: serde::Deserializer<'de>>(deserializer: D)
+      -> Result<Self, D::Error>
+    {   let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,58 @@
+// allm/src/utils/version.rs
+
+//! Pinning floating model names to the version they stand for
+//!
+//! Mistral moves names like `mistral-small-latest` to each new
+//! release. With `AllmConfig::pin_model_versions` the Mistral client
+//! looks such a name up (`GET /v1/models/{model}`) the first time it
+//! is used and sends the canonical id from then on, so a long-running
+//! backend keeps answering with the model it started with.
+
+use std::collections::HashMap;
+
+/// Name endings marking a model name that moves between versions
+pub const FLOATING_SUFFIXES: &[&str] = &["-latest"];
+
+/// True for names like `mistral-small-latest`
+pub fn is_floating(model: &str) -> bool
+{   FLOATING_SUFFIXES.iter().any(|suffix| model.ends_with(suffix))
+}
+
+/// Floating model names and the versions they were pinned to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelVersionResolver
+{   pinned: HashMap<String, String>
+}
+
+impl ModelVersionResolver
+{   pub fn new() -> Self
+    {   Self::default()
+    }
+
+    /// Version `model` is pinned to, otherwise `model` itself
+    pub fn resolve(&self, model: &str) -> String
+    {   self.pinned.get(model)
+          .cloned()
+          .unwrap_or_else(|| model.to_string())
+    }
+
+    /// Send `version` wherever `alias` is asked for
+    pub fn pin(&mut self, alias: &str, version: &str)
+    {   self.pinned.insert(alias.to_string(), version.to_string());
+    }
+
+    /// True when `model` is floating and not pinned yet
+    pub fn needs_lookup(&self, model: &str) -> bool
+    {   is_floating(model) && !self.pinned.contains_key(model)
+    }
+
+    /// Forget every pin; aliases are looked up again on next use
+    pub fn clear_cache(&mut self)
+    {   self.pinned.clear();
+    }
+
+    /// Pinned version of each alias
+    pub fn pinned_versions(&self) -> &HashMap<String, String>
+    {   &self.pinned
+    }
+}
@@ -0,0 +1,3583 @@
+// allm/tests/backend_tests.rs
+//
+// AllmBackend routing, validation and failover against mock
+// providers.
+
+mod common;
+
+use allm::capabilities::ModelFilter;
+use allm::config::{AllmConfig, DeduplicationConfig, ProviderConfig};
+use allm::error::ProviderErrorCode;
+use allm::middleware::{ContentValidator, RefusalDetector, ValidationCode};
+use allm::providers::mistral::ChatMessage;
+use allm::utils::secrets::{secret_id, MemorySecretStore};
+use allm::{AllmBackend, ApiKeySpec, Provider};
+use common::{chat_completion, MockResponse, MockServer};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::timeout;
+
+fn mock_provider(name: &str, server: &MockServer) -> ProviderConfig
+{ ProviderConfig
+  { name: name.to_string()
+  , api_base: Some(format!("{}/v1", server.url()))
+  , api_key: Some(format!("{}-key", name))
+  , ..Default::default()
+  }
+}
+
+async fn ack<T: std::fmt::Debug>
+( rx: Result<tokio::sync::mpsc::UnboundedReceiver<Result<T, allm::Error>>, allm::Error>
+) -> T
+{ let mut rx = rx.expect("queue command");
+  timeout(Duration::from_secs(5), rx.recv())
+    .await
+    .expect("Timeout waiting for reply")
+    .expect("Reply channel closed")
+    .expect("command failed")
+}
+
+#[test]
+fn test_refusal_detector_flags_known_phrases()
+{ let detector = RefusalDetector::new();
+  for refusal in
+  [ "I cannot help with that request."
+  , "I'm unable to provide medical advice."
+  , "As an AI language model, I don't have opinions."
+  , "Sorry, I can\u{2019}t do that."
+  ]
+  { let err = detector.validate(refusal).expect_err(refusal);
+    assert_eq!(err.code, ValidationCode::RefusalDetected);
+  }
+  assert!(detector.validate("Paris is the capital of France.").is_ok());
+}
+
+#[tokio::test]
+async fn test_validator_rejects_refusal()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("I cannot answer that."))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.add_response_validator(Box::new(RefusalDetector::new())).await).await;
+
+  assert_eq!
+  ( backend.ask("hi", "mistral-small-latest").await
+  , Err(allm::Error::Other("validation failed: refusal_detected".to_string()))
+  );
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_retry_on_refusal_uses_next_fallback()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("As an AI, I can't help."))
+  );
+  let cerebras = MockServer::start().await;
+  cerebras.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("Here you go."))
+  );
+  let config = AllmConfig
+  { providers: vec!
+    [ mock_provider("mistral", &mistral)
+    , mock_provider("cerebras", &cerebras)
+    ]
+  , retry_on_refusal: true
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.add_response_validator(Box::new(RefusalDetector::new())).await).await;
+  ack(backend.set_model_fallback_preference(vec!
+  [ (Provider::MistralAi, "mistral-small-latest".to_string())
+  , (Provider::Cerebras, "llama3.1-8b".to_string())
+  ]).await).await;
+
+  assert_eq!
+  ( backend.ask("hi", "mistral-small-latest").await
+  , Ok("Here you go.".to_string())
+  );
+  assert_eq!(mistral.requests().len(), 1);
+  assert_eq!(cerebras.requests()[0].json()["model"], "llama3.1-8b");
+  backend.shutdown().await.expect("shutdown");
+}
+
+/// Hands out `token-1`, `token-2`, ... like a refreshing STS source
+struct RotatingKeys
+{ calls: std::sync::atomic::AtomicUsize
+}
+
+impl allm::auth::ApiKeyProvider for RotatingKeys
+{ fn current_key(&self) -> allm::auth::KeyFuture<'_>
+  { Box::pin(async move
+    { let n = self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+      Ok(format!("token-{}", n + 1))
+    })
+  }
+}
+
+#[tokio::test]
+async fn test_key_provider_is_asked_before_each_request()
+{ let server = MockServer::start().await;
+  server.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("ok"))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("openai", &server)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await
+    .expect("valid config");
+  ack(backend
+    .set_key_provider
+    ( Provider::OpenAI
+    , None
+    , std::sync::Arc::new(RotatingKeys { calls: Default::default() })
+    )
+    .await
+  ).await;
+
+  for _ in 0..3
+  { let mut rx = backend
+      .send_prompt_to(Provider::OpenAI, "hi".to_string(), "gpt-4o".to_string())
+      .await
+      .expect("queue prompt");
+    timeout(Duration::from_secs(5), rx.recv())
+      .await
+      .expect("Timeout waiting for reply")
+      .expect("Reply channel closed")
+      .expect("prompt failed");
+  }
+
+  let auth: Vec<_> = server
+    .requests_to("/v1/chat/completions")
+    .iter()
+    .map(|r| r.header("authorization").unwrap_or_default().to_string())
+    .collect();
+  assert_eq!(auth, ["Bearer token-1", "Bearer token-2", "Bearer token-3"]);
+
+  // A static key still works as before
+  ack(backend
+    .set_key_provider
+    ( Provider::OpenAI
+    , None
+    , allm::auth::StaticKeyProvider::shared("fixed".to_string())
+    )
+    .await
+  ).await;
+  let mut rx = backend
+    .send_prompt_to(Provider::OpenAI, "hi".to_string(), "gpt-4o".to_string())
+    .await
+    .expect("queue prompt");
+  rx.recv().await.expect("reply").expect("prompt failed");
+  let last = server.requests_to("/v1/chat/completions").pop().unwrap();
+  assert_eq!(last.header("authorization"), Some("Bearer fixed"));
+}
+
+#[tokio::test]
+async fn test_warm_up_on_start_pings_configured_providers()
+{ let cerebras = MockServer::start().await;
+  cerebras.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("Hello"))
+  );
+  let unreachable = MockServer::start().await;
+  unreachable.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(500, serde_json::json!({ "error": "down" }))
+  );
+  let config = AllmConfig
+  { providers: vec!
+    [ mock_provider("cerebras", &cerebras)
+    , mock_provider("openai", &unreachable)
+    ]
+  , warm_up_on_start: true
+  , ..Default::default()
+  };
+  let backend = timeout(Duration::from_secs(10), AllmBackend::new_with_config(config))
+    .await
+    .expect("warm-up finished")
+    .expect("failed providers are skipped");
+
+  let warm_up = cerebras.requests_to("/v1/chat/completions");
+  assert_eq!(warm_up.len(), 1);
+  assert_eq!(warm_up[0].json()["max_tokens"], 1);
+  assert_eq!(warm_up[0].json()["messages"][0]["content"], "Hi");
+  assert_eq!(unreachable.requests().len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_warm_up_providers_reports_each_and_primes_the_pool()
+{ let cerebras = MockServer::start().await;
+  cerebras.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("Hello"))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let results = backend.warm_up_providers(vec![Provider::Cerebras, Provider::Groq])
+    .await
+    .expect("warm-up");
+  assert_eq!(results[&Provider::Cerebras], Ok(()));
+  assert!(matches!(results[&Provider::Groq], Err(allm::Error::ProviderNotImplemented(_))));
+  let warm_up = cerebras.requests_to("/v1/chat/completions");
+  assert_eq!(warm_up.len(), 1);
+  assert_eq!(warm_up[0].header("authorization"), Some("Bearer cerebras-key"));
+  assert_eq!(cerebras.connection_count(), 1);
+
+  let request = allm::request::PromptRequest::new
+  ( Provider::Cerebras, "llama3.1-8b".to_string(), "Hello?".to_string()
+  );
+  assert_eq!(backend.ask_request(request).await, Ok("Hello".to_string()));
+  assert_eq!(cerebras.requests().len(), 2);
+  assert_eq!(cerebras.connection_count(), 1, "the prompt reuses the warm connection");
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_provider_status_watchers_see_health_transitions()
+{ use allm::metrics::ProviderHealth;
+
+  let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(400, serde_json::json!({ "message": "bad request" }))
+  );
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("fine"))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let mut status = backend.watch_provider_status(Provider::MistralAi).await.expect("watch");
+  assert_eq!(status.borrow_and_update().health, ProviderHealth::Healthy);
+  assert!(backend.ask("Hi", "mistral-small-latest").await.is_err());
+  let degraded = timeout
+  ( Duration::from_secs(5)
+  , status.wait_for(|s| s.health == ProviderHealth::Degraded)
+  ).await.expect("status change").expect("backend running").clone();
+  assert_eq!(degraded.failed, 1);
+  assert_eq!
+  ( backend.wait_for_provider_healthy(Provider::MistralAi, Duration::from_millis(100)).await
+  , Err(allm::Error::Timeout)
+  );
+
+  let waiter = backend.wait_for_provider_healthy(Provider::MistralAi, Duration::from_secs(5));
+  let (healthy, reply) = tokio::join!(waiter, backend.ask("Hi", "mistral-small-latest"));
+  assert_eq!(reply, Ok("fine".to_string()));
+  assert_eq!(healthy, Ok(()));
+  status.changed().await.expect("backend running");
+  let recovered = status.borrow_and_update().clone();
+  assert_eq!((recovered.health, recovered.succeeded), (ProviderHealth::Healthy, 1));
+
+  assert!(matches!
+  ( backend.watch_provider_status(Provider::Groq).await
+  , Err(allm::Error::ProviderNotImplemented(_))
+  ));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_extra_headers_are_sent_and_checked()
+{ let cerebras = MockServer::start().await;
+  cerebras.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("Hello"))
+  );
+  let with_headers = |headers: &[(&str, &str)]| AllmConfig
+  { providers: vec!
+    [ ProviderConfig
+      { extra_headers: Some
+        ( headers.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+        )
+      , ..mock_provider("cerebras", &cerebras)
+      }
+    ]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(with_headers(&[("X-Tenant-Id", "acme")]))
+    .await
+    .expect("valid config");
+  let request = allm::request::PromptRequest::new
+  ( Provider::Cerebras, "llama3.1-8b".to_string(), "Hello?".to_string()
+  );
+  assert_eq!(backend.ask_request(request).await, Ok("Hello".to_string()));
+  let sent = cerebras.requests_to("/v1/chat/completions");
+  assert_eq!(sent[0].header("x-tenant-id"), Some("acme"));
+  assert_eq!(sent[0].header("authorization"), Some("Bearer cerebras-key"));
+  backend.shutdown().await.expect("shutdown");
+
+  for invalid in [("bad header", "acme"), ("X-Tenant-Id", "line\nbreak")]
+  { assert!(matches!
+    ( AllmBackend::new_with_config(with_headers(&[invalid])).await
+    , Err(allm::Error::InvalidConfiguration(_))
+    ));
+  }
+}
+
+#[tokio::test]
+async fn test_request_headers_override_provider_headers()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("Hello"))
+  );
+  let header = |n: &str, v: &str| (n.to_string(), v.to_string());
+  let config = AllmConfig
+  { providers: vec!
+    [ ProviderConfig
+      { extra_headers: Some(HashMap::from(
+        [ header("X-Department", "engineering")
+        , header("X-Project-Id", "proj-123")
+        ]))
+      , ..mock_provider("mistral", &mistral)
+      }
+    ]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let headers = HashMap::from(
+  [ header("X-Project-Id", "proj-999")
+  , header("X-Cost-Center", "cc-456")
+  ]);
+  assert_eq!
+  ( backend.ask_with_headers("Hello?", "mistral-small-latest", headers).await
+  , Ok("Hello".to_string())
+  );
+  assert_eq!
+  ( backend.ask("Hello?", "mistral-small-latest").await
+  , Ok("Hello".to_string())
+  );
+  let sent = mistral.requests_to("/v1/chat/completions");
+  assert_eq!(sent[0].header("x-department"), Some("engineering"));
+  assert_eq!(sent[0].header("x-project-id"), Some("proj-999"));
+  assert_eq!(sent[0].header("x-cost-center"), Some("cc-456"));
+  assert_eq!(sent[1].header("x-project-id"), Some("proj-123"));
+  assert_eq!(sent[1].header("x-cost-center"), None);
+
+  let invalid = HashMap::from([header("bad header", "x")]);
+  assert!(matches!
+  ( backend.ask_with_headers("Hello?", "mistral-small-latest", invalid).await
+  , Err(allm::Error::InvalidConfiguration(_))
+  ));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_set_api_keys_flags_keys_without_a_client()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("Hello"))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let key = |provider: Provider, model: &str, key: &str| ApiKeySpec
+  { provider, model: model.to_string(), key: key.to_string()
+  };
+
+  let results = ack(backend.set_api_keys(vec!
+  [ key(Provider::MistralAi, "", "new-key")
+  , key(Provider::Anthropic, "claude-3-opus", "anthropic-key")
+  ]).await).await;
+  let outcomes: Vec<_> = results.into_iter()
+    .map(|(spec, result)| (spec.provider, spec.model, result))
+    .collect();
+  assert_eq!(outcomes,
+  [ (Provider::MistralAi, String::new(), Ok(()))
+  , ( Provider::Anthropic, "claude-3-opus".to_string()
+    , Err(allm::Error::ProviderNotImplemented("Anthropic".to_string()))
+    )
+  ]);
+  assert_eq!
+  ( backend.ask("Hello?", "mistral-small-latest").await
+  , Ok("Hello".to_string())
+  );
+  let sent = mistral.requests_to("/v1/chat/completions");
+  assert_eq!(sent[0].header("authorization"), Some("Bearer new-key"));
+  backend.shutdown().await.expect("shutdown");
+
+  // The flagged key is stored all the same
+  let store = MemorySecretStore::shared();
+  let backend = AllmBackend::new_with_secret_store(store.clone());
+  ack(backend.set_api_keys(vec![key(Provider::Anthropic, "", "anthropic-key")]).await).await;
+  assert_eq!
+  ( store.get(&secret_id(&Provider::Anthropic, None)).await
+  , Ok("anthropic-key".to_string())
+  );
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_keys_set_after_construction_reach_the_provider()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("Hello"))
+  );
+  let config = AllmConfig
+  { providers: vec![ProviderConfig { api_key: None, ..mock_provider("mistral", &mistral) }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let key = |model: &str, key: &str| ApiKeySpec
+  { provider: Provider::MistralAi, model: model.to_string(), key: key.to_string()
+  };
+
+  let results = ack(backend.set_api_keys(vec!
+  [ key("", "master-key")
+  , key("mistral-large-latest", "large-key")
+  ]).await).await;
+  assert!(results.iter().all(|(_, result)| result.is_ok()), "{:?}", results);
+  assert_eq!
+  ( backend.ask("Hello?", "mistral-small-latest").await
+  , Ok("Hello".to_string())
+  );
+  assert_eq!
+  ( backend.ask("Hello?", "mistral-large-latest").await
+  , Ok("Hello".to_string())
+  );
+  let sent = mistral.requests_to("/v1/chat/completions");
+  assert_eq!(sent[0].header("authorization"), Some("Bearer master-key"));
+  assert_eq!(sent[1].header("authorization"), Some("Bearer large-key"));
+  backend.shutdown().await.expect("shutdown");
+}
+
+fn catalog_entry(name: &str, context: usize) -> allm::ModelInfo
+{ allm::ModelInfo
+  { name: name.to_string()
+  , max_context_tokens: context
+  , ..allm::providers::mistral::default_model_info()
+  }
+}
+
+#[tokio::test]
+async fn test_context_overflow_upgrades_to_larger_family_model()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(400, serde_json::json!({
+      "object": "error",
+      "message": "Prompt contains 40000 tokens, too large for model with 32768 maximum context length",
+      "type": "invalid_request_error"
+    }))
+  );
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("long answer"))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , context_overflow_upgrade: true
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.register_models(vec!
+  [ catalog_entry("mistral-small-32k", 32_000)
+  , catalog_entry("mistral-large-256k", 256_000)
+  , catalog_entry("mistral-medium-128k", 128_000)
+  , catalog_entry("codestral-512k", 512_000)
+  ]).await).await;
+
+  assert_eq!
+  ( backend.ask("a very long prompt", "mistral-small-32k").await
+  , Ok("long answer".to_string())
+  );
+  let models: Vec<_> = mistral.requests_to("/v1/chat/completions").iter()
+    .map(|r| r.json()["model"].as_str().unwrap().to_string())
+    .collect();
+  assert_eq!(models, ["mistral-small-32k", "mistral-medium-128k"]);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_capability_matrix_lists_catalog_models()
+{ use allm::capabilities::ModelRequirements;
+  let mistral = MockServer::start().await;
+  let cerebras = MockServer::start().await;
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.register_models(vec![allm::ModelInfo
+  { input_modalities: allm::ModelModalities
+    { supported: vec![allm::InputModality::Combined(allm::CombinedModality
+      { modalities: vec![allm::BaseModality::Text, allm::BaseModality::Image]
+      })]
+    }
+  , cost_per_million_input_tokens: Some(2.0)
+  , cost_per_million_output_tokens: Some(6.0)
+  , ..catalog_entry("pixtral-large-latest", 128_000)
+  }]).await).await;
+
+  let matrix = backend.capability_matrix().await.expect("matrix");
+  let models: Vec<_> = matrix.rows.iter()
+    .map(|r| (r.provider.clone(), r.model.as_str(), r.supports_vision))
+    .collect();
+  assert_eq!
+  ( models
+  , [ (Provider::Cerebras, "llama3.1-8b", false)
+    , (Provider::MistralAi, "mistral-small-latest", false)
+    , (Provider::MistralAi, "pixtral-large-latest", true)
+    ]
+  );
+  assert_eq!(matrix.cheapest_for(ModelRequirements::default()).unwrap().model, "llama3.1-8b");
+  let vision = ModelRequirements { vision: true, ..Default::default() };
+  assert_eq!(matrix.cheapest_for(vision).unwrap().model, "pixtral-large-latest");
+  let huge = ModelRequirements { min_context_tokens: 1_000_000, ..Default::default() };
+  assert_eq!(matrix.cheapest_for(huge), None);
+
+  let markdown = matrix.to_markdown_table();
+  assert_eq!(markdown.lines().count(), 5);
+  assert!(markdown.contains("| cerebras | llama3.1-8b | 8192 | yes | yes | no | 0.1 | 0.1 | yes |"));
+  let csv = matrix.to_csv();
+  assert!(csv.starts_with("provider,model,context_tokens,"));
+  assert!(csv.contains("\nmistral,pixtral-large-latest,128000,true,true,true,2,6,true\n"));
+  backend.shutdown().await.expect("shutdown");
+}
+
+fn populated_model_info() -> allm::ModelInfo
+{ allm::ModelInfo
+  { name: "pixtral-large-latest".to_string()
+  , max_context_tokens: 128_000
+  , max_response_tokens: 4_096
+  , can_save_context: true
+  , input_modalities: allm::ModelModalities
+    { supported: vec!
+      [ allm::InputModality::Single(allm::BaseModality::Text)
+      , allm::InputModality::Combined(allm::CombinedModality
+        { modalities: vec![allm::BaseModality::Text, allm::BaseModality::Image]
+        })
+      , allm::InputModality::Single(allm::BaseModality::File)
+      ]
+    }
+  , supports_streaming: true
+  , supports_tools: true
+  , supports_logprobs: true
+  , supports_reproducible_outputs: true
+  , supports_raw_completion: true
+  , provider: Provider::MistralAi
+  , default_system_prompt: Some("Describe images precisely.".to_string())
+  , supported_file_extensions: Some(vec!["png".to_string(), "pdf".to_string()])
+  , cost_per_million_input_tokens: Some(2.0)
+  , cost_per_million_output_tokens: Some(6.0)
+  , is_available: false
+  }
+}
+
+#[tokio::test]
+async fn test_model_registry_round_trips_every_field()
+{ let model = populated_model_info();
+  let json = serde_json::to_string(&model).expect("serialize");
+  assert_eq!(serde_json::from_str::<allm::ModelInfo>(&json).expect("deserialize"), model);
+
+  let mistral = MockServer::start().await;
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config.clone()).await.expect("valid config");
+  ack(backend.register_models(vec![model.clone()]).await).await;
+  let exported = backend.export_model_registry().await.expect("export");
+  backend.shutdown().await.expect("shutdown");
+
+  let restored = AllmBackend::new_with_config(config).await.expect("valid config");
+  let count = serde_json::from_str::<Vec<allm::ModelInfo>>(&exported).unwrap().len();
+  assert_eq!(restored.import_model_registry(&exported).await, Ok(count));
+  let registry: Vec<allm::ModelInfo> =
+    serde_json::from_str(&restored.export_model_registry().await.expect("export")).unwrap();
+  assert_eq!(registry.iter().filter(|m| **m == model).count(), 1);
+  assert!(matches!
+  ( restored.import_model_registry("not json").await
+  , Err(allm::Error::ParseError(_))
+  ));
+  restored.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_model_registry_cache_persists_across_restarts()
+{ let path = std::env::temp_dir().join(format!("allm-registry-{}.json", std::process::id()));
+  let _ = std::fs::remove_file(&path);
+  let mistral = MockServer::start().await;
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , model_registry_cache_path: Some(path.to_string_lossy().into_owned())
+  , ..Default::default()
+  };
+
+  let backend = AllmBackend::new_with_config(config.clone()).await.expect("missing cache is fine");
+  ack(backend.register_models(vec![populated_model_info()]).await).await;
+  backend.shutdown().await.expect("shutdown");
+  assert!(path.exists());
+
+  let restarted = AllmBackend::new_with_config(config).await.expect("valid config");
+  let matrix = restarted.capability_matrix().await.expect("matrix");
+  assert!(matrix.rows.iter().any(|r| r.model == "pixtral-large-latest" && !r.is_available));
+  restarted.shutdown().await.expect("shutdown");
+  let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_model_override_replaces_built_in_cost()
+{ let mistral = MockServer::start().await;
+  let config: AllmConfig = serde_json::from_value(serde_json::json!({
+    "providers": [{ "name": "mistral", "api_base": format!("{}/v1", mistral.url()) }],
+    "failover": serde_json::to_value(allm::config::FailoverConfig::default()).unwrap(),
+    "model_overrides": [serde_json::to_value(allm::ModelInfo
+    { cost_per_million_input_tokens: Some(0.1)
+    , cost_per_million_output_tokens: Some(0.3)
+    , ..allm::providers::mistral::default_model_info()
+    }).unwrap()]
+  })).expect("config");
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let matrix = backend.capability_matrix().await.expect("matrix");
+  let rows: Vec<_> = matrix.rows.iter()
+    .filter(|r| r.model == "mistral-small-latest")
+    .collect();
+  assert_eq!(rows.len(), 1);
+  assert_eq!(rows[0].cost_per_million_in, Some(0.1));
+  assert_eq!(rows[0].cost_per_million_out, Some(0.3));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_context_overflow_fails_without_upgrade_flag()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(400, serde_json::json!({
+      "error": { "code": "context_length_exceeded", "message": "too long" }
+    }))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.register_models(vec!
+  [ catalog_entry("mistral-small-32k", 32_000)
+  , catalog_entry("mistral-medium-128k", 128_000)
+  ]).await).await;
+
+  assert_eq!
+  ( backend.ask("a very long prompt", "mistral-small-32k").await
+  , Err(allm::Error::ContextWindowExceeded)
+  );
+  assert_eq!(mistral.requests().len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_metrics_count_provider_outcomes()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("ok")));
+  mistral.respond("/v1/chat/completions", MockResponse::text(500, "boom"));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  assert!(backend.ask("one", "mistral-small-latest").await.is_ok());
+  assert!(backend.ask("two", "mistral-small-latest").await.is_err());
+
+  let metrics = ack(backend.get_metrics().await).await;
+  assert_eq!(metrics.pending_prompts, 0);
+  let mistral_status = &metrics.providers[0];
+  assert_eq!(mistral_status.provider, Provider::MistralAi);
+  assert_eq!
+  ( (mistral_status.requests, mistral_status.succeeded, mistral_status.failed)
+  , (2, 1, 1)
+  );
+  assert_eq!(mistral_status.last_error.as_deref(), Some("MistralAi API error (500): boom"));
+  assert!(metrics.to_prometheus_text()
+    .contains("allm_provider_failed_total{provider=\"MistralAi\"} 1"));
+  assert!(backend.backend_ref().is_alive());
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_model_aliases_resolve_to_provider_and_model()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from mistral")));
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  let config = AllmConfig
+  { providers: vec!
+    [ mock_provider("mistral", &mistral)
+    , mock_provider("cerebras", &cerebras)
+    ]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.set_model_aliases(std::collections::HashMap::from(
+  [ ("small".to_string(), (Provider::MistralAi, "mistral-small-latest".to_string()))
+  , ("fast".to_string(), (Provider::Cerebras, "llama3.1-8b".to_string()))
+  ])).await).await;
+
+  assert_eq!(backend.ask("hi", "fast").await, Ok("from cerebras".to_string()));
+  assert_eq!(backend.ask("hi", "small").await, Ok("from mistral".to_string()));
+  // Not an alias: a literal model on the current provider
+  assert_eq!(backend.ask("hi", "open-mistral-nemo").await, Ok("from mistral".to_string()));
+
+  assert_eq!(cerebras.requests()[0].json()["model"], "llama3.1-8b");
+  let mistral_models: Vec<_> = mistral.requests().iter()
+    .map(|r| r.json()["model"].as_str().unwrap().to_string())
+    .collect();
+  assert_eq!(mistral_models, ["mistral-small-latest", "open-mistral-nemo"]);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_dry_run_returns_body_without_sending()
+{ let mistral = MockServer::start().await;
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let mut request = allm::request::PromptRequest::new
+  ( Provider::MistralAi
+  , "mistral-small-latest".to_string()
+  , "Capital of France?".to_string()
+  );
+  request.system_message = Some("Answer in one word.".to_string());
+  request.max_tokens = Some(16);
+
+  let body = backend.dry_run(request).await.expect("dry run");
+  assert_eq!
+  ( body
+  , serde_json::json!({
+      "model": "mistral-small-latest",
+      "messages": [
+        { "role": "system", "content": "Answer in one word." },
+        { "role": "user", "content": "Capital of France?" }
+      ],
+      "max_tokens": 16,
+      "temperature": allm::providers::DEFAULT_TEMPERATURE,
+      "stream": false
+    })
+  );
+  assert!(mistral.requests().is_empty());
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_prefill_continues_assistant_turn_or_is_rejected()
+{ let mistral = MockServer::start().await;
+  let cerebras = MockServer::start().await;
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let request = |provider, model: &str| allm::request::PromptRequest
+  { prefill: Some("{\"city\": \"".to_string())
+  , ..allm::request::PromptRequest::new(provider, model.to_string(), "Capital of France as JSON?".to_string())
+  };
+
+  let body = backend.dry_run(request(Provider::MistralAi, "mistral-small-latest")).await.expect("dry run");
+  assert_eq!
+  ( body["messages"]
+  , serde_json::json!(
+    [ { "role": "user", "content": "Capital of France as JSON?" }
+    , { "role": "assistant", "content": "{\"city\": \"", "prefix": true }
+    ])
+  );
+  // Without a prefill no message is marked
+  let plain = allm::request::PromptRequest { prefill: None, ..request(Provider::MistralAi, "mistral-small-latest") };
+  let body = backend.dry_run(plain).await.expect("dry run");
+  assert!(body["messages"][0].get("prefix").is_none());
+
+  // Replicate writes the prefill as the open assistant turn
+  let prefilled = allm::providers::ChatRequest
+  { messages: vec![ChatMessage::user("Capital of France?"), ChatMessage::assistant("The capital is")]
+  , model: "meta/llama".to_string()
+  , ..Default::default()
+  };
+  let body = allm::providers::replicate::prediction_body(prefilled);
+  assert_eq!(body.input.prompt, "user: Capital of France?\nassistant: The capital is");
+
+  let rejected = allm::Error::InvalidConfiguration("Cerebras does not support assistant prefill".to_string());
+  assert_eq!(backend.dry_run(request(Provider::Cerebras, "llama3.1-8b")).await, Err(rejected.clone()));
+  assert_eq!(backend.ask_request(request(Provider::Cerebras, "llama3.1-8b")).await, Err(rejected));
+  assert!(cerebras.requests().is_empty());
+  assert!(mistral.requests().is_empty());
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_return_raw_keeps_provider_body()
+{ let cerebras = MockServer::start().await;
+  let mut body = chat_completion("Paris.");
+  body["system_fingerprint"] = serde_json::json!("fp_42");
+  body["choices"][0]["logprobs"] = serde_json::json!({ "content": [{ "token": "Paris", "logprob": -0.01 }] });
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, body.clone()));
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, body.clone()));
+  let config = AllmConfig
+  { providers: vec![mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let mut request = allm::request::PromptRequest::new
+  ( Provider::Cerebras
+  , "llama3.1-8b".to_string()
+  , "Capital of France?".to_string()
+  );
+
+  let plain = backend.ask_full(request.clone()).await.expect("reply");
+  assert_eq!(plain.text, "Paris.");
+  assert_eq!(plain.model, "llama3.1-8b");
+  assert_eq!(plain.raw, None);
+
+  request.return_raw = true;
+  let full = backend.ask_full(request).await.expect("reply");
+  assert_eq!(full.text, "Paris.");
+  assert_eq!(full.raw, Some(body));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_request_id_reaches_provider_and_reply()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("hi")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let mut request = allm::request::PromptRequest::new
+  ( Provider::MistralAi
+  , "mistral-small-latest".to_string()
+  , "hello".to_string()
+  );
+
+  let generated = backend.ask_full(request.clone()).await.expect("reply");
+  assert!(generated.request_id.parse::<allm::utils::uuid::Uuid>().is_ok());
+
+  request.request_id = Some("trace-42".to_string());
+  let supplied = backend.ask_full(request).await.expect("reply");
+  assert_eq!(supplied.request_id, "trace-42");
+
+  let requests = mistral.requests();
+  assert_eq!(requests[0].header("x-request-id"), Some(generated.request_id.as_str()));
+  assert_eq!(requests[1].header("x-request-id"), Some("trace-42"));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_pii_is_redacted_both_ways()
+{ use allm::middleware::{PiiConfig, PiiRedactor};
+  let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("Wrote to [EMAIL_1], cc max@example.net"))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let redactor = PiiRedactor::new(PiiConfig::default()).expect("valid patterns");
+  ack(backend.add_request_interceptor(Box::new(redactor.clone())).await).await;
+  ack(backend.add_response_validator(Box::new(redactor)).await).await;
+
+  let response = backend.ask_full(allm::request::PromptRequest::new
+  ( Provider::MistralAi
+  , "mistral-small-latest".to_string()
+  , "Email ann@example.com, SSN 123-45-6789".to_string()
+  )).await.expect("reply");
+
+  let sent = mistral.requests()[0].json();
+  assert_eq!(sent["messages"][0]["content"], "Email [EMAIL_1], SSN [SSN_1]");
+  assert_eq!(response.text, "Wrote to [EMAIL_1], cc [EMAIL_2]");
+  assert_eq!
+  ( PiiRedactor::unredact(&response.text, &response.metadata.redactions)
+  , "Wrote to ann@example.com, cc max@example.net"
+  );
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_rate_limit_is_retried_after_retry_after()
+{ let openai = MockServer::start().await;
+  openai.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(429, serde_json::json!({
+      "error": { "code": "rate_limit_exceeded", "type": "tokens", "message": "Rate limit reached" }
+    })).with_header("retry-after-ms", "50")
+  );
+  openai.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("ok")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("openai", &openai)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let mut rx = backend
+    .send_prompt_to(Provider::OpenAI, "hi".to_string(), "gpt-4o".to_string())
+    .await
+    .expect("queue prompt");
+  let reply = timeout(Duration::from_secs(5), rx.recv()).await.expect("Timeout waiting for reply");
+  assert_eq!(reply, Some(Ok("ok".to_string())));
+  assert_eq!(openai.requests_to("/v1/chat/completions").len(), 2);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_provider_errors_map_to_error_codes()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(529, serde_json::json!({
+      "type": "error",
+      "error": { "type": "overloaded_error", "message": "Overloaded" }
+    })).with_header("retry-after", "7")
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , failover: allm::config::FailoverConfig { enabled: false, ..Default::default() }
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  assert_eq!
+  ( backend.ask("hi", "mistral-small-latest").await
+  , Err(allm::Error::ProviderApiError
+    { provider: Provider::MistralAi
+    , code: ProviderErrorCode::Overloaded
+    , message: "Overloaded".to_string()
+    , retry_after: Some(Duration::from_secs(7))
+    })
+  );
+  assert_eq!(mistral.requests().len(), 1);
+  backend.shutdown().await.expect("shutdown");
+
+  assert_eq!
+  ( ProviderErrorCode::classify(429, Some("rate_limit_exceeded"), Some("requests"))
+  , ProviderErrorCode::RateLimitRequests
+  );
+  assert_eq!(ProviderErrorCode::classify(401, None, None), ProviderErrorCode::InvalidApiKey);
+  assert_eq!
+  ( ProviderErrorCode::classify(400, Some("model_not_found"), Some("invalid_request_error"))
+  , ProviderErrorCode::ModelNotFound
+  );
+  assert_eq!
+  ( ProviderErrorCode::classify(400, Some("content_policy_violation"), None)
+  , ProviderErrorCode::ContentFiltered
+  );
+  assert_eq!
+  ( ProviderErrorCode::classify(500, None, Some("server_error"))
+  , ProviderErrorCode::Unknown("server_error".to_string())
+  );
+}
+
+#[test]
+fn test_errors_round_trip_through_json()
+{ let cases =
+  [ ( allm::Error::RateLimitExceeded
+    , serde_json::json!({ "type": "rate_limit_exceeded" })
+    )
+  , ( allm::Error::ApiError("overloaded".to_string())
+    , serde_json::json!({ "type": "api_error", "message": "overloaded" })
+    )
+  , ( allm::Error::ProviderApiError
+      { provider: Provider::OpenAI
+      , code: ProviderErrorCode::RateLimitTokens
+      , message: "slow down".to_string()
+      , retry_after: Some(Duration::from_millis(1500))
+      }
+    , serde_json::json!({
+        "type": "provider_api_error",
+        "provider": "OpenAI",
+        "code": "rate_limit_tokens",
+        "message": "slow down",
+        "retry_after_ms": 1500
+      })
+    )
+  , ( allm::Error::PromptNotFound(7)
+    , serde_json::json!({ "type": "prompt_not_found", "id": 7 })
+    )
+  ];
+  for (error, json) in cases
+  { assert_eq!(serde_json::to_value(&error).unwrap(), json);
+    assert_eq!(serde_json::from_value::<allm::Error>(json).unwrap(), error);
+  }
+  let unknown: ProviderErrorCode = serde_json::from_str("\"quota_exhausted\"").unwrap();
+  assert_eq!(unknown, ProviderErrorCode::Unknown("quota_exhausted".to_string()));
+}
+
+#[tokio::test]
+async fn test_standard_errors_convert_into_error()
+{ let server = MockServer::start().await;
+  let status_error = |status| {
+    let path = format!("/status/{}", status);
+    server.respond(&path, MockResponse::text(status, "nope"));
+    let url = format!("{}{}", server.url(), path);
+    async move
+    { let e = reqwest::get(url).await.unwrap().error_for_status().unwrap_err();
+      allm::Error::from(e)
+    }
+  };
+  assert!(matches!(status_error(401).await, allm::Error::MissingApiKey(host) if host == "127.0.0.1"));
+  assert_eq!(status_error(429).await, allm::Error::RateLimitExceeded);
+  assert_eq!(status_error(408).await, allm::Error::Timeout);
+  assert_eq!(status_error(504).await, allm::Error::Timeout);
+  assert!(matches!(status_error(500).await, allm::Error::HttpError(_)));
+
+  let json = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+  assert!(matches!(allm::Error::from(json), allm::Error::ParseError(_)));
+  let io = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+  assert_eq!(allm::Error::from(io), allm::Error::Other("no such file".to_string()));
+  let elapsed = timeout(Duration::from_millis(1), std::future::pending::<()>()).await.unwrap_err();
+  assert_eq!(allm::Error::from(elapsed), allm::Error::Timeout);
+  let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<u8>();
+  drop(rx);
+  assert_eq!(allm::Error::from(tx.send(1).unwrap_err()), allm::Error::Other("channel closed".to_string()));
+
+  assert_eq!(std::io::Error::from(allm::Error::Timeout).kind(), std::io::ErrorKind::TimedOut);
+  let io = std::io::Error::from(allm::Error::RateLimitExceeded);
+  assert_eq!(io.kind(), std::io::ErrorKind::Other);
+  assert_eq!(io.to_string(), "API rate limit exceeded");
+}
+
+#[tokio::test]
+async fn test_invalid_api_key_fails_over_without_retry()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(401, serde_json::json!({ "message": "Unauthorized", "request_id": "r1" }))
+  );
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend
+    .set_model_fallback_preference(vec![(Provider::Cerebras, "llama3.1-8b".to_string())])
+    .await
+  ).await;
+
+  assert_eq!
+  ( backend.ask("hi", "mistral-small-latest").await
+  , Ok("from cerebras".to_string())
+  );
+  assert_eq!(mistral.requests().len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_extra_params_merge_into_request_body()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("ok")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let mut request = allm::request::PromptRequest::new
+  ( Provider::MistralAi
+  , "mistral-small-latest".to_string()
+  , "Say yes".to_string()
+  );
+  let extra = serde_json::json!({
+    "logit_bias": { "1734": -100 },
+    "parallel_tool_calls": false,
+    "model": "not-this-one"
+  });
+  request.extra_params = extra.as_object().cloned();
+
+  let body = backend.dry_run(request.clone()).await.expect("dry run");
+  assert_eq!(body["logit_bias"], serde_json::json!({ "1734": -100 }));
+  assert_eq!(backend.ask_request(request).await, Ok("ok".to_string()));
+
+  let sent = mistral.requests_to("/v1/chat/completions")[0].json();
+  assert_eq!(sent, body);
+  assert_eq!(sent["logit_bias"]["1734"], -100);
+  assert_eq!(sent["parallel_tool_calls"], false);
+  // Fields allm sets win over extra params
+  assert_eq!(sent["model"], "mistral-small-latest");
+  backend.shutdown().await.expect("shutdown");
+}
+
+/// Holds the backend inside `validate` until `release` is dropped
+struct Gate
+{ entered: tokio::sync::mpsc::UnboundedSender<()>
+, release: std::sync::Mutex<std::sync::mpsc::Receiver<()>>
+}
+
+impl ContentValidator for Gate
+{ fn validate(&self, _response: &str) -> Result<(), allm::middleware::ValidationError>
+  { let _ = self.entered.send(());
+    let _ = self.release.lock().unwrap().recv();
+    Ok(())
+  }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_full_command_queue_holds_back_senders()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("ok")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , command_queue_capacity: 1
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let (entered_tx, mut entered_rx) = tokio::sync::mpsc::unbounded_channel();
+  let (release_tx, release_rx) = std::sync::mpsc::channel();
+  let gate = Gate { entered: entered_tx, release: std::sync::Mutex::new(release_rx) };
+  ack(backend.add_response_validator(Box::new(gate)).await).await;
+
+  let model = "mistral-small-latest".to_string();
+  let mut first = backend.send_prompt("one".to_string(), model.clone()).await.expect("queue");
+  timeout(Duration::from_secs(5), entered_rx.recv()).await.expect("backend busy");
+  // The backend is stuck validating: one more command fits the queue,
+  // the next one waits for room
+  let mut second = backend.send_prompt("two".to_string(), model.clone()).await.expect("queue");
+  let mut third = Box::pin(backend.send_prompt("three".to_string(), model));
+  assert!(timeout(Duration::from_millis(200), &mut third).await.is_err());
+
+  drop(release_tx);
+  let mut third = timeout(Duration::from_secs(5), third)
+    .await
+    .expect("room freed")
+    .expect("queue");
+  for rx in [&mut first, &mut second, &mut third]
+  { assert_eq!
+    ( timeout(Duration::from_secs(5), rx.recv()).await.expect("answered")
+    , Some(Ok("ok".to_string()))
+    );
+  }
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_map_over_texts_pairs_each_text_with_its_reply()
+{ use futures_util::StreamExt;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+
+  let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("short")).with_delay(Duration::from_millis(50))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let texts: Vec<String> = (1..=5).map(|i| format!("document {}", i)).collect();
+  let progress = Arc::new(AtomicUsize::new(0));
+  let seen = progress.clone();
+  let map = allm::request::MapConfig
+  { concurrency: 2
+  , progress_callback: Some(Box::new(move |done, total|
+    { assert_eq!(total, 5);
+      seen.store(done, Ordering::SeqCst);
+    }))
+  , ..Default::default()
+  };
+  let template = allm::request::PromptTemplate::new("Summarize: {{text}}");
+  let mut pairs: Vec<(String, String)> = backend
+    .map_over_texts_with(texts.clone(), template, "mistral-small-latest", map)
+    .map(|pair| pair.expect("reply"))
+    .collect()
+    .await;
+  pairs.sort();
+  let expected: Vec<(String, String)> = texts.iter()
+    .map(|text| (text.clone(), "short".to_string()))
+    .collect();
+  assert_eq!(pairs, expected);
+  assert_eq!(progress.load(Ordering::SeqCst), 5);
+  let mut prompts: Vec<String> = mistral.requests_to("/v1/chat/completions").iter()
+    .map(|r| r.json()["messages"][0]["content"].as_str().unwrap_or_default().to_string())
+    .collect();
+  prompts.sort();
+  assert_eq!(prompts[0], "Summarize: document 1");
+  assert_eq!(prompts.len(), 5);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_map_over_texts_fail_fast_stops_at_first_error()
+{ use futures_util::StreamExt;
+
+  let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(400, serde_json::json!({ "message": "bad request" }))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let texts = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+
+  let results: Vec<_> = backend
+    .map_over_texts(texts.clone(), "{{text}}", "mistral-small-latest", 1)
+    .collect()
+    .await;
+  assert_eq!(results.len(), 3);
+  assert!(results.iter().all(Result::is_err));
+
+  let map = allm::request::MapConfig { concurrency: 1, fail_fast: true, ..Default::default() };
+  let template = allm::request::PromptTemplate::new("{{text}}");
+  let results: Vec<_> = backend
+    .map_over_texts_with(texts, template, "mistral-small-latest", map)
+    .collect()
+    .await;
+  assert_eq!(results.len(), 1);
+  assert!(results[0].is_err());
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_prompt_status_follows_a_prompt_from_queue_to_reply()
+{ use allm::PromptStatus;
+
+  let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("first")).with_delay(Duration::from_millis(300))
+  );
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { rate_limit_rpm: Some(1)
+    , ..mock_provider("mistral", &mistral)
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let (first, mut first_rx) = backend
+    .send_prompt_with_id("one".to_string(), "mistral-small-latest".to_string())
+    .await
+    .expect("queue prompt");
+  assert_eq!(backend.get_prompt_status(first).await, Ok(PromptStatus::InFlight));
+  // The rate limit holds the second prompt back for a minute
+  let (second, _second_rx) = backend
+    .send_prompt_with_id("two".to_string(), "mistral-small-latest".to_string())
+    .await
+    .expect("queue prompt");
+  assert!(second > first);
+  assert_eq!(backend.get_prompt_status(second).await, Ok(PromptStatus::Queued));
+
+  assert_eq!
+  ( timeout(Duration::from_secs(5), first_rx.recv()).await.expect("answered")
+  , Some(Ok("first".to_string()))
+  );
+  assert_eq!(backend.get_prompt_status(first).await, Ok(PromptStatus::Done));
+  assert_eq!(backend.get_prompt_status(second).await, Ok(PromptStatus::Queued));
+  assert_eq!
+  ( backend.get_prompt_status(second + 1).await
+  , Err(allm::Error::PromptNotFound(second + 1))
+  );
+  assert_eq!(mistral.requests_to("/v1/chat/completions").len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_full_prompt_queue_rejects_until_drained()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("slow")).with_delay(Duration::from_millis(300))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , max_queued_prompts: Some(3)
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let model = "mistral-small-latest".to_string();
+
+  let mut queued = vec![];
+  for i in 0..3
+  { queued.push(backend.send_prompt(format!("prompt {}", i), model.clone()).await.expect("queue"));
+  }
+  let mut rejected = backend.send_prompt("one too many".to_string(), model.clone())
+    .await
+    .expect("queue");
+  assert_eq!
+  ( timeout(Duration::from_millis(100), rejected.recv()).await.expect("rejected at once")
+  , Some(Err(allm::Error::Other("queue full".to_string())))
+  );
+  let request = allm::request::PromptRequest::new
+  ( Provider::MistralAi, model.clone(), "also too many".to_string()
+  );
+  assert_eq!(backend.ask_request(request).await, Err(allm::Error::Other("queue full".to_string())));
+
+  for mut rx in queued
+  { assert_eq!
+    ( timeout(Duration::from_secs(5), rx.recv()).await.expect("answered")
+    , Some(Ok("slow".to_string()))
+    );
+  }
+  assert_eq!(backend.ask("room again", &model).await, Ok("slow".to_string()));
+  assert_eq!(mistral.requests_to("/v1/chat/completions").len(), 4);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_ask_structured_deserializes_the_json_reply()
+{ #[derive(Debug, PartialEq, serde::Deserialize)]
+  struct PersonInfo
+  { name: String
+  , age: u32
+  }
+
+  let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("```json\n{\"name\": \"Ada\", \"age\": 36}\n```"))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let ada = PersonInfo { name: "Ada".to_string(), age: 36 };
+
+  let person: PersonInfo = backend
+    .ask_structured("Ada Lovelace died aged 36.", "mistral-small-latest", None)
+    .await
+    .expect("structured reply");
+  assert_eq!(person, ada);
+  let schema = serde_json::json!(
+  { "type": "object"
+  , "properties": { "name": { "type": "string" }, "age": { "type": "integer" } }
+  , "required": ["name", "age"]
+  });
+  let person: PersonInfo = backend
+    .ask_structured("Ada Lovelace died aged 36.", "mistral-small-latest", Some(schema.clone()))
+    .await
+    .expect("structured reply");
+  assert_eq!(person, ada);
+  let sent = mistral.requests_to("/v1/chat/completions");
+  assert_eq!(sent[0].json()["response_format"], serde_json::json!({ "type": "json_object" }));
+  assert_eq!(sent[1].json()["response_format"]["type"], "json_schema");
+  assert_eq!(sent[1].json()["response_format"]["json_schema"]["schema"], schema);
+
+  let wrong: Result<Vec<String>, _> = backend
+    .ask_structured("List names", "mistral-small-latest", None)
+    .await;
+  assert!(matches!(wrong, Err(allm::Error::ParseError(_))));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_rate_limited_prompts_dispatch_by_priority()
+{ use allm::request::Priority;
+
+  let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("ok"))
+  );
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { rate_limit_tpm: Some(600)
+    , ..mock_provider("mistral", &mistral)
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let model = "mistral-small-latest".to_string();
+  // Client creation is slow enough to refill the bucket for "low 1"
+  backend.preload_providers(&[Provider::MistralAi]).await.expect("preload");
+
+  // 600 estimated tokens: empties the bucket, which refills 10 a second
+  let filler = backend.send_prompt("x".repeat(2400), model.clone()).await.expect("queue");
+  let mut waiting = vec![filler];
+  for (prompt, priority) in
+  [ ("low 1", Priority::Low)
+  , ("normal 1", Priority::Normal)
+  , ("high 1", Priority::High)
+  , ("low 2", Priority::Low)
+  , ("high 2", Priority::High)
+  ]
+  { waiting.push
+    ( backend.send_prompt_with_priority(prompt.to_string(), model.clone(), priority)
+        .await
+        .expect("queue")
+    );
+  }
+  for mut rx in waiting
+  { assert_eq!
+    ( timeout(Duration::from_secs(10), rx.recv()).await.expect("answered")
+    , Some(Ok("ok".to_string()))
+    );
+  }
+  let order: Vec<String> = mistral.requests_to("/v1/chat/completions").iter()
+    .skip(1)
+    .map(|r| r.json()["messages"][0]["content"].as_str().unwrap_or_default().to_string())
+    .collect();
+  assert_eq!(order, ["high 1", "high 2", "normal 1", "low 1", "low 2"]);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_identical_prompts_share_one_request()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("shared")).with_delay(Duration::from_millis(200))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , deduplication: DeduplicationConfig { enabled: true, ..Default::default() }
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let mut replies = vec![];
+  for _ in 0..10
+  { replies.push
+    ( backend
+        .send_prompt("same question".to_string(), "mistral-small-latest".to_string())
+        .await
+        .expect("queue prompt")
+    );
+  }
+  let mut other = backend
+    .send_prompt("another question".to_string(), "mistral-small-latest".to_string())
+    .await
+    .expect("queue prompt");
+  for rx in replies.iter_mut().chain([&mut other])
+  { assert_eq!
+    ( timeout(Duration::from_secs(5), rx.recv()).await.expect("answered")
+    , Some(Ok("shared".to_string()))
+    );
+  }
+  assert_eq!(mistral.requests_to("/v1/chat/completions").len(), 2);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_prompts_differing_in_settings_are_not_shared()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("own")).with_delay(Duration::from_millis(200))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , deduplication: DeduplicationConfig { enabled: true, ..Default::default() }
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let request = |temperature| allm::request::PromptRequest
+  { temperature: Some(temperature)
+  , ..allm::request::PromptRequest::new
+    ( Provider::MistralAi
+    , "mistral-small-latest".to_string()
+    , "same question".to_string()
+    )
+  };
+  let mut cold = backend.send_request(request(0.0)).await.expect("queue prompt");
+  let mut hot = backend.send_request(request(1.0)).await.expect("queue prompt");
+  for rx in [&mut cold, &mut hot]
+  { assert_eq!
+    ( timeout(Duration::from_secs(5), rx.recv()).await.expect("answered")
+    , Some(Ok("own".to_string()))
+    );
+  }
+  let temperatures: Vec<serde_json::Value> = mistral.requests_to("/v1/chat/completions")
+    .iter()
+    .map(|r| r.json()["temperature"].clone())
+    .collect();
+  assert_eq!(temperatures.len(), 2);
+  assert_ne!(temperatures[0], temperatures[1]);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_shutdown_returns_after_backend_task_ends()
+{ let backend = AllmBackend::new(Some("key".to_string()));
+  let backend_ref = backend.backend_ref();
+  backend.shutdown().await.expect("shutdown");
+
+  // The task has dropped its receivers, so nothing is left to answer
+  assert!(!backend_ref.is_alive());
+  assert_eq!
+  ( backend_ref.get_metrics().await.err()
+  , Some(allm::Error::Other("Backend disconnected".to_string()))
+  );
+}
+
+#[tokio::test]
+async fn test_clients_are_created_on_first_use()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("hi")));
+  let cerebras = MockServer::start().await;
+  let replicate = MockServer::start().await;
+  let config = AllmConfig
+  { providers: vec!
+    [ mock_provider("mistral", &mistral)
+    , mock_provider("cerebras", &cerebras)
+    , ProviderConfig { lazy_init: false, ..mock_provider("replicate", &replicate) }
+    ]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let initialized = |metrics: &allm::metrics::MetricsSnapshot| -> Vec<(Provider, bool)>
+  { metrics.providers.iter().map(|p| (p.provider.clone(), p.initialized)).collect()
+  };
+
+  let metrics = ack(backend.get_metrics().await).await;
+  assert_eq!
+  ( initialized(&metrics)
+  , vec![(Provider::Cerebras, false), (Provider::MistralAi, false), (Provider::Replicate, true)]
+  );
+
+  assert_eq!(backend.ask("hello", "mistral-small-latest").await, Ok("hi".to_string()));
+  let metrics = ack(backend.get_metrics().await).await;
+  assert_eq!
+  ( initialized(&metrics)
+  , vec![(Provider::Cerebras, false), (Provider::MistralAi, true), (Provider::Replicate, true)]
+  );
+  assert!(cerebras.requests().is_empty());
+
+  backend.preload_providers(&[Provider::Cerebras]).await.expect("preload");
+  let metrics = ack(backend.get_metrics().await).await;
+  assert!(metrics.providers.iter().all(|p| p.initialized));
+  assert!(matches!
+  ( backend.preload_providers(&[Provider::TogetherAi]).await
+  , Err(allm::Error::ProviderNotImplemented(_))
+  ));
+  backend.shutdown().await.expect("shutdown");
+}
+
+/// Client whose actor panics as soon as it is spawned
+struct CrashingClient
+{ task: tokio::task::JoinHandle<()>
+}
+
+impl CrashingClient
+{ fn spawn() -> Self
+  { CrashingClient { task: tokio::spawn(async { panic!("actor crashed") }) }
+  }
+}
+
+impl allm::providers::ProviderClient for CrashingClient
+{ fn provider(&self) -> Provider
+  { Provider::Cerebras
+  }
+
+  fn is_alive(&self) -> bool
+  { !self.task.is_finished()
+  }
+
+  fn send_chat
+  ( &self
+  , _request: allm::providers::ChatRequest
+  , _reply: allm::SendPromptReplySender
+  ) -> Result<(), allm::Error>
+  { Err(allm::Error::Other("crashed".to_string()))
+  }
+
+  fn get_available_models
+  ( &self
+  , _reply: tokio::sync::mpsc::UnboundedSender<Result<Vec<String>, allm::Error>>
+  ) -> Result<(), allm::Error>
+  { Err(allm::Error::Other("crashed".to_string()))
+  }
+
+  fn set_api_key
+  ( &self
+  , _model: Option<String>
+  , _key: String
+  , _reply: tokio::sync::mpsc::UnboundedSender<Result<(), allm::Error>>
+  ) -> Result<(), allm::Error>
+  { Err(allm::Error::Other("crashed".to_string()))
+  }
+
+  fn set_key_provider
+  ( &self
+  , _model: Option<String>
+  , _key_provider: allm::auth::SharedKeyProvider
+  , _reply: tokio::sync::mpsc::UnboundedSender<Result<(), allm::Error>>
+  ) -> Result<(), allm::Error>
+  { Err(allm::Error::Other("crashed".to_string()))
+  }
+}
+
+#[tokio::test]
+async fn test_crashing_provider_is_eventually_marked_failed()
+{ use allm::supervisor::SupervisionState;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+
+  let mut state = allm::client::AllmBackendState::new(None);
+  state.supervisor = allm::supervisor::Supervisor::new(allm::config::SupervisorConfig
+  { max_restarts: 3
+  , initial_backoff_ms: 10
+  , check_interval_ms: 10
+  , ..Default::default()
+  });
+  let created = Arc::new(AtomicUsize::new(0));
+  let counter = created.clone();
+  state.register_client(Provider::Cerebras, Arc::new(move || {
+    counter.fetch_add(1, Ordering::SeqCst);
+    Ok(Box::new(CrashingClient::spawn()))
+  })).expect("register");
+  let backend = AllmBackend::from_state(state);
+
+  let cerebras_state = || async {
+    backend.get_supervision_status().await.expect("status")
+      .into_iter()
+      .find(|s| s.provider == Provider::Cerebras)
+      .expect("cerebras supervised")
+  };
+  let failed = timeout(Duration::from_secs(5), async {
+    loop
+    { let status = cerebras_state().await;
+      if status.state == SupervisionState::Failed
+      { break status;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  }).await.expect("provider marked failed");
+
+  // The first client plus three restarts, each of which crashed
+  assert_eq!(failed.restarts, 3);
+  assert_eq!(created.load(Ordering::SeqCst), 4);
+  let metrics = ack(backend.get_metrics().await).await;
+  assert!(metrics.providers.iter().all(|p| p.provider != Provider::Cerebras));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_ask_until_stop_sends_and_strips_stop_sequence()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("1, 2, 3END"))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  assert_eq!
+  ( backend.ask_until_stop("Count to three", "mistral-small-latest", vec!["END", "\n\n"]).await
+  , Ok("1, 2, 3".to_string())
+  );
+  let body = mistral.requests_to("/v1/chat/completions")[0].json();
+  assert_eq!(body["stop"], serde_json::json!(["END", "\n\n"]));
+
+  assert!(matches!
+  ( backend.ask_until_stop("Count", "mistral-small-latest", vec!["a", "b", "c", "d", "e"]).await
+  , Err(allm::Error::InvalidConfiguration(_))
+  ));
+  assert_eq!(mistral.requests_to("/v1/chat/completions").len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_moderate_before_send_rejects_flagged_prompts()
+{ let mistral = MockServer::start().await;
+  let openai = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("hello")));
+  let verdict = |flagged: bool| MockResponse::json(200, serde_json::json!({
+    "results": [{
+      "flagged": flagged,
+      "categories": { "violence": flagged },
+      "category_scores": { "violence": if flagged { 0.9 } else { 0.01 } }
+    }]
+  }));
+  openai.respond("/v1/moderations", verdict(true));
+  openai.respond("/v1/moderations", verdict(false));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("openai", &openai)]
+  , moderate_before_send: true
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  assert_eq!
+  ( backend.ask("something violent", "mistral-small-latest").await
+  , Err(allm::Error::ContentFlagged(vec!["violence".to_string()]))
+  );
+  assert!(mistral.requests().is_empty());
+  assert_eq!
+  ( backend.ask("hi", "mistral-small-latest").await
+  , Ok("hello".to_string())
+  );
+  let screened: Vec<_> = openai.requests_to("/v1/moderations").iter()
+    .map(|r| r.json()["input"].as_str().unwrap().to_string())
+    .collect();
+  assert_eq!(screened, ["something violent", "hi"]);
+  assert_eq!(mistral.requests_to("/v1/chat/completions").len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_from_env_discovers_providers_and_lists_models()
+{ let server = MockServer::start().await;
+  server.respond("/v1/models", MockResponse::json(200, serde_json::json!(
+  { "data": [{ "id": "llama3" }, { "id": "mistral-small-latest" }]
+  })));
+  let url = server.url();
+  std::env::set_var("ALLM_MISTRAL_API_KEY", "mistral-key");
+  std::env::set_var("ALLM_MISTRAL_API_BASE", format!("{}/v1", url));
+  std::env::set_var("ALLM_ANTHROPIC_API_KEY", "unsupported");
+  std::env::set_var("ALLM_OLLAMA_URL", &url);
+
+  let config = AllmConfig::from_env();
+  let names: Vec<&str> = config.providers.iter()
+    .map(|p| p.name.as_str())
+    .collect();
+  assert_eq!(names, vec!["mistral", "local"]);
+  assert_eq!(config.providers[0].api_key.as_deref(), Some("mistral-key"));
+  assert_eq!(
+    config.providers[1].api_base.as_deref(),
+    Some(format!("{}/v1", url).as_str())
+  );
+
+  let backend = AllmBackend::from_env().await.expect("backend");
+  let rows = backend.capability_matrix().await.expect("matrix").rows;
+  let llama = rows.iter()
+    .find(|row| row.provider == Provider::Local && row.model == "llama3")
+    .expect("listed local model registered");
+  assert_eq!(llama.context_tokens, 0);
+  let small = rows.iter()
+    .find(|row| {
+      row.provider == Provider::MistralAi
+        && row.model == "mistral-small-latest"
+    })
+    .expect("built-in model kept");
+  assert!(small.context_tokens > 0);
+  assert!(server.requests_to("/v1/models").len() >= 2);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_context_warning_fires_at_threshold()
+{ let mistral = MockServer::start().await;
+  let usage = |prompt_tokens: usize| {
+    let mut body = chat_completion("ok");
+    body["usage"]["prompt_tokens"] = serde_json::json!(prompt_tokens);
+    MockResponse::json(200, body)
+  };
+  mistral.respond("/v1/chat/completions", usage(79));
+  mistral.respond("/v1/chat/completions", usage(80));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let mut backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let (tx, mut warnings) = tokio::sync::mpsc::unbounded_channel();
+  backend.with_context_warning_channel(tx);
+  ack(backend.register_models(vec![catalog_entry("mistral-100", 100)]).await).await;
+
+  assert_eq!(backend.ask("hi", "mistral-100").await, Ok("ok".to_string()));
+  assert!(warnings.try_recv().is_err(), "79% is below the threshold");
+
+  assert_eq!(backend.ask("hi", "mistral-100").await, Ok("ok".to_string()));
+  assert_eq!
+  ( warnings.try_recv().expect("warning at 80%")
+  , allm::ContextUsageWarning
+    { provider: Provider::MistralAi
+    , model: "mistral-100".to_string()
+    , tokens_used: 80
+    , tokens_available: 100
+    , percent_full: 80.0
+    }
+  );
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_total_deadline_stops_retries_and_failover()
+{ let overloaded = || MockResponse::json(529, serde_json::json!({
+    "type": "error",
+    "error": { "type": "overloaded_error", "message": "Overloaded" }
+  })).with_delay(Duration::from_millis(100));
+  let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", overloaded());
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", overloaded());
+  let config = AllmConfig
+  { providers: vec!
+    [ mock_provider("mistral", &mistral)
+    , mock_provider("cerebras", &cerebras)
+    ]
+  , failover: allm::config::FailoverConfig
+    { max_retries: 3
+    , initial_backoff_ms: 10
+    , backoff_multiplier: 1.0
+    , ..Default::default()
+    }
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.set_model_fallback_preference(vec!
+  [ (Provider::MistralAi, "mistral-small-latest".to_string())
+  , (Provider::Cerebras, "llama3.1-8b".to_string())
+  ]).await).await;
+
+  let request = allm::request::PromptRequest
+  { total_deadline: Some(Duration::from_millis(250))
+  , ..allm::request::PromptRequest::new
+    ( Provider::MistralAi
+    , "mistral-small-latest".to_string()
+    , "hi".to_string()
+    )
+  };
+  let started = std::time::Instant::now();
+  let mut rx = backend.send_request(request).await.expect("queue prompt");
+  let reply = timeout(Duration::from_secs(5), rx.recv())
+    .await
+    .expect("Timeout waiting for reply")
+    .expect("Reply channel closed");
+  // Without the deadline: four attempts on each provider, ~800ms
+  assert!(started.elapsed() < Duration::from_millis(600));
+  match reply
+  { Err(allm::Error::DeadlineExceeded(last)) => assert!(matches!
+    ( *last
+    , allm::Error::ProviderApiError { code: ProviderErrorCode::Overloaded, .. }
+    ))
+  , other => panic!("expected DeadlineExceeded, got {:?}", other)
+  }
+  assert!(mistral.requests().len() < 4);
+  assert!(cerebras.requests().is_empty());
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_json_schema_retry_corrects_invalid_output()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("Sure! {name: Ada}")));
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion(r#"{"name": "Ada"}"#)));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let schema = serde_json::json!(
+  { "type": "object"
+  , "required": ["name"]
+  , "properties": { "name": { "type": "string" } }
+  });
+  let request = |max_attempts| allm::request::PromptRequest
+  { json_schema: Some(allm::request::JsonSchemaRetry::new(schema.clone(), max_attempts))
+  , ..allm::request::PromptRequest::new
+    ( Provider::MistralAi
+    , "mistral-small-latest".to_string()
+    , "Who wrote the first program? Answer as JSON.".to_string()
+    )
+  };
+
+  let mut rx = backend.send_request(request(3)).await.expect("queue prompt");
+  let reply = timeout(Duration::from_secs(5), rx.recv()).await.expect("Timeout waiting for reply");
+  assert_eq!(reply, Some(Ok(r#"{"name": "Ada"}"#.to_string())));
+  let requests = mistral.requests_to("/v1/chat/completions");
+  assert_eq!(requests.len(), 2);
+  let messages = requests[1].json()["messages"].as_array().unwrap().clone();
+  assert_eq!(messages.len(), 3);
+  assert_eq!(messages[1]["content"], "Sure! {name: Ada}");
+  assert!(messages[2]["content"].as_str().unwrap().starts_with("Your output didn't match the schema: not JSON"));
+
+  // One attempt allowed: the invalid first reply is final
+  let second = MockServer::start().await;
+  second.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("[]")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &second)]
+  , ..Default::default()
+  };
+  let strict = AllmBackend::new_with_config(config).await.expect("valid config");
+  let mut rx = strict.send_request(request(1)).await.expect("queue prompt");
+  let reply = timeout(Duration::from_secs(5), rx.recv()).await.expect("Timeout waiting for reply");
+  assert_eq!
+  ( reply
+  , Some(Err(allm::Error::ParseError("reply does not match the schema: $: expected object".to_string())))
+  );
+  assert_eq!(second.requests().len(), 1);
+  strict.shutdown().await.expect("shutdown");
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[test]
+fn test_prompt_template_render()
+{ use allm::request::PromptTemplate;
+  let vars: HashMap<String, String> =
+  [ ("city", "Paris"), ("days", "3") ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+
+  let template = PromptTemplate::new("Plan {{days}} days in {{ city }}, starting in {{city}}.");
+  assert_eq!(template.variables(), vec!["days", "city"]);
+  assert_eq!(template.render(&vars).unwrap(), "Plan 3 days in Paris, starting in Paris.");
+
+  let missing = PromptTemplate::new("{{greeting}}, {{city}} in {{month}}");
+  assert_eq!
+  ( missing.render(&vars)
+  , Err(allm::Error::InvalidConfiguration("missing template variables: greeting, month".to_string()))
+  );
+  assert!(matches!
+  ( PromptTemplate::new("{{city").render(&vars)
+  , Err(allm::Error::InvalidConfiguration(_))
+  ));
+
+  let escaped = PromptTemplate::new(r"Write \{{city\}} literally, then {{city}}: {not a var}");
+  assert_eq!(escaped.variables(), vec!["city"]);
+  assert_eq!(escaped.render(&vars).unwrap(), "Write {{city}} literally, then Paris: {not a var}");
+}
+
+#[tokio::test]
+async fn test_ask_template_sends_rendered_prompt()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("Bonjour")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let template = allm::request::PromptTemplate::new("Say hello in {{language}}");
+  let vars = HashMap::from([("language".to_string(), "French".to_string())]);
+
+  assert_eq!
+  ( backend.ask_template(&template, &vars, "mistral-small-latest").await
+  , Ok("Bonjour".to_string())
+  );
+  let requests = mistral.requests_to("/v1/chat/completions");
+  assert_eq!(requests[0].json()["messages"][0]["content"], "Say hello in French");
+
+  // A missing variable fails before anything is sent
+  assert!(matches!
+  ( backend.ask_template(&template, &HashMap::new(), "mistral-small-latest").await
+  , Err(allm::Error::InvalidConfiguration(_))
+  ));
+  assert_eq!(mistral.requests().len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_response_normalization_applies_to_replies()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion(" Certainly! Paris.\n\n")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , response_normalization: allm::utils::normalize::NormalizationRules
+    { trim_whitespace: true
+    , strip_leading_ai_prefixes: true
+    , ..Default::default()
+    }
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  assert_eq!(backend.ask("capital of France?", "mistral-small-latest").await, Ok("Paris.".to_string()));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_ask_with_retry_on_refusal_rephrases_then_gives_up()
+{ let mistral = MockServer::start().await;
+  for reply in ["I cannot help with that.", "How do locks get picked in films?", "With a tension wrench."]
+  { mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion(reply)));
+  }
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  assert_eq!
+  ( backend.ask_with_retry_on_refusal("How do I pick a lock?", "mistral-small-latest", 2).await
+  , Ok("With a tension wrench.".to_string())
+  );
+  let prompts: Vec<_> = mistral.requests_to("/v1/chat/completions").iter()
+    .map(|r| r.json()["messages"][0]["content"].as_str().unwrap().to_string())
+    .collect();
+  assert_eq!
+  ( prompts
+  , vec!
+    [ "How do I pick a lock?".to_string()
+    , "Rephrase this question to be less likely to trigger a safety filter: How do I pick a lock?".to_string()
+    , "How do locks get picked in films?".to_string()
+    ]
+  );
+
+  backend.shutdown().await.expect("shutdown");
+
+  // Every reply refuses: prompt, one rephrase, its retry, then the limit
+  let refusing = MockServer::start().await;
+  refusing.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("I'm unable to help.")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &refusing)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  assert_eq!
+  ( backend.ask_with_retry_on_refusal("How do I pick a lock?", "mistral-small-latest", 1).await
+  , Err(allm::Error::Other("max rephrases exceeded".to_string()))
+  );
+  assert_eq!(refusing.requests().len(), 3);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_ask_with_rephrasing_counts_attempts()
+{ use allm::request::{PromptRequest, RephrasingStrategy};
+  let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("I cannot answer that.")));
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("Paris.")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  // A registered detector's rejection counts as a refusal too
+  ack(backend.add_response_validator(Box::new(RefusalDetector::new())).await).await;
+
+  let request = PromptRequest::new(Provider::MistralAi, "mistral-small-latest".to_string(), "Capital?".to_string());
+  let strategy = RephrasingStrategy::CustomPrefix("For a geography quiz: ".to_string());
+  let response = backend.ask_with_rephrasing(request, strategy, 3).await.expect("answered");
+  assert_eq!(response.text, "Paris.");
+  assert_eq!(response.rephrase_attempts, 1);
+  let requests = mistral.requests_to("/v1/chat/completions");
+  assert_eq!(requests.len(), 2);
+  assert_eq!(requests[1].json()["messages"][0]["content"], "For a geography quiz: Capital?");
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_ask_n_returns_every_choice_in_order()
+{ let mistral = MockServer::start().await;
+  let choice = |index: usize, content: &str| serde_json::json!(
+  { "index": index
+  , "message": { "role": "assistant", "content": content }
+  , "finish_reason": "stop"
+  });
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, serde_json::json!(
+    { "choices": [choice(0, "Paris"), choice(1, "Lyon"), choice(2, "Nice")]
+    }))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let request = allm::request::PromptRequest::new
+  ( Provider::MistralAi, "mistral-small-latest".to_string(), "Name a French city".to_string()
+  );
+
+  assert_eq!
+  ( backend.ask_n(request.clone(), 3).await
+  , Ok(vec!["Paris".to_string(), "Lyon".to_string(), "Nice".to_string()])
+  );
+  assert_eq!(mistral.requests_to("/v1/chat/completions")[0].json()["n"], 3);
+  // The single-reply path takes the first choice
+  let response = backend.ask_full(request.clone()).await.expect("reply");
+  assert_eq!(response.text, "Paris");
+  assert!(mistral.requests_to("/v1/chat/completions")[1].json().get("n").is_none());
+
+  assert_eq!
+  ( backend.ask_n(request.clone(), 0).await
+  , Err(allm::Error::InvalidConfiguration("n must be at least 1".to_string()))
+  );
+  let zero = allm::request::PromptRequest { n: Some(0), ..request };
+  assert_eq!
+  ( backend.ask_full(zero).await.map(|r| r.text)
+  , Err(allm::Error::InvalidConfiguration("n must be at least 1".to_string()))
+  );
+  assert_eq!(mistral.requests().len(), 2);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_ask_by_model_routes_to_the_provider_cataloging_it()
+{ let mistral = MockServer::start().await;
+  let cerebras = MockServer::start().await;
+  let openai = MockServer::start().await;
+  for (server, answer) in [(&mistral, "from mistral"), (&cerebras, "from cerebras"), (&openai, "from openai")]
+  { server.respond("/v1/chat/completions", MockResponse::json(200, chat_completion(answer)));
+  }
+  let config = AllmConfig
+  { providers: vec!
+    [ mock_provider("mistral", &mistral)
+    , mock_provider("cerebras", &cerebras)
+    , mock_provider("openai", &openai)
+    ]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let entry = |provider: Provider, name: &str| allm::ModelInfo
+  { provider
+  , ..catalog_entry(name, 128_000)
+  };
+  ack(backend.register_models(vec!
+  [ entry(Provider::OpenAI, "gpt-4o")
+  , entry(Provider::OpenAI, "shared-model")
+  , entry(Provider::Cerebras, "shared-model")
+  , entry(Provider::Groq, "groq-only")
+  ]).await).await;
+
+  assert_eq!(backend.ask_by_model("Hi", "gpt-4o").await, Ok("from openai".to_string()));
+  assert_eq!(backend.ask_by_model("Hi", "llama3.1-8b").await, Ok("from cerebras".to_string()));
+  assert_eq!(backend.ask_by_model("Hi", "mistral-small-latest").await, Ok("from mistral".to_string()));
+  // Ambiguous: by name without a preference, else preference order
+  assert_eq!(backend.ask_by_model("Hi", "shared-model").await, Ok("from cerebras".to_string()));
+  ack(backend.set_model_fallback_preference(vec!
+  [ (Provider::OpenAI, "gpt-4o".to_string())
+  , (Provider::Cerebras, "llama3.1-8b".to_string())
+  ]).await).await;
+  assert_eq!(backend.ask_by_model("Hi", "shared-model").await, Ok("from openai".to_string()));
+  let models: Vec<_> = openai.requests_to("/v1/chat/completions").iter()
+    .map(|r| r.json()["model"].as_str().unwrap().to_string())
+    .collect();
+  assert_eq!(models, ["gpt-4o", "shared-model"]);
+  // Groq has no client here
+  assert!(matches!
+  ( backend.ask_by_model("Hi", "groq-only").await
+  , Err(allm::Error::ModelNotFound(model)) if model == "groq-only"
+  ));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_ask_by_model_looks_up_uncataloged_models_in_model_lists()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/models", MockResponse::json(200, serde_json::json!(
+  { "data": [{ "id": "mistral-small-latest" }]
+  })));
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("fast answer")));
+  cerebras.respond("/v1/models", MockResponse::json(200, serde_json::json!(
+  { "object": "list"
+  , "data": [{ "id": "llama-3.3-70b", "object": "model" }]
+  })));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  assert_eq!(backend.ask_by_model("Hi", "llama-3.3-70b").await, Ok("fast answer".to_string()));
+  // Cataloged now: the second prompt needs no listing
+  assert_eq!(backend.ask_by_model("Hi", "llama-3.3-70b").await, Ok("fast answer".to_string()));
+  assert_eq!(cerebras.requests_to("/v1/models").len(), 1);
+  assert_eq!(cerebras.requests_to("/v1/chat/completions").len(), 2);
+
+  assert!(matches!
+  ( backend.ask_by_model("Hi", "no-such-model").await
+  , Err(allm::Error::ModelNotFound(_))
+  ));
+  assert_eq!(mistral.requests_to("/v1/chat/completions").len(), 0);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_model_lists_filter_by_capability_and_cost()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/models", MockResponse::json(200, serde_json::json!(
+  { "data":
+    [ { "id": "mistral-small-latest" }
+    , { "id": "pixtral-large-latest" }
+    , { "id": "codestral-latest" }
+    , { "id": "uncataloged-model" }
+    ]
+  })));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.register_models(vec!
+  [ allm::ModelInfo
+    { supports_tools: true
+    , cost_per_million_output_tokens: Some(0.3)
+    , ..catalog_entry("mistral-small-latest", 32_000)
+    }
+  , allm::ModelInfo
+    { is_available: true
+    , ..populated_model_info()
+    }
+  , allm::ModelInfo
+    { supports_tools: false
+    , cost_per_million_output_tokens: Some(0.9)
+    , ..catalog_entry("codestral-latest", 256_000)
+    }
+  ]).await).await;
+  let models = |filter| async
+  { let mut rx = backend.get_model_lists_filtered(filter).await.expect("queue");
+    timeout(Duration::from_secs(5), rx.recv())
+      .await
+      .expect("Timeout waiting for model lists")
+      .expect("Reply channel closed")
+      .expect("model lists")
+      .into_iter()
+      .map(|(_, name)| name)
+      .collect::<Vec<_>>()
+  };
+
+  assert_eq!(models(None).await.len(), 4);
+  assert_eq!
+  ( models(Some(ModelFilter { requires_modality: Some(allm::BaseModality::Image), ..Default::default() })).await
+  , ["pixtral-large-latest"]
+  );
+  assert_eq!
+  ( models(Some(ModelFilter
+    { requires_tools: true
+    , max_cost_per_million_output: Some(1.0)
+    , ..Default::default()
+    })).await
+  , ["mistral-small-latest"]
+  );
+  assert_eq!
+  ( models(Some(ModelFilter { max_cost_per_million_output: Some(1.0), ..Default::default() })).await
+  , ["mistral-small-latest", "codestral-latest"]
+  );
+  // Models the catalog does not describe are left out
+  assert_eq!(models(Some(ModelFilter::default())).await.len(), 3);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_rate_limit_status_reflects_last_response_headers()
+{ let cerebras = MockServer::start().await;
+  cerebras.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("Hello"))
+      .with_header("x-ratelimit-remaining-requests", "3")
+      .with_header("x-ratelimit-remaining-tokens", "12000")
+      .with_header("x-ratelimit-reset-requests", "1m30s")
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  assert_eq!
+  ( backend.get_rate_limit_status(Provider::Cerebras, "llama3.1-8b").await
+  , Ok(allm::utils::rate_limiter::RateLimitStatus::default())
+  );
+
+  let request = allm::request::PromptRequest::new
+  ( Provider::Cerebras, "llama3.1-8b".to_string(), "Hi".to_string()
+  );
+  let before = std::time::SystemTime::now();
+  backend.ask_request(request).await.expect("reply");
+  let status = backend.get_rate_limit_status(Provider::Cerebras, "llama3.1-8b").await.expect("status");
+  assert_eq!(status.remaining_requests, Some(3));
+  assert_eq!(status.remaining_tokens, Some(12_000));
+  let reset_in = status.reset_at.expect("reset").duration_since(before).unwrap();
+  assert!(reset_in >= Duration::from_secs(90) && reset_in < Duration::from_secs(95));
+
+  assert!(matches!
+  ( backend.get_rate_limit_status(Provider::Groq, "llama3.1-8b").await
+  , Err(allm::Error::ProviderNotImplemented(_))
+  ));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_rate_limited_model_waits_for_its_reset()
+{ let cerebras = MockServer::start().await;
+  cerebras.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(429, serde_json::json!({ "message": "Too many requests" }))
+      .with_header("retry-after", "30")
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("cerebras", &cerebras)]
+  , failover: allm::config::FailoverConfig { enabled: false, ..Default::default() }
+  , max_retry_after_secs: Some(1)
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  assert_eq!
+  ( backend.wait_for_rate_limit_reset(Provider::Cerebras, "llama3.1-8b").await
+  , Ok(Duration::ZERO)
+  );
+
+  let request = allm::request::PromptRequest::new
+  ( Provider::Cerebras, "llama3.1-8b".to_string(), "Hi".to_string()
+  );
+  assert!(backend.ask_request(request).await.is_err());
+  let status = backend.get_rate_limit_status(Provider::Cerebras, "llama3.1-8b").await.expect("status");
+  assert!(status.is_rate_limited);
+  assert!(status.reset_at.is_some());
+  let other = backend.get_rate_limit_status(Provider::Cerebras, "llama3.1-70b").await.expect("status");
+  assert!(!other.is_rate_limited);
+
+  // The 30s retry-after is cut to max_retry_after_secs
+  let waited = backend.wait_for_rate_limit_reset(Provider::Cerebras, "llama3.1-8b").await.expect("waited");
+  assert!(waited > Duration::ZERO && waited <= Duration::from_secs(1), "{:?}", waited);
+  let status = backend.get_rate_limit_status(Provider::Cerebras, "llama3.1-8b").await.expect("status");
+  assert!(!status.is_rate_limited);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_pin_model_versions_looks_floating_names_up_once()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("pinned")));
+  mistral.respond("/v1/models/mistral-small-latest", MockResponse::json(200, serde_json::json!(
+  { "id": "mistral-small-2409"
+  , "object": "model"
+  , "aliases": ["mistral-small-latest"]
+  })));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , pin_model_versions: true
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  for model in ["mistral-small-latest", "mistral-small-latest", "open-mistral-nemo"]
+  { assert_eq!(backend.ask("Hi", model).await, Ok("pinned".to_string()));
+  }
+  let lookup = mistral.requests_to("/v1/models/mistral-small-latest");
+  assert_eq!(lookup.len(), 1);
+  assert_eq!(lookup[0].header("authorization"), Some("Bearer mistral-key"));
+  let models: Vec<_> = mistral.requests_to("/v1/chat/completions").iter()
+    .map(|r| r.json()["model"].as_str().unwrap().to_string())
+    .collect();
+  assert_eq!(models, ["mistral-small-2409", "mistral-small-2409", "open-mistral-nemo"]);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_floating_names_are_sent_as_given_without_pinning()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("floating")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  assert_eq!(backend.ask("Hi", "mistral-small-latest").await, Ok("floating".to_string()));
+  assert!(mistral.requests_to("/v1/models").is_empty());
+  assert_eq!
+  ( mistral.requests_to("/v1/chat/completions")[0].json()["model"]
+  , "mistral-small-latest"
+  );
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_count_tokens_batch_estimates_without_a_counting_endpoint()
+{ let mistral = MockServer::start().await;
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let long = "word ".repeat(2_000);
+
+  assert_eq!
+  ( backend.count_tokens_batch(&["", "Hi", "Hello, world!", &long], "mistral-small-latest").await
+  , Ok(vec![0, 1, 4, 2_500])
+  );
+  assert_eq!(backend.count_tokens_batch(&[], "mistral-small-latest").await, Ok(vec![]));
+  assert!(mistral.requests().is_empty(), "estimates need no API call");
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_requests_for_unsupported_features_are_rejected()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("fine"))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.register_models(vec![allm::ModelInfo
+  { supports_tools: false
+  , supports_logprobs: false
+  , supports_reproducible_outputs: false
+  , ..catalog_entry("plain-model", 32_000)
+  }]).await).await;
+
+  let request_with = |key: &str, value: serde_json::Value| {
+    let mut request = allm::request::PromptRequest::new
+    ( Provider::MistralAi, "plain-model".to_string(), "hi".to_string()
+    );
+    request.extra_params = Some(serde_json::Map::from_iter([(key.to_string(), value)]));
+    request
+  };
+  for (key, value, feature) in
+  [ ("tools", serde_json::json!([{"type": "function"}]), "tools")
+  , ("logprobs", serde_json::json!(true), "logprobs")
+  , ("random_seed", serde_json::json!(42), "seed")
+  ]
+  { assert_eq!
+    ( backend.ask_request(request_with(key, value)).await
+    , Err(allm::Error::InvalidConfiguration(format!("model plain-model does not support {}", feature)))
+    );
+  }
+  assert_eq!
+  ( backend.ask_request(request_with("logprobs", serde_json::json!(false))).await
+  , Ok("fine".to_string())
+  );
+  assert_eq!(mistral.requests_to("/v1/chat/completions").len(), 1);
+  backend.shutdown().await.expect("shutdown");
+
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , strict_capability_validation: false
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.register_models(vec![catalog_entry("plain-model", 32_000)]).await).await;
+  assert_eq!
+  ( backend.ask_request(request_with("random_seed", serde_json::json!(42))).await
+  , Ok("fine".to_string())
+  );
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_connect_timeout_gives_up_on_an_unreachable_host()
+{ // A listener that never accepts, its backlog filled: further
+  // connection attempts hang until the client gives up
+  let socket = tokio::net::TcpSocket::new_v4().expect("socket");
+  socket.bind("127.0.0.1:0".parse().unwrap()).expect("bind");
+  let listener = socket.listen(1).expect("listen");
+  let addr = listener.local_addr().expect("address");
+  let mut backlog = vec![];
+  while let Ok(Ok(stream)) =
+    timeout(Duration::from_millis(200), tokio::net::TcpStream::connect(addr)).await
+  { backlog.push(stream);
+  }
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "mistral".to_string()
+    , api_base: Some(format!("http://{}/v1", addr))
+    , api_key: Some("mistral-key".to_string())
+    , connect_timeout_secs: Some(1)
+    , timeout_secs: Some(30)
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let started = std::time::Instant::now();
+  let result = timeout(Duration::from_secs(10), backend.ask("hi", "mistral-small-latest"))
+    .await
+    .expect("connect timeout well before the request timeout");
+  assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+  assert_eq!(result, Err(allm::Error::Timeout));
+  backend.shutdown().await.expect("shutdown");
+  drop(listener);
+}
+
+#[tokio::test]
+async fn test_complete_posts_a_raw_prompt_to_the_completion_endpoint()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/completions"
+  , MockResponse::json(200, serde_json::json!({
+      "id": "cmpl-1",
+      "object": "text_completion",
+      "choices": [{ "index": 0, "text": "    println!(\"hi\");\n}", "finish_reason": "stop" }]
+    }))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  assert_eq!
+  ( backend.complete("fn main() {\n", "codestral-latest", Some(64), Some(0.0), Some(vec!["\n\n".to_string()])).await
+  , Ok("    println!(\"hi\");\n}".to_string())
+  );
+  let requests = mistral.requests_to("/v1/completions");
+  assert_eq!(requests.len(), 1);
+  let body = requests[0].json();
+  assert_eq!(body["prompt"], "fn main() {\n");
+  assert_eq!(body["model"], "codestral-latest");
+  assert_eq!(body["max_tokens"], 64);
+  assert_eq!(body["stop"], serde_json::json!(["\n\n"]));
+  assert_eq!(body["stream"], false);
+  assert!(body.get("messages").is_none(), "{}", body);
+
+  // Chat-only models are refused before anything is sent, unless
+  // their catalog entry says otherwise
+  assert_eq!
+  ( backend.complete("fn main() {\n", "mistral-small-latest", None, None, None).await
+  , Err(allm::Error::InvalidConfiguration(
+      "model mistral-small-latest does not support raw completion".to_string()
+    ))
+  );
+  ack(backend.register_models(vec![allm::ModelInfo
+  { supports_raw_completion: true
+  , ..catalog_entry("my-base-model", 32_000)
+  }]).await).await;
+  assert!(backend.complete("Once upon", "my-base-model", None, None, None).await.is_ok());
+  assert_eq!(mistral.requests_to("/v1/completions").len(), 2);
+  assert!(mistral.requests_to("/v1/chat/completions").is_empty());
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_custom_ca_certificate_is_loaded_and_checked()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("trusted"))
+  );
+  let with_ca = |path: &str| AllmConfig
+  { providers: vec![ProviderConfig
+    { ca_cert_path: Some(path.to_string())
+    , ..mock_provider("mistral", &mistral)
+    }]
+  , ..Default::default()
+  };
+  let ca = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/test_ca.pem");
+  let backend = AllmBackend::new_with_config(with_ca(ca)).await.expect("client builds with the CA");
+  assert_eq!(backend.ask("hi", "mistral-small-latest").await, Ok("trusted".to_string()));
+  backend.shutdown().await.expect("shutdown");
+
+  let not_pem = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/providers.json");
+  for invalid in ["/nonexistent/ca.pem", not_pem]
+  { assert!(matches!
+    ( AllmBackend::new_with_config(with_ca(invalid)).await
+    , Err(allm::Error::InvalidConfiguration(message)) if message.contains("CA certificate")
+    ));
+  }
+
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { danger_accept_invalid_certs: Some(true)
+    , ..mock_provider("mistral", &mistral)
+    }]
+  , ..Default::default()
+  };
+  assert!(AllmBackend::new_with_config(config).await.is_ok());
+}
+
+fn echoed_prompt(tokens: &[&str], logprobs: &[Option<f64>]) -> serde_json::Value
+{ serde_json::json!({
+    "id": "cmpl-1",
+    "object": "text_completion",
+    "prompt": [{ "text": tokens.concat(), "logprobs": { "tokens": tokens, "token_logprobs": logprobs } }],
+    "choices": [{ "index": 0, "text": " next", "finish_reason": "length" }]
+  })
+}
+
+#[tokio::test]
+async fn test_perplexity_is_computed_from_the_echoed_prompt_logprobs()
+{ let together = MockServer::start().await;
+  together.respond
+  ( "/v1/completions"
+  , MockResponse::json(200, echoed_prompt(&["The", " cat", " sat", "."], &[None, Some(-1.0), Some(-2.0), Some(-3.0)]))
+  );
+  let mistral = MockServer::start().await;
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("together", &together)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let model = "meta-llama/Llama-3-8b-hf";
+
+  let perplexity = backend.compute_perplexity("The cat sat.", Provider::TogetherAi, model)
+    .await
+    .expect("perplexity");
+  assert!((perplexity - 2.0f64.exp()).abs() < 1e-9, "{}", perplexity);
+  let sent = together.requests_to("/v1/completions");
+  assert_eq!(sent[0].header("authorization"), Some("Bearer together-key"));
+  let body = sent[0].json();
+  assert_eq!(body["prompt"], "The cat sat.");
+  assert_eq!(body["model"], model);
+  assert_eq!(body["echo"], true);
+  assert_eq!(body["logprobs"], 1);
+
+  assert!(matches!
+  ( backend.compute_perplexity("The cat sat.", Provider::MistralAi, "mistral-small-latest").await
+  , Err(allm::Error::ProviderNotImplemented(_))
+  ));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_echoed_prompt_logprobs_reach_the_prompt_response()
+{ let together = MockServer::start().await;
+  let mut body = echoed_prompt(&["Hi", "!"], &[None, Some(-0.5)]);
+  body["choices"] = chat_completion("Hello")["choices"].clone();
+  together.respond("/v1/chat/completions", MockResponse::json(200, body));
+  let config = AllmConfig
+  { providers: vec![mock_provider("together", &together)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let mut request = allm::request::PromptRequest::new
+  ( Provider::TogetherAi, "meta-llama/Llama-3-8b-chat-hf".to_string(), "Hi!".to_string()
+  );
+  request.extra_params = Some(serde_json::Map::from_iter(
+  [ ("echo".to_string(), serde_json::json!(true))
+  , ("logprobs".to_string(), serde_json::json!(1))
+  ]));
+  let response = backend.ask_full(request).await.expect("response");
+  assert_eq!(response.text, "Hello");
+  assert_eq!
+  ( response.prompt_logprobs
+  , Some(vec!
+    [ allm::request::TokenLogprob { token: "Hi".to_string(), logprob: None }
+    , allm::request::TokenLogprob { token: "!".to_string(), logprob: Some(-0.5) }
+    ])
+  );
+  assert_eq!(together.requests_to("/v1/chat/completions")[0].json()["echo"], true);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_client_identity_is_loaded_and_checked()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("identified"))
+  );
+  let with_identity = |password: &str| AllmConfig
+  { providers: vec![ProviderConfig
+    { client_identity_path: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/test_identity.p12").to_string())
+    , client_identity_password: Some(password.to_string())
+    , ..mock_provider("mistral", &mistral)
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(with_identity("allm-test")).await.expect("client builds with the identity");
+  assert_eq!(backend.ask("hi", "mistral-small-latest").await, Ok("identified".to_string()));
+  backend.shutdown().await.expect("shutdown");
+
+  assert!(matches!
+  ( AllmBackend::new_with_config(with_identity("wrong-password")).await
+  , Err(allm::Error::InvalidConfiguration(message)) if message.contains("client identity")
+  ));
+}
+
+#[tokio::test]
+async fn test_failover_callback_reports_each_hop()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(401, serde_json::json!({ "message": "Unauthorized" }))
+  );
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let mut backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+  let seen = events.clone();
+  backend.with_failover_callback(move |event| seen.lock().unwrap().push(event));
+  ack(backend
+    .set_model_fallback_preference(vec![(Provider::Cerebras, "llama3.1-8b".to_string())])
+    .await
+  ).await;
+
+  assert_eq!(backend.ask("hi", "mistral-small-latest").await, Ok("from cerebras".to_string()));
+  let events = events.lock().unwrap().clone();
+  assert_eq!(events.len(), 1, "{:?}", events);
+  assert_eq!(events[0].from, (Provider::MistralAi, "mistral-small-latest".to_string()));
+  assert_eq!(events[0].to, (Provider::Cerebras, "llama3.1-8b".to_string()));
+  assert!(matches!
+  ( &events[0].reason
+  , allm::Error::ProviderApiError { provider: Provider::MistralAi, code: ProviderErrorCode::InvalidApiKey, .. }
+  ), "{:?}", events[0].reason);
+  assert_eq!(events[0].attempt, 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_deadline_blocked_failover_is_not_reported()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(401, serde_json::json!({ "message": "Unauthorized" }))
+      .with_delay(Duration::from_millis(300))
+  );
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let mut backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let failovers = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+  let seen = failovers.clone();
+  backend.with_failover_callback(move |event| seen.lock().unwrap().push(event));
+  ack(backend
+    .set_model_fallback_preference(vec![(Provider::Cerebras, "llama3.1-8b".to_string())])
+    .await
+  ).await;
+  let mut events = backend.subscribe_events().await.expect("subscribe");
+
+  let request = allm::request::PromptRequest
+  { total_deadline: Some(Duration::from_millis(200))
+  , ..allm::request::PromptRequest::new
+    ( Provider::MistralAi
+    , "mistral-small-latest".to_string()
+    , "hi".to_string()
+    )
+  };
+  assert!(matches!
+  ( backend.ask_full(request).await
+  , Err(allm::Error::DeadlineExceeded(_))
+  ));
+  assert!(failovers.lock().unwrap().is_empty());
+  while let Ok(event) = events.try_recv()
+  { assert!(!matches!(event, allm::AllmEvent::Failover { .. }), "{:?}", event);
+  }
+  assert!(cerebras.requests().is_empty());
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_prompt_response_records_the_failover_chain()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(401, serde_json::json!({ "message": "Unauthorized" }))
+  );
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend
+    .set_model_fallback_preference(vec![(Provider::Cerebras, "llama3.1-8b".to_string())])
+    .await
+  ).await;
+
+  let request = allm::request::PromptRequest::new
+  ( Provider::MistralAi
+  , "mistral-small-latest".to_string()
+  , "hi".to_string()
+  );
+  let response = backend.ask_full(request).await.expect("reply");
+  assert_eq!(response.text, "from cerebras");
+  assert!(response.was_failover());
+  let hops = response.failover_hops();
+  assert_eq!(hops.len(), 2, "{:?}", hops);
+  assert_eq!((&hops[0].provider, hops[0].model.as_str()), (&Provider::MistralAi, "mistral-small-latest"));
+  assert!(matches!
+  ( &hops[0].error
+  , Some(allm::Error::ProviderApiError { code: ProviderErrorCode::InvalidApiKey, .. })
+  ), "{:?}", hops[0].error);
+  assert_eq!((&hops[1].provider, hops[1].model.as_str()), (&Provider::Cerebras, "llama3.1-8b"));
+  assert_eq!(hops[1].error, None);
+
+  // The chain serializes with the rest of the response
+  let json = serde_json::to_string(&response).expect("serialize");
+  let back: allm::request::PromptResponse = serde_json::from_str(&json).expect("deserialize");
+  assert_eq!(back.failover_chain, response.failover_chain);
+
+  // A prompt answered by its own model has one hop
+  let request = allm::request::PromptRequest::new
+  ( Provider::Cerebras
+  , "llama3.1-8b".to_string()
+  , "hi".to_string()
+  );
+  let response = backend.ask_full(request).await.expect("reply");
+  assert!(!response.was_failover());
+  assert_eq!(response.failover_hops().len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+/// Next event, failing the test after 5s
+async fn next_event(events: &mut tokio::sync::mpsc::UnboundedReceiver<allm::AllmEvent>)
+  -> allm::AllmEvent
+{ timeout(Duration::from_secs(5), events.recv())
+    .await
+    .expect("Timeout waiting for an event")
+    .expect("Event channel closed")
+}
+
+#[tokio::test]
+async fn test_event_subscribers_see_requests_and_failovers()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(401, serde_json::json!({ "message": "Unauthorized" }))
+  );
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend
+    .set_model_fallback_preference(vec![(Provider::Cerebras, "llama3.1-8b".to_string())])
+    .await
+  ).await;
+  // A dropped subscriber does not stop the others
+  drop(backend.subscribe_events().await.expect("subscribe"));
+  let mut events = backend.subscribe_events().await.expect("subscribe");
+
+  let mut request = allm::request::PromptRequest::new
+  ( Provider::MistralAi
+  , "mistral-small-latest".to_string()
+  , "hi".to_string()
+  );
+  request.request_id = Some("req-1".to_string());
+  assert_eq!(backend.ask_full(request.clone()).await.map(|r| r.text), Ok("from cerebras".to_string()));
+
+  assert_eq!
+  ( next_event(&mut events).await
+  , allm::AllmEvent::RequestStarted
+    { request_id: "req-1".to_string()
+    , provider: Provider::MistralAi
+    , model: "mistral-small-latest".to_string()
+    }
+  );
+  match next_event(&mut events).await
+  { allm::AllmEvent::Failover { from, to, reason } =>
+    { assert_eq!(from, (Provider::MistralAi, "mistral-small-latest".to_string()));
+      assert_eq!(to, (Provider::Cerebras, "llama3.1-8b".to_string()));
+      assert!(reason.contains("Unauthorized"), "{}", reason);
+    }
+  , other => panic!("expected a failover, got {:?}", other)
+  }
+  assert!(matches!
+  ( next_event(&mut events).await
+  , allm::AllmEvent::RequestStarted { provider: Provider::Cerebras, .. }
+  ));
+  let mut completed = None;
+  while completed.is_none()
+  { match next_event(&mut events).await
+    { allm::AllmEvent::ProviderStatusChanged { provider, from, to } =>
+      { assert_eq!(provider, Provider::MistralAi);
+        assert_eq!
+        ( (from.health, to.health)
+        , (allm::metrics::ProviderHealth::Healthy, allm::metrics::ProviderHealth::Degraded)
+        );
+      }
+    , allm::AllmEvent::RequestCompleted { request_id, tokens_out, .. } =>
+        completed = Some((request_id, tokens_out))
+    , other => panic!("unexpected event {:?}", other)
+    }
+  }
+  assert_eq!(completed, Some(("req-1".to_string(), 4)));
+
+  request.no_failover = true;
+  request.request_id = Some("req-2".to_string());
+  assert!(backend.ask_full(request).await.is_err());
+  next_event(&mut events).await;
+  assert!(matches!
+  ( next_event(&mut events).await
+  , allm::AllmEvent::RequestFailed { request_id, error: allm::Error::ProviderApiError { .. } }
+      if request_id == "req-2"
+  ));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_explain_describes_a_catalog_model()
+{ let mistral = MockServer::start().await;
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.register_models(vec![populated_model_info()]).await).await;
+
+  let explanation = backend.explain(Provider::MistralAi, "pixtral-large-latest").await
+    .expect("explanation");
+  assert_eq!(explanation.model_info, populated_model_info());
+  assert_eq!
+  ( explanation.human_description
+  , "pixtral-large-latest by MistralAi supports up to 128000 input tokens and 4096 output \
+     tokens. It does support tools and does support image input."
+  );
+  assert!(explanation.example_use_cases.iter().any(|u| u.contains("images")));
+  assert!(explanation.example_use_cases.iter().any(|u| u.contains("Long documents")));
+  assert_eq!(explanation.limitations, ["Currently unavailable"]);
+
+  let (small_info, small) = backend.explain(Provider::MistralAi, "mistral-small-latest").await
+    .map(|e| (e.model_info, e.limitations))
+    .expect("default model");
+  assert!(small_info.to_human_description().contains("doesn't support image input"));
+  assert!(small.contains(&"No image input".to_string()));
+  assert_eq!
+  ( backend.explain(Provider::MistralAi, "no-such-model").await
+  , Err(allm::Error::ModelNotFound("no-such-model".to_string()))
+  );
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_no_failover_returns_the_requested_models_error()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(529, serde_json::json!({ "message": "Overloaded" }))
+  );
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , failover: allm::config::FailoverConfig { initial_backoff_ms: 10, ..Default::default() }
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend
+    .set_model_fallback_preference(vec![(Provider::Cerebras, "llama3.1-8b".to_string())])
+    .await
+  ).await;
+  let request = |no_failover| allm::request::PromptRequest
+  { no_failover
+  , ..allm::request::PromptRequest::new
+    ( Provider::MistralAi
+    , "mistral-small-latest".to_string()
+    , "hi".to_string()
+    )
+  };
+
+  let mut rx = backend.send_request(request(true)).await.expect("queue prompt");
+  let reply = timeout(Duration::from_secs(5), rx.recv()).await.expect("answered");
+  assert!(matches!
+  ( reply
+  , Some(Err(allm::Error::ProviderApiError { provider: Provider::MistralAi, .. }))
+  ), "{:?}", reply);
+  assert_eq!(mistral.requests().len(), 1);
+  assert!(cerebras.requests().is_empty());
+
+  let mut rx = backend.send_request(request(false)).await.expect("queue prompt");
+  let reply = timeout(Duration::from_secs(5), rx.recv()).await.expect("answered");
+  assert_eq!(reply, Some(Ok("from cerebras".to_string())));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_rate_limit_fails_over_only_when_failover_on_lists_it()
+{ use allm::failover::FailoverTrigger;
+
+  for (failover_on, fails_over) in
+  [ (vec![FailoverTrigger::RateLimit], true)
+  , (vec![FailoverTrigger::ServerError, FailoverTrigger::Timeout], false)
+  , (vec![], false)
+  ]
+  { let mistral = MockServer::start().await;
+    mistral.respond
+    ( "/v1/chat/completions"
+    , MockResponse::json(429, serde_json::json!({ "message": "Too many requests" }))
+    );
+    let cerebras = MockServer::start().await;
+    cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+    let config = AllmConfig
+    { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+    , failover: allm::config::FailoverConfig
+      { max_retries: 1
+      , initial_backoff_ms: 10
+      , failover_on: failover_on.clone()
+      , ..Default::default()
+      }
+    , ..Default::default()
+    };
+    let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+    ack(backend
+      .set_model_fallback_preference(vec![(Provider::Cerebras, "llama3.1-8b".to_string())])
+      .await
+    ).await;
+
+    let reply = backend.ask("hi", "mistral-small-latest").await;
+    match reply
+    { Ok(text) => assert!(fails_over && text == "from cerebras", "{:?}: {}", failover_on, text)
+    , Err(error) =>
+      { assert!(!fails_over && matches!
+        ( error
+        , allm::Error::ProviderApiError { code: ProviderErrorCode::RateLimitRequests, .. }
+        ), "{:?}: {:?}", failover_on, error);
+        assert!(cerebras.requests().is_empty());
+      }
+    }
+    // Retried once on the same model either way
+    assert_eq!(mistral.requests().len(), 2);
+    backend.shutdown().await.expect("shutdown");
+  }
+}
+
+#[tokio::test]
+async fn test_failover_sequence_skips_rate_limited_entries_until_cooled_off()
+{ use allm::failover::FailoverSequence;
+
+  let mut sequence = FailoverSequence::new(vec!
+  [ (Provider::MistralAi, "mistral-small-latest".to_string())
+  , (Provider::Cerebras, "llama3.1-8b".to_string())
+  , (Provider::MistralAi, "mistral-large-latest".to_string())
+  ]);
+  sequence.mark_rate_limited(&Provider::MistralAi, Duration::from_secs(60));
+  assert_eq!(sequence.current().map(|e| e.model.as_str()), Some("llama3.1-8b"));
+  assert!(!sequence.has_next());
+  assert_eq!(sequence.next(), None);
+
+  // Still cooling off: nothing comes back
+  sequence.smart_reset(std::time::Instant::now());
+  assert_eq!(sequence.current().map(|e| e.model.as_str()), Some("llama3.1-8b"));
+  assert_eq!(sequence.available().count(), 1);
+
+  sequence.smart_reset(std::time::Instant::now() + Duration::from_secs(61));
+  assert_eq!(sequence.current().map(|e| e.model.as_str()), Some("mistral-small-latest"));
+  assert!(sequence.has_next());
+  assert!(sequence.providers.iter().all(|e| e.rate_limited_until.is_none()));
+}
+
+#[tokio::test]
+async fn test_rate_limited_entry_returns_once_its_cool_off_passes()
+{ use allm::failover::FailoverSequence;
+
+  let mut sequence = FailoverSequence::new(vec!
+  [ (Provider::MistralAi, "mistral-small-latest".to_string())
+  , (Provider::Cerebras, "llama3.1-8b".to_string())
+  ]);
+  sequence.mark_rate_limited(&Provider::MistralAi, Duration::from_millis(50));
+  assert_eq!(sequence.current().map(|e| e.model.as_str()), Some("llama3.1-8b"));
+
+  // No smart_reset in between
+  tokio::time::sleep(Duration::from_millis(80)).await;
+  assert_eq!(sequence.current().map(|e| e.model.as_str()), Some("mistral-small-latest"));
+  assert_eq!(sequence.available().count(), 2);
+}
+
+#[tokio::test]
+async fn test_rate_limited_fallback_is_skipped_by_later_prompts()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(429, serde_json::json!({ "message": "Too many requests" }))
+  );
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  cerebras.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(401, serde_json::json!({ "message": "Unauthorized" }))
+  );
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , failover: allm::config::FailoverConfig { max_retries: 0, ..Default::default() }
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.set_model_fallback_preference(vec!
+  [ (Provider::MistralAi, "mistral-small-latest".to_string())
+  , (Provider::Cerebras, "llama3.1-8b".to_string())
+  ]).await).await;
+
+  assert_eq!(backend.ask("hi", "mistral-small-latest").await, Ok("from cerebras".to_string()));
+  // Mistral is cooling off, so the rejected key has nowhere to go
+  let reply = backend.ask_by_model("hi", "llama3.1-8b").await;
+  assert!(matches!
+  ( reply
+  , Err(allm::Error::ProviderApiError { provider: Provider::Cerebras, code: ProviderErrorCode::InvalidApiKey, .. })
+  ), "{:?}", reply);
+  assert_eq!(mistral.requests().len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+fn priced_entry(name: &str, cost: f32) -> allm::ModelInfo
+{ allm::ModelInfo
+  { provider: Provider::Cerebras
+  , cost_per_million_input_tokens: Some(cost)
+  , ..catalog_entry(name, 8_000)
+  }
+}
+
+#[test]
+fn test_cost_aware_strategy_ranks_by_input_price()
+{ use allm::failover::{CostAwareFailoverStrategy, ProviderEntry};
+
+  let catalog = [priced_entry("llama-a", 3.0), priced_entry("llama-b", 0.5), priced_entry("llama-c", 1.0)];
+  let entries: Vec<ProviderEntry> = ["llama-unpriced", "llama-a", "llama-c", "llama-b"].iter()
+    .map(|name| ProviderEntry::new(Provider::Cerebras, name.to_string()))
+    .collect();
+  let prices = allm::utils::cost::PriceTable::new();
+  let costs = CostAwareFailoverStrategy { catalog: &catalog, prices: &prices, budget_usd: None };
+  assert_eq!(costs.cheapest(&entries).map(|e| e.model.as_str()), Some("llama-b"));
+  assert_eq!(costs.cheapest(&entries[..2]).map(|e| e.model.as_str()), Some("llama-a"));
+
+  // A million tokens costs each model its price
+  let costs = CostAwareFailoverStrategy { catalog: &catalog, prices: &prices, budget_usd: Some(1.0) };
+  let within: Vec<&str> = entries.iter()
+    .filter(|e| costs.within_budget(e, 1_000_000))
+    .map(|e| e.model.as_str())
+    .collect();
+  assert_eq!(within, ["llama-unpriced", "llama-c", "llama-b"]);
+
+  // The price table overrides the catalog, and prices uncataloged models
+  let prices = allm::utils::cost::PriceTable::from(
+  [ ((Provider::Cerebras, "llama-a".to_string()), (0.2, 0.6))
+  , ((Provider::Cerebras, "llama-unpriced".to_string()), (0.1, 0.3))
+  ]);
+  let costs = CostAwareFailoverStrategy { catalog: &catalog, prices: &prices, budget_usd: None };
+  assert_eq!(costs.cheapest(&entries).map(|e| e.model.as_str()), Some("llama-unpriced"));
+  assert_eq!(costs.cheapest(&entries[1..]).map(|e| e.model.as_str()), Some("llama-a"));
+}
+
+#[tokio::test]
+async fn test_cost_minimizing_failover_picks_the_cheapest_fallback()
+{ use allm::failover::FailoverStrategy;
+
+  let prices = std::env::temp_dir()
+    .join(format!("allm-failover-prices-{}.json", std::process::id()));
+  std::fs::write
+  ( &prices
+  , r#"{ "cerebras": { "llama-a": { "input": 0.2, "output": 0.6 } } }"#
+  ).unwrap();
+  for (strategy, budget, price_table_path, expected) in
+  [ (FailoverStrategy::CostMinimizing, None, None, "llama-b")
+  , (FailoverStrategy::Ordered, None, None, "llama-a")
+  , (FailoverStrategy::Ordered, Some(0.000_002), None, "llama-c")
+  , // The price table makes llama-a the cheapest
+    (FailoverStrategy::CostMinimizing, None, Some(prices.display().to_string()), "llama-a")
+  ]
+  { let mistral = MockServer::start().await;
+    mistral.respond
+    ( "/v1/chat/completions"
+    , MockResponse::json(429, serde_json::json!({ "message": "Too many requests" }))
+    );
+    let cerebras = MockServer::start().await;
+    cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+    let config = AllmConfig
+    { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+    , failover: allm::config::FailoverConfig { max_retries: 0, strategy, ..Default::default() }
+    , failover_budget_constraint_usd: budget
+    , price_table_path
+    , ..Default::default()
+    };
+    let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+    ack(backend.register_models(vec!
+    [ priced_entry("llama-a", 3.0), priced_entry("llama-b", 0.5), priced_entry("llama-c", 1.0)
+    ]).await).await;
+    ack(backend.set_model_fallback_preference(vec!
+    [ (Provider::MistralAi, "mistral-small-latest".to_string())
+    , (Provider::Cerebras, "llama-a".to_string())
+    , (Provider::Cerebras, "llama-c".to_string())
+    , (Provider::Cerebras, "llama-b".to_string())
+    ]).await).await;
+
+    assert_eq!(backend.ask("hi", "mistral-small-latest").await, Ok("from cerebras".to_string()));
+    let sent = cerebras.requests_to("/v1/chat/completions");
+    assert_eq!(sent.len(), 1, "{:?}", strategy);
+    assert_eq!(sent[0].json()["model"], expected, "{:?} {:?}", strategy, budget);
+    backend.shutdown().await.expect("shutdown");
+  }
+  std::fs::remove_file(&prices).unwrap();
+}
+
+fn limited_provider(server: &MockServer, max_tokens: usize) -> ProviderConfig
+{ ProviderConfig { max_single_request_tokens: Some(max_tokens), ..mock_provider("mistral", server) }
+}
+
+async fn ask_with_strategy
+( server: &MockServer
+, strategy: allm::utils::prompt_splitter::LargePromptStrategy
+, prompt: &str
+) -> Result<String, allm::Error>
+{ let config = AllmConfig
+  { providers: vec![limited_provider(server, 6)]
+  , large_prompt_strategy: strategy
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let reply = timeout(Duration::from_secs(5), backend.ask(prompt, "mistral-small-latest"))
+    .await
+    .expect("answered");
+  backend.shutdown().await.expect("shutdown");
+  reply
+}
+
+const LONG_PROMPT: &str = "First sentence here. Second one! Fourth sentence ends it.";
+
+fn sent_prompts(server: &MockServer) -> Vec<String>
+{ let mut prompts: Vec<String> = server.requests().iter()
+    .map(|request| request.json()["messages"][0]["content"].as_str().unwrap_or_default().to_string())
+    .collect();
+  prompts.sort();
+  prompts
+}
+
+#[tokio::test]
+async fn test_large_prompts_fail_or_are_truncated()
+{ use allm::utils::prompt_splitter::LargePromptStrategy;
+
+  let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("ok")));
+
+  let reply = ask_with_strategy(&mistral, LargePromptStrategy::Fail, LONG_PROMPT).await;
+  assert!(matches!(reply, Err(allm::Error::RequestTooLarge(_))), "{:?}", reply);
+  assert!(mistral.requests().is_empty());
+  // Prompts within the limits are sent whatever the strategy
+  let reply = ask_with_strategy(&mistral, LargePromptStrategy::Fail, "Short.").await;
+  assert_eq!(reply.expect("reply"), "ok");
+
+  let reply = ask_with_strategy(&mistral, LargePromptStrategy::Truncate, LONG_PROMPT).await;
+  assert_eq!(reply.expect("reply"), "ok");
+  assert_eq!(mistral.requests()[1].json()["messages"][0]["content"], "First sentence here. Sec");
+}
+
+#[tokio::test]
+async fn test_split_prompts_are_sent_in_chunks_and_merged()
+{ use allm::utils::prompt_splitter::{ChunkMergeStrategy, LargePromptStrategy};
+
+  let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("part")));
+  let concatenate = LargePromptStrategy::Split { merge_strategy: ChunkMergeStrategy::Concatenate };
+  let reply = ask_with_strategy(&mistral, concatenate, LONG_PROMPT).await;
+  assert_eq!(reply.expect("reply"), "part\n\npart\n\npart");
+  assert_eq!
+  ( sent_prompts(&mistral)
+  , vec!["First sentence here.", "Fourth sentence ends it.", "Second one!"]
+  );
+
+  // Room for the merge prompt, sent as one more request
+  let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("part")));
+  let config = AllmConfig
+  { providers: vec![limited_provider(&mistral, 60)]
+  , large_prompt_strategy: LargePromptStrategy::Split { merge_strategy: ChunkMergeStrategy::LlmMerge }
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let reply = backend.ask(&LONG_PROMPT.repeat(5), "mistral-small-latest").await;
+  assert_eq!(reply.expect("reply"), "part");
+  let prompts = sent_prompts(&mistral);
+  assert_eq!(prompts.len(), 3);
+  assert!(prompts.iter().any(|prompt| prompt.contains("Part 2:\npart")), "{:?}", prompts);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_split_prompts_keep_multibyte_chunks_within_the_byte_limit()
+{ use allm::utils::prompt_splitter::LargePromptStrategy;
+
+  let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("part")));
+  let ask = |max_bytes: usize, prompt: &'static str| {
+    let config = AllmConfig
+    { providers: vec![ProviderConfig { max_request_size_bytes: Some(max_bytes), ..mock_provider("mistral", &mistral) }]
+    , large_prompt_strategy: LargePromptStrategy::Split { merge_strategy: Default::default() }
+    , ..Default::default()
+    };
+    async move {
+      let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+      let reply = timeout(Duration::from_secs(5), backend.ask(prompt, "mistral-small-latest"))
+        .await
+        .expect("answered");
+      backend.shutdown().await.expect("shutdown");
+      reply
+    }
+  };
+
+  // The first sentence is 20 characters but 25 bytes
+  let reply = ask(24, "Ça été très agréable. Où ça?").await;
+  assert_eq!(reply.expect("reply"), "part\n\npart");
+  let prompts = sent_prompts(&mistral);
+  assert_eq!(prompts, vec!["agréable. Où ça?", "Ça été très"]);
+  assert!(prompts.iter().all(|prompt| prompt.len() <= 24), "{:?}", prompts);
+
+  // A character wider than the limit cannot be split further
+  let reply = ask(1, "é").await;
+  assert!(matches!(reply, Err(allm::Error::RequestTooLarge(_))), "{:?}", reply);
+  assert_eq!(mistral.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn test_ask_chunked_replies_once_per_chunk()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("part")));
+  // The default Fail strategy does not apply to ask_chunked
+  let config = AllmConfig { providers: vec![limited_provider(&mistral, 6)], ..Default::default() };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let replies = backend.ask_chunked(LONG_PROMPT, "mistral-small-latest").await.expect("replies");
+  assert_eq!(replies, vec!["part", "part", "part"]);
+  assert_eq!(mistral.requests().len(), 3);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_thinking_is_split_from_the_answer()
+{ let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("<think>Capital of France.</think>\n\nParis"))
+  );
+  let cerebras = MockServer::start().await;
+  let mut deepseek = chat_completion("Paris");
+  deepseek["choices"][0]["message"]["reasoning_content"] = "Capital of France.".into();
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, deepseek));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let thinking = Some("Capital of France.".to_string());
+
+  // Thinking in the text, through `ask_with_reasoning` or `ask_full`
+  let reply = backend.ask_with_reasoning("capital?", "mistral-small-latest").await.expect("reply");
+  assert_eq!(reply, allm::request::DeepSeekResponse { thinking: thinking.clone(), answer: "Paris".to_string() });
+  let response = backend.ask_full(allm::request::PromptRequest::new
+  ( Provider::MistralAi, "mistral-small-latest".to_string(), "capital?".to_string()
+  )).await.expect("reply");
+  assert_eq!((response.thinking, response.text), (thinking.clone(), "Paris".to_string()));
+  assert_eq!(response.choices, vec!["Paris"]);
+
+  // Thinking in its own field
+  let response = backend.ask_full(allm::request::PromptRequest::new
+  ( Provider::Cerebras, "llama3.1-8b".to_string(), "capital?".to_string()
+  )).await.expect("reply");
+  assert_eq!(allm::request::DeepSeekResponse::from(response), allm::request::DeepSeekResponse
+  { thinking, answer: "Paris".to_string() });
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_few_shot_examples_precede_the_prompt()
+{ use allm::request::{FewShot, PromptRequest};
+
+  let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("negative")));
+  let config = AllmConfig { providers: vec![mock_provider("mistral", &mistral)], ..Default::default() };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let request = |examples: Vec<(&str, &str)>| PromptRequest
+  { system_message: Some("Classify the sentiment.".to_string())
+  , few_shot: Some(FewShot::new(examples.into_iter()
+      .map(|(user, assistant)| (user.to_string(), assistant.to_string()))
+      .collect()))
+  , ..PromptRequest::new(Provider::MistralAi, "mistral-small-latest".to_string(), "Awful service.".to_string())
+  };
+
+  let reply = backend.ask_request(request(vec![("Loved it!", "positive"), ("It was fine.", "neutral")])).await;
+  assert_eq!(reply, Ok("negative".to_string()));
+  assert_eq!
+  ( mistral.requests()[0].json()["messages"]
+  , serde_json::json!(
+    [ { "role": "system", "content": "Classify the sentiment." }
+    , { "role": "user", "content": "Loved it!" }
+    , { "role": "assistant", "content": "positive" }
+    , { "role": "user", "content": "It was fine." }
+    , { "role": "assistant", "content": "neutral" }
+    , { "role": "user", "content": "Awful service." }
+    ])
+  );
+
+  let empty = allm::Error::InvalidConfiguration("few_shot needs at least one example".to_string());
+  assert_eq!(backend.ask_request(request(vec![])).await, Err(empty.clone()));
+  assert_eq!(backend.dry_run(request(vec![])).await, Err(empty));
+  assert_eq!(mistral.requests().len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_blocked_provider_routes_to_the_fallback()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from mistral")));
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  let policy = |strict_provider_policy| AllmConfig
+  { providers: vec![mock_provider("cerebras", &cerebras)]
+  , blocked_providers: vec![Provider::MistralAi]
+  , strict_provider_policy
+  , ..Default::default()
+  };
+
+  // Configuring a forbidden provider is an error
+  let forbidden = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , allowed_providers: Some(vec![Provider::Cerebras])
+  , ..Default::default()
+  };
+  let not_allowed = allm::Error::InvalidConfiguration("MistralAi is not allowed by the provider policy".to_string());
+  assert_eq!(AllmBackend::new_with_config(forbidden).await.err(), Some(not_allowed.clone()));
+
+  let backend = AllmBackend::new_with_config(policy(false)).await.expect("valid config");
+  assert!(!backend.allowed_providers().contains(&Provider::MistralAi));
+  assert!(backend.allowed_providers().contains(&Provider::Cerebras));
+  let blocked_fallback = backend
+    .set_model_fallback_preference(vec![(Provider::MistralAi, "mistral-small-latest".to_string())])
+    .await.expect("queue command").recv().await;
+  assert_eq!(blocked_fallback, Some(Err(not_allowed.clone())));
+  // Without an allowed fallback the prompt fails
+  assert_eq!(backend.ask("hi", "mistral-small-latest").await, Err(not_allowed.clone()));
+  ack(backend
+    .set_model_fallback_preference(vec![(Provider::Cerebras, "llama3.1-8b".to_string())])
+    .await
+  ).await;
+  assert_eq!(backend.ask("hi", "mistral-small-latest").await, Ok("from cerebras".to_string()));
+  assert_eq!(cerebras.requests()[0].json()["model"], "llama3.1-8b");
+  backend.shutdown().await.expect("shutdown");
+
+  let backend = AllmBackend::new_with_config(policy(true)).await.expect("valid config");
+  ack(backend
+    .set_model_fallback_preference(vec![(Provider::Cerebras, "llama3.1-8b".to_string())])
+    .await
+  ).await;
+  assert_eq!(backend.ask("hi", "mistral-small-latest").await, Err(not_allowed));
+  assert!(mistral.requests().is_empty());
+  assert_eq!(cerebras.requests().len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_first_available_provider_skips_a_cooling_primary()
+{ use allm::capabilities::ModelRequirements;
+
+  let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(429, serde_json::json!({ "message": "Too many requests" }))
+  );
+  let cerebras = MockServer::start().await;
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  let config = AllmConfig
+  { providers: vec![mock_provider("mistral", &mistral), mock_provider("cerebras", &cerebras)]
+  , failover: allm::config::FailoverConfig { max_retries: 0, ..Default::default() }
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  ack(backend.set_model_fallback_preference(vec!
+  [ (Provider::MistralAi, "mistral-small-latest".to_string())
+  , (Provider::Cerebras, "llama3.1-8b".to_string())
+  ]).await).await;
+  let mistral_small = (Provider::MistralAi, "mistral-small-latest".to_string());
+  let llama = (Provider::Cerebras, "llama3.1-8b".to_string());
+  assert_eq!(backend.get_first_available_provider(None).await, Ok(mistral_small));
+
+  // The rate limit opens Mistral's breaker until it cools off
+  assert_eq!(backend.ask("hi", "mistral-small-latest").await, Ok("from cerebras".to_string()));
+  assert_eq!(backend.get_first_available_provider(None).await, Ok(llama.clone()));
+  assert_eq!
+  ( backend.get_first_available_provider(Some(ModelRequirements::default())).await
+  , Ok(llama)
+  );
+  let huge = ModelRequirements { min_context_tokens: 10_000_000, ..Default::default() };
+  assert!(matches!
+  ( backend.get_first_available_provider(Some(huge)).await
+  , Err(allm::Error::Other(_))
+  ));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_cancellation_token_drops_a_slow_prompt()
+{ use tokio_util::sync::CancellationToken;
+
+  let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("too late")).with_delay(Duration::from_secs(10))
+  );
+  let config = AllmConfig { providers: vec![mock_provider("mistral", &mistral)], ..Default::default() };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let cancelled = Some(Err(allm::Error::Other("cancelled".to_string())));
+
+  let token = CancellationToken::new();
+  let mut rx = backend
+    .send_prompt_cancellable("hi".to_string(), "mistral-small-latest".to_string(), token.child_token())
+    .await
+    .expect("queue prompt");
+  for _ in 0..100
+  { if !mistral.requests().is_empty() { break; }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+  }
+  assert_eq!(mistral.requests().len(), 1);
+  // Cancelling the parent cancels the prompt's child token
+  token.cancel();
+  let reply = timeout(Duration::from_secs(2), rx.recv()).await.expect("answered promptly");
+  assert_eq!(reply, cancelled);
+
+  // A token cancelled up front keeps the prompt from being sent
+  let mut rx = backend
+    .send_prompt_cancellable("again".to_string(), "mistral-small-latest".to_string(), token)
+    .await
+    .expect("queue prompt");
+  let reply = timeout(Duration::from_secs(2), rx.recv()).await.expect("answered promptly");
+  assert_eq!(reply, cancelled);
+  assert_eq!(mistral.requests().len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_cancellation_ends_backoff_and_rate_limit_waits()
+{ use tokio_util::sync::CancellationToken;
+
+  let mistral = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(429, serde_json::json!({ "message": "Too many requests" }))
+      .with_header("retry-after", "30")
+  );
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { rate_limit_rpm: Some(1)
+    , ..mock_provider("mistral", &mistral)
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let cancelled = Some(Err(allm::Error::Other("cancelled".to_string())));
+
+  // Waiting 30s on the 429's retry-after
+  let backoff = CancellationToken::new();
+  let mut backoff_rx = backend
+    .send_prompt_cancellable("hi".to_string(), "mistral-small-latest".to_string(), backoff.clone())
+    .await
+    .expect("queue prompt");
+  // Waiting a minute for the rate limiter
+  let queued = CancellationToken::new();
+  let mut queued_rx = backend
+    .send_prompt_cancellable("next".to_string(), "mistral-small-latest".to_string(), queued.clone())
+    .await
+    .expect("queue prompt");
+  for _ in 0..100
+  { if !mistral.requests().is_empty() { break; }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+  }
+  tokio::time::sleep(Duration::from_millis(100)).await;
+
+  backoff.cancel();
+  let reply = timeout(Duration::from_secs(2), backoff_rx.recv()).await.expect("answered promptly");
+  assert_eq!(reply, cancelled);
+  queued.cancel();
+  let reply = timeout(Duration::from_secs(2), queued_rx.recv()).await.expect("answered promptly");
+  assert_eq!(reply, cancelled);
+  assert_eq!(mistral.requests().len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
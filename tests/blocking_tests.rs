@@ -0,0 +1,56 @@
+// allm/tests/blocking_tests.rs
+//
+// BlockingAllm from plain threads (`cargo test --features blocking`).
+
+#![cfg(feature = "blocking")]
+
+mod common;
+
+use allm::blocking::BlockingAllm;
+use allm::config::{AllmConfig, ProviderConfig};
+use common::{chat_completion, MockResponse, MockServer};
+
+fn mock_config(server: &MockServer) -> AllmConfig
+{ AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "mistral".to_string()
+    , api_base: Some(format!("{}/v1", server.url()))
+    , api_key: Some("mistral-key".to_string())
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  }
+}
+
+#[test]
+fn test_blocking_send_prompt_waits_for_the_reply()
+{ // The mock server runs on a runtime of its own
+  let server_runtime = tokio::runtime::Runtime::new().expect("runtime");
+  let server = server_runtime.block_on(MockServer::start());
+  server.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("Paris")));
+
+  let allm = BlockingAllm::new(mock_config(&server)).expect("backend");
+  assert_eq!(allm.send_prompt("Capital of France?", "mistral-small-latest"), Ok("Paris".to_string()));
+  assert_eq!(server.requests().len(), 1);
+  allm.shutdown().expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_blocking_calls_fail_inside_a_runtime()
+{ let server = MockServer::start().await;
+  match BlockingAllm::new(mock_config(&server))
+  { Err(allm::Error::Other(message)) => assert!(message.contains("async context"), "{}", message)
+  , Err(e) => panic!("unexpected error: {}", e)
+  , Ok(_) => panic!("BlockingAllm started inside a runtime")
+  }
+
+  // One made outside a runtime refuses to block inside it, and
+  // dropping it there does not panic
+  let config = mock_config(&server);
+  let allm = std::thread::spawn(move || BlockingAllm::new(config))
+    .join()
+    .expect("thread")
+    .expect("backend");
+  assert!(matches!(allm.send_prompt("hi", "mistral-small-latest"), Err(allm::Error::Other(_))));
+  drop(allm);
+}
@@ -0,0 +1,275 @@
+// allm/tests/common/mod.rs
+//
+// Minimal HTTP/1.1 mock server for provider tests. Responses are
+// queued per path prefix; the last response queued for a path is
+// reused once the queue drains.
+
+#![allow(dead_code, clippy::suspicious_else_formatting)]
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest
+{ pub method: String
+, pub path: String
+, pub headers: Vec<(String, String)>
+, pub body: String
+}
+
+impl RecordedRequest
+{ /// Header value by case-insensitive name
+  pub fn header(&self, name: &str) -> Option<&str>
+  { let name = name.to_ascii_lowercase();
+    self.headers.iter()
+      .find(|(k, _)| *k == name)
+      .map(|(_, v)| v.as_str())
+  }
+
+  pub fn json(&self) -> serde_json::Value
+  { serde_json::from_str(&self.body)
+      .expect("request body is not JSON")
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockResponse
+{ pub status: u16
+, pub headers: Vec<(String, String)>
+, /// Delay before the headers are written
+  pub delay: Duration
+, /// Body pieces, each written after its delay. More than one
+  /// piece switches to chunked transfer encoding.
+  pub chunks: Vec<(Duration, Vec<u8>)>
+}
+
+impl MockResponse
+{ pub fn json(status: u16, body: serde_json::Value) -> Self
+  { MockResponse
+    { status
+    , headers: vec![("content-type".into(), "application/json".into())]
+    , delay: Duration::ZERO
+    , chunks: vec![(Duration::ZERO, body.to_string().into_bytes())]
+    }
+  }
+
+  pub fn text(status: u16, body: &str) -> Self
+  { MockResponse
+    { status
+    , headers: vec![("content-type".into(), "text/plain".into())]
+    , delay: Duration::ZERO
+    , chunks: vec![(Duration::ZERO, body.as_bytes().to_vec())]
+    }
+  }
+
+  /// Server-sent events, one chunk per line group
+  pub fn sse(events: &[&str], gap: Duration) -> Self
+  { MockResponse
+    { status: 200
+    , headers: vec![("content-type".into(), "text/event-stream".into())]
+    , delay: Duration::ZERO
+    , chunks: events.iter()
+        .map(|e| (gap, format!("{}\n\n", e).into_bytes()))
+        .collect()
+    }
+  }
+
+  pub fn with_header(mut self, name: &str, value: &str) -> Self
+  { self.headers.push((name.to_ascii_lowercase(), value.to_string()));
+    self
+  }
+
+  pub fn with_delay(mut self, delay: Duration) -> Self
+  { self.delay = delay;
+    self
+  }
+}
+
+/// An OpenAI-style chat completion body
+pub fn chat_completion(content: &str) -> serde_json::Value
+{ serde_json::json!({
+    "id": "cmpl-1",
+    "object": "chat.completion",
+    "choices": [{
+      "index": 0,
+      "message": { "role": "assistant", "content": content },
+      "finish_reason": "stop"
+    }],
+    "usage": { "prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8 }
+  })
+}
+
+#[derive(Default)]
+struct MockState
+{ routes: HashMap<String, VecDeque<MockResponse>>
+, requests: Vec<RecordedRequest>
+}
+
+pub struct MockServer
+{ addr: std::net::SocketAddr
+, state: Arc<Mutex<MockState>>
+, connections: Arc<AtomicUsize>
+, _task: tokio::task::JoinHandle<()>
+}
+
+impl MockServer
+{ pub async fn start() -> Self
+  { let listener = TcpListener::bind("127.0.0.1:0").await
+      .expect("bind mock server");
+    let addr = listener.local_addr().unwrap();
+    let state = Arc::new(Mutex::new(MockState::default()));
+    let connections = Arc::new(AtomicUsize::new(0));
+    let task_state = state.clone();
+    let task_connections = connections.clone();
+    let _task = tokio::spawn(async move
+    { loop
+      { let Ok((stream, _)) = listener.accept().await else { break };
+        task_connections.fetch_add(1, Ordering::SeqCst);
+        let state = task_state.clone();
+        tokio::spawn(async move
+        { let _ = serve_connection(stream, state).await;
+        });
+      }
+    });
+    MockServer { addr, state, connections, _task }
+  }
+
+  /// Base URL, e.g. `http://127.0.0.1:1234`
+  pub fn url(&self) -> String
+  { format!("http://{}", self.addr)
+  }
+
+  /// Queue a response for requests whose path starts with `path`
+  pub fn respond(&self, path: &str, response: MockResponse)
+  { self.state.lock().unwrap()
+      .routes.entry(path.to_string())
+      .or_default()
+      .push_back(response);
+  }
+
+  pub fn requests(&self) -> Vec<RecordedRequest>
+  { self.state.lock().unwrap().requests.clone()
+  }
+
+  pub fn requests_to(&self, path: &str) -> Vec<RecordedRequest>
+  { self.requests().into_iter()
+      .filter(|r| r.path.starts_with(path))
+      .collect()
+  }
+
+  /// Number of TCP connections accepted so far
+  pub fn connection_count(&self) -> usize
+  { self.connections.load(Ordering::SeqCst)
+  }
+}
+
+async fn serve_connection(
+  mut stream: TcpStream
+, state: Arc<Mutex<MockState>>
+) -> std::io::Result<()>
+{ let mut buf: Vec<u8> = Vec::new();
+  loop
+  { // Read until the end of the headers
+    let header_end = loop
+    { if let Some(pos) = find(&buf, b"\r\n\r\n")
+      { break pos;
+      }
+      let mut chunk = [0u8; 4096];
+      let n = stream.read(&mut chunk).await?;
+      if n == 0
+      { return Ok(());
+      }
+      buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+      .filter_map(|l| l.split_once(':'))
+      .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+      .collect();
+    let content_length = headers.iter()
+      .find(|(k, _)| k == "content-length")
+      .and_then(|(_, v)| v.parse::<usize>().ok())
+      .unwrap_or(0);
+
+    let body_start = header_end + 4;
+    while buf.len() < body_start + content_length
+    { let mut chunk = [0u8; 4096];
+      let n = stream.read(&mut chunk).await?;
+      if n == 0
+      { return Ok(());
+      }
+      buf.extend_from_slice(&chunk[..n]);
+    }
+    let body = String::from_utf8_lossy(
+      &buf[body_start..body_start + content_length]
+    ).to_string();
+    buf.drain(..body_start + content_length);
+
+    let response =
+    { let mut state = state.lock().unwrap();
+      state.requests.push(RecordedRequest
+      { method: method.clone()
+      , path: path.clone()
+      , headers
+      , body
+      });
+      let key = state.routes.keys()
+        .filter(|k| path.starts_with(k.as_str()))
+        .max_by_key(|k| k.len())
+        .cloned();
+      match key.and_then(|k| state.routes.get_mut(&k))
+      { Some(queue) if queue.len() > 1 => queue.pop_front().unwrap()
+      , Some(queue) => queue.front().cloned().unwrap()
+      , None => MockResponse::text(404, "no mock route")
+      }
+    };
+
+    write_response(&mut stream, response).await?;
+  }
+}
+
+async fn write_response(
+  stream: &mut TcpStream
+, response: MockResponse
+) -> std::io::Result<()>
+{ tokio::time::sleep(response.delay).await;
+  let chunked = response.chunks.len() > 1;
+  let mut head = format!("HTTP/1.1 {} MOCK\r\n", response.status);
+  for (k, v) in &response.headers
+  { head.push_str(&format!("{}: {}\r\n", k, v));
+  }
+  if chunked
+  { head.push_str("transfer-encoding: chunked\r\n\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    for (delay, chunk) in response.chunks
+    { tokio::time::sleep(delay).await;
+      stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+      stream.write_all(&chunk).await?;
+      stream.write_all(b"\r\n").await?;
+      stream.flush().await?;
+    }
+    stream.write_all(b"0\r\n\r\n").await?;
+  } else
+  { let body: Vec<u8> = response.chunks.into_iter()
+      .flat_map(|(_, c)| c)
+      .collect();
+    head.push_str(&format!("content-length: {}\r\n\r\n", body.len()));
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+  }
+  stream.flush().await
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize>
+{ haystack.windows(needle.len()).position(|w| w == needle)
+}
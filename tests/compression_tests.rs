@@ -0,0 +1,59 @@
+// allm/tests/compression_tests.rs
+//
+// Compressed response bodies (the default `compression` feature):
+// negotiated with `Accept-Encoding` and parsed once decompressed.
+
+#![cfg(feature = "compression")]
+
+mod common;
+
+use std::io::Write;
+use std::time::Duration;
+
+use allm::config::{AllmConfig, ProviderConfig};
+use allm::AllmBackend;
+use common::{chat_completion, MockResponse, MockServer};
+use tokio::time::timeout;
+
+fn gzip_json(body: serde_json::Value) -> MockResponse
+{ let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+  encoder.write_all(body.to_string().as_bytes()).unwrap();
+  MockResponse
+  { chunks: vec![(Duration::ZERO, encoder.finish().unwrap())]
+  , ..MockResponse::json(200, serde_json::json!({}))
+  }.with_header("content-encoding", "gzip")
+}
+
+#[tokio::test]
+async fn test_gzip_bodies_are_decompressed_before_parsing()
+{ let server = MockServer::start().await;
+  let models: Vec<serde_json::Value> = (0..500)
+    .map(|i| serde_json::json!({ "id": format!("model-{}", i) }))
+    .collect();
+  server.respond("/v1/models", gzip_json(serde_json::json!({ "data": models })));
+  server.respond("/v1/chat/completions", gzip_json(chat_completion("unzipped")));
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "mistral".to_string()
+    , api_base: Some(format!("{}/v1", server.url()))
+    , api_key: Some("mistral-key".to_string())
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let mut rx = backend.get_model_lists().await.expect("queue");
+  let listed = timeout(Duration::from_secs(5), rx.recv())
+    .await
+    .expect("Timeout waiting for model lists")
+    .expect("Reply channel closed")
+    .expect("model lists");
+  assert_eq!(listed.len(), 500);
+  assert_eq!(listed[499].1, "model-499");
+
+  assert_eq!(backend.ask("hi", "mistral-small-latest").await, Ok("unzipped".to_string()));
+  let accepted = server.requests()[0].header("accept-encoding").unwrap_or_default().to_string();
+  assert!(accepted.contains("gzip") && accepted.contains("br"), "{}", accepted);
+  backend.shutdown().await.expect("shutdown");
+}
@@ -0,0 +1,91 @@
+// allm/tests/config_schema_tests.rs
+//
+// Configs checked against AllmConfig::json_schema
+// (`cargo test --features schema-validation`).
+
+#![cfg(feature = "schema-validation")]
+
+use allm::config::{AllmConfig, FailoverConfig, ProviderConfig};
+use allm::failover::{FailoverStrategy, FailoverTrigger};
+use allm::utils::prompt_splitter::{ChunkMergeStrategy, LargePromptStrategy};
+
+#[test]
+fn test_default_config_passes_schema_validation()
+{ let schema = AllmConfig::json_schema();
+  assert_eq!(schema["type"], "object");
+  assert_eq!(schema["required"], serde_json::json!(["failover", "providers"]));
+  assert_eq!(schema["properties"]["providers"]["items"]["properties"]["name"]["type"], "string");
+
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "mistral".to_string()
+    , api_key: Some("key".to_string())
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  for config in [AllmConfig::default(), config]
+  { let json = serde_json::to_string(&config).expect("serialize");
+    assert_eq!(AllmConfig::validate_with_schema(&json), Ok(()));
+  }
+}
+
+#[test]
+fn test_non_default_config_round_trips_through_schema_validation()
+{ let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "openai".to_string()
+    , api_base: Some("https://gateway.example/v1".to_string())
+    , timeout_secs: Some(30)
+    , rate_limit_rpm: Some(60)
+    , log_level: serde_json::from_value(serde_json::json!("trace")).expect("log level")
+    , extra_headers: Some([("X-Tenant-Id".to_string(), "acme".to_string())].into())
+    , max_request_size_bytes: Some(4096)
+    , lazy_init: false
+    , ..Default::default()
+    }]
+  , failover: FailoverConfig
+    { failover_on: vec![FailoverTrigger::Timeout, FailoverTrigger::ContextWindow]
+    , strategy: FailoverStrategy::CostMinimizing
+    , ..Default::default()
+    }
+  , large_prompt_strategy: LargePromptStrategy::Split
+    { merge_strategy: ChunkMergeStrategy::LlmMerge
+    }
+  , model_overrides: vec![allm::providers::mistral::default_model_info()]
+  , allowed_providers: Some(vec![allm::Provider::OpenAI])
+  , failover_budget_constraint_usd: Some(0.01)
+  , ..Default::default()
+  };
+  let json = serde_json::to_string(&config).expect("serialize");
+  assert_eq!(AllmConfig::validate_with_schema(&json), Ok(()));
+  // Enums are left to deserialization
+  assert_eq!(AllmConfig::json_schema()["properties"]["large_prompt_strategy"], serde_json::json!({}));
+}
+
+#[test]
+fn test_malformed_config_reports_each_bad_field()
+{ let errors = AllmConfig::validate_with_schema(r#"{
+    "failover": { "enabled": "yes", "max_retries": 3, "backoff_multiplier": 2.0, "initial_backoff_ms": 100 },
+    "stream_buffer_size": -1
+  }"#).expect_err("malformed");
+  assert_eq!
+  ( errors
+  , [ "$: missing field providers"
+    , "$.failover.enabled: expected boolean"
+    , "$.stream_buffer_size: below minimum 0"
+    ]
+  );
+
+  assert_eq!
+  ( AllmConfig::validate_with_schema("[]")
+  , Err(vec!["$: expected object".to_string()])
+  );
+  assert!(AllmConfig::validate_with_schema("{").unwrap_err()[0].starts_with("not JSON"));
+  // Schema-valid, but not a log level
+  let errors = AllmConfig::validate_with_schema(r#"{
+    "providers": [{ "name": "mistral", "log_level": "loud" }],
+    "failover": { "enabled": true, "max_retries": 3, "backoff_multiplier": 2.0, "initial_backoff_ms": 100 }
+  }"#).expect_err("bad log level");
+  assert!(errors[0].contains("loud"), "{:?}", errors);
+}
@@ -0,0 +1,69 @@
+// allm/tests/health_server_tests.rs
+//
+// `/health`, `/metrics` and `/providers` of the health server
+// (`cargo test --features health-server`).
+
+#![cfg(feature = "health-server")]
+
+mod common;
+
+use allm::config::{AllmConfig, ProviderConfig};
+use allm::health_server::AllmHealthServer;
+use allm::AllmBackend;
+use common::{chat_completion, MockResponse, MockServer};
+
+async fn get(addr: std::net::SocketAddr, path: &str) -> (u16, String)
+{ let response = reqwest::get(format!("http://{}{}", addr, path))
+    .await
+    .expect("health server reachable");
+  (response.status().as_u16(), response.text().await.expect("body"))
+}
+
+#[tokio::test]
+async fn test_health_metrics_and_providers_endpoints()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("ok")));
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "mistral".to_string()
+    , api_base: Some(format!("{}/v1", mistral.url()))
+    , api_key: Some("mistral-key".to_string())
+    , ..Default::default()
+    }]
+  , health_server_port: Some(0)
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let addr = backend.health_server_addr().expect("health server running");
+  backend.ask("hi", "mistral-small-latest").await.expect("prompt");
+
+  assert_eq!(get(addr, "/health").await, (200, r#"{"status":"ok"}"#.to_string()));
+
+  let (status, metrics) = get(addr, "/metrics").await;
+  assert_eq!(status, 200);
+  assert!(metrics.contains("# TYPE allm_provider_requests_total counter"));
+  assert!(metrics.contains("allm_provider_requests_total{provider=\"MistralAi\"} 1"));
+  assert!(metrics.contains("allm_pending_prompts 0"));
+
+  let (status, providers) = get(addr, "/providers").await;
+  assert_eq!(status, 200);
+  let providers: serde_json::Value = serde_json::from_str(&providers).unwrap();
+  assert_eq!(providers[0]["provider"], "MistralAi");
+  assert_eq!(providers[0]["succeeded"], 1);
+
+  assert_eq!(get(addr, "/nope").await.0, 404);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_health_reports_unavailable_after_shutdown()
+{ let backend = AllmBackend::new(Some("key".to_string()));
+  let server = AllmHealthServer::spawn(backend.backend_ref(), 0).expect("bind");
+  assert_eq!(get(server.addr(), "/health").await.0, 200);
+
+  backend.shutdown().await.expect("shutdown");
+  assert_eq!
+  ( get(server.addr(), "/health").await
+  , (503, r#"{"status":"shutting_down"}"#.to_string())
+  );
+}
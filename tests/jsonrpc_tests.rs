@@ -0,0 +1,120 @@
+// allm/tests/jsonrpc_tests.rs
+//
+// JSON-RPC requests served over an in-memory pipe
+// (`cargo test --features jsonrpc_server`).
+
+#![cfg(feature = "jsonrpc_server")]
+
+mod common;
+
+use allm::config::{AllmConfig, ProviderConfig};
+use allm::utils::jsonrpc::serve;
+use allm::AllmBackend;
+use common::{chat_completion, MockResponse, MockServer};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+/// Responses to `requests`, one JSON value per output line
+async fn exchange(backend: &AllmBackend, requests: &[&str]) -> Vec<serde_json::Value>
+{ let (mut client, server) = tokio::io::duplex(64 * 1024);
+  let (server_in, server_out) = tokio::io::split(server);
+  let input = requests.join("\n") + "\n";
+  let write = async {
+    client.write_all(input.as_bytes()).await.expect("write requests");
+    let mut lines = BufReader::new(client).lines();
+    let mut responses = Vec::new();
+    while let Some(line) = lines.next_line().await.expect("read response")
+    { responses.push(serde_json::from_str(&line).expect("response is JSON"));
+      if responses.len() == requests.len()
+      { break;
+      }
+    }
+    responses
+  };
+  let (served, responses) = tokio::join!(serve(backend, BufReader::new(server_in), server_out), write);
+  served.expect("serve");
+  responses
+}
+
+fn by_id(responses: &[serde_json::Value], id: i64) -> &serde_json::Value
+{ responses.iter().find(|r| r["id"] == id).expect("response for id")
+}
+
+#[tokio::test]
+async fn test_jsonrpc_methods_errors_and_shutdown()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("Hello!")));
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "mistral".to_string()
+    , api_base: Some(format!("{}/v1", mistral.url()))
+    , api_key: Some("mistral-key".to_string())
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let responses = exchange(&backend,
+  &[ r#"{"jsonrpc":"2.0","method":"set_api_keys","params":{"keys":[{"provider":"mistral","model":"","key":"new-key"}]},"id":1}"#
+   , r#"{"jsonrpc":"2.0","method":"send_prompt","params":{"prompt":"Hi","model":"mistral-small-latest"},"id":2}"#
+   , r#"{"jsonrpc":"2.0","method":"set_fallback_preferences","params":{"preferences":[{"provider":"mistral","model":"mistral-small-latest"}]},"id":3}"#
+   , r#"{"jsonrpc":"2.0","method":"send_prompt","params":{"prompt":"Hi"},"id":4}"#
+   , r#"{"jsonrpc":"2.0","method":"set_api_keys","params":{"keys":[{"provider":"nowhere","key":"k"}]},"id":5}"#
+   , r#"{"jsonrpc":"2.0","method":"translate","id":6}"#
+   , r#"{"jsonrpc":"1.0","method":"shutdown","id":7}"#
+   , r#"not json"#
+   , r#"{"jsonrpc":"2.0","method":"shutdown","id":9}"#
+   , r#"{"jsonrpc":"2.0","method":"send_prompt","params":{"prompt":"after","model":"m"},"id":10}"#
+   ]
+  ).await;
+
+  assert_eq!(responses.len(), 9, "{:?}", responses);
+  for response in &responses
+  { assert_eq!(response["jsonrpc"], "2.0");
+  }
+  assert_eq!(by_id(&responses, 1)["result"], serde_json::Value::Null);
+  assert_eq!(by_id(&responses, 2)["result"], "Hello!");
+  assert_eq!(by_id(&responses, 3)["result"], serde_json::Value::Null);
+  assert_eq!(by_id(&responses, 4)["error"]["code"], -32602);
+  assert_eq!(by_id(&responses, 5)["error"]["code"], -32602);
+  assert_eq!(by_id(&responses, 6)["error"]["code"], -32601);
+  assert_eq!(by_id(&responses, 7)["error"]["code"], -32600);
+  let parse_error = responses.iter().find(|r| r["id"].is_null()).expect("parse error response");
+  assert_eq!(parse_error["error"]["code"], -32700);
+  assert_eq!(by_id(&responses, 9)["result"], serde_json::Value::Null);
+  // Nothing is read after `shutdown`
+  assert!(responses.iter().all(|r| r["id"] != 10));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_jsonrpc_reports_backend_errors_and_model_lists()
+{ let mistral = MockServer::start().await;
+  mistral.respond("/v1/models", MockResponse::json(200, serde_json::json!({ "data": [{ "id": "mistral-small-latest" }] })));
+  mistral.respond("/v1/chat/completions", MockResponse::json(401, serde_json::json!({ "message": "Unauthorized" })));
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "mistral".to_string()
+    , api_base: Some(format!("{}/v1", mistral.url()))
+    , api_key: Some("mistral-key".to_string())
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let responses = exchange(&backend,
+  &[ r#"{"jsonrpc":"2.0","method":"get_model_lists","id":"models"}"#
+   , r#"{"jsonrpc":"2.0","method":"send_prompt","params":{"prompt":"Hi","model":"mistral-small-latest"},"id":"denied"}"#
+   , r#"{"jsonrpc":"2.0","method":"shutdown","id":"bye"}"#
+   ]
+  ).await;
+
+  let models = responses.iter().find(|r| r["id"] == "models").expect("models");
+  assert_eq!(models["result"][0]["model"], "mistral-small-latest");
+  assert_eq!(models["result"][0]["provider"], "MistralAi");
+  let denied = &responses.iter().find(|r| r["id"] == "denied").expect("denied")["error"];
+  assert_eq!(denied["code"], -32000);
+  assert!(denied["data"]["type"].is_string(), "{}", denied);
+  backend.shutdown().await.expect("shutdown");
+}
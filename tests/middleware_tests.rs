@@ -0,0 +1,80 @@
+// allm/tests/middleware_tests.rs
+//
+// PII redaction: each kind of personal data, placeholder numbering,
+// and the way back with `unredact`.
+
+use allm::middleware::{PiiConfig, PiiRedactor};
+use std::collections::HashMap;
+
+fn redact(text: &str) -> (String, HashMap<String, String>)
+{ let redactor = PiiRedactor::new(PiiConfig::default()).expect("valid patterns");
+  let mut map = HashMap::new();
+  let redacted = redactor.redact(text, &mut map);
+  (redacted, map)
+}
+
+#[test]
+fn test_emails_are_numbered_and_reused()
+{ let (text, map) = redact("Mail ann.lee+work@example.co.uk or bob@mail.example.org, again ann.lee+work@example.co.uk");
+  assert_eq!(text, "Mail [EMAIL_1] or [EMAIL_2], again [EMAIL_1]");
+  assert_eq!(map["[EMAIL_1]"], "ann.lee+work@example.co.uk");
+  assert_eq!(map["[EMAIL_2]"], "bob@mail.example.org");
+}
+
+#[test]
+fn test_us_and_eu_phone_numbers()
+{ let (text, map) = redact
+  ( "Call +1 (555) 123-4567, 555.987.6543, +49 30 1234567 or +33 1 23 45 67 89."
+  );
+  assert_eq!(text, "Call [PHONE_1], [PHONE_2], [PHONE_3] or [PHONE_4].");
+  assert_eq!(map["[PHONE_1]"], "+1 (555) 123-4567");
+  assert_eq!(map["[PHONE_3]"], "+49 30 1234567");
+  assert_eq!(map["[PHONE_4]"], "+33 1 23 45 67 89");
+}
+
+#[test]
+fn test_ssns()
+{ let (text, map) = redact("SSN 123-45-6789; order 12-345-6789 stays");
+  assert_eq!(text, "SSN [SSN_1]; order 12-345-6789 stays");
+  assert_eq!(map.len(), 1);
+}
+
+#[test]
+fn test_card_numbers_need_a_valid_checksum()
+{ let (text, map) = redact("Visa 4111 1111 1111 1111, Amex 378282246310005, ref 1234567890123");
+  assert_eq!(text, "Visa [CARD_1], Amex [CARD_2], ref 1234567890123");
+  assert_eq!(map["[CARD_1]"], "4111 1111 1111 1111");
+  assert_eq!(map["[CARD_2]"], "378282246310005");
+}
+
+#[test]
+fn test_ip_addresses()
+{ let (text, _) = redact("From 192.168.0.12 and 2001:0db8:85a3:0000:0000:8a2e:0370:7334, not 300.1.2.3");
+  assert_eq!(text, "From [IP_1] and [IP_2], not 300.1.2.3");
+}
+
+#[test]
+fn test_disabled_kinds_and_custom_patterns()
+{ let config = PiiConfig
+  { redact_emails: false
+  , custom_patterns: vec![r"EMP-\d{5}".to_string()]
+  , ..Default::default()
+  };
+  let redactor = PiiRedactor::new(config).expect("valid patterns");
+  let mut map = HashMap::new();
+  assert_eq!
+  ( redactor.redact("EMP-00042 is eve@example.com", &mut map)
+  , "[CUSTOM_1] is eve@example.com"
+  );
+
+  let invalid = PiiConfig { custom_patterns: vec!["(".to_string()], ..Default::default() };
+  assert!(matches!(PiiRedactor::new(invalid), Err(allm::Error::InvalidConfiguration(_))));
+}
+
+#[test]
+fn test_unredact_restores_original_text()
+{ let original = "Reach jo@example.com at 555-010-4477 from 10.0.0.1";
+  let (text, map) = redact(original);
+  assert!(!text.contains("jo@example.com"));
+  assert_eq!(PiiRedactor::unredact(&text, &map), original);
+}
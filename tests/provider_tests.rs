@@ -0,0 +1,709 @@
+// allm/tests/provider_tests.rs
+//
+// Provider clients exercised against a local mock server.
+
+mod common;
+
+use allm::config::{AllmConfig, ProviderConfig};
+use allm::providers::openai::BatchItem;
+use allm::{AllmBackend, Provider};
+use common::{chat_completion, MockResponse, MockServer};
+use std::time::Duration;
+use tokio::time::timeout;
+
+async fn recv_text(
+  mut rx: tokio::sync::mpsc::UnboundedReceiver<allm::SendPromptReply>
+) -> allm::SendPromptReply
+{ timeout(Duration::from_secs(5), rx.recv())
+    .await
+    .expect("Timeout waiting for reply")
+    .expect("Reply channel closed")
+}
+
+#[tokio::test]
+async fn test_cloudflare_interpolates_account_id()
+{ let server = MockServer::start().await;
+  server.respond
+  ( "/client/v4/accounts/acct-123/ai/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("hello from workers"))
+  );
+
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "cloudflare".to_string()
+    , api_base: Some(format!("{}/client/v4", server.url()))
+    , api_key: Some("cf-token".to_string())
+    , account_id: Some("acct-123".to_string())
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await
+    .expect("valid config");
+
+  let rx = backend
+    .send_prompt_to
+    ( Provider::CloudflareAi
+    , "hi".to_string()
+    , "@cf/meta/llama-3.1-8b-instruct".to_string()
+    )
+    .await
+    .expect("queue prompt");
+  assert_eq!(recv_text(rx).await.unwrap(), "hello from workers");
+
+  let requests = server.requests();
+  assert_eq!(requests.len(), 1);
+  assert_eq!
+  ( requests[0].path
+  , "/client/v4/accounts/acct-123/ai/v1/chat/completions"
+  );
+  assert_eq!(requests[0].header("authorization"), Some("Bearer cf-token"));
+
+  assert_eq!
+  ( allm::providers::cloudflare::chat_url
+    ( "https://api.cloudflare.com/client/v4/", "abc" )
+  , "https://api.cloudflare.com/client/v4/accounts/abc/ai/v1/chat/completions"
+  );
+
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_cloudflare_requires_account_id()
+{ let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "cloudflare".to_string()
+    , api_key: Some("cf-token".to_string())
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  assert!(matches!
+  ( AllmBackend::new_with_config(config).await
+  , Err(allm::Error::InvalidConfiguration(_))
+  ));
+}
+
+#[tokio::test]
+async fn test_cerebras_chat_and_model_listing()
+{ use allm::providers::{CerebrasClient, ProviderClient};
+
+  let server = MockServer::start().await;
+  server.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("fast answer"))
+  );
+  server.respond
+  ( "/v1/models"
+  , MockResponse::json(200, serde_json::json!({
+      "object": "list",
+      "data": [
+        { "id": "llama3.1-8b", "object": "model" },
+        { "id": "llama-3.3-70b", "object": "model" }
+      ]
+    }))
+  );
+
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "cerebras".to_string()
+    , api_base: Some(format!("{}/v1", server.url()))
+    , api_key: Some("csk-test".to_string())
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await
+    .expect("valid config");
+  let rx = backend
+    .send_prompt_to(Provider::Cerebras, "hi".to_string(), "llama3.1-8b".to_string())
+    .await
+    .expect("queue prompt");
+  assert_eq!(recv_text(rx).await.unwrap(), "fast answer");
+  let chat = &server.requests_to("/v1/chat/completions")[0];
+  assert_eq!(chat.header("authorization"), Some("Bearer csk-test"));
+  assert_eq!(chat.json()["model"], "llama3.1-8b");
+
+  let client = CerebrasClient::new
+  ( Some("csk-test".to_string())
+  , Some(format!("{}/v1", server.url()))
+  , reqwest::Client::new()
+  );
+  let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+  client.get_available_models(tx).expect("queue get_models");
+  let models = timeout(Duration::from_secs(5), rx.recv())
+    .await
+    .expect("Timeout waiting for models")
+    .expect("channel closed")
+    .expect("models");
+  assert_eq!(models, vec!["llama3.1-8b", "llama-3.3-70b"]);
+
+  let info = allm::providers::cerebras::default_model_info();
+  assert_eq!(info.name, "llama3.1-8b");
+  assert_eq!(info.provider, Provider::Cerebras);
+}
+
+#[test]
+fn test_model_info_round_trips_through_json()
+{ for info in
+  [ allm::providers::mistral::default_model_info()
+  , allm::providers::cerebras::default_model_info()
+  ]
+  { let json = serde_json::to_value(&info).expect("serialize");
+    let back: allm::ModelInfo = serde_json::from_value(json).expect("deserialize");
+    assert_eq!(back, info);
+  }
+
+  let modality = allm::InputModality::Combined(allm::CombinedModality
+  { modalities: vec![allm::BaseModality::Text, allm::BaseModality::Image]
+  });
+  assert_eq!
+  ( serde_json::to_value(&modality).unwrap()
+  , serde_json::json!({ "type": "combined", "value": { "modalities": ["text", "image"] } })
+  );
+  assert_eq!
+  ( serde_json::to_value(allm::InputModality::Single(allm::BaseModality::File)).unwrap()
+  , serde_json::json!({ "type": "single", "value": "file" })
+  );
+}
+
+#[test]
+fn test_stop_sequences_serialize_only_when_set()
+{ use allm::providers::{mistral, openai_compat, ChatRequest};
+  let request = ChatRequest::from_prompt("hi".to_string(), "m".to_string());
+  let mistral_body = serde_json::to_value(mistral::chat_body(request.clone(), false)).unwrap();
+  let compat_body = serde_json::to_value(openai_compat::chat_body(request.clone(), false)).unwrap();
+  assert!(mistral_body.get("stop").is_none());
+  assert!(compat_body.get("stop").is_none());
+
+  let stopped = ChatRequest { stop: Some(vec!["\n".to_string()]), ..request };
+  let mistral_body = serde_json::to_value(mistral::chat_body(stopped.clone(), false)).unwrap();
+  let compat_body = serde_json::to_value(openai_compat::chat_body(stopped, false)).unwrap();
+  assert_eq!(mistral_body["stop"], serde_json::json!(["\n"]));
+  assert_eq!(compat_body["stop"], serde_json::json!(["\n"]));
+}
+
+#[test]
+fn test_roles_serialize_to_wire_names()
+{ use allm::providers::mistral::{ChatMessage, Role};
+  for (role, wire) in
+  [ (Role::System, "system")
+  , (Role::User, "user")
+  , (Role::Assistant, "assistant")
+  , (Role::Tool, "tool")
+  ]
+  { assert_eq!(serde_json::to_value(role).unwrap(), wire);
+    assert_eq!(serde_json::from_value::<Role>(serde_json::json!(wire)).unwrap(), role);
+    assert_eq!(role.to_string(), wire);
+  }
+  assert!(serde_json::from_value::<Role>(serde_json::json!("asistant")).is_err());
+
+  let request = allm::providers::ChatRequest
+  { messages: vec![ChatMessage::system("Be brief."), ChatMessage::user("hi"), ChatMessage::assistant("hello")]
+  , model: "m".to_string()
+  , ..Default::default()
+  };
+  let body = serde_json::to_value(allm::providers::mistral::chat_body(request, false)).unwrap();
+  assert_eq!
+  ( body["messages"]
+  , serde_json::json!(
+    [ { "role": "system", "content": "Be brief." }
+    , { "role": "user", "content": "hi" }
+    , { "role": "assistant", "content": "hello" }
+    ])
+  );
+}
+
+#[test]
+fn test_strip_stop_sequence_removes_trailing_sequence_only()
+{ use allm::providers::strip_stop_sequence;
+  let stop = vec!["###".to_string(), String::new()];
+  assert_eq!(strip_stop_sequence("answer###", &stop), "answer");
+  assert_eq!(strip_stop_sequence("a ### b", &stop), "a ### b");
+  assert_eq!(strip_stop_sequence("answer", &[]), "answer");
+}
+
+#[tokio::test]
+async fn test_openai_azure_deployment_url_and_api_key()
+{ let server = MockServer::start().await;
+  server.respond
+  ( "/openai/deployments/gpt4o-prod/chat/completions"
+  , MockResponse::json(200, chat_completion("hello from azure"))
+  );
+
+  let azure = allm::config::AzureConfig
+  { resource: "contoso".to_string()
+  , deployment: "gpt4o-prod".to_string()
+  , api_version: "2024-06-01".to_string()
+  };
+  assert_eq!
+  ( azure.chat_url(None)
+  , "https://contoso.openai.azure.com/openai/deployments/gpt4o-prod\
+/chat/completions?api-version=2024-06-01"
+  );
+
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "openai".to_string()
+    , api_base: Some(server.url())
+    , api_key: Some("azure-key".to_string())
+    , azure: Some(azure)
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await
+    .expect("valid config");
+  let rx = backend
+    .send_prompt_to(Provider::OpenAI, "hi".to_string(), "gpt-4o".to_string())
+    .await
+    .expect("queue prompt");
+  assert_eq!(recv_text(rx).await.unwrap(), "hello from azure");
+
+  let requests = server.requests();
+  assert_eq!(requests.len(), 1);
+  assert_eq!
+  ( requests[0].path
+  , "/openai/deployments/gpt4o-prod/chat/completions?api-version=2024-06-01"
+  );
+  assert_eq!(requests[0].header("api-key"), Some("azure-key"));
+  assert_eq!(requests[0].header("authorization"), None);
+
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_openai_without_azure_uses_bearer()
+{ let server = MockServer::start().await;
+  server.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("hello from openai"))
+  );
+
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "openai".to_string()
+    , api_base: Some(format!("{}/v1", server.url()))
+    , api_key: Some("sk-test".to_string())
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await
+    .expect("valid config");
+  let rx = backend
+    .send_prompt_to(Provider::OpenAI, "hi".to_string(), "gpt-4o".to_string())
+    .await
+    .expect("queue prompt");
+  assert_eq!(recv_text(rx).await.unwrap(), "hello from openai");
+
+  let chat = &server.requests_to("/v1/chat/completions")[0];
+  assert_eq!(chat.header("authorization"), Some("Bearer sk-test"));
+  assert_eq!(chat.header("api-key"), None);
+}
+
+#[tokio::test]
+async fn test_user_agent_default_and_provider_override()
+{ let server = MockServer::start().await;
+  server.respond
+  ( "/openai/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("from openai"))
+  );
+  server.respond
+  ( "/cerebras/v1/chat/completions"
+  , MockResponse::json(200, chat_completion("from cerebras"))
+  );
+
+  let mut config = AllmConfig
+  { providers: vec!
+    [ ProviderConfig
+      { name: "openai".to_string()
+      , api_base: Some(format!("{}/openai/v1", server.url()))
+      , api_key: Some("sk-test".to_string())
+      , ..Default::default()
+      }
+    , ProviderConfig
+      { name: "cerebras".to_string()
+      , api_base: Some(format!("{}/cerebras/v1", server.url()))
+      , api_key: Some("csk-test".to_string())
+      , ..Default::default()
+      }
+    ]
+  , ..Default::default()
+  };
+  config.http_client_config.provider_specific_user_agents
+    .insert(Provider::Cerebras, "acme-partner/2.0".to_string());
+  let backend = AllmBackend::new_with_config(config).await
+    .expect("valid config");
+
+  for (provider, model) in
+  [ (Provider::OpenAI, "gpt-4o")
+  , (Provider::Cerebras, "llama3.1-8b")
+  ]
+  { let rx = backend
+      .send_prompt_to(provider, "hi".to_string(), model.to_string())
+      .await
+      .expect("queue prompt");
+    recv_text(rx).await.expect("reply");
+  }
+
+  let openai = &server.requests_to("/openai/")[0];
+  assert_eq!
+  ( openai.header("user-agent")
+  , Some(allm::config::DEFAULT_USER_AGENT)
+  );
+  assert!(openai.header("user-agent").unwrap().starts_with
+  ( &format!("allm/{} (rust; ", env!("CARGO_PKG_VERSION"))
+  ));
+  let cerebras = &server.requests_to("/cerebras/")[0];
+  assert_eq!(cerebras.header("user-agent"), Some("acme-partner/2.0"));
+}
+
+async fn replicate_backend(server: &MockServer) -> AllmBackend
+{ let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "replicate".to_string()
+    , api_base: Some(format!("{}/v1", server.url()))
+    , api_key: Some("r8-test".to_string())
+    , poll_interval_ms: Some(10)
+    , poll_timeout_secs: Some(5)
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  AllmBackend::new_with_config(config).await.expect("valid config")
+}
+
+fn prediction(server: &MockServer, status: &str, output: serde_json::Value)
+  -> serde_json::Value
+{ serde_json::json!({
+    "id": "pred-1",
+    "status": status,
+    "output": output,
+    "error": null,
+    "urls": {
+      "get": format!("{}/v1/predictions/pred-1", server.url()),
+      "cancel": format!("{}/v1/predictions/pred-1/cancel", server.url())
+    }
+  })
+}
+
+#[tokio::test]
+async fn test_replicate_polls_until_succeeded()
+{ let server = MockServer::start().await;
+  server.respond
+  ( "/v1/models/meta/meta-llama-3-8b-instruct/predictions"
+  , MockResponse::json(201, prediction(&server, "starting", serde_json::Value::Null))
+  );
+  server.respond
+  ( "/v1/predictions/pred-1"
+  , MockResponse::json(200, prediction(&server, "processing", serde_json::Value::Null))
+  );
+  server.respond
+  ( "/v1/predictions/pred-1"
+  , MockResponse::json(200, prediction(&server, "succeeded", serde_json::json!(["Hello", ",", " world"])))
+  );
+
+  let backend = replicate_backend(&server).await;
+  let rx = backend
+    .send_prompt_to
+    ( Provider::Replicate
+    , "hi".to_string()
+    , "meta/meta-llama-3-8b-instruct".to_string()
+    )
+    .await
+    .expect("queue prompt");
+  assert_eq!(recv_text(rx).await.unwrap(), "Hello, world");
+
+  let create = &server.requests_to("/v1/models/")[0];
+  assert_eq!(create.method, "POST");
+  assert_eq!(create.header("authorization"), Some("Bearer r8-test"));
+  assert_eq!(create.json()["input"]["prompt"], "hi");
+  let polls = server.requests_to("/v1/predictions/pred-1");
+  assert_eq!(polls.len(), 2);
+  assert!(polls.iter().all(|r| r.method == "GET"));
+}
+
+#[tokio::test]
+async fn test_replicate_failed_prediction_is_error()
+{ let server = MockServer::start().await;
+  server.respond
+  ( "/v1/predictions"
+  , MockResponse::json(201, prediction(&server, "starting", serde_json::Value::Null))
+  );
+  let mut failed = prediction(&server, "failed", serde_json::Value::Null);
+  failed["error"] = serde_json::json!("CUDA out of memory");
+  server.respond("/v1/predictions/pred-1", MockResponse::json(200, failed));
+
+  let backend = replicate_backend(&server).await;
+  let rx = backend
+    .send_prompt_to
+    ( Provider::Replicate
+    , "hi".to_string()
+    , "owner/model:5c7d5dc6dd8bf75c1acaa8565735e7986bc5b66206b55cca93cb72c9bf15ccaa".to_string()
+    )
+    .await
+    .expect("queue prompt");
+  match recv_text(rx).await
+  { Err(allm::Error::ApiError(msg)) => assert!(msg.contains("CUDA out of memory"))
+  , other => panic!("expected ApiError, got {:?}", other)
+  }
+  let create = &server.requests()[0];
+  assert_eq!(create.path, "/v1/predictions");
+  assert_eq!
+  ( create.json()["version"]
+  , "5c7d5dc6dd8bf75c1acaa8565735e7986bc5b66206b55cca93cb72c9bf15ccaa"
+  );
+}
+
+async fn openai_backend(server: &MockServer, max_poll_attempts: u32) -> AllmBackend
+{ let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "openai".to_string()
+    , api_base: Some(format!("{}/v1", server.url()))
+    , api_key: Some("sk-test".to_string())
+    , poll_interval_ms: Some(10)
+    , max_poll_attempts: Some(max_poll_attempts)
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  AllmBackend::new_with_config(config).await.expect("valid config")
+}
+
+fn run(status: &str) -> serde_json::Value
+{ serde_json::json!({ "id": "run_1", "object": "thread.run", "status": status })
+}
+
+#[tokio::test]
+async fn test_openai_assistant_thread_and_run()
+{ let server = MockServer::start().await;
+  server.respond("/v1/threads", MockResponse::json(200, serde_json::json!({ "id": "thread_1" })));
+  server.respond
+  ( "/v1/threads/thread_1/messages"
+  , MockResponse::json(200, serde_json::json!({ "id": "msg_1" }))
+  );
+  server.respond
+  ( "/v1/threads/thread_1/messages"
+  , MockResponse::json(200, serde_json::json!({ "data": [{
+      "id": "msg_2",
+      "role": "assistant",
+      "content": [{ "type": "text", "text": { "value": "Hi there", "annotations": [] } }]
+    }] }))
+  );
+  server.respond("/v1/threads/thread_1/runs", MockResponse::json(200, run("queued")));
+  server.respond("/v1/threads/thread_1/runs/run_1", MockResponse::json(200, run("in_progress")));
+  server.respond("/v1/threads/thread_1/runs/run_1", MockResponse::json(200, run("completed")));
+
+  let backend = openai_backend(&server, 10).await;
+  let thread = backend.create_thread().await.expect("thread");
+  assert_eq!(thread.id, "thread_1");
+  backend.add_thread_message(&thread.id, "hello".to_string()).await.expect("message");
+  assert_eq!
+  ( backend.run_assistant(&thread.id, "asst_1").await
+  , Ok("Hi there".to_string())
+  );
+
+  let message = &server.requests_to("/v1/threads/thread_1/messages")[0];
+  assert_eq!(message.json()["content"], "hello");
+  assert_eq!(message.header("openai-beta"), Some("assistants=v2"));
+  assert_eq!(message.header("authorization"), Some("Bearer sk-test"));
+  let runs = server.requests_to("/v1/threads/thread_1/runs");
+  assert_eq!(runs[0].json()["assistant_id"], "asst_1");
+  assert_eq!(runs.len(), 3);
+  assert_eq!(server.requests_to("/v1/threads/thread_1/messages?order=desc").len(), 1);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_openai_run_polling_gives_up()
+{ let server = MockServer::start().await;
+  server.respond("/v1/threads/thread_1/runs/run_1", MockResponse::json(200, run("in_progress")));
+
+  let backend = openai_backend(&server, 3).await;
+  assert_eq!
+  ( backend.get_run_result("thread_1", "run_1").await
+  , Err(allm::Error::Timeout)
+  );
+  assert_eq!(server.requests_to("/v1/threads/thread_1/runs/run_1").len(), 3);
+  backend.shutdown().await.expect("shutdown");
+}
+
+fn batch(status: &str) -> serde_json::Value
+{ serde_json::json!({
+    "id": "batch_1",
+    "object": "batch",
+    "status": status,
+    "output_file_id": "file-out",
+    "error_file_id": "file-err"
+  })
+}
+
+#[tokio::test]
+async fn test_openai_batch_uploads_polls_and_maps_results()
+{ let server = MockServer::start().await;
+  server.respond("/v1/files", MockResponse::json(200, serde_json::json!({ "id": "file-in" })));
+  server.respond("/v1/batches", MockResponse::json(200, batch("validating")));
+  server.respond("/v1/batches/batch_1", MockResponse::json(200, batch("in_progress")));
+  server.respond("/v1/batches/batch_1", MockResponse::json(200, batch("completed")));
+  let output = |id: &str, text: &str| serde_json::json!({
+    "id": format!("batch_req_{}", id),
+    "custom_id": id,
+    "response": { "status_code": 200, "body": chat_completion(text) },
+    "error": null
+  }).to_string();
+  server.respond
+  ( "/v1/files/file-out/content"
+  , MockResponse::text(200, &format!("{}\n{}\n", output("b", "second"), output("a", "first")))
+  );
+  server.respond
+  ( "/v1/files/file-err/content"
+  , MockResponse::text(200, "")
+  );
+
+  let backend = openai_backend(&server, 10).await;
+  let replies = backend.batch_ask
+  ( vec![("a".to_string(), "one".to_string()), ("b".to_string(), "two".to_string())]
+  , Provider::OpenAI
+  ).await;
+  assert_eq!
+  ( replies
+  , Ok(vec![("a".to_string(), "first".to_string()), ("b".to_string(), "second".to_string())])
+  );
+
+  let upload = &server.requests_to("/v1/files")[0];
+  assert!(upload.header("content-type").unwrap().starts_with("multipart/form-data; boundary="));
+  assert!(upload.body.contains("name=\"purpose\"\r\n\r\nbatch\r\n"));
+  let line: serde_json::Value = upload.body.lines()
+    .find(|line| line.contains("\"custom_id\":\"a\""))
+    .map(|line| serde_json::from_str(line).unwrap())
+    .expect("input line for a");
+  assert_eq!(line["method"], "POST");
+  assert_eq!(line["url"], "/v1/chat/completions");
+  assert_eq!(line["body"]["model"], "gpt-4o-mini");
+  assert_eq!(line["body"]["messages"][0]["content"], "one");
+
+  let create = &server.requests_to("/v1/batches")[0];
+  assert_eq!(create.json()["input_file_id"], "file-in");
+  assert_eq!(create.json()["endpoint"], "/v1/chat/completions");
+  assert_eq!(create.json()["completion_window"], "24h");
+  assert_eq!(server.requests_to("/v1/batches/batch_1").len(), 2);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_openai_batch_reports_failed_requests()
+{ let server = MockServer::start().await;
+  server.respond("/v1/files", MockResponse::json(200, serde_json::json!({ "id": "file-in" })));
+  server.respond("/v1/batches", MockResponse::json(200, batch("completed")));
+  server.respond("/v1/files/file-out/content", MockResponse::text(200, ""));
+  server.respond
+  ( "/v1/files/file-err/content"
+  , MockResponse::text(200, &serde_json::json!({
+      "custom_id": "a",
+      "response": null,
+      "error": { "code": "batch_expired", "message": "request expired" }
+    }).to_string())
+  );
+
+  let backend = openai_backend(&server, 10).await;
+  let results = backend.openai_batch(vec![BatchItem
+  { custom_id: "a".to_string()
+  , prompt: "one".to_string()
+  , model: Some("gpt-4o".to_string())
+  }]).await.expect("batch");
+  assert_eq!(results[0].custom_id, "a");
+  assert_eq!(results[0].response, Err(allm::Error::ApiError("request expired".to_string())));
+  assert!(server.requests_to("/v1/files")[0].body.contains("\"model\":\"gpt-4o\""));
+
+  assert!(matches!
+  ( backend.batch_ask(vec![], Provider::MistralAi).await
+  , Err(allm::Error::ProviderNotImplemented(_))
+  ));
+  backend.shutdown().await.expect("shutdown");
+}
+
+fn moderation(flagged: bool) -> serde_json::Value
+{ serde_json::json!({
+    "id": "modr-1",
+    "model": "omni-moderation-latest",
+    "results": [{
+      "flagged": flagged,
+      "categories": { "violence": flagged, "harassment": false, "self-harm": flagged },
+      "category_scores": { "violence": 0.91, "harassment": 0.002, "self-harm": 0.64 }
+    }]
+  })
+}
+
+#[tokio::test]
+async fn test_openai_moderation_reports_flagged_categories()
+{ let server = MockServer::start().await;
+  server.respond("/v1/moderations", MockResponse::json(200, moderation(true)));
+
+  let backend = openai_backend(&server, 10).await;
+  let result = backend.moderate("some text".to_string()).await.expect("moderation");
+  assert!(result.flagged);
+  assert!(!result.categories["harassment"]);
+  assert!((result.category_scores["violence"] - 0.91).abs() < 1e-6);
+  assert_eq!(result.flagged_categories(), ["self-harm", "violence"]);
+
+  let request = &server.requests_to("/v1/moderations")[0];
+  assert_eq!(request.json()["input"], "some text");
+  assert_eq!(request.header("authorization"), Some("Bearer sk-test"));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_local_requests_are_hmac_signed()
+{ use allm::providers::{local, ProviderClient};
+  use allm::utils::auth::HmacSha256Signer;
+
+  let server = MockServer::start().await;
+  server.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("signed")));
+  server.respond("/v1/models", MockResponse::json(200, serde_json::json!({ "data": [{ "id": "llama3" }] })));
+
+  let client = local::new_with_signing
+  ( &format!("{}/v1", server.url())
+  , HmacSha256Signer::shared("key-1".to_string(), "test-secret".to_string())
+  );
+  let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+  client
+    .send_chat(allm::providers::ChatRequest::from_prompt("hi".to_string(), "llama3".to_string()), tx)
+    .expect("queue chat");
+  assert_eq!(recv_text(rx).await.unwrap(), "signed");
+  let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+  client.get_available_models(tx).expect("queue get_models");
+  timeout(Duration::from_secs(5), rx.recv()).await.expect("Timeout").expect("closed").expect("models");
+
+  let signer = HmacSha256Signer::new("key-1".to_string(), "test-secret".to_string());
+  for request in server.requests()
+  { assert_eq!(request.header("x-signature-keyid"), Some("key-1"));
+    assert_eq!
+    ( request.header("x-signature")
+    , Some(signer.signature(&request.method, &request.path, request.body.as_bytes()).as_str())
+    );
+  }
+
+  // Configured signing applies to the backend's clients
+  let config = AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "local".to_string()
+    , api_base: Some(format!("{}/v1", server.url()))
+    , hmac_signing: Some(allm::config::HmacSigningConfig
+      { key_id: "key-2".to_string()
+      , secret: "other-secret".to_string()
+      })
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+  let rx = backend
+    .send_prompt_to(Provider::Local, "hi".to_string(), "llama3".to_string())
+    .await
+    .expect("queue prompt");
+  assert_eq!(recv_text(rx).await.unwrap(), "signed");
+  let sent = server.requests_to("/v1/chat/completions").pop().expect("request");
+  assert_eq!(sent.header("x-signature-keyid"), Some("key-2"));
+  backend.shutdown().await.expect("shutdown");
+}
@@ -0,0 +1,359 @@
+// allm/tests/session_tests.rs
+//
+// Conversation sessions: history, pruning and summarization against
+// mock providers.
+
+mod common;
+
+use allm::config::{AllmConfig, ProviderConfig};
+use allm::session::{MessageRole, PruningStrategy, SummarizationStrategy, SUMMARY_PREFIX};
+use allm::{AllmBackend, Provider};
+use common::{chat_completion, MockResponse, MockServer};
+use std::time::Duration;
+use tokio::time::timeout;
+
+const SUMMARY: &str = "The user asked for two capitals.";
+
+async fn recv<T: std::fmt::Debug>
+( rx: Result<tokio::sync::mpsc::UnboundedReceiver<Result<T, allm::Error>>, allm::Error>
+) -> T
+{ let mut rx = rx.expect("queue command");
+  timeout(Duration::from_secs(5), rx.recv())
+    .await
+    .expect("Timeout waiting for reply")
+    .expect("Reply channel closed")
+    .expect("command failed")
+}
+
+/// Chat on Mistral, summaries written by Cerebras
+async fn backend(chat: &MockServer, summarizer: &MockServer) -> AllmBackend
+{ summarizer.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(200, chat_completion(SUMMARY))
+  );
+  let config = AllmConfig
+  { providers: vec!
+    [ ProviderConfig
+      { name: "mistral".to_string()
+      , api_base: Some(format!("{}/v1", chat.url()))
+      , api_key: Some("mistral-key".to_string())
+      , ..Default::default()
+      }
+    , ProviderConfig
+      { name: "cerebras".to_string()
+      , api_base: Some(format!("{}/v1", summarizer.url()))
+      , api_key: Some("cerebras-key".to_string())
+      , ..Default::default()
+      }
+    ]
+  , ..Default::default()
+  };
+  AllmBackend::new_with_config(config).await.expect("valid config")
+}
+
+fn summary_model() -> (Provider, String)
+{ (Provider::Cerebras, "llama3.1-8b".to_string())
+}
+
+fn sent_messages(request: &common::RecordedRequest) -> Vec<(String, String)>
+{ request.json()["messages"]
+    .as_array()
+    .expect("messages array")
+    .iter()
+    .map(|m|
+    ( m["role"].as_str().unwrap().to_string()
+    , m["content"].as_str().unwrap().to_string()
+    ))
+    .collect()
+}
+
+#[tokio::test]
+async fn test_session_sends_history()
+{ let chat = MockServer::start().await;
+  let summarizer = MockServer::start().await;
+  chat.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("Paris.")));
+  chat.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("Berlin.")));
+  let backend = backend(&chat, &summarizer).await;
+
+  let id = recv(backend.create_session(10, PruningStrategy::DropOldest).await).await;
+  for prompt in ["Capital of France?", "And Germany?"]
+  { recv(backend
+      .send_session_prompt(id, prompt.to_string(), "mistral-small-latest".to_string())
+      .await
+    ).await;
+  }
+
+  let requests = chat.requests_to("/v1/chat/completions");
+  assert_eq!
+  ( sent_messages(&requests[1])
+  , [ ("user".to_string(), "Capital of France?".to_string())
+    , ("assistant".to_string(), "Paris.".to_string())
+    , ("user".to_string(), "And Germany?".to_string())
+    ]
+  );
+  let session = recv(backend.get_session(id).await).await;
+  assert_eq!(session.messages.len(), 4);
+
+  let missing = allm::utils::uuid::Uuid::new_v4();
+  let mut rx = backend
+    .send_session_prompt(missing, "hi".to_string(), "m".to_string())
+    .await
+    .unwrap();
+  assert_eq!(rx.recv().await.unwrap(), Err(allm::Error::SessionNotFound(missing)));
+}
+
+#[tokio::test]
+async fn test_summarize_session_compresses_history()
+{ let chat = MockServer::start().await;
+  let summarizer = MockServer::start().await;
+  chat.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("Paris.")));
+  chat.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("Berlin.")));
+  chat.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("Rome.")));
+  let backend = backend(&chat, &summarizer).await;
+
+  let id = recv(backend.create_session(10, PruningStrategy::DropOldest).await).await;
+  for prompt in ["Capital of France?", "And Germany?"]
+  { recv(backend
+      .send_session_prompt(id, prompt.to_string(), "mistral-small-latest".to_string())
+      .await
+    ).await;
+  }
+
+  let (provider, model) = summary_model();
+  let summary = recv(backend.summarize_session(id, provider, model).await).await;
+  assert_eq!(summary, SUMMARY);
+
+  let asked = &summarizer.requests_to("/v1/chat/completions")[0];
+  let prompt = &sent_messages(asked)[0].1;
+  assert!(prompt.starts_with("Summarize this conversation in 3-5 sentences"));
+  assert!(prompt.contains("User: Capital of France?"));
+  assert!(prompt.contains("Assistant: Berlin."));
+
+  let session = recv(backend.get_session(id).await).await;
+  assert_eq!(session.summary.as_deref(), Some(SUMMARY));
+  assert_eq!(session.messages.len(), 1);
+  assert_eq!(session.messages[0].role, MessageRole::System);
+  assert_eq!
+  ( session.messages[0].content
+  , format!("{} {}", SUMMARY_PREFIX, SUMMARY)
+  );
+
+  // The summary leads every later request
+  recv(backend
+    .send_session_prompt(id, "And Italy?".to_string(), "mistral-small-latest".to_string())
+    .await
+  ).await;
+  let last = chat.requests_to("/v1/chat/completions").pop().unwrap();
+  assert_eq!
+  ( sent_messages(&last)
+  , [ ("system".to_string(), format!("{} {}", SUMMARY_PREFIX, SUMMARY))
+    , ("user".to_string(), "And Italy?".to_string())
+    ]
+  );
+}
+
+#[tokio::test]
+async fn test_summary_pruning_runs_past_threshold()
+{ let chat = MockServer::start().await;
+  let summarizer = MockServer::start().await;
+  chat.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("ok")));
+  let backend = backend(&chat, &summarizer).await;
+
+  let pruning = PruningStrategy::Summary { model: summary_model() };
+  let id = recv(backend.create_session(4, pruning).await).await;
+  for turn in 1..=3
+  { recv(backend
+      .send_session_prompt(id, format!("turn {}", turn), "mistral-small-latest".to_string())
+      .await
+    ).await;
+  }
+  // The third turn pushed the history to 6 messages and started a
+  // summary; the next prompt waits for it
+  recv(backend
+    .send_session_prompt(id, "turn 4".to_string(), "mistral-small-latest".to_string())
+    .await
+  ).await;
+
+  assert_eq!(summarizer.requests_to("/v1/chat/completions").len(), 1);
+  let last = chat.requests_to("/v1/chat/completions").pop().unwrap();
+  assert_eq!
+  ( sent_messages(&last)
+  , [ ("system".to_string(), format!("{} {}", SUMMARY_PREFIX, SUMMARY))
+    , ("user".to_string(), "turn 4".to_string())
+    ]
+  );
+  let session = recv(backend.get_session(id).await).await;
+  assert_eq!(session.messages.len(), 3);
+  assert_eq!(session.summary.as_deref(), Some(SUMMARY));
+}
+
+#[tokio::test]
+async fn test_drop_oldest_pruning_keeps_latest_turns()
+{ let chat = MockServer::start().await;
+  let summarizer = MockServer::start().await;
+  chat.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("ok")));
+  let backend = backend(&chat, &summarizer).await;
+
+  let id = recv(backend.create_session(2, PruningStrategy::DropOldest).await).await;
+  for turn in 1..=3
+  { recv(backend
+      .send_session_prompt(id, format!("turn {}", turn), "mistral-small-latest".to_string())
+      .await
+    ).await;
+  }
+  let session = recv(backend.get_session(id).await).await;
+  let contents: Vec<_> = session.messages.iter().map(|m| m.content.as_str()).collect();
+  assert_eq!(contents, ["turn 3", "ok"]);
+  assert!(summarizer.requests().is_empty());
+}
+
+#[tokio::test]
+async fn test_exported_session_imports_under_new_id()
+{ let chat = MockServer::start().await;
+  let summarizer = MockServer::start().await;
+  chat.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("Paris.")));
+  let backend = backend(&chat, &summarizer).await;
+
+  let pruning = PruningStrategy::Summary { model: summary_model() };
+  let id = recv(backend.create_session(6, pruning.clone()).await).await;
+  recv(backend
+    .send_session_prompt(id, "Capital of France?".to_string(), "mistral-small-latest".to_string())
+    .await
+  ).await;
+
+  let json = backend.export_session(id).await.expect("export");
+  let imported = backend.import_session(&json).await.expect("import");
+  assert_ne!(imported, id);
+  let session = recv(backend.get_session(imported).await).await;
+  assert_eq!(session.id, imported);
+  assert_eq!(session.max_messages, 6);
+  assert_eq!(session.pruning, pruning);
+  let contents: Vec<_> = session.messages.iter().map(|m| m.content.as_str()).collect();
+  assert_eq!(contents, ["Capital of France?", "Paris."]);
+  assert!(json.contains(r#""role":"user""#));
+
+  assert!(matches!
+  ( backend.import_session(r#"{"id":"nope"}"#).await
+  , Err(allm::Error::ParseError(_))
+  ));
+  let missing = allm::utils::uuid::Uuid::new_v4();
+  assert_eq!
+  ( backend.export_session(missing).await
+  , Err(allm::Error::SessionNotFound(missing))
+  );
+}
+
+#[tokio::test]
+async fn test_pinned_session_keeps_its_model()
+{ let mistral = MockServer::start().await;
+  let cerebras = MockServer::start().await;
+  mistral.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from mistral")));
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  let backend = backend(&mistral, &cerebras).await;
+  let id = recv(backend.create_session(10, PruningStrategy::DropOldest).await).await;
+  recv(backend.pin_session_model(id, Provider::MistralAi, "mistral-small-latest").await).await;
+
+  // Turns naming another provider's model, directly or by alias,
+  // still go to the pinned one
+  recv(backend.set_model_aliases(std::collections::HashMap::from(
+    [("fast".to_string(), (Provider::Cerebras, "llama3.1-8b".to_string()))]
+  )).await).await;
+  for model in ["fast", "llama3.1-8b"]
+  { assert_eq!
+    ( recv(backend.send_session_prompt_to(id, Provider::Cerebras, "hi".to_string(), model.to_string()).await).await
+    , "from mistral"
+    );
+  }
+  assert!(cerebras.requests().is_empty());
+  let requests = mistral.requests_to("/v1/chat/completions");
+  assert_eq!(requests.len(), 2);
+  assert_eq!(requests[1].json()["model"], "mistral-small-latest");
+  let session = recv(backend.get_session(id).await).await;
+  assert_eq!(session.pinned_model, Some((Provider::MistralAi, "mistral-small-latest".to_string())));
+  assert_eq!(session.last_used_model, session.pinned_model);
+
+  let missing = allm::utils::uuid::Uuid::new_v4();
+  let mut rx = backend.pin_session_model(missing, Provider::MistralAi, "m").await.unwrap();
+  assert_eq!(rx.recv().await.unwrap(), Err(allm::Error::SessionNotFound(missing)));
+}
+
+#[tokio::test]
+async fn test_only_unpinned_sessions_fail_over()
+{ let mistral = MockServer::start().await;
+  let cerebras = MockServer::start().await;
+  mistral.respond
+  ( "/v1/chat/completions"
+  , MockResponse::json(401, serde_json::json!({ "message": "Unauthorized" }))
+  );
+  cerebras.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("from cerebras")));
+  let backend = backend(&mistral, &cerebras).await;
+  recv(backend.set_model_fallback_preference(vec![summary_model()]).await).await;
+
+  let unpinned = recv(backend.create_session(10, PruningStrategy::DropOldest).await).await;
+  assert_eq!
+  ( recv(backend.send_session_prompt(unpinned, "hi".to_string(), "mistral-small-latest".to_string()).await).await
+  , "from cerebras"
+  );
+  let session = recv(backend.get_session(unpinned).await).await;
+  assert_eq!(session.last_used_model, Some(summary_model()));
+
+  let pinned = recv(backend.create_session(10, PruningStrategy::DropOldest).await).await;
+  recv(backend.pin_session_model(pinned, Provider::MistralAi, "mistral-small-latest").await).await;
+  let mut rx = backend
+    .send_session_prompt(pinned, "hi".to_string(), "mistral-small-latest".to_string())
+    .await
+    .unwrap();
+  let reply = timeout(Duration::from_secs(5), rx.recv()).await.expect("reply").unwrap();
+  assert!(matches!(reply, Err(allm::Error::ProviderApiError { provider: Provider::MistralAi, .. })));
+  assert_eq!(cerebras.requests().len(), 1);
+  let session = recv(backend.get_session(pinned).await).await;
+  assert_eq!(session.last_used_model, None);
+}
+
+#[tokio::test]
+async fn test_rolling_summary_fires_near_the_context_window()
+{ let chat = MockServer::start().await;
+  let summarizer = MockServer::start().await;
+  chat.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("ok")));
+  let backend = backend(&chat, &summarizer).await;
+  // Each turn is about 21 tokens of a 120 token window
+  let mut model = allm::providers::mistral::default_model_info();
+  model.max_context_tokens = 120;
+  recv(backend.register_models(vec![model]).await).await;
+
+  let id = recv(backend.create_session(100, PruningStrategy::DropOldest).await).await;
+  let strategy = SummarizationStrategy
+  { trigger_percent: 50.0
+  , ..SummarizationStrategy::new(summary_model(), 2)
+  };
+  recv(backend.set_session_summarization(id, Some(strategy)).await).await;
+  for turn in 1..=3
+  { let prompt = format!("turn {} {}", turn, "x".repeat(73));
+    recv(backend
+      .send_session_prompt(id, prompt, "mistral-small-latest".to_string())
+      .await
+    ).await;
+  }
+
+  // The third turn filled half the window and summarized the first
+  let mut session = recv(backend.get_session(id).await).await;
+  for _ in 0..50
+  { if session.summary.is_some()
+    { break;
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    session = recv(backend.get_session(id).await).await;
+  }
+  assert_eq!(session.summary.as_deref(), Some(SUMMARY));
+  assert_eq!(session.messages.len(), 5);
+  assert_eq!
+  ( session.messages[0].content
+  , format!("{} {}", SUMMARY_PREFIX, SUMMARY)
+  );
+  assert!(session.messages[1].content.starts_with("turn 2"));
+
+  let asked = &summarizer.requests_to("/v1/chat/completions")[0];
+  let prompt = &sent_messages(asked)[0].1;
+  assert!(prompt.contains("User: turn 1"));
+  assert!(!prompt.contains("turn 2"));
+}
@@ -0,0 +1,359 @@
+// allm/tests/stream_tests.rs
+//
+// Streaming replies: how each of the three SSE stream endings reaches
+// the caller, backpressure from a slow consumer, and identical
+// streams sharing one request.
+
+mod common;
+
+use allm::config::{AllmConfig, DeduplicationConfig, ProviderConfig};
+use allm::providers::sse::{parse_frame, SseFrame, SseParser};
+use allm::{AllmBackend, CompletionStream, StreamChunk, StreamEnd};
+use futures_util::StreamExt;
+use common::{chat_completion, MockResponse, MockServer};
+use std::time::Duration;
+use tokio::time::timeout;
+
+fn delta(content: &str) -> String
+{ format!
+  ( "data: {}"
+  , serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": content } }] })
+  )
+}
+
+fn finish(reason: &str) -> String
+{ format!
+  ( "data: {}"
+  , serde_json::json!({ "choices": [{ "index": 0, "delta": {}, "finish_reason": reason }] })
+  )
+}
+
+fn mistral_config(server: &MockServer) -> AllmConfig
+{ AllmConfig
+  { providers: vec![ProviderConfig
+    { name: "mistral".to_string()
+    , api_base: Some(format!("{}/v1", server.url()))
+    , api_key: Some("mistral-key".to_string())
+    , ..Default::default()
+    }]
+  , ..Default::default()
+  }
+}
+
+fn respond_sse(server: &MockServer, events: &[String])
+{ let events: Vec<&str> = events.iter().map(|e| e.as_str()).collect();
+  server.respond
+  ( "/v1/chat/completions"
+  , MockResponse::sse(&events, Duration::from_millis(5))
+  );
+}
+
+async fn stream_backend(server: &MockServer, events: &[String]) -> AllmBackend
+{ respond_sse(server, events);
+  AllmBackend::new_with_config(mistral_config(server)).await.expect("valid config")
+}
+
+/// Every item of the stream, up to the channel closing
+async fn collect(backend: &AllmBackend) -> Vec<Result<StreamChunk, allm::Error>>
+{ let rx = backend
+    .send_prompt_stream("hi".to_string(), "mistral-small-latest".to_string())
+    .await
+    .expect("queue stream");
+  drain(rx).await
+}
+
+async fn drain
+( mut rx: CompletionStream
+) -> Vec<Result<StreamChunk, allm::Error>>
+{ let mut items = vec![];
+  while let Some(item) = timeout(Duration::from_secs(5), rx.recv())
+    .await
+    .expect("Timeout waiting for chunk")
+  { items.push(item);
+  }
+  items
+}
+
+fn text(items: &[Result<StreamChunk, allm::Error>]) -> String
+{ items.iter()
+    .filter_map(|i| i.as_ref().ok())
+    .map(|c| c.delta.as_str())
+    .collect()
+}
+
+#[tokio::test]
+async fn test_done_sentinel_ends_stream_cleanly()
+{ let server = MockServer::start().await;
+  let backend = stream_backend
+  ( &server
+  , &[delta("Hel"), delta("lo"), finish("stop"), "data: [DONE]".to_string()]
+  ).await;
+
+  let items = collect(&backend).await;
+  assert_eq!(text(&items), "Hello");
+  assert_eq!
+  ( items.last().unwrap()
+  , &Ok(StreamChunk::last(Some("stop".to_string()), StreamEnd::Done))
+  );
+  assert_eq!(server.requests()[0].json()["stream"], true);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_mid_stream_error_is_surfaced()
+{ let server = MockServer::start().await;
+  let error = serde_json::json!({ "error": { "message": "overloaded", "type": "server_error" } });
+  let backend = stream_backend
+  ( &server
+  , &[delta("Hel"), format!("data: {}", error), delta("never sent")]
+  ).await;
+
+  let items = collect(&backend).await;
+  assert_eq!(items.len(), 2);
+  assert_eq!(text(&items), "Hel");
+  assert_eq!(items[1], Err(allm::Error::ApiError("overloaded".to_string())));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_eof_without_done_keeps_last_finish_reason()
+{ let server = MockServer::start().await;
+  let backend = stream_backend
+  ( &server
+  , &[delta("cut "), delta("short"), finish("length")]
+  ).await;
+
+  let items = collect(&backend).await;
+  assert_eq!(text(&items), "cut short");
+  assert_eq!
+  ( items.last().unwrap()
+  , &Ok(StreamChunk::last(Some("length".to_string()), StreamEnd::Eof))
+  );
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_completion_stream_works_with_stream_combinators()
+{ let server = MockServer::start().await;
+  let backend = stream_backend
+  ( &server
+  , &[delta("Hel"), delta("lo"), finish("stop"), "data: [DONE]".to_string()]
+  ).await;
+
+  let stream = backend
+    .send_prompt_stream("hi".to_string(), "mistral-small-latest".to_string())
+    .await
+    .expect("queue stream");
+  let chunks: Vec<StreamChunk> = timeout
+  ( Duration::from_secs(5)
+  , stream.map(|chunk| chunk.expect("chunk")).collect()
+  ).await.expect("Timeout waiting for stream");
+  let text: String = chunks.iter().map(|c| c.delta.as_str()).collect();
+  assert_eq!(text, "Hello");
+  assert_eq!(chunks.last().and_then(|c| c.end), Some(StreamEnd::Done));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[test]
+fn test_sse_parser_joins_split_events()
+{ let mut parser = SseParser::new();
+  assert!(parser.push(b": keep-alive\n\ndata: [DO").is_empty());
+  assert_eq!(parser.push(b"NE]\r\n\r\ndata: {\"choices\":[]}"), ["[DONE]"]);
+  assert_eq!(parser.finish().as_deref(), Some("{\"choices\":[]}"));
+  assert_eq!(parse_frame("[DONE]"), Ok(SseFrame::Done));
+}
+
+#[tokio::test]
+async fn test_slow_consumer_holds_back_the_provider()
+{ let server = MockServer::start().await;
+  let mut events: Vec<String> = (0..8).map(|i| delta(&i.to_string())).collect();
+  events.push("data: [DONE]".to_string());
+  respond_sse(&server, &events);
+  server.respond("/v1/chat/completions", MockResponse::json(200, chat_completion("next")));
+  let config = AllmConfig { stream_buffer_size: 2, ..mistral_config(&server) };
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let mut stream = backend
+    .send_prompt_stream("count".to_string(), "mistral-small-latest".to_string())
+    .await
+    .expect("queue stream");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  // The whole response has arrived, but only a buffer's worth of it
+  // was handed over; the provider waits for the consumer
+  assert_eq!(stream.len(), 2);
+  let mut prompt = backend
+    .send_prompt("after".to_string(), "mistral-small-latest".to_string())
+    .await
+    .expect("queue prompt");
+  assert!(timeout(Duration::from_millis(200), prompt.recv()).await.is_err());
+
+  let mut received = String::new();
+  while let Some(chunk) = stream.recv().await
+  { received.push_str(&chunk.expect("chunk").delta);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+  }
+  assert_eq!(received, "01234567");
+  assert_eq!
+  ( timeout(Duration::from_secs(5), prompt.recv()).await.expect("prompt answered")
+  , Some(Ok("next".to_string()))
+  );
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_timed_out_checkpoint_resumes_from_partial_reply()
+{ let server = MockServer::start().await;
+  let stalled = MockResponse
+  { chunks: vec!
+    [ (Duration::ZERO, format!("{}\n\n", delta("Once ")).into_bytes())
+    , (Duration::ZERO, format!("{}\n\n", delta("upon ")).into_bytes())
+    , (Duration::from_secs(5), format!("{}\n\n", delta("never sent")).into_bytes())
+    ]
+  , ..MockResponse::sse(&[], Duration::ZERO)
+  };
+  server.respond("/v1/chat/completions", stalled);
+  respond_sse(&server, &[delta("a time"), finish("stop"), "data: [DONE]".to_string()]);
+  let mut config = mistral_config(&server);
+  config.providers[0].timeout_secs = Some(1);
+  let backend = AllmBackend::new_with_config(config).await.expect("valid config");
+
+  let mut rx = backend
+    .send_prompt_checkpointed
+    ( "story-1".to_string()
+    , "Tell me a story".to_string()
+    , "mistral-small-latest".to_string()
+    )
+    .await
+    .expect("queue prompt");
+  let reply = timeout(Duration::from_secs(10), rx.recv()).await.expect("reply");
+  assert_eq!(reply, Some(Ok("Once upon a time".to_string())));
+
+  let requests = server.requests();
+  assert_eq!(requests.len(), 2);
+  assert_eq!(requests[0].json()["messages"][0]["role"], "user");
+  assert_eq!
+  ( requests[1].json()["messages"][0]
+  , serde_json::json!({ "role": "system", "content": "Continue from: Once upon " })
+  );
+  assert_eq!(requests[1].header("x-request-id"), Some("story-1"));
+  // Completed, so the checkpoint is gone
+  assert_eq!(backend.get_partial_response("story-1".to_string()).await, Ok(None));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[test]
+fn test_resume_prefix_keeps_system_message()
+{ assert_eq!
+  ( allm::client::resume_system_message("Once upon ", Some("Be brief."))
+  , "Continue from: Once upon \n\nBe brief."
+  );
+  assert_eq!(allm::client::resume_system_message("x", None), "Continue from: x");
+}
+
+fn dedup_config(server: &MockServer, replay_buffer_size: usize) -> AllmConfig
+{ AllmConfig
+  { deduplication: DeduplicationConfig { enabled: true, ..Default::default() }
+  , stream_dedup_replay_buffer_size: replay_buffer_size
+  , ..mistral_config(server)
+  }
+}
+
+#[tokio::test]
+async fn test_identical_streams_share_one_request_under_load()
+{ let server = MockServer::start().await;
+  respond_sse
+  ( &server
+  , &[delta("Hel"), delta("lo"), finish("stop"), "data: [DONE]".to_string()]
+  );
+  let backend = AllmBackend::new_with_config(dedup_config(&server, 16))
+    .await
+    .expect("valid config");
+
+  let mut streams = vec![];
+  for _ in 0..50
+  { streams.push
+    ( backend
+        .send_prompt_stream("hi".to_string(), "mistral-small-latest".to_string())
+        .await
+        .expect("queue stream")
+    );
+  }
+  let other = backend
+    .send_prompt_stream("bye".to_string(), "mistral-small-latest".to_string())
+    .await
+    .expect("queue stream");
+  for rx in streams
+  { let items = drain(rx).await;
+    assert_eq!(text(&items), "Hello");
+    assert_eq!
+    ( items.last()
+    , Some(&Ok(StreamChunk::last(Some("stop".to_string()), StreamEnd::Done)))
+    );
+  }
+  assert_eq!(text(&drain(other).await), "Hello");
+  assert_eq!(server.requests_to("/v1/chat/completions").len(), 2);
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_stream_past_its_replay_buffer_takes_no_subscribers()
+{ let server = MockServer::start().await;
+  respond_sse
+  ( &server
+  , &[delta("Hel"), delta("lo"), finish("stop"), "data: [DONE]".to_string()]
+  );
+  let backend = AllmBackend::new_with_config(dedup_config(&server, 1))
+    .await
+    .expect("valid config");
+
+  assert_eq!(text(&collect(&backend).await), "Hello");
+  // Within the window, but the first chunks are gone from the buffer
+  assert_eq!(text(&collect(&backend).await), "Hello");
+  assert_eq!(server.requests_to("/v1/chat/completions").len(), 2);
+  backend.shutdown().await.expect("shutdown");
+}
+
+fn read_timeout_config(server: &MockServer) -> AllmConfig
+{ let mut config = mistral_config(server);
+  config.providers[0].timeout_secs = Some(1);
+  config
+}
+
+#[tokio::test]
+async fn test_read_timeout_applies_per_chunk_not_to_the_whole_stream()
+{ let server = MockServer::start().await;
+  let events: Vec<String> = (0..6).map(|i| delta(&i.to_string()))
+    .chain([finish("stop"), "data: [DONE]".to_string()])
+    .collect();
+  let events: Vec<&str> = events.iter().map(|e| e.as_str()).collect();
+  // 8 chunks 300ms apart outlast `timeout_secs`, but none waits for it
+  server.respond
+  ( "/v1/chat/completions"
+  , MockResponse::sse(&events, Duration::from_millis(300))
+  );
+  let backend = AllmBackend::new_with_config(read_timeout_config(&server))
+    .await
+    .expect("valid config");
+
+  let items = collect(&backend).await;
+  assert!(items.iter().all(|item| item.is_ok()), "{:?}", items);
+  assert_eq!(text(&items), "012345");
+  assert_eq!(items.last().unwrap().as_ref().unwrap().end, Some(StreamEnd::Done));
+  backend.shutdown().await.expect("shutdown");
+}
+
+#[tokio::test]
+async fn test_stalled_stream_times_out_after_its_last_chunk()
+{ let server = MockServer::start().await;
+  let mut response = MockResponse::sse(&[&delta("partial")], Duration::ZERO);
+  response.chunks.push((Duration::from_millis(2500), format!("{}\n\n", delta(" late")).into_bytes()));
+  server.respond("/v1/chat/completions", response);
+  let backend = AllmBackend::new_with_config(read_timeout_config(&server))
+    .await
+    .expect("valid config");
+
+  let items = collect(&backend).await;
+  assert_eq!(text(&items[..1]), "partial");
+  assert_eq!(items[1..], [Err(allm::Error::Timeout)]);
+  backend.shutdown().await.expect("shutdown");
+}
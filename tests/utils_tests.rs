@@ -0,0 +1,509 @@
+// allm/tests/utils_tests.rs
+//
+// Tests for the helpers in allm::utils.
+
+use allm::utils::auth::{hex, hmac_sha256, sha256, HmacSha256Signer, RequestSigner};
+use allm::utils::cost::{estimate_cost, load_price_table, price_for, PriceTable};
+use allm::utils::hash::content_hash;
+use allm::utils::json_schema::{parse_output, validate};
+use allm::utils::logger::{json_logger_builder, LogLevel, ProviderLogger};
+use allm::utils::normalize::{NormalizationRules, ResponseNormalizer};
+use allm::utils::prompt_splitter::{split_by_sentences, PromptLimits};
+use allm::utils::rate_limiter::{parse_reset, TokenBucketRateLimiter};
+use allm::utils::secrets::{secret_id, EnvVarSecretStore, MemorySecretStore, SecretStore};
+use allm::utils::telemetry::GenAiSpan;
+use allm::utils::thinking::ExtractThinking;
+use allm::utils::version::{is_floating, ModelVersionResolver};
+use allm::Provider;
+use std::time::Duration;
+
+#[tokio::test(start_paused = true)]
+async fn test_token_bucket_empties_then_waits()
+{ let mut bucket = TokenBucketRateLimiter::new(3, 1.0);
+
+  for _ in 0..3
+  { assert_eq!(bucket.acquire(1).await, Duration::ZERO);
+  }
+  // Empty: the next token arrives after one second at 1 token/s,
+  // and the one after that queues behind it
+  assert_eq!(bucket.acquire(1).await, Duration::from_secs(1));
+  assert_eq!(bucket.acquire(1).await, Duration::from_secs(2));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_token_bucket_refills_at_rate()
+{ let mut bucket = TokenBucketRateLimiter::per_minute(60);
+  assert_eq!(bucket.acquire(60).await, Duration::ZERO);
+  assert!(bucket.available().abs() < 1e-9);
+
+  tokio::time::advance(Duration::from_secs(10)).await;
+  assert!((bucket.available() - 10.0).abs() < 1e-6);
+  assert_eq!(bucket.acquire(10).await, Duration::ZERO);
+
+  // Refill never exceeds capacity
+  tokio::time::advance(Duration::from_secs(600)).await;
+  assert!((bucket.available() - 60.0).abs() < 1e-6);
+}
+
+#[tokio::test]
+async fn test_env_var_store_reads_process_environment()
+{ let store = EnvVarSecretStore::with_prefix("ALLM_TEST_ENV");
+  let id = secret_id(&Provider::MistralAi, None);
+  assert_eq!(store.var_name(&id), "ALLM_TEST_ENV_MISTRAL_KEY");
+  assert_eq!
+  ( store.get(&id).await
+  , Err(allm::Error::MissingApiKey("ALLM_TEST_ENV_MISTRAL_KEY".to_string()))
+  );
+
+  std::env::set_var("ALLM_TEST_ENV_MISTRAL_KEY", "from-env");
+  assert_eq!(store.get(&id).await, Ok("from-env".to_string()));
+
+  let model_id = secret_id(&Provider::MistralAi, Some("mistral-large"));
+  store.set(&model_id, "model-key").await.expect("set");
+  assert_eq!(store.get(&model_id).await, Ok("model-key".to_string()));
+  // Set keys shadow the environment, which is left alone
+  assert!(std::env::var("ALLM_TEST_ENV_MISTRAL_MISTRAL_LARGE_KEY").is_err());
+  store.set(&id, "set-key").await.expect("set");
+  assert_eq!(store.get(&id).await, Ok("set-key".to_string()));
+  assert_eq!(std::env::var("ALLM_TEST_ENV_MISTRAL_KEY").as_deref(), Ok("from-env"));
+  assert_eq!(EnvVarSecretStore::new().var_name("cerebras"), "ALLM_CEREBRAS_KEY");
+}
+
+#[tokio::test]
+async fn test_memory_store_keeps_set_secrets()
+{ let store = MemorySecretStore::new();
+  assert!(store.get("mistral").await.is_err());
+  store.set("mistral", "plain").await.expect("set");
+  assert_eq!(store.get("mistral").await, Ok("plain".to_string()));
+}
+
+#[test]
+fn test_content_hash_separates_parts()
+{ assert_eq!(content_hash(&["mistral", "hi"]), content_hash(&["mistral", "hi"]));
+  assert_ne!(content_hash(&["ab", "c"]), content_hash(&["a", "bc"]));
+}
+
+#[test]
+fn test_price_table_overrides_catalog_price()
+{ let path = std::env::temp_dir().join(format!("allm-prices-{}.json", std::process::id()));
+  std::fs::write
+  ( &path
+  , r#"{ "mistral": { "mistral-small-latest": { "input": 0.1, "output": 0.3 } } }"#
+  ).unwrap();
+  let table = load_price_table(&path).expect("price table");
+  std::fs::remove_file(&path).unwrap();
+
+  let small = allm::providers::mistral::default_model_info();
+  assert_eq!(small.cost_per_million_input_tokens, Some(0.14));
+  assert_eq!(price_for(&table, &small), Some((0.1, 0.3)));
+  let cost = estimate_cost(&table, &small, 1_000_000, 500_000).unwrap();
+  assert!((cost - 0.25).abs() < 1e-6);
+
+  // Models the table lacks keep the catalog's price
+  let cerebras = allm::providers::cerebras::default_model_info();
+  assert_eq!
+  ( price_for(&table, &cerebras)
+  , cerebras.cost_per_million_input_tokens.zip(cerebras.cost_per_million_output_tokens)
+  );
+  assert_eq!(price_for(&PriceTable::new(), &small), Some((0.14, 0.42)));
+  assert!(load_price_table(std::env::temp_dir().join("allm-no-such-prices.json")).is_err());
+}
+
+#[test]
+fn test_json_schema_validation_reports_first_mismatch()
+{ let schema = serde_json::json!(
+  { "type": "object"
+  , "required": ["name", "tags"]
+  , "additionalProperties": false
+  , "properties":
+    { "name": { "type": "string", "minLength": 1 }
+    , "age": { "type": "integer", "minimum": 0 }
+    , "tags": { "type": "array", "items": { "type": "string" } }
+    , "status": { "enum": ["active", "retired"] }
+    }
+  });
+  let ok = parse_output("```json\n{\"name\": \"Ada\", \"tags\": [\"math\"], \"age\": 36}\n```")
+    .expect("fenced JSON");
+  assert_eq!(validate(&schema, &ok), Ok(()));
+
+  let check = |json: serde_json::Value| validate(&schema, &json).unwrap_err();
+  assert_eq!(check(serde_json::json!({ "name": "Ada" })), "$: missing field tags");
+  assert_eq!
+  ( check(serde_json::json!({ "name": "Ada", "tags": ["a", 2] }))
+  , "$.tags[1]: expected string"
+  );
+  assert_eq!
+  ( check(serde_json::json!({ "name": "Ada", "tags": [], "age": -1 }))
+  , "$.age: below minimum 0"
+  );
+  assert_eq!
+  ( check(serde_json::json!({ "name": "Ada", "tags": [], "extra": 1 }))
+  , "$.extra: unexpected field"
+  );
+  assert!(check(serde_json::json!({ "name": "Ada", "tags": [], "status": "gone" }))
+    .starts_with("$.status: not one of"));
+  assert!(parse_output("not json").is_err());
+}
+
+#[test]
+fn test_provider_logger_level_from_config()
+{ let config = |verbose, log_level| allm::config::ProviderConfig
+  { name: "openai".to_string()
+  , verbose
+  , log_level
+  , ..Default::default()
+  };
+  assert_eq!(ProviderLogger::from_config(&config(None, None)).level(), log::Level::Debug);
+  assert_eq!(ProviderLogger::from_config(&config(Some(false), None)).level(), log::Level::Debug);
+  assert_eq!(ProviderLogger::from_config(&config(Some(true), None)).level(), log::Level::Trace);
+  // An explicit level wins over `verbose`
+  let logger = ProviderLogger::from_config(&config(Some(true), Some(LogLevel::Info)));
+  assert_eq!(logger.level(), log::Level::Info);
+  assert_eq!(logger.target(), "allm::providers::openai");
+  assert_eq!(serde_json::to_value(LogLevel::Warn).unwrap(), "warn");
+
+  let event = logger.exchange_event
+  ( "gpt-4o"
+  , &serde_json::json!({ "model": "gpt-4o" })
+  , &serde_json::json!({ "choices": [] })
+  , Duration::from_millis(42)
+  );
+  assert_eq!
+  ( event
+  , serde_json::json!(
+    { "event": "exchange"
+    , "provider": "openai"
+    , "model": "gpt-4o"
+    , "elapsed_ms": 42
+    , "request": { "model": "gpt-4o" }
+    , "response": { "choices": [] }
+    })
+  );
+}
+
+#[test]
+fn test_normalize_defaults_leave_text_alone()
+{ let rules = NormalizationRules::default();
+  for text in [" Paris", "Paris\n\n\n", "Certainly! Paris", "a  b", ""]
+  { assert_eq!(ResponseNormalizer::normalize(text, &rules), text);
+  }
+  // Missing fields deserialize to the defaults
+  let parsed: NormalizationRules = serde_json::from_str(r#"{ "trim_whitespace": true }"#).unwrap();
+  assert_eq!(parsed, NormalizationRules { trim_whitespace: true, ..Default::default() });
+}
+
+#[test]
+fn test_normalize_trims_and_limits_trailing_newlines()
+{ let trim = NormalizationRules { trim_whitespace: true, ..Default::default() };
+  // OpenAI's leading space, Ollama's trailing newlines
+  assert_eq!(ResponseNormalizer::normalize(" The capital is Paris.", &trim), "The capital is Paris.");
+  assert_eq!(ResponseNormalizer::normalize("Paris\n\n\n", &trim), "Paris");
+  assert_eq!(ResponseNormalizer::normalize(" \n\t ", &trim), "");
+
+  let one = NormalizationRules { max_trailing_newlines: 1, ..Default::default() };
+  assert_eq!(ResponseNormalizer::normalize("Paris\n \n\n", &one), "Paris\n");
+  // At or under the limit the tail is untouched
+  assert_eq!(ResponseNormalizer::normalize("Paris \n", &one), "Paris \n");
+  assert_eq!(ResponseNormalizer::normalize("Paris  ", &one), "Paris  ");
+  let none = NormalizationRules { max_trailing_newlines: 0, ..Default::default() };
+  assert_eq!(ResponseNormalizer::normalize("Paris\r\n", &none), "Paris");
+  // Newlines inside the reply are not trailing
+  assert_eq!(ResponseNormalizer::normalize("a\n\n\nb", &none), "a\n\n\nb");
+}
+
+#[test]
+fn test_normalize_collapses_internal_whitespace()
+{ let rules = NormalizationRules { collapse_internal_whitespace: true, ..Default::default() };
+  assert_eq!(ResponseNormalizer::normalize("The  capital \t is   Paris.", &rules), "The capital is Paris.");
+  // Indentation and blank lines survive, so code blocks stay intact
+  let code = "```\nfn main()\n{   println!(\"hi\");\n}\n```\n\nDone.";
+  assert_eq!(ResponseNormalizer::normalize(code, &rules), "```\nfn main()\n{ println!(\"hi\");\n}\n```\n\nDone.");
+  assert_eq!(ResponseNormalizer::normalize("    indented  line", &rules), "    indented line");
+}
+
+#[test]
+fn test_normalize_strips_leading_ai_prefixes()
+{ let rules = NormalizationRules { strip_leading_ai_prefixes: true, ..Default::default() };
+  let strip = |text| ResponseNormalizer::normalize(text, &rules);
+  assert_eq!(strip("As an AI assistant, I recommend Paris."), "I recommend Paris.");
+  assert_eq!(strip("As an AI language model, I don't have opinions."), "I don't have opinions.");
+  assert_eq!(strip("Certainly! Here is the list:"), "Here is the list:");
+  assert_eq!(strip("certainly!  Here is the list:"), "Here is the list:");
+  // Stacked openers all go; leading whitespace stays without trimming
+  assert_eq!(strip(" Sure! Certainly! Paris."), " Paris.");
+  // Only at the start, and only whole openers
+  assert_eq!(strip("Paris. Certainly! "), "Paris. Certainly! ");
+  assert_eq!(strip("Surely not."), "Surely not.");
+  assert_eq!(strip("Certainly!"), "Certainly!");
+  assert_eq!(strip("Sure, "), "");
+  // Non-ASCII text does not trip the comparison
+  assert_eq!(strip("Été à Paris"), "Été à Paris");
+
+  let all = NormalizationRules
+  { trim_whitespace: true
+  , collapse_internal_whitespace: true
+  , strip_leading_ai_prefixes: true
+  , max_trailing_newlines: 0
+  };
+  assert_eq!(ResponseNormalizer::normalize("  Of course!   Paris  is  lovely.\n\n", &all), "Paris is lovely.");
+}
+
+#[test]
+fn test_parse_reset_reads_seconds_and_go_durations()
+{ assert_eq!(parse_reset("20"), Some(Duration::from_secs(20)));
+  assert_eq!(parse_reset("1.5"), Some(Duration::from_millis(1500)));
+  assert_eq!(parse_reset("6m0s"), Some(Duration::from_secs(360)));
+  assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+  assert_eq!(parse_reset("1h2m3s"), Some(Duration::from_secs(3723)));
+  assert_eq!(parse_reset(""), None);
+  assert_eq!(parse_reset("soon"), None);
+}
+
+#[test]
+fn test_model_version_resolver_pins_floating_names()
+{ assert!(is_floating("mistral-small-latest"));
+  assert!(!is_floating("mistral-small-2409"));
+
+  let mut versions = ModelVersionResolver::new();
+  assert!(versions.needs_lookup("mistral-small-latest"));
+  assert!(!versions.needs_lookup("open-mistral-nemo"));
+  assert_eq!(versions.resolve("mistral-small-latest"), "mistral-small-latest");
+
+  versions.pin("mistral-small-latest", "mistral-small-2409");
+  assert!(!versions.needs_lookup("mistral-small-latest"));
+  assert_eq!(versions.resolve("mistral-small-latest"), "mistral-small-2409");
+  assert_eq!(versions.resolve("open-mistral-nemo"), "open-mistral-nemo");
+  assert_eq!(versions.pinned_versions().len(), 1);
+
+  versions.clear_cache();
+  assert!(versions.pinned_versions().is_empty());
+  assert!(versions.needs_lookup("mistral-small-latest"));
+}
+
+/// Subscriber keeping every field written to a span or event, as
+/// `(name, value)`
+#[derive(Clone, Default)]
+struct FieldRecorder
+{ fields: std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>
+}
+
+impl tracing::field::Visit for FieldRecorder
+{ fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug)
+  { self.fields.lock().unwrap().push((field.name().to_string(), format!("{:?}", value)));
+  }
+}
+
+impl tracing::Subscriber for FieldRecorder
+{ fn enabled(&self, _: &tracing::Metadata<'_>) -> bool { true }
+  fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id
+  { span.record(&mut self.clone());
+    tracing::span::Id::from_u64(1)
+  }
+  fn record(&self, _: &tracing::span::Id, values: &tracing::span::Record<'_>)
+  { values.record(&mut self.clone());
+  }
+  fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+  fn event(&self, event: &tracing::Event<'_>)
+  { event.record(&mut self.clone());
+  }
+  fn enter(&self, _: &tracing::span::Id) {}
+  fn exit(&self, _: &tracing::span::Id) {}
+}
+
+#[test]
+fn test_chat_span_records_gen_ai_attributes()
+{ let recorder = FieldRecorder::default();
+  let request = allm::providers::ChatRequest
+  { model: "mistral-small-latest".to_string()
+  , max_tokens: Some(256)
+  , temperature: Some(0.5)
+  , ..Default::default()
+  };
+  tracing::subscriber::with_default(recorder.clone(), || {
+    let span = GenAiSpan::chat(&Provider::MistralAi, &request);
+    span.record_usage(Some(&allm::providers::TokenUsage
+    { prompt_tokens: Some(12)
+    , completion_tokens: Some(3)
+    }));
+    span.record_error(&allm::Error::Timeout);
+  });
+
+  let fields = recorder.fields.lock().unwrap().clone();
+  let value = |name: &str| fields.iter()
+    .find(|(field, _)| field == name)
+    .map(|(_, value)| value.as_str())
+    .unwrap_or_else(|| panic!("{} not recorded", name));
+  assert_eq!(value("gen_ai.system"), "\"mistral_ai\"");
+  assert_eq!(value("gen_ai.operation.name"), "\"chat\"");
+  assert_eq!(value("gen_ai.request.model"), "mistral-small-latest");
+  assert_eq!(value("gen_ai.request.max_tokens"), "256");
+  assert_eq!(value("gen_ai.request.temperature"), "0.5");
+  assert_eq!(value("gen_ai.usage.input_tokens"), "12");
+  assert_eq!(value("gen_ai.usage.output_tokens"), "3");
+  assert_eq!(value("error.type"), "\"Timeout\"");
+  assert!(fields.iter().any(|(field, value)| field == "message" && value == "gen_ai.usage"));
+}
+
+#[test]
+fn test_sha256_and_hmac_match_known_vectors()
+{ assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+  assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+  // RFC 4231 test cases 2 and 6 (key longer than a block)
+  assert_eq!
+  ( hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?"))
+  , "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+  );
+  assert_eq!
+  ( hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First"))
+  , "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+  );
+}
+
+#[test]
+fn test_hmac_signer_signs_method_path_and_body_hash()
+{ let signer = HmacSha256Signer::new("key-1".to_string(), "test-secret".to_string());
+  let body = br#"{"model":"llama3"}"#;
+  let expected = "3c9e36a5157cb3260626d48f5c2992f7d59a4e0d1187c845fb5f413c6a07e899";
+  assert_eq!(signer.signature("POST", "/v1/chat/completions", body), expected);
+
+  let mut headers = reqwest::header::HeaderMap::new();
+  signer.sign("POST", "http://gateway.local/v1/chat/completions?trace=1", body, &mut headers)
+    .expect("sign");
+  assert_eq!(headers["x-signature-keyid"], "key-1");
+  assert_eq!(headers["x-signature"], expected);
+
+  assert!(matches!
+  ( signer.sign("POST", "not a url", body, &mut headers)
+  , Err(allm::Error::InvalidConfiguration(_))
+  ));
+}
+
+/// `Write` into a buffer the test keeps a handle on
+#[derive(Clone, Default)]
+struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for SharedBuffer
+{ fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+  { self.0.lock().unwrap().write(buf)
+  }
+
+  fn flush(&mut self) -> std::io::Result<()>
+  { Ok(())
+  }
+}
+
+#[test]
+fn test_json_logger_writes_one_object_per_record()
+{ use log::Log;
+
+  let buffer = SharedBuffer::default();
+  let logger = json_logger_builder()
+    .filter_level(log::LevelFilter::Trace)
+    .target(env_logger::Target::Pipe(Box::new(buffer.clone())))
+    .build();
+  let provider = ProviderLogger::new("mistral", log::Level::Trace);
+  let exchange = provider.exchange_event
+  ( "mistral-small-latest"
+  , &serde_json::json!({}), &serde_json::json!({}), Duration::from_millis(5)
+  );
+  for (target, message) in
+  [ ("allm::client", "Prompt 1 falling back".to_string())
+  , (provider.target(), "[mistral] Sending request".to_string())
+  , (provider.target(), format!("[mistral] {}", exchange))
+  ]
+  { logger.log(&log::Record::builder()
+      .level(log::Level::Info)
+      .target(target)
+      .args(format_args!("{}", message))
+      .build());
+  }
+  logger.flush();
+
+  let output = String::from_utf8(buffer.0.lock().unwrap().clone()).expect("utf-8");
+  let lines: Vec<serde_json::Value> = output.lines()
+    .map(|line| serde_json::from_str(line).expect("JSON line"))
+    .collect();
+  assert_eq!(lines.len(), 3);
+  for line in &lines
+  { for key in ["level", "target", "provider", "model", "message"]
+    { assert!(line.get(key).is_some(), "{} missing from {}", key, line);
+    }
+    assert_eq!(line["level"], "INFO");
+  }
+  assert_eq!(lines[0]["target"], "allm::client");
+  assert_eq!(lines[0]["provider"], serde_json::Value::Null);
+  assert_eq!(lines[1]["provider"], "mistral");
+  assert_eq!(lines[1]["message"], "Sending request");
+  assert_eq!(lines[2]["model"], "mistral-small-latest");
+}
+
+#[test]
+fn test_split_by_sentences_keeps_sentences_whole_within_the_limit()
+{ let text = "First sentence here. Second one! Third? Fourth sentence ends it.";
+  // 6 tokens is 24 characters a chunk
+  let chunks = split_by_sentences(text, 6);
+  assert_eq!(chunks, vec!["First sentence here.", "Second one! Third?", "Fourth sentence ends it."]);
+  assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 24));
+  assert_eq!(split_by_sentences(text, 1000), vec![text]);
+  assert!(split_by_sentences("  ", 10).is_empty());
+  // A sentence above the limit is broken between words
+  let long = split_by_sentences("one two three four five six seven eight", 3);
+  assert_eq!(long, vec!["one two", "three four", "five six", "seven eight"]);
+}
+
+#[test]
+fn test_prompt_limits_fit_and_truncate_by_bytes_and_tokens()
+{ let limits = PromptLimits { max_bytes: Some(10), max_tokens: None };
+  assert!(limits.fits("0123456789"));
+  assert!(!limits.fits("0123456789a"));
+  assert_eq!(limits.truncate("0123456789abc"), "0123456789");
+  // A cut never splits a character
+  assert_eq!(limits.truncate("012345678é"), "012345678");
+  // Chunks are cut by bytes, so multibyte text makes more of them
+  let text = "Ça va. Très bien, merci. Éé ééééé.";
+  let chunks = limits.split(text);
+  assert_eq!(chunks, vec!["Ça va.", "Très", "bien,", "merci.", "Éé", "ééééé", "."]);
+  assert!(chunks.iter().all(|chunk| limits.fits(chunk)));
+
+  let limits = PromptLimits { max_bytes: None, max_tokens: Some(2) };
+  assert!(limits.fits("12345678"));
+  assert!(!limits.fits("123456789"));
+  assert_eq!(limits.truncate("123456789"), "12345678");
+  assert_eq!(limits.split("One two. Three four."), vec!["One", "two.", "Three", "four."]);
+}
+
+#[test]
+fn test_extract_thinking_splits_a_leading_think_block()
+{ let thought = |text: &str| ExtractThinking::extract(text);
+  assert_eq!
+  ( thought("<think>\nParis is the capital.\n</think>\n\nParis")
+  , (Some("Paris is the capital.".to_string()), "Paris".to_string())
+  );
+  assert_eq!(thought("  <think>a</think>b"), (Some("a".to_string()), "b".to_string()));
+  // No tags, or an empty block
+  assert_eq!(thought("Paris"), (None, "Paris".to_string()));
+  assert_eq!(thought("<think>\n\n</think>\n\nParis"), (None, "Paris".to_string()));
+  // Templates that open the block in the prompt leave only its end
+  assert_eq!(thought("Recall the map.</think>Paris"), (Some("Recall the map.".to_string()), "Paris".to_string()));
+}
+
+#[test]
+fn test_extract_thinking_handles_nested_and_malformed_tags()
+{ let thought = |text: &str| ExtractThinking::extract(text);
+  // A nested block stays inside the thinking
+  assert_eq!
+  ( thought("<think>outer <think>inner</think> more</think>answer")
+  , (Some("outer <think>inner</think> more".to_string()), "answer".to_string())
+  );
+  // Unclosed blocks, including unbalanced nesting, keep the whole text
+  assert_eq!(thought("<think>cut off"), (None, "<think>cut off".to_string()));
+  assert_eq!
+  ( thought("<think>a <think>b</think>")
+  , (None, "<think>a <think>b</think>".to_string())
+  );
+  // Tags further in are part of the answer
+  let quoted = "Use <think>...</think> to show reasoning.";
+  assert_eq!(thought(quoted), (None, quoted.to_string()));
+  assert_eq!(thought("</think>"), (None, String::new()));
+  assert_eq!(thought("<thinking>x</thinking>"), (None, "<thinking>x</thinking>".to_string()));
+}